@@ -0,0 +1,12 @@
+use std::env;
+
+// Writes the domain as a JSON archive, for moving it to another server or backend.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let domname = args.get(1).expect("domain name");
+    let path = args.get(2).expect("archive file");
+    let dom = simplets::Domain::try_new(domname, "", 0).expect("open domain");
+    let archive = dom.export_archive().expect("database error");
+    std::fs::write(path, serde_json::to_string_pretty(&archive).expect("serialize archive")).expect("write archive");
+    dom.audit(None, "domain.archive", None, path).expect("database error");
+}
@@ -0,0 +1,13 @@
+use std::env;
+
+// Loads a JSON archive of export-archive into a new, empty domain.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let domname = args.get(1).expect("domain name");
+    let path = args.get(2).expect("archive file");
+    let archive: simplets::Archive = serde_json::from_str(&std::fs::read_to_string(path).expect("read archive")).expect("malformed archive");
+    let mut dom = simplets::Domain::try_new(domname, "", 0).expect("open domain");
+    dom.import_archive(&archive).expect("import archive");
+    println!("imported {} users and {} payments of {}", archive.users.len(), archive.payments.len(), archive.domain);
+    dom.audit(None, "domain.import", None, path).expect("database error");
+}
@@ -0,0 +1,20 @@
+use std::env;
+use std::fs::File;
+
+// Usage: import-history <domain> <history.csv> [members.csv]
+// Members (name,email,balance,id) are imported first, with zero balances the history makes up for.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let domname = args.get(1).expect("domain name");
+    let history = std::fs::read_to_string(args.get(2).expect("history file")).expect("read history");
+    let dom = simplets::Domain::try_new(domname, "", 0).expect("open domain");
+    if let Some(members) = args.get(3) {
+        let ids = dom.import_users_csv(File::open(members).expect("open members")).expect("import members");
+        println!("imported {} members", ids.len());
+        dom.audit(None, "user.import", None, &format!("{} members", ids.len())).expect("database error");
+    }
+    let payments = simplets::parse_history_csv(&history).unwrap_or_else(|line| panic!("malformed history on line {}", line));
+    let count = dom.import_payments(payments).expect("import history");
+    println!("imported {} payments", count);
+    dom.audit(None, "payment.import", None, &format!("{} payments", count)).expect("database error");
+}
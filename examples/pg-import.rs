@@ -0,0 +1,15 @@
+use std::env;
+use simplets::pg::PgLedger;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let domname = args.get(1).expect("domain name");
+    let params = args.get(2).expect("postgres connection string");
+    let dom = simplets::Domain::try_new(domname, "", 0).expect("open domain");
+    let pg = PgLedger::connect(params).expect("postgres connection");
+    pg.migrate().expect("postgres migration");
+    let conn = dom.reader().expect("database error");
+    let (users, payments) = pg.import_sqlite(&conn).expect("import failed");
+    dom.audit(None, "domain.export.postgres", None, &format!("{} users, {} payments", users, payments)).expect("database error");
+    println!("imported {} users and {} payments", users, payments);
+}
@@ -0,0 +1,166 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Amounts and balances in the smallest units, see `CurrencyFormat::unit`.
+//!
+//! The database stores both as signed 64-bit integers, so an `Amount` is at most `i64::MAX` and
+//! always turns into a `Balance`. Arithmetic is checked: what doesn't fit is `None` or
+//! `SimpletsError::AmountOutOfRange` instead of wrapping around, which SQLite would silently turn
+//! into a floating point number.
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use crate::SimpletsError;
+
+/// What a payment moves, never negative.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+/// An account's balance or a limit, negative when owed or exceeded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Balance(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(i64::MAX as u64);
+
+    /// `None` above `Amount::MAX`.
+    pub fn new(units: u64) -> Option<Amount> {
+        if units > Amount::MAX.0 { None } else { Some(Amount(units)) }
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).and_then(Amount::new)
+    }
+
+    pub fn saturating_sub(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_sub(other.0))
+    }
+
+    /// The same number as a balance, which always fits.
+    pub fn balance(self) -> Balance {
+        Balance(self.0 as i64)
+    }
+}
+
+impl Balance {
+    pub const ZERO: Balance = Balance(0);
+
+    pub fn new(units: i64) -> Balance {
+        Balance(units)
+    }
+
+    pub fn get(self) -> i64 {
+        self.0
+    }
+
+    /// The balance after receiving `amount`.
+    pub fn checked_add(self, amount: Amount) -> Option<Balance> {
+        self.0.checked_add(amount.balance().0).map(Balance)
+    }
+
+    /// The balance after paying `amount`.
+    pub fn checked_sub(self, amount: Amount) -> Option<Balance> {
+        self.0.checked_sub(amount.balance().0).map(Balance)
+    }
+
+    /// The limit left after paying `amount`, at worst `i64::MIN`.
+    pub fn saturating_sub(self, amount: Amount) -> Balance {
+        Balance(self.0.saturating_sub(amount.balance().0))
+    }
+
+    /// The part above zero, nothing of a debt.
+    pub fn positive(self) -> Amount {
+        Amount(self.0.max(0) as u64)
+    }
+}
+
+impl TryFrom<u64> for Amount {
+    type Error = SimpletsError;
+
+    fn try_from(units: u64) -> Result<Amount, SimpletsError> {
+        Amount::new(units).ok_or(SimpletsError::AmountOutOfRange)
+    }
+}
+
+impl TryFrom<i64> for Amount {
+    type Error = SimpletsError;
+
+    fn try_from(units: i64) -> Result<Amount, SimpletsError> {
+        u64::try_from(units).map(Amount).map_err(|_| SimpletsError::AmountOutOfRange)
+    }
+}
+
+impl From<Amount> for Balance {
+    fn from(amount: Amount) -> Balance {
+        amount.balance()
+    }
+}
+
+impl From<Balance> for i64 {
+    fn from(balance: Balance) -> i64 {
+        balance.0
+    }
+}
+
+impl std::fmt::Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::fmt::Display for Balance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ToSql for Amount {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0 as i64))
+    }
+}
+
+impl FromSql for Amount {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let units = i64::column_result(value)?;
+        u64::try_from(units).map(Amount).map_err(|_| FromSqlError::OutOfRange(units))
+    }
+}
+
+impl ToSql for Balance {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+impl FromSql for Balance {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        i64::column_result(value).map(Balance)
+    }
+}
+
+/// `Amount::try_from` where rusqlite errors are returned, too large is a failed conversion.
+pub(crate) fn sql_amount<T>(units: T) -> rusqlite::Result<Amount> where Amount: TryFrom<T, Error = SimpletsError> {
+    Amount::try_from(units).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
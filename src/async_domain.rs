@@ -0,0 +1,124 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use rusqlite::Result;
+use crate::{Domain, SimpletsError, Payment, PaymentFilter, Submitted, User};
+
+/// Async facade over a shared `Domain`. Queries run on tokio's blocking thread pool,
+/// so async callers (the web server) never stall their executor on SQLite.
+#[derive(Clone)]
+pub struct AsyncDomain {
+    inner: Arc<RwLock<Domain>>,
+}
+
+impl AsyncDomain {
+    pub fn new(domain: Domain) -> Self {
+        AsyncDomain { inner: Arc::new(RwLock::new(domain)) }
+    }
+
+    /// Direct access for cheap in-memory data like settings and the message catalog.
+    /// Don't hold the guard across an `.await`, and don't query the database through it.
+    pub fn read(&self) -> RwLockReadGuard<'_, Domain> {
+        self.inner.read().unwrap()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, Domain> {
+        self.inner.write().unwrap()
+    }
+
+    /// Runs `f` on the blocking thread pool with shared access to the domain. Events `f` logs
+    /// belong to the caller's tracing span.
+    pub async fn run<T, F>(&self, f: F) -> T
+        where T: Send + 'static, F: FnOnce(&Domain) -> T + Send + 'static {
+        let inner = self.inner.clone();
+        let span = tracing::Span::current();
+        unwind(tokio::task::spawn_blocking(move || span.in_scope(|| f(&inner.read().unwrap()))).await)
+    }
+
+    /// Like `run`, with exclusive access, for the few operations that change the domain itself.
+    pub async fn run_mut<T, F>(&self, f: F) -> T
+        where T: Send + 'static, F: FnOnce(&mut Domain) -> T + Send + 'static {
+        let inner = self.inner.clone();
+        let span = tracing::Span::current();
+        unwind(tokio::task::spawn_blocking(move || span.in_scope(|| f(&mut inner.write().unwrap()))).await)
+    }
+
+    /// Refuses new payments, waits for the queries already running, then drains the domain, see
+    /// `Domain::close` and `Domain::drain`.
+    pub async fn shutdown(&self) -> Result<usize> {
+        self.read().close();
+        self.run_mut(|d| d.drain()).await
+    }
+
+    pub async fn get_user(&self, id: i64) -> Result<User> {
+        self.run(move |d| d.get_user(id)).await
+    }
+
+    pub async fn get_user_by_number(&self, member_number: u64) -> Result<User> {
+        self.run(move |d| d.get_user_by_number(member_number)).await
+    }
+
+    pub async fn get_user_by_name(&self, name: &str) -> Result<User> {
+        let name = name.to_string();
+        self.run(move |d| d.get_user_by_name(&name)).await
+    }
+
+    pub async fn get_users(&self) -> Result<Vec<User>> {
+        self.run(|d| d.get_users()).await
+    }
+
+    pub async fn add_user(&self, name: &str, password: &str) -> Result<u64, SimpletsError> {
+        let (name, password) = (name.to_string(), password.to_string());
+        self.run(move |d| d.add_user(&name, &password)).await
+    }
+
+    pub async fn set_password(&self, user_id: i64, new_password: &str) -> Result<usize, SimpletsError> {
+        let new_password = new_password.to_string();
+        self.run(move |d| d.set_password(user_id, &new_password)).await
+    }
+
+    pub async fn get_payments_by_user(&self, user: i64, filter: PaymentFilter) -> Result<Vec<Payment>> {
+        self.run(move |d| d.get_payments_by_user(user, &filter)).await
+    }
+
+    pub async fn add_payment(&self, payer: i64, payee: i64, amount: u64, message: &str, category: Option<&str>,
+                             idempotency_key: Option<&str>) -> Result<Submitted, SimpletsError> {
+        let message = message.to_string();
+        let category = category.map(|c| c.to_string());
+        let key = idempotency_key.map(|k| k.to_string());
+        self.run(move |d| d.add_payment(payer, payee, amount, &message, category.as_deref(), key.as_deref())).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_event_payment(&self, event: u64, payer: i64, payee: i64, amount: u64, message: &str, category: Option<&str>,
+                                   idempotency_key: Option<&str>) -> Result<Submitted, SimpletsError> {
+        let message = message.to_string();
+        let category = category.map(|c| c.to_string());
+        let key = idempotency_key.map(|k| k.to_string());
+        self.run(move |d| d.add_event_payment(event, payer, payee, amount, &message, category.as_deref(), key.as_deref())).await
+    }
+
+    pub async fn authenticate_token(&self, token: &str) -> Result<User> {
+        let token = token.to_string();
+        self.run(move |d| d.authenticate_token(&token)).await
+    }
+}
+
+fn unwind<T>(result: std::result::Result<T, tokio::task::JoinError>) -> T {
+    result.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
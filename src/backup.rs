@@ -0,0 +1,118 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Encrypted backup files: `MAGIC`, a random nonce and the SQLite database encrypted with
+//! ChaCha20-Poly1305 under a 32 byte key, the `backup_key` config key in hex.
+
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use chacha20poly1305::aead::Aead;
+use rand::RngCore;
+use std::path::{Path, PathBuf};
+use crate::{Domain, DomainError};
+
+/// Start of every encrypted backup file.
+pub const MAGIC: &[u8] = b"SIMPLETS-BACKUP-1\n";
+const NONCE_LEN: usize = 12;
+
+/// Reads a key written as 64 hex digits.
+pub fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    hex::decode(hex.trim()).ok()?.try_into().ok()
+}
+
+pub fn encrypt(key: &[u8; 32], plain: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let sealed = ChaCha20Poly1305::new(key.into()).encrypt(Nonce::from_slice(&nonce), plain)
+        .expect("backup fits the cipher's length limit");
+    [MAGIC, &nonce, &sealed].concat()
+}
+
+/// Returns the database, or `None` if `data` isn't a backup or was encrypted with another key
+/// or changed since.
+pub fn decrypt(key: &[u8; 32], data: &[u8]) -> Option<Vec<u8>> {
+    let rest = data.strip_prefix(MAGIC)?;
+    if rest.len() < NONCE_LEN { return None }
+    let (nonce, sealed) = rest.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(key.into()).decrypt(Nonce::from_slice(nonce), sealed).ok()
+}
+
+/// Snapshot of the running domain, encrypted. The snapshot passes through a temporary file
+/// as the SQLite backup API writes to a database file.
+pub fn encrypted_snapshot(domain: &Domain, key: &[u8; 32]) -> Result<Vec<u8>, DomainError> {
+    let path = std::env::temp_dir().join(format!("simplets-backup-{}.sqlite", hex::encode(rand::random::<[u8; 8]>())));
+    let snapshot = domain.backup_to(&path).map_err(DomainError::from).and_then(|_| Ok(std::fs::read(&path)?));
+    let _ = std::fs::remove_file(&path);
+    Ok(encrypt(key, &snapshot?))
+}
+
+/// Writes a timestamped snapshot of the domain into `dir`, encrypted (`.backup`) if there is a
+/// key, else a plain `.sqlite` file, and removes all but the `keep` newest. The outcome is
+/// recorded in the audit log as `backup.created` or `backup.failed`.
+pub fn scheduled(domain: &Domain, dir: &Path, key: Option<&[u8; 32]>, keep: usize) -> Result<PathBuf, DomainError> {
+    let result = write_snapshot(domain, dir, key).and_then(|path| {
+        rotate(dir, &domain.name, keep)?;
+        Ok(path)
+    });
+    match &result {
+        Ok(path) => domain.audit(None, "backup.created", None, &path.display().to_string())?,
+        Err(e) => domain.audit(None, "backup.failed", None, &e.to_string())?,
+    }
+    result
+}
+
+fn write_snapshot(domain: &Domain, dir: &Path, key: Option<&[u8; 32]>) -> Result<PathBuf, DomainError> {
+    std::fs::create_dir_all(dir)?;
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    match key {
+        Some(key) => {
+            let path = dir.join(format!("{}-{}.backup", domain.name, stamp));
+            std::fs::write(&path, encrypted_snapshot(domain, key)?)?;
+            Ok(path)
+        }
+        None => {
+            let path = dir.join(format!("{}-{}.sqlite", domain.name, stamp));
+            domain.backup_to(&path)?;
+            Ok(path)
+        }
+    }
+}
+
+/// Deletes the snapshots of domain `name` in `dir` except the `keep` newest, returns the deleted.
+/// Other files are left alone.
+pub fn rotate(dir: &Path, name: &str, keep: usize) -> std::io::Result<Vec<PathBuf>> {
+    let prefix = format!("{}-", name);
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let file = entry?.file_name().to_string_lossy().to_string();
+        let stamp = match file.strip_prefix(&prefix).and_then(|f| f.strip_suffix(".backup").or_else(|| f.strip_suffix(".sqlite"))) {
+            Some(s) => s,
+            None => continue
+        };
+        if stamp.len() == 15 && stamp.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            snapshots.push(file);
+        }
+    }
+    // the timestamps sort chronologically
+    snapshots.sort_unstable_by(|a, b| b.cmp(a));
+    let mut deleted = Vec::new();
+    for file in snapshots.into_iter().skip(keep) {
+        let path = dir.join(file);
+        std::fs::remove_file(&path)?;
+        deleted.push(path);
+    }
+    Ok(deleted)
+}
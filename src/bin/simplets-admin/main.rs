@@ -0,0 +1,351 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Operator's tool for a domain on this machine, e.g. `simplets-admin user list`.
+//!
+//! The domain is found like the server finds it without a config file, see
+//! `DomainConfig::from_env`, or given with `--domain`, `--data-dir` and `--db`. Changes are
+//! recorded in the audit log without an actor.
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use clap::{Args, Parser, Subcommand};
+use rand::Rng;
+use simplets::{Domain, DomainConfig, PaymentFilter, Role, User};
+
+#[cfg(feature = "tui")]
+mod tui;
+
+#[derive(Parser)]
+#[command(name = "simplets-admin", about = "Manage a simplets domain")]
+struct Cli {
+    /// Domain name, `SIMPLETS_DOMAIN` or `lets` by default.
+    #[arg(long, global = true)]
+    domain: Option<String>,
+    /// Directory of the domain's database, `SIMPLETS_DATA_DIR` by default.
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+    /// Database file instead of the one in the data directory, `SIMPLETS_DB_PATH` by default.
+    #[arg(long, global = true)]
+    db: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Member accounts.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Payments.
+    #[command(subcommand)]
+    Payment(PaymentCommand),
+    /// API tokens.
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Copies the database to FILE, encrypted with `SIMPLETS_BACKUP_KEY` if it's set.
+    Backup { file: PathBuf },
+    /// Replaces the database with the backup in FILE, decrypted with `SIMPLETS_BACKUP_KEY` if
+    /// it's encrypted. Stop the server first, it keeps the settings of the replaced database.
+    Restore { file: PathBuf },
+    /// Corrects the stored balances and payment counters to what the payments give.
+    Recompute,
+    /// Charges every member with a positive balance RATE of it for PERIOD, once per period.
+    Demurrage {
+        /// Share of the balance, e.g. 0.01.
+        rate: f64,
+        /// The current month in the domain's timezone, e.g. 2022-05, by default.
+        period: Option<String>,
+    },
+    /// Checks the hash chain of the payments, fails if it's broken.
+    Verify,
+    /// Checks the consistency of the whole domain, fails if something is wrong.
+    Report,
+    /// Browses the members and books payments in the terminal.
+    #[cfg(feature = "tui")]
+    Tui,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Creates a member, prints their number and the password if it was generated.
+    Add {
+        name: String,
+        #[command(flatten)]
+        password: PasswordArg,
+    },
+    /// Lists all accounts with their balances and limits.
+    List,
+    /// Sets a new password, prints it if it was generated.
+    ResetPassword {
+        /// Member number or name.
+        user: String,
+        #[command(flatten)]
+        password: PasswordArg,
+    },
+    /// Stops the member's payments out until `unfreeze`.
+    Freeze {
+        /// Member number or name.
+        user: String,
+        #[arg(long)]
+        reason: String,
+        /// Stops payments to the member too.
+        #[arg(long)]
+        incoming: bool,
+    },
+    /// Lets a frozen member pay again.
+    Unfreeze {
+        /// Member number or name.
+        user: String,
+    },
+    /// Gives the user a role: member, treasurer, admin or auditor.
+    Role {
+        /// Member number or name.
+        user: String,
+        role: Role,
+    },
+}
+
+#[derive(Subcommand)]
+enum PaymentCommand {
+    /// Lists payments, newest first.
+    List {
+        /// Only those of this member, by number or name.
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Books a regular payment and its fee back to the payer.
+    Reverse {
+        id: u64,
+        #[arg(long)]
+        reason: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenCommand {
+    /// Issues an API token for the member and prints it, it isn't shown again.
+    Issue {
+        /// Member number or name.
+        user: String,
+        label: String,
+    },
+}
+
+#[derive(Args)]
+struct PasswordArg {
+    /// Password to set, a random one is generated without it.
+    #[arg(long)]
+    password: Option<String>,
+}
+
+impl PasswordArg {
+    /// The given password, or a generated one that is also returned for printing.
+    fn resolve(self) -> (String, Option<String>) {
+        match self.password {
+            Some(password) => (password, None),
+            None => {
+                let password: String = rand::thread_rng().sample_iter(rand::distributions::Alphanumeric).take(16).map(char::from).collect();
+                (password.clone(), Some(password))
+            }
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut config = DomainConfig::from_env();
+    if let Some(name) = &cli.domain {
+        config = config.name(name);
+    }
+    if let Some(dir) = cli.data_dir {
+        config = config.data_dir(dir);
+    }
+    if let Some(path) = cli.db {
+        config = config.db_path(path);
+    }
+    let result = config.load().map_err(Box::<dyn Error>::from)
+        .and_then(|mut domain| run(&mut domain, cli.command));
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the command, `false` when a check found a problem.
+fn run(domain: &mut Domain, command: Command) -> Result<bool, Box<dyn Error>> {
+    match command {
+        Command::User(UserCommand::Add { name, password }) => {
+            let (password, generated) = password.resolve();
+            simplets::validation::password(&name, &password)?;
+            let id = domain.add_user(&name, &password)?;
+            domain.audit(None, "user.create", Some(id as i64), &name)?;
+            println!("{}", domain.get_user(id as i64)?.member_number);
+            if let Some(password) = generated { println!("password: {}", password) }
+        }
+        Command::User(UserCommand::List) => {
+            let users = domain.get_users()?;
+            println!("number\tname\tbalance\tsend limit\treceive limit");
+            for user in users.iter() {
+                let (send_limit, receive_limit) = domain.get_limits(user.id)?;
+                println!("{}\t{}\t{}\t{}\t{}", user.member_number, user.name, domain.format_amount(user.credit),
+                         domain.format_amount(send_limit), domain.format_amount(receive_limit));
+            }
+            eprintln!("{} accounts", users.len());
+        }
+        Command::User(UserCommand::ResetPassword { user, password }) => {
+            let user = find_user(domain, &user)?;
+            let (password, generated) = password.resolve();
+            domain.set_password(user.id, &password)?;
+            domain.audit(None, "password.reset", Some(user.id), "")?;
+            if let Some(password) = generated { println!("password: {}", password) }
+        }
+        Command::User(UserCommand::Freeze { user, reason, incoming }) => {
+            let user = find_user(domain, &user)?;
+            domain.freeze_user(user.id, &reason, incoming)?;
+            domain.audit(None, "user.freeze", Some(user.id), &format!("{}{}", if incoming { "incoming too: " } else { "" }, reason))?;
+        }
+        Command::User(UserCommand::Unfreeze { user }) => {
+            let user = find_user(domain, &user)?;
+            domain.unfreeze_user(user.id)?;
+            domain.audit(None, "user.unfreeze", Some(user.id), "")?;
+        }
+        Command::User(UserCommand::Role { user, role }) => {
+            let user = find_user(domain, &user)?;
+            if domain.set_role(user.id, role)? == 0 { return Err(format!("{} is the last admin", user.name).into()) }
+            domain.audit(None, "user.role", Some(user.id), role.as_str())?;
+        }
+        Command::Payment(PaymentCommand::List { user, limit }) => {
+            let mut payments = match user {
+                Some(user) => domain.get_payments_by_user(find_user(domain, &user)?.id, &PaymentFilter::default())?,
+                None => domain.get_payments()?,
+            };
+            payments.sort_by_key(|p| std::cmp::Reverse(p.id));
+            println!("id\tcreated\tpayer\tpayee\tamount\tmessage");
+            for p in payments.iter().take(limit) {
+                println!("{}\t{}\t{}\t{}\t{}\t{}", p.id, p.created, p.payer, p.payee, domain.format_amount(p.amount as i64), p.message);
+            }
+        }
+        Command::Payment(PaymentCommand::Reverse { id, reason }) => {
+            let reversal = domain.reverse_payment(None, id, &reason)?;
+            println!("{}", reversal.id);
+        }
+        Command::Backup { file } => {
+            match simplets::env_setting("backup_key") {
+                Some(key) => {
+                    let key = simplets::backup::parse_key(&key).ok_or("SIMPLETS_BACKUP_KEY must be 64 hex digits")?;
+                    std::fs::write(&file, simplets::backup::encrypted_snapshot(domain, &key)?)?;
+                }
+                None => domain.backup_to(&file)?,
+            }
+            domain.audit(None, "domain.backup", None, &file.display().to_string())?;
+        }
+        Command::Restore { file } => {
+            let data = std::fs::read(&file)?;
+            if data.starts_with(simplets::backup::MAGIC) {
+                let key = simplets::env_setting("backup_key").ok_or("SIMPLETS_BACKUP_KEY is needed for an encrypted backup")?;
+                let key = simplets::backup::parse_key(&key).ok_or("SIMPLETS_BACKUP_KEY must be 64 hex digits")?;
+                let plain = simplets::backup::decrypt(&key, &data).ok_or("wrong key or damaged backup")?;
+                let tmp = std::env::temp_dir().join(format!("simplets-restore-{}.sqlite", std::process::id()));
+                std::fs::write(&tmp, plain)?;
+                let result = domain.restore_from(&tmp);
+                std::fs::remove_file(&tmp)?;
+                result?;
+            } else {
+                domain.restore_from(&file)?;
+            }
+            domain.audit(None, "domain.restore", None, &file.display().to_string())?;
+        }
+        Command::Recompute => {
+            let fixed = domain.recompute_balances()?;
+            for m in fixed.iter() {
+                println!("{}: credit {} -> {}, in {} -> {}, out {} -> {}",
+                         m.name, m.credit, m.actual_credit, m.payments_in, m.actual_in, m.payments_out, m.actual_out);
+                domain.audit(None, "balance.recompute", Some(m.id), &format!("credit {} -> {}", m.credit, m.actual_credit))?;
+            }
+            eprintln!("{} accounts fixed", fixed.len());
+        }
+        Command::Demurrage { rate, period } => {
+            let period = period.unwrap_or_else(|| domain.timezone.now().format("%Y-%m").to_string());
+            let charges = domain.apply_demurrage(rate, &period)?;
+            println!("number\tname\tcharged");
+            for (user, amount) in charges.iter() {
+                let user = domain.get_user(*user)?;
+                println!("{}\t{}\t{}", user.member_number, user.name, domain.format_amount(*amount as i64));
+            }
+            domain.audit(None, "demurrage.apply", None, &format!("{} for {}", rate, period))?;
+            eprintln!("{} accounts charged for {}", charges.len(), period);
+        }
+        Command::Token(TokenCommand::Issue { user, label }) => {
+            let user = find_user(domain, &user)?;
+            let token = domain.issue_token(user.id, &label, None)?;
+            domain.audit(None, "token.issue", Some(user.id), &label)?;
+            println!("{}", token);
+        }
+        Command::Verify => {
+            let chain = domain.verify_chain()?;
+            match chain.broken_at {
+                Some(id) => println!("hash chain broken at payment {} after {} verified", id, chain.entries),
+                None => println!("{} payments verified", chain.entries),
+            }
+            return Ok(chain.is_intact())
+        }
+        Command::Report => {
+            let report = domain.health_report()?;
+            println!("{} accounts", report.users);
+            if report.credit_sum != 0 {
+                println!("balances sum to {}", report.credit_sum);
+            }
+            for u in report.over_limit.iter() {
+                println!("{} holds {} over the receive limit", u.name, -u.receive_limit);
+            }
+            for id in report.orphaned_payments.iter() {
+                println!("payment {} references a missing account", id);
+            }
+            for m in report.mismatches.iter() {
+                println!("{} stores credit {} in {} out {}, payments give {} in {} out {}",
+                         m.name, m.credit, m.payments_in, m.payments_out, m.actual_credit, m.actual_in, m.actual_out);
+            }
+            if let Some(id) = report.chain.broken_at {
+                println!("hash chain broken at payment {} after {} verified", id, report.chain.entries);
+            }
+            return Ok(report.is_healthy())
+        }
+        #[cfg(feature = "tui")]
+        Command::Tui => tui::run(domain)?,
+    }
+    Ok(true)
+}
+
+/// The account with member number or name `key`.
+fn find_user(domain: &Domain, key: &str) -> Result<User, Box<dyn Error>> {
+    let user = match key.trim().parse() {
+        Ok(number) => domain.get_user_by_number(number),
+        Err(_) => domain.get_user_by_name(key.trim()),
+    };
+    user.map_err(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => format!("no account {}", key).into(),
+        e => e.into(),
+    })
+}
@@ -0,0 +1,232 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! `simplets-admin tui`: browsing the members and booking payments with the keyboard only, e.g.
+//! on a laptop at a market day. Payments go through `Domain::add_payment` as on the web, so the
+//! limits, fees and message rules apply.
+//!
+//! In the members, arrows or `j`/`k` move, `/` filters, `p` or Enter starts a payment from the
+//! selected member, `r` one to them and `q` quits. In the payment form Tab and Enter move to the
+//! next field, Enter on the message books the payment and Esc goes back. The payer stays filled
+//! in after a payment, for the next trade of the same stall.
+
+use std::collections::HashMap;
+use std::error::Error;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use simplets::{Domain, Payment, Submitted, User};
+
+/// Labels of the payment form, in the order of `App::form`.
+const FIELDS: [&str; 4] = ["payer", "payee", "amount", "message"];
+/// Payments shown below the form.
+const RECENT_PAYMENTS: usize = 100;
+
+enum Mode {
+    Members,
+    Filter,
+    Form,
+}
+
+struct App<'a> {
+    domain: &'a Domain,
+    users: Vec<User>,
+    /// Newest first.
+    payments: Vec<Payment>,
+    filter: String,
+    members: TableState,
+    mode: Mode,
+    /// Payer and payee (member number or name), amount and message as typed.
+    form: [String; 4],
+    field: usize,
+    status: String,
+}
+
+/// Runs the UI until the user quits, the terminal is restored even on errors.
+pub fn run(domain: &Domain) -> Result<(), Box<dyn Error>> {
+    let mut app = App::new(domain)?;
+    let mut terminal = ratatui::try_init()?;
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+/// The users matching `filter` by name or member number.
+fn visible<'u>(users: &'u [User], filter: &str) -> Vec<&'u User> {
+    let filter = filter.to_lowercase();
+    users.iter().filter(|u| u.name.to_lowercase().contains(&filter) || u.member_number.to_string().starts_with(&filter)).collect()
+}
+
+impl<'a> App<'a> {
+    fn new(domain: &'a Domain) -> rusqlite::Result<Self> {
+        let mut app = App {
+            domain, users: Vec::new(), payments: Vec::new(), filter: String::new(), members: TableState::default().with_selected(0),
+            mode: Mode::Members, form: Default::default(), field: 0, status: String::new(),
+        };
+        app.reload()?;
+        Ok(app)
+    }
+
+    fn reload(&mut self) -> rusqlite::Result<()> {
+        self.users = self.domain.get_users()?;
+        self.payments = self.domain.get_payments()?;
+        self.payments.sort_by_key(|p| std::cmp::Reverse(p.id));
+        self.payments.truncate(RECENT_PAYMENTS);
+        Ok(())
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Box<dyn Error>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.handle(key)? { return Ok(()) }
+            }
+        }
+    }
+
+    /// Handles a key press, `false` to quit.
+    fn handle(&mut self, key: KeyEvent) -> rusqlite::Result<bool> {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') { return Ok(false) }
+        match self.mode {
+            Mode::Members => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+                KeyCode::Down | KeyCode::Char('j') => self.members.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.members.select_previous(),
+                KeyCode::Char('/') => self.mode = Mode::Filter,
+                KeyCode::Char('p') | KeyCode::Enter => self.start_payment(0),
+                KeyCode::Char('r') => self.start_payment(1),
+                _ => {}
+            },
+            Mode::Filter => {
+                match key.code {
+                    KeyCode::Enter => self.mode = Mode::Members,
+                    KeyCode::Esc => {
+                        self.filter.clear();
+                        self.mode = Mode::Members;
+                    }
+                    KeyCode::Backspace => { self.filter.pop(); }
+                    KeyCode::Char(c) => self.filter.push(c),
+                    _ => {}
+                }
+                self.members.select(Some(0));
+            }
+            Mode::Form => match key.code {
+                KeyCode::Esc => self.mode = Mode::Members,
+                KeyCode::Tab | KeyCode::Down => self.field = (self.field + 1) % FIELDS.len(),
+                KeyCode::BackTab | KeyCode::Up => self.field = (self.field + FIELDS.len() - 1) % FIELDS.len(),
+                KeyCode::Enter if self.field + 1 < FIELDS.len() => self.field += 1,
+                KeyCode::Enter => self.submit()?,
+                KeyCode::Backspace => { self.form[self.field].pop(); }
+                KeyCode::Char(c) => self.form[self.field].push(c),
+                _ => {}
+            },
+        }
+        Ok(true)
+    }
+
+    /// Opens the form with the selected member as the payer (`side` 0) or payee (1).
+    fn start_payment(&mut self, side: usize) {
+        let users = visible(&self.users, &self.filter);
+        let Some(user) = self.members.selected().and_then(|i| users.get(i)) else { return };
+        self.form[side] = user.member_number.to_string();
+        self.field = if self.form[1 - side].is_empty() { 1 - side } else { 2 };
+        self.mode = Mode::Form;
+    }
+
+    fn find(&self, key: &str) -> Option<&User> {
+        let key = key.trim();
+        self.users.iter().find(|u| u.member_number.to_string() == key).or_else(|| self.users.iter().find(|u| u.name == key))
+    }
+
+    /// Books the payment in the form. Refused payments stay in the form to be corrected.
+    fn submit(&mut self) -> rusqlite::Result<()> {
+        let (payer, payee) = match (self.find(&self.form[0]), self.find(&self.form[1])) {
+            (Some(payer), Some(payee)) => (payer.id, payee.id),
+            (payer, _) => {
+                self.field = if payer.is_none() { 0 } else { 1 };
+                self.status = format!("no member {}", self.form[self.field].trim());
+                return Ok(())
+            }
+        };
+        let Some(amount) = self.domain.parse_amount(&self.form[2]) else {
+            self.field = 2;
+            self.status = format!("{} isn't an amount", self.form[2].trim());
+            return Ok(())
+        };
+        self.status = match self.domain.add_payment(payer, payee, amount, &self.form[3], None, None) {
+            Ok(Submitted::Booked(id)) => format!("payment {} of {} booked", id, self.domain.format_amount(amount as i64)),
+            Ok(Submitted::Pending(_)) => format!("payment of {} waits for the payee's confirmation", self.domain.format_amount(amount as i64)),
+            Err(e) => {
+                self.status = format!("payment refused: {}", e);
+                return Ok(())
+            }
+        };
+        for field in self.form[1..].iter_mut() { field.clear() }
+        self.field = 1;
+        self.reload()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(main);
+        let [form_area, payments_area] = Layout::vertical([Constraint::Length(FIELDS.len() as u16 + 2), Constraint::Min(0)]).areas(right);
+        let domain = self.domain;
+
+        let title = if self.filter.is_empty() { " members ".to_string() } else { format!(" members /{} ", self.filter) };
+        let rows = visible(&self.users, &self.filter).into_iter()
+            .map(|u| Row::new([u.member_number.to_string(), u.name.clone(), domain.format_amount(u.credit)]));
+        let members = Table::new(rows, [Constraint::Length(6), Constraint::Min(10), Constraint::Length(14)])
+            .header(Row::new(["number", "name", "balance"]).style(Style::new().add_modifier(Modifier::BOLD)))
+            .block(Block::bordered().title(title))
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(members, left, &mut self.members);
+
+        let editing = matches!(self.mode, Mode::Form);
+        let lines: Vec<Line> = FIELDS.iter().zip(self.form.iter()).enumerate().map(|(i, (label, value))| {
+            let style = if editing && i == self.field { Style::new().add_modifier(Modifier::BOLD) } else { Style::new() };
+            Line::from(vec![Span::styled(format!("{:>8}: ", label), style), Span::raw(value.as_str())])
+        }).collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" payment ")), form_area);
+        if editing {
+            let x = form_area.x + 11 + self.form[self.field].chars().count() as u16;
+            frame.set_cursor_position(Position::new(x.min(form_area.right().saturating_sub(2)), form_area.y + 1 + self.field as u16));
+        }
+
+        let names: HashMap<i64, &str> = self.users.iter().map(|u| (u.id, u.name.as_str())).collect();
+        let rows = self.payments.iter().map(|p| Row::new([
+            p.created.clone(),
+            names.get(&(p.payer as i64)).copied().unwrap_or_default().to_string(),
+            names.get(&(p.payee as i64)).copied().unwrap_or_default().to_string(),
+            domain.format_amount(p.amount as i64),
+            p.message.clone(),
+        ]));
+        let payments = Table::new(rows, [Constraint::Length(19), Constraint::Min(8), Constraint::Min(8), Constraint::Length(12), Constraint::Min(10)])
+            .block(Block::bordered().title(" recent payments "));
+        frame.render_widget(payments, payments_area);
+
+        let hint = match self.mode {
+            Mode::Members => "↑↓ move  / filter  p pay from  r pay to  q quit",
+            Mode::Filter => "type to filter  Enter keep  Esc clear",
+            Mode::Form => "Tab next field  Enter on message book  Esc back",
+        };
+        let status_line = if self.status.is_empty() { hint.to_string() } else { format!("{}  |  {}", self.status, hint) };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+}
@@ -0,0 +1,141 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Payments between simplets servers, see `Domain::add_cross_domain_payment`. Peers post
+//! `TransferRequest`s to each other's `TRANSFER_PATH`, signed with their shared secret like
+//! webhook calls, and name themselves in the `DOMAIN_HEADER`.
+
+/// Header naming the calling domain.
+pub const DOMAIN_HEADER: &str = "X-Simplets-Domain";
+pub const TRANSFER_PATH: &str = "/api/federation/transfers";
+/// Answers with the peer's `BridgePosition` for the calling domain.
+pub const BALANCE_PATH: &str = "/api/federation/balance";
+
+/// Why talking to a peer failed.
+#[derive(Debug)]
+pub enum PeerError {
+    Db(rusqlite::Error),
+    /// The peer couldn't be reached or answered something unexpected.
+    Unreachable(String),
+    /// The peer answered with an error status.
+    Refused(u16, String),
+}
+
+impl std::fmt::Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerError::Db(e) => write!(f, "database error: {}", e),
+            PeerError::Unreachable(e) => write!(f, "peer unreachable: {}", e),
+            PeerError::Refused(status, body) => write!(f, "peer refused with {}: {}", status, body),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+impl From<rusqlite::Error> for PeerError {
+    fn from(e: rusqlite::Error) -> Self {
+        PeerError::Db(e)
+    }
+}
+
+#[cfg(feature = "federation")]
+pub use client::Client;
+
+#[cfg(feature = "federation")]
+mod client {
+    use std::time::Duration;
+    use rusqlite::Result;
+    use crate::{Domain, FederatedTransfer, Reconciliation, RemoteDomain, TransferRequest};
+    use crate::webhook::{sign, SIGNATURE_HEADER};
+    use super::{PeerError, BALANCE_PATH, DOMAIN_HEADER, TRANSFER_PATH};
+
+    /// Failed attempts after which an outgoing transfer is given up and refunded.
+    const MAX_ATTEMPTS: u64 = 10;
+
+    /// Posts transfers to peers and asks them for their bridge balance.
+    pub struct Client {
+        agent: ureq::Agent,
+    }
+
+    impl Default for Client {
+        fn default() -> Self {
+            Client { agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build() }
+        }
+    }
+
+    impl Client {
+        /// Posts up to `limit` due transfers and returns how many the peers booked. A transfer
+        /// the peer refuses (unknown payee, limits) is refunded right away, one that fails
+        /// otherwise is retried with backoff and refunded after `MAX_ATTEMPTS`.
+        pub fn deliver(&self, domain: &Domain, limit: u32) -> Result<usize> {
+            let mut delivered = 0;
+            for (remote, transfer) in domain.pending_transfers(limit)? {
+                match self.post_transfer(domain, &remote, &transfer) {
+                    Ok(()) => {
+                        domain.mark_transfer_delivered(transfer.id)?;
+                        delivered += 1;
+                    }
+                    Err(PeerError::Refused(status, body)) if status == 404 || status == 422 => {
+                        domain.refund_transfer(transfer.id, &format!("{} refused: {}", remote.name, body))?;
+                    }
+                    Err(e) if transfer.attempts + 1 >= MAX_ATTEMPTS => {
+                        domain.refund_transfer(transfer.id, &e.to_string())?;
+                    }
+                    Err(e) => { domain.mark_transfer_failed(transfer.id, &e.to_string())?; }
+                }
+            }
+            Ok(delivered)
+        }
+
+        fn post_transfer(&self, domain: &Domain, remote: &RemoteDomain, transfer: &FederatedTransfer) -> std::result::Result<(), PeerError> {
+            let body = serde_json::to_string(&TransferRequest {
+                domain: domain.name.clone(),
+                reference: transfer.reference.clone(),
+                payer: transfer.local_account,
+                payee: transfer.remote_account,
+                amount: transfer.remote_amount,
+                original_amount: Some(transfer.amount),
+                message: transfer.message.clone(),
+            }).expect("transfer serializes");
+            self.post(domain, remote, TRANSFER_PATH, &body).map(|_| ())
+        }
+
+        /// Compares the local bridge account for `remote` with the peer's one for this domain.
+        pub fn reconcile(&self, domain: &Domain, remote: &RemoteDomain) -> std::result::Result<Reconciliation, PeerError> {
+            let local = domain.bridge_position(remote)?;
+            let body = serde_json::json!({ "domain": domain.name }).to_string();
+            let answer = self.post(domain, remote, BALANCE_PATH, &body)?;
+            let remote = serde_json::from_str(&answer).map_err(|e| PeerError::Unreachable(e.to_string()))?;
+            Ok(Reconciliation { local, remote })
+        }
+
+        fn post(&self, domain: &Domain, remote: &RemoteDomain, path: &str, body: &str) -> std::result::Result<String, PeerError> {
+            let signature = format!("sha256={}", sign(&remote.secret, body));
+            let response = self.agent.post(&format!("{}{}", remote.url, path))
+                .set("Content-Type", "application/json")
+                .set(DOMAIN_HEADER, &domain.name)
+                .set(SIGNATURE_HEADER, &signature)
+                .send_string(body);
+            match response {
+                Ok(r) => r.into_string().map_err(|e| PeerError::Unreachable(e.to_string())),
+                Err(ureq::Error::Status(status, r)) => Err(PeerError::Refused(status, r.into_string().unwrap_or_default())),
+                Err(e) => Err(PeerError::Unreachable(e.to_string())),
+            }
+        }
+    }
+}
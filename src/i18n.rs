@@ -0,0 +1,107 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::{fs, io};
+
+/// Translated user-facing messages keyed by locale and message key.
+/// Sources are plain `key = value` lines, `{}` marks a positional argument.
+pub struct Catalog {
+    default: String,
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        let mut catalog = Catalog::new("cs");
+        catalog.load("cs", include_str!("../locales/cs.txt"));
+        catalog.load("en", include_str!("../locales/en.txt"));
+        catalog
+    }
+}
+
+impl Catalog {
+    pub fn new(default: &str) -> Self {
+        Catalog { default: default.to_string(), locales: HashMap::new() }
+    }
+
+    /// Adds or overrides messages of a locale.
+    pub fn load(&mut self, locale: &str, source: &str) {
+        let messages = self.locales.entry(locale.to_string()).or_default();
+        for line in source.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') { continue }
+            if let Some((key, value)) = line.split_once('=') {
+                messages.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+    }
+
+    /// Loads every `<locale>.txt` file found in the directory.
+    pub fn load_dir(&mut self, dir: impl AsRef<Path>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "txt") { continue }
+            if let Some(locale) = path.file_stem().and_then(|s| s.to_str()) {
+                self.load(locale, &fs::read_to_string(&path)?);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default
+    }
+
+    pub fn has_locale(&self, locale: &str) -> bool {
+        self.locales.contains_key(locale)
+    }
+
+    /// Looks the key up in the locale, then in the default locale, and returns the key itself
+    /// when neither has it.
+    pub fn get<'a>(&'a self, locale: &str, key: &'a str) -> &'a str {
+        self.locales.get(locale).and_then(|m| m.get(key))
+            .or_else(|| self.locales.get(&self.default).and_then(|m| m.get(key)))
+            .map_or(key, String::as_str)
+    }
+
+    pub fn format(&self, locale: &str, key: &str, args: &[&dyn Display]) -> String {
+        let mut parts = self.get(locale, key).split("{}");
+        let mut message = parts.next().unwrap_or_default().to_string();
+        for (i, part) in parts.enumerate() {
+            if let Some(arg) = args.get(i) { message.push_str(&arg.to_string()); }
+            message.push_str(part);
+        }
+        message
+    }
+
+    /// Picks the locale for a request: an explicitly chosen one (e.g. from a cookie) wins,
+    /// then the first supported language of an `Accept-Language` header, then the default.
+    pub fn negotiate(&self, chosen: Option<&str>, accept_language: Option<&str>) -> String {
+        if let Some(locale) = chosen.filter(|l| self.has_locale(l)) {
+            return locale.to_string();
+        }
+        accept_language.into_iter()
+            .flat_map(|h| h.split(','))
+            .filter_map(|tag| tag.split(';').next())
+            .filter_map(|tag| tag.trim().split('-').next())
+            .map(str::to_lowercase)
+            .find(|l| self.has_locale(l))
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
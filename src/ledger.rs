@@ -0,0 +1,155 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use crate::{entry_hash, Amount, payment_from_row, user_from_row, Direction, Payment, PaymentFilter, User, ESCROW_DISPUTED, ESCROW_OPEN, GENESIS_HASH,
+            PAYMENT_REGULAR};
+
+/// A payment to be recorded by `Ledger::book`.
+pub struct Booking<'a> {
+    pub payer: i64,
+    pub payee: i64,
+    pub amount: Amount,
+    pub message: &'a str,
+    pub kind: i64,
+    pub parent: Option<i64>,
+    pub category: Option<&'a str>,
+    /// Whether the payment counts into `payments_in`/`payments_out` and so into the limits.
+    /// Fees and demurrage don't.
+    pub counted: bool,
+    /// When the payment was made, as `YYYY-MM-DD HH:MM:SS` UTC. Now if `None`, set
+    /// only by imports of history.
+    pub created: Option<&'a str>,
+}
+
+/// Storage of the accounts and payments the payment logic in `Domain` works with. Implementations
+/// don't open transactions themselves, the caller runs one transfer (payment, fee) inside one.
+///
+/// The rusqlite `Connection`, and so also `Transaction`, is the default implementation.
+pub trait Ledger {
+    fn user(&self, id: i64) -> Result<User>;
+    fn user_by_name(&self, name: &str) -> Result<User>;
+    fn users(&self) -> Result<Vec<User>>;
+    /// Payments of `user` matching the filter, newest first.
+    fn payments(&self, user: i64, filter: &PaymentFilter) -> Result<Vec<Payment>>;
+    /// Extra limit `a` and `b` both agreed on, 0 if either hasn't offered one.
+    fn trustline(&self, a: i64, b: i64) -> Result<u64>;
+    /// Sum of the regular payments `user` made from the stored time `since` on, fees and
+    /// charges left out.
+    fn sent_since(&self, user: i64, since: &str) -> Result<Amount>;
+    /// Regular payments `user` received and made in the last `months` months.
+    fn turnover(&self, user: i64, months: u64) -> Result<(u64, u64)>;
+    /// Send and receive bonus of the user's limit override, 0 without one or once it expired.
+    fn limit_bonus(&self, user: i64) -> Result<(i64, i64)>;
+    /// Sum the user holds in open or disputed escrows as the payer.
+    fn escrowed(&self, user: i64) -> Result<Amount>;
+    /// `Some` if an admin froze the account, `Some(true)` when also its incoming payments are.
+    fn frozen(&self, user: i64) -> Result<Option<bool>>;
+    /// Whether the account's closure was started and hasn't finished yet.
+    fn closing(&self, user: i64) -> Result<bool>;
+    /// Moves the amount between the two balances and records the payment.
+    fn book(&self, booking: &Booking) -> Result<Payment>;
+}
+
+impl Ledger for Connection {
+    fn user(&self, id: i64) -> Result<User> {
+        self.query_row("SELECT * FROM user WHERE id = ?", [id], user_from_row)
+    }
+
+    fn user_by_name(&self, name: &str) -> Result<User> {
+        self.query_row("SELECT * FROM user WHERE name = ?", [name], user_from_row)
+    }
+
+    fn users(&self) -> Result<Vec<User>> {
+        let mut stmt = self.prepare("SELECT * FROM user")?;
+        let iter = stmt.query_map([], user_from_row)?;
+        iter.collect()
+    }
+
+    fn payments(&self, user: i64, filter: &PaymentFilter) -> Result<Vec<Payment>> {
+        let mut stmt = self.prepare("SELECT * FROM payment \
+        WHERE (payer = ?1 OR payee = ?1) \
+        AND (?2 IS NULL OR created >= ?2) AND (?3 IS NULL OR created < ?3) \
+        AND (?4 IS NULL OR payer = ?4 OR payee = ?4) \
+        AND (?5 IS NULL OR amount >= ?5) AND (?6 IS NULL OR amount <= ?6) \
+        AND (?7 IS NULL OR (?7 = 'in' AND payee = ?1) OR (?7 = 'out' AND payer = ?1)) \
+        ORDER BY created DESC")?;
+        let direction = filter.direction.map(|d| match d {
+            Direction::Incoming => "in",
+            Direction::Outgoing => "out",
+        });
+        let (from, to) = filter.bounds();
+        let iter = stmt.query_map(params![user, from, to,
+            filter.counterparty, filter.min_amount, filter.max_amount, direction], payment_from_row)?;
+        iter.collect()
+    }
+
+    fn trustline(&self, a: i64, b: i64) -> Result<u64> {
+        self.query_row("SELECT CASE WHEN COUNT(*) = 2 THEN MIN(extra_limit) ELSE 0 END FROM trustline \
+        WHERE (truster = ?1 AND trustee = ?2) OR (truster = ?2 AND trustee = ?1)", params![a, b], |row| row.get(0))
+    }
+
+    fn sent_since(&self, user: i64, since: &str) -> Result<Amount> {
+        self.query_row("SELECT COALESCE(SUM(amount), 0) FROM payment WHERE payer = ?1 AND kind = ?2 \
+        AND created >= ?3", params![user, PAYMENT_REGULAR, since], |row| row.get(0))
+    }
+
+    fn turnover(&self, user: i64, months: u64) -> Result<(u64, u64)> {
+        self.query_row("SELECT COALESCE(SUM(payee = ?1), 0), COALESCE(SUM(payer = ?1), 0) FROM payment \
+        WHERE (payer = ?1 OR payee = ?1) AND kind = ?2 AND created >= datetime('now', ?3)",
+                       params![user, PAYMENT_REGULAR, format!("-{} months", months)], |row| Ok((row.get(0)?, row.get(1)?)))
+    }
+
+    fn limit_bonus(&self, user: i64) -> Result<(i64, i64)> {
+        Ok(self.query_row("SELECT send_bonus, receive_bonus FROM limit_override WHERE user = ?1 \
+        AND (expiry IS NULL OR expiry > datetime('now'))", [user], |row| Ok((row.get(0)?, row.get(1)?))).optional()?
+            .unwrap_or((0, 0)))
+    }
+
+    fn escrowed(&self, user: i64) -> Result<Amount> {
+        self.query_row("SELECT COALESCE(SUM(amount), 0) FROM escrow WHERE payer = ?1 AND status IN (?2, ?3)",
+                       params![user, ESCROW_OPEN, ESCROW_DISPUTED], |row| row.get(0))
+    }
+
+    fn frozen(&self, user: i64) -> Result<Option<bool>> {
+        self.query_row("SELECT incoming FROM account_freeze WHERE user = ?1", [user], |row| row.get(0)).optional()
+    }
+
+    fn closing(&self, user: i64) -> Result<bool> {
+        self.query_row("SELECT COUNT(*) > 0 FROM account_closure WHERE user = ?1 AND closed IS NULL", [user], |row| row.get(0))
+    }
+
+    fn book(&self, booking: &Booking) -> Result<Payment> {
+        let counter = booking.counted as i64;
+        self.execute("UPDATE user SET credit = credit - ?1, payments_out = payments_out + ?2 WHERE id = ?3",
+                     params![booking.amount, counter, booking.payer])?;
+        self.execute("UPDATE user SET credit = credit + ?1, payments_in = payments_in + ?2 WHERE id = ?3",
+                     params![booking.amount, counter, booking.payee])?;
+        self.execute("INSERT INTO payment (payer, payee, amount, created, message, kind, parent, category)\
+        VALUES (?1, ?2, ?3, COALESCE(?8, datetime('now')), ?4, ?5, ?6, ?7)",
+                     params![booking.payer, booking.payee, booking.amount, booking.message, booking.kind, booking.parent, booking.category,
+                         booking.created])?;
+        let payment = self.query_row("SELECT * FROM payment WHERE id = ?", [self.last_insert_rowid()], payment_from_row)?;
+        // chain it to the previous payment, see `Domain::verify_chain`
+        let prev: String = self.query_row("SELECT entry_hash FROM payment WHERE id < ? ORDER BY id DESC LIMIT 1",
+                                          [payment.id], |row| row.get::<_, Option<String>>(0)).optional()?.flatten()
+            .unwrap_or_else(|| GENESIS_HASH.to_string());
+        self.execute("UPDATE payment SET prev_hash = ?1, entry_hash = ?2 WHERE id = ?3",
+                     params![prev, entry_hash(&payment, &prev), payment.id])?;
+        Ok(payment)
+    }
+}
@@ -17,13 +17,46 @@
 
 #[cfg(test)]
 mod tests;
+pub mod i18n;
+pub mod async_domain;
+#[cfg(feature = "email")]
+pub mod notify;
+pub mod webhook;
+pub mod totp;
+pub mod ledger;
+pub mod amount;
+pub mod limits;
+pub mod moderation;
+pub mod validation;
+pub mod backup;
+pub mod federation;
+pub mod oidc;
+pub mod roles;
+pub mod pdf;
+#[cfg(feature = "postgres")]
+pub mod pg;
 
-use chrono::Local;
-use rusqlite::{Connection, Error, params, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use rand::RngCore;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{ffi, Connection, DatabaseName, Error, OpenFlags, OptionalExtension, params, Result, Row, TransactionBehavior};
 use sha2::{Sha256, Digest};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use i18n::Catalog;
+use tracing::{error, info, warn};
+pub use async_domain::AsyncDomain;
+pub use ledger::{Booking, Ledger};
+pub use amount::{Amount, Balance};
+pub use moderation::{MessagePolicy, MessageRules};
+pub use roles::{Capability, Role};
+use amount::sql_amount;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
     pub name: String,
@@ -32,239 +65,5924 @@ pub struct User {
     pub payments_out: u64,
     pub password: String,
     pub created: String,
-    pub permission: i64,
+    /// Archives from before roles have the `permission` number instead, see `roles::deserialize`.
+    #[serde(default, alias = "permission", deserialize_with = "roles::deserialize")]
+    pub role: Role,
+    pub active: bool,
+    pub account_type: i64,
+    pub email: Option<String>,
+    /// Events the user wants to be emailed about, a mask of the `NOTIFY_*` flags.
+    pub notify: i64,
+    /// Number the member knows the account by, shown and looked up instead of the internal id.
+    /// Accounts from before it existed have their id as the number.
+    #[serde(default)]
+    pub member_number: u64,
+    /// The user confirmed `email`, see `Domain::verify_email`. Changing the address clears it.
+    #[serde(default)]
+    pub email_verified: bool,
 }
 
+pub const NOTIFY_PAYMENT: i64 = 1;
+pub const NOTIFY_REQUEST: i64 = 2;
+pub const NOTIFY_PASSWORD: i64 = 4;
+
+pub const LOGIN_FREE_ATTEMPTS: u64 = 3;
+
+pub const ACCOUNT_MEMBER: i64 = 0;
+pub const ACCOUNT_SYSTEM: i64 = 1;
+/// Shared account without a login of its own, paid from by its managers.
+pub const ACCOUNT_GROUP: i64 = 2;
+
 impl User {
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        parse_utc(&self.created)
+    }
+
+    /// System (community) accounts are not limited in either direction.
+    pub fn is_system(&self) -> bool {
+        self.account_type == ACCOUNT_SYSTEM
+    }
+
     pub fn receive_limit(&self) -> i64 {
-        (((self.payments_out + 1) as f64).sqrt() * 2500.0) as i64 - self.credit
+        self.receive_limit_in(1.0)
     }
 
     pub fn credit_limit(&self) -> i64 {
-        (((self.payments_in + 1) as f64).sqrt() * 1000.0) as i64 - 1000
+        self.credit_limit_in(1.0)
     }
 
     pub fn send_limit(&self) -> i64 {
-        self.credit_limit() + self.credit
+        self.send_limit_in(1.0)
     }
 
-    pub fn payment_limit(&self, payee: &User) -> Outcome {
-        let send_limit = self.send_limit();
-        let receive_limit = payee.receive_limit();
-        if send_limit <= receive_limit {
-            Outcome::PaymentSendLimit(send_limit)
-        } else { Outcome::PaymentReceiveLimit(receive_limit) }
+    /// `receive_limit` of a domain counting `unit` smallest units to a unit of the formulas, see
+    /// `CurrencyFormat::limit_unit` and the `limits` module.
+    pub fn receive_limit_in(&self, unit: f64) -> i64 {
+        if self.is_system() { return i64::MAX }
+        limits::receive_limit(self.payments_out, self.credit, unit)
     }
-}
 
-#[derive(Debug, Serialize)]
-pub struct Payment {
-    pub id: u64,
-    pub payer: u64,
-    pub payee: u64,
-    pub amount: u64,
-    pub created: String,
-    pub message: String,
+    pub fn credit_limit_in(&self, unit: f64) -> i64 {
+        limits::credit_limit(self.payments_in, unit)
+    }
+
+    pub fn send_limit_in(&self, unit: f64) -> i64 {
+        if self.is_system() { return i64::MAX }
+        limits::send_limit(self.payments_in, self.credit, unit)
+    }
+
+    pub fn payment_limit(&self, payee: &User) -> Limit {
+        self.payment_limit_trusted(payee, 0)
+    }
+
+    /// Like `payment_limit`, with both limits raised by the `extra` the two sides agreed on in
+    /// their trustline.
+    pub fn payment_limit_trusted(&self, payee: &User, extra: i64) -> Limit {
+        Limit::lower(self.send_limit().saturating_add(extra), payee.receive_limit().saturating_add(extra))
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum Outcome {
-    Db(Error),
-    PaymentLessMin(u64),
-    PaymentSidesEq,
-    PaymentReceiveLimit(i64),
-    PaymentSendLimit(i64),
-    MustNotHappen,
+/// The largest payment from one user to another and which side limits it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Limit {
+    /// The payer's send limit is the lower one.
+    Send(i64),
+    /// The payee's receive limit is the lower one.
+    Receive(i64),
 }
 
-impl From<Error> for Outcome {
-    fn from(e: Error) -> Self {
-        Outcome::Db(e)
+impl Limit {
+    /// The lower of the payer's send limit and the payee's receive limit.
+    pub fn lower(send_limit: i64, receive_limit: i64) -> Limit {
+        if send_limit <= receive_limit {
+            Limit::Send(send_limit)
+        } else { Limit::Receive(receive_limit) }
+    }
+
+    pub fn amount(&self) -> i64 {
+        match self {
+            Limit::Send(l) | Limit::Receive(l) => *l,
+        }
+    }
+
+    /// The error for a payment over this limit.
+    pub fn exceeded(&self) -> SimpletsError {
+        match *self {
+            Limit::Send(limit) => SimpletsError::PaymentSendLimit { limit },
+            Limit::Receive(limit) => SimpletsError::PaymentReceiveLimit { limit },
+        }
     }
 }
 
-pub struct Domain {
-    pub name: String,
-    pub description: String,
-    pub conn: Connection,
-    pub minimal_amount: u64,
+/// Format of the times stored in the database, always UTC.
+pub const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Parses a time stored in the database.
+pub fn parse_utc(stored: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(stored, TIME_FORMAT).ok().map(|t| DateTime::from_utc(t, Utc))
 }
 
-impl Domain {
-    pub fn new(name: &str, description: &str, minimal_amount: u64) -> Self {
-        let conn = Domain::init_database(name);
-        Domain {name: name.to_string(), description: description.to_string(), conn, minimal_amount}
+/// The zone a domain shows times in and counts days by, the server's own one or a fixed offset
+/// from UTC. Times are stored in UTC and only converted for people.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timezone {
+    #[default]
+    Local,
+    /// Seconds east of UTC.
+    Fixed(i32),
+}
+
+impl Timezone {
+    /// `local` or an offset like `+02:00` or `-05:30`.
+    pub fn parse(text: &str) -> Option<Timezone> {
+        let text = text.trim();
+        if text.eq_ignore_ascii_case("local") { return Some(Timezone::Local) }
+        if text.eq_ignore_ascii_case("utc") { return Some(Timezone::Fixed(0)) }
+        let sign = match text.chars().next()? { '+' => 1, '-' => -1, _ => return None };
+        let (hours, minutes) = text[1..].split_once(':').unwrap_or((&text[1..], "0"));
+        let (hours, minutes): (i32, i32) = (hours.parse().ok()?, minutes.parse().ok()?);
+        if hours > 14 || minutes > 59 { return None }
+        Some(Timezone::Fixed(sign * (hours * 3600 + minutes * 60)))
     }
 
-    pub fn get_user(&self, id: i64) -> Result<User> {
-        self.conn.query_row("SELECT * FROM user WHERE id = ?", [id],
-                       |row| {
-                           Ok(User {
-                               id: row.get(0)?,
-                               name: row.get(1)?,
-                               credit: row.get(2)?,
-                               payments_in: row.get(3)?,
-                               payments_out: row.get(4)?,
-                               password: row.get(5)?,
-                               created: row.get(6)?,
-                               permission: row.get(7)?,
-                           })
-                       })
+    pub fn to_local(&self, utc: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Timezone::Local => utc.with_timezone(&Local).naive_local(),
+            Timezone::Fixed(offset) => utc.with_timezone(&FixedOffset::east(*offset)).naive_local(),
+        }
     }
 
-    pub fn get_user_by_name(&self, name: &str) -> Result<User> {
-        self.conn.query_row("SELECT * FROM user WHERE name = ?", [name],
-                            |row| {
-                                Ok(User {
-                                    id: row.get(0)?,
-                                    name: row.get(1)?,
-                                    credit: row.get(2)?,
-                                    payments_in: row.get(3)?,
-                                    payments_out: row.get(4)?,
-                                    password: row.get(5)?,
-                                    created: row.get(6)?,
-                                    permission: row.get(7)?,
-                                })
-                            })
+    /// The UTC time of a local one, the earlier of two in the hour repeated when clocks go back.
+    pub fn to_utc(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        let utc = match self {
+            Timezone::Local => Local.from_local_datetime(&local).earliest().map(|t| t.naive_utc()),
+            Timezone::Fixed(offset) => FixedOffset::east(*offset).from_local_datetime(&local).earliest().map(|t| t.naive_utc()),
+        };
+        // a time skipped when clocks go forward is taken an hour later
+        let utc = utc.unwrap_or_else(|| self.to_utc(local + chrono::Duration::hours(1)).naive_utc());
+        DateTime::from_utc(utc, Utc)
     }
 
-    pub fn get_users(&self) -> Result<Vec<User>> {
-        let mut stmt = self.conn.prepare("SELECT * FROM user")?;
-        let iter = stmt.query_map([], |row| {
-            Ok(User {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                credit: row.get(2)?,
-                payments_in: row.get(3)?,
-                payments_out: row.get(4)?,
-                password: row.get(5)?,
-                created: row.get(6)?,
-                permission: row.get(7)?,
-            })
-        })?;
-        let mut vec = Vec::new();
-        for person in iter {
-            match person {
-                Ok(u) => vec.push(u),
-                Err(e) => return Err(e)
-            }
+    pub fn now(&self) -> NaiveDateTime {
+        self.to_local(Utc::now())
+    }
+
+    pub fn today(&self) -> NaiveDate {
+        self.now().date()
+    }
+
+    /// Stored UTC time of the local midnight starting `date`, for comparing with stored times.
+    pub fn day_start(&self, date: NaiveDate) -> String {
+        self.to_utc(date.and_hms(0, 0, 0)).format(TIME_FORMAT).to_string()
+    }
+
+    /// SQLite date modifier turning a stored time into a local one.
+    pub fn sql_modifier(&self) -> String {
+        match self {
+            Timezone::Local => "localtime".to_string(),
+            Timezone::Fixed(offset) => format!("{:+} seconds", offset),
         }
-        Ok(vec)
     }
 
-    pub fn add_user(&self, name: &str, password: &str) -> Result<u64> {
-        let hash = hash(password);
-        let timestamp = Local::now().timestamp();
-        self.conn.execute("INSERT INTO user (id, name, credit, payments_in, payments_out, password, created, permission)\
-    VALUES (?1, ?2, 0, 0, 0, ?3, datetime('now', 'localtime'), 1)",
-                          params![timestamp, name, hash])?;
-        Ok(timestamp.try_into().unwrap()) //err will not happen unless someone has bad clock
+    /// A stored time as local time. Whatever doesn't parse as one is returned as it is.
+    pub fn render(&self, stored: &str) -> String {
+        match parse_utc(stored) {
+            Some(t) => self.to_local(t).format(TIME_FORMAT).to_string(),
+            None => stored.to_string(),
+        }
     }
+}
 
-    pub fn set_password(&self, user_id: i64, new_password: &str) -> Result<usize> {
-        let hash = hash(new_password);
-        self.conn.execute("UPDATE user SET password = ?1 WHERE id = ?2",
-                          params![hash, user_id])
+impl std::fmt::Display for Timezone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Timezone::Local => write!(f, "local"),
+            Timezone::Fixed(offset) => write!(f, "{}{:02}:{:02}", if *offset < 0 { '-' } else { '+' }, offset.abs() / 3600, offset.abs() % 3600 / 60),
+        }
     }
+}
 
-    pub fn get_payments(&self) -> Result<Vec<Payment>> {
-        let mut stmt = self.conn.prepare("SELECT * FROM payment")?;
-        let iter = stmt.query_map([], |row| {
-            Ok(Payment {
-                id: row.get(0)?,
-                payer: row.get(1)?,
-                payee: row.get(2)?,
-                amount: row.get(3)?,
-                created: row.get(4)?,
-                message: row.get(5)?,
-            })
-        })?;
-        let mut vec = Vec::new();
-        for person in iter {
-            match person {
-                Ok(u) => vec.push(u),
-                Err(e) => return Err(e)
-            }
+/// Reduced limits for new members, until their account is `days` old and they made `payments`
+/// payments. Either condition 0 is left out, both 0 turn probation off. With `email` members
+/// also stay on probation until they verify their email address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Probation {
+    pub days: u64,
+    pub payments: u64,
+    /// Share of the usual limits left to members on probation, in percent.
+    pub percent: u64,
+    pub email: bool,
+}
+
+impl Probation {
+    /// Whether `user` is still on probation on `today`. System and group accounts never are.
+    pub fn applies(&self, user: &User, today: NaiveDate) -> bool {
+        if user.account_type != ACCOUNT_MEMBER { return false }
+        if self.email && !user.email_verified { return true }
+        if self.days == 0 && self.payments == 0 { return false }
+        let created = NaiveDate::parse_from_str(user.created.get(..10).unwrap_or_default(), "%Y-%m-%d").unwrap_or(today);
+        (today - created).num_days() < self.days as i64 || user.payments_in + user.payments_out < self.payments
+    }
+
+    /// The part of `limit` left on probation. A limit already used up stays as it is.
+    pub fn reduce(&self, limit: i64) -> i64 {
+        if limit <= 0 { return limit }
+        (limit as i128 * self.percent.min(100) as i128 / 100) as i64
+    }
+}
+
+/// How amounts are written for people, see `Domain::format_amount`. Amounts are counted in the
+/// smallest unit, `decimals` places of which make one unit of the currency.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CurrencyFormat {
+    /// Name after an amount of exactly one unit.
+    pub singular: String,
+    /// Name after any other amount.
+    pub plural: String,
+    pub decimals: u32,
+    pub decimal_separator: String,
+    /// Put between groups of three digits, empty for none.
+    pub thousands_separator: String,
+    /// Amounts are minutes, written and typed as hours and minutes, `1:30`. `decimals` and the
+    /// decimal separator don't apply.
+    #[serde(default)]
+    pub time: bool,
+}
+
+impl Default for CurrencyFormat {
+    fn default() -> Self {
+        CurrencyFormat { singular: "kr.".to_string(), plural: "kr.".to_string(), decimals: 0, decimal_separator: ",".to_string(),
+            thousands_separator: String::new(), time: false }
+    }
+}
+
+impl CurrencyFormat {
+    /// Most decimal places a currency may have.
+    pub const MAX_DECIMALS: u32 = 6;
+
+    /// Smallest units in one unit of the currency, 100 with 2 decimal places, 60 minutes in an hour.
+    pub fn unit(&self) -> i64 {
+        if self.time { return 60 }
+        10i64.pow(self.decimals.min(Self::MAX_DECIMALS))
+    }
+
+    /// Smallest units in a unit of the limit formulas, see `User::receive_limit_in`. In time mode
+    /// that is a hundredth of an hour, a new member may receive 25 hours.
+    pub fn limit_unit(&self) -> f64 {
+        if self.time { 0.6 } else { self.unit() as f64 }
+    }
+
+    /// Minutes of `-1:30` or whole hours `2`, minutes below 60. `None` if it isn't a time.
+    fn parse_time(text: &str) -> Option<i64> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let (hours, minutes) = text.split_once(':').unwrap_or((text, "00"));
+        if hours.is_empty() || minutes.len() != 2 || !hours.chars().chain(minutes.chars()).all(|c| c.is_ascii_digit()) {
+            return None
         }
-        Ok(vec)
+        let minutes: i64 = minutes.parse().ok().filter(|m| *m < 60)?;
+        let total = hours.parse::<i64>().ok()?.checked_mul(60)?.checked_add(minutes)?;
+        Some(if negative { -total } else { total })
     }
 
-    pub fn get_payments_by_user(&self, user: i64) -> Result<Vec<Payment>> {
-        let mut stmt = self.conn.prepare("SELECT * FROM payment \
-        WHERE payer = ?1 OR payee = ?1 ORDER BY created DESC")?;
-        let iter = stmt.query_map([&user], |row| {
-            Ok(Payment {
-                id: row.get(0)?,
-                payer: row.get(1)?,
-                payee: row.get(2)?,
-                amount: row.get(3)?,
-                created: row.get(4)?,
-                message: row.get(5)?,
-            })
-        })?;
-        let mut vec = Vec::new();
-        for person in iter {
-            match person {
-                Ok(u) => vec.push(u),
-                Err(e) => return Err(e)
-            }
+    /// `(whole, fraction)` of the absolute amount, the fraction as `plain` and `format` write it.
+    fn split(&self, amount: i64) -> (u64, String) {
+        let unit = self.unit() as u64;
+        let (whole, fraction) = (amount.unsigned_abs() / unit, amount.unsigned_abs() % unit);
+        let width = if self.time { 2 } else { self.decimals.min(Self::MAX_DECIMALS) as usize };
+        (whole, if unit > 1 { format!("{:0width$}", fraction, width = width) } else { String::new() })
+    }
+
+    /// Amount in the smallest units of a number typed in whole units, `-1.5` or `1,5`, with at most
+    /// `decimals` places, in time mode of `h:mm`. Thousands separators and spaces are skipped.
+    /// `None` if it isn't a number, has more places or doesn't fit.
+    pub fn parse(&self, text: &str) -> Option<i64> {
+        let mut text = text.trim().replace(' ', "");
+        if !self.thousands_separator.is_empty() && self.thousands_separator != "." && self.thousands_separator != "," {
+            text = text.replace(self.thousands_separator.as_str(), "");
         }
-        Ok(vec)
+        if self.time { return Self::parse_time(&text) }
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.as_str()),
+        };
+        let (whole, fraction) = match digits.find(['.', ',']) {
+            Some(i) => (&digits[..i], &digits[i + 1..]),
+            None => (digits, ""),
+        };
+        let decimals = self.decimals.min(Self::MAX_DECIMALS) as usize;
+        if whole.is_empty() || fraction.len() > decimals || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return None
+        }
+        let fraction = format!("{:0<width$}", fraction, width = decimals);
+        let units = whole.parse::<i64>().ok()?.checked_mul(self.unit())?.checked_add(fraction.parse::<i64>().unwrap_or(0))?;
+        Some(if negative { -units } else { units })
     }
 
-    pub fn add_payment(&mut self, payer: User, payee: User, amount: u64, message: &str) -> Result<(), Outcome> {
-        let tx = self.conn.transaction()?;
-        if amount < self.minimal_amount { return Err(Outcome::PaymentLessMin(self.minimal_amount)); }
-        if payer.id == payee.id { return Err(Outcome::PaymentSidesEq); }
-        let limit = payer.payment_limit(&payee);
-        match limit {
-            Outcome::PaymentSendLimit(l) => if amount as i64 > l { return Err(limit) },
-            Outcome::PaymentReceiveLimit(l) => if amount as i64 > l { return Err(limit) },
-            _ => return Err(Outcome::MustNotHappen)
+    /// `amount` in whole units the way inputs take it, `1.50` or `1:30`, without separators or name.
+    pub fn plain(&self, amount: i64) -> String {
+        let (whole, fraction) = self.split(amount);
+        let sign = if amount < 0 { "-" } else { "" };
+        if fraction.is_empty() { return format!("{}{}", sign, whole) }
+        format!("{}{}{}{}", sign, whole, if self.time { ":" } else { "." }, fraction)
+    }
+
+    /// `amount` with its digits grouped, the decimal places and the currency name, `-1 234,50 kr.`
+    /// or `1:30 h`.
+    pub fn format(&self, amount: i64) -> String {
+        let (whole, fraction) = self.split(amount);
+        let digits = whole.to_string();
+        let mut text = String::from(if amount < 0 { "-" } else { "" });
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 { text.push_str(&self.thousands_separator); }
+            text.push(digit);
         }
-        tx.execute("UPDATE user SET credit = credit - ?1, payments_out = payments_out + 1 WHERE id = ?2", params![amount, payer.id])?;
-        tx.execute("UPDATE user SET credit = credit + ?1, payments_in = payments_in + 1 WHERE id = ?2", params![amount, payee.id])?;
-        tx.execute("INSERT INTO payment (payer, payee, amount, created, message)\
-        VALUES (?1, ?2, ?3, datetime('now', 'localtime'), ?4)", params![&payer.id, &payee.id, &amount, &message])?;
-        tx.commit()?;
-        Ok(())
+        if !fraction.is_empty() {
+            text.push_str(if self.time { ":" } else { &self.decimal_separator });
+            text.push_str(&fraction);
+        }
+        let name = if amount.unsigned_abs() == self.unit() as u64 { &self.singular } else { &self.plural };
+        if name.is_empty() { text } else { format!("{} {}", text, name) }
     }
+}
 
-    fn init_database(name: &str) -> Connection {
-        let path = format!("{}.sqlite", name);
-        let conn = Connection::open(&path).expect("db file");
-        let db_version: i64 = conn.query_row("PRAGMA user_version",[], |row| {row.get(0)})
-            .expect("lookup db table version");
-        if db_version == 0 {
-            conn.execute("PRAGMA user_version = 1", []).expect("alter db version");
-            conn.execute("PRAGMA foreign_keys = ON", []).expect("change pragma");
-            conn.execute("CREATE TABLE user (
-                    id              INTEGER PRIMARY KEY,
-                    name            TEXT,
-                    credit          INTEGER NOT NULL,
-                    payments_in     INTEGER NOT NULL,
-                    payments_out    INTEGER NOT NULL,
-                    password        TEXT NOT NULL,
-                    created         TEXT NOT NULL,
-                    permission      INTEGER NOT NULL
-                    )", [])
-                .expect("create table");
-            conn.execute("CREATE TABLE payment (
-                    id              INTEGER PRIMARY KEY,
-                    payer           INTEGER NOT NULL,
-                    payee           INTEGER NOT NULL,
-                    amount          INTEGER NOT NULL,
-                    created         TEXT NOT NULL,
-                    message         TEXT NOT NULL,
-                    FOREIGN KEY(payer) REFERENCES user(id),
-                    FOREIGN KEY(payee) REFERENCES user(id)
-                    )", [])
-                .expect("create table");
+/// How the domain's pages look, the template set and what the templates fill in. Empty fields
+/// leave the theme's own choice.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Branding {
+    /// Template set the pages are rendered with, empty for the classic one.
+    pub theme: String,
+    /// Community name in page titles and headers.
+    pub title: String,
+    /// Path or address of the logo image.
+    pub logo: String,
+    /// CSS colors of headings and links, and of the page.
+    pub primary_color: String,
+    pub background_color: String,
+}
+
+/// Which names members may sign up with. Names are made of letters, digits and `extra_chars`,
+/// the `reserved` ones are refused whatever their case.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub extra_chars: String,
+    pub reserved: Vec<String>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy {
+            min_length: 3, max_length: 32, extra_chars: "._-".to_string(),
+            reserved: ["admin", "root", "system", "simplets"].iter().map(|r| r.to_string()).collect(),
         }
-        conn
     }
 }
 
+impl UsernamePolicy {
+    /// Whether `name` is allowed, length counted in characters.
+    pub fn allows(&self, name: &str) -> bool {
+        let length = name.chars().count();
+        length >= self.min_length && length <= self.max_length
+            && name.chars().all(|c| c.is_alphanumeric() || self.extra_chars.contains(c))
+            && !self.reserved.iter().any(|r| r.to_lowercase() == name.to_lowercase())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Payment {
+    pub id: u64,
+    pub payer: u64,
+    pub payee: u64,
+    pub amount: u64,
+    pub created: String,
+    pub message: String,
+    pub kind: i64,
+    /// The payment a fee was charged for.
+    pub parent: Option<i64>,
+    /// One of `Domain::get_categories`, if the payer picked one.
+    pub category: Option<String>,
+}
+
+impl Payment {
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        parse_utc(&self.created)
+    }
+}
+
+pub const PAYMENT_REGULAR: i64 = 0;
+pub const PAYMENT_DEMURRAGE: i64 = 1;
+pub const PAYMENT_FEE: i64 = 2;
+/// Balance brought over from another system, see `Domain::import_users_csv`.
+pub const PAYMENT_OPENING: i64 = 3;
+/// Credit an admin granted or levied by hand, see `Domain::add_adjustment`.
+pub const PAYMENT_ADJUSTMENT: i64 = 4;
+/// Booking that undoes a regular payment or its fee, see `Domain::reverse_payment`.
+pub const PAYMENT_REVERSAL: i64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// Narrows a user's payment history, unset fields don't filter. Dates are inclusive days of
+/// `timezone`, the domain fills it in.
+#[derive(Debug, Clone, Default)]
+pub struct PaymentFilter {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub timezone: Timezone,
+    pub counterparty: Option<i64>,
+    pub min_amount: Option<u64>,
+    pub max_amount: Option<u64>,
+    pub direction: Option<Direction>,
+}
+
+impl PaymentFilter {
+    /// `from` and `to` as the stored times the days start and end at, the end exclusive.
+    pub fn bounds(&self) -> (Option<String>, Option<String>) {
+        (self.from.map(|d| self.timezone.day_start(d)), self.to.map(|d| self.timezone.day_start(d.succ())))
+    }
+}
+
+/// Summary of a member's payments, see `Domain::user_stats`.
+#[derive(Debug, Serialize)]
+pub struct UserStats {
+    pub sent: u64,
+    pub received: u64,
+    pub payments_sent: u64,
+    pub payments_received: u64,
+    pub average: u64,
+    /// Newest month first.
+    pub months: Vec<MonthTurnover>,
+    pub counterparties: Vec<Counterparty>,
+    /// Turnover of categorized payments, biggest first.
+    pub categories: Vec<CategoryTurnover>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MonthTurnover {
+    /// `YYYY-MM`
+    pub month: String,
+    pub sent: u64,
+    pub received: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CategoryTurnover {
+    pub category: String,
+    pub sent: u64,
+    pub received: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Counterparty {
+    pub id: i64,
+    pub name: String,
+    pub payments: u64,
+    pub turnover: u64,
+}
+
+/// Payment on a statement with the balance after it, see `Domain::statement`.
+#[derive(Debug, Serialize)]
+pub struct StatementLine {
+    /// Local time in the domain's timezone, statements are read by people.
+    pub created: String,
+    pub counterparty: String,
+    pub amount: i64,
+    pub message: String,
+    pub balance: i64,
+    /// The `PAYMENT_*` kind, adjustments are marked on statements.
+    pub kind: i64,
+}
+
+/// A month of the whole domain's activity, see `Domain::turnover_report`. Only regular payments
+/// and member accounts count, fees and system accounts don't make a community active.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DomainMonth {
+    /// `YYYY-MM`
+    pub month: String,
+    pub payments: u64,
+    pub volume: u64,
+    /// Members who paid or were paid in the month.
+    pub active_members: u64,
+    /// Members whose accounts were opened in the month.
+    pub new_members: u64,
+}
+
+/// Members without a regular payment for this many days count as dormant.
+pub const DORMANT_DAYS: u32 = 90;
+
+/// How evenly the active members hold credit and trade, see `Domain::participation_metrics`.
+#[derive(Debug, Serialize)]
+pub struct ParticipationMetrics {
+    pub members: u64,
+    pub in_debit: u64,
+    pub in_credit: u64,
+    pub min_balance: i64,
+    pub median_balance: i64,
+    pub max_balance: i64,
+    /// Gini coefficient of the positive balances, 0 when everyone holds the same and towards 1
+    /// when a few members hold all the credit.
+    pub credit_gini: f64,
+    /// Part of the last year's volume that the busiest tenth of the members sent or received.
+    pub top_decile_share: f64,
+    /// Members without a regular payment for `DORMANT_DAYS`.
+    pub dormant: u64,
+}
+
+/// A member who stopped trading, see `Domain::dormant_users`.
+#[derive(Debug, Serialize)]
+pub struct DormantUser {
+    pub id: i64,
+    pub member_number: u64,
+    pub name: String,
+    pub email: Option<String>,
+    pub credit: i64,
+    /// When they last paid or were paid, `None` if they never traded.
+    pub last_payment: Option<String>,
+}
+
+/// A market day or swap meet whose payments are tagged to be summed up, see `Domain::open_event`.
+#[derive(Debug, Serialize)]
+pub struct Event {
+    pub id: u64,
+    pub name: String,
+    pub created: String,
+    /// Once closed no more payments are tagged with it.
+    pub closed: Option<String>,
+}
+
+/// News from the committee on the members' page, see `Domain::post_announcement`.
+#[derive(Debug, Serialize)]
+pub struct Announcement {
+    pub id: u64,
+    pub author: i64,
+    pub author_name: String,
+    pub title: String,
+    pub body: String,
+    pub created: String,
+}
+
+/// A version of the domain's terms of use, see `Domain::publish_terms`.
+#[derive(Debug, Serialize)]
+pub struct Terms {
+    /// Counts up from 1 with each published text.
+    pub version: u32,
+    pub text: String,
+    pub published: String,
+}
+
+/// An account at an OpenID Connect provider the user logs in with, see `Domain::link_identity`.
+#[derive(Debug, Serialize)]
+pub struct FederatedIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub created: String,
+}
+
+/// A file documenting a payment, such as a photo of what was traded or a receipt, see
+/// `Domain::attach_file`. The content is kept in the domain's `attachment_dir`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Attachment {
+    pub id: u64,
+    pub payment: u64,
+    pub uploader: i64,
+    /// As the uploader named it, without directories.
+    pub name: String,
+    /// Told by the content, see `validation::attachment`.
+    pub media_type: String,
+    pub size: u64,
+    pub created: String,
+}
+
+/// What a member took in at an event, see `EventSummary`.
+#[derive(Debug, Serialize)]
+pub struct EventStall {
+    pub id: i64,
+    pub member_number: u64,
+    pub name: String,
+    pub received: u64,
+    pub payments: u64,
+}
+
+/// Totals of an event's payments, see `Domain::event_summary`.
+#[derive(Debug, Serialize)]
+pub struct EventSummary {
+    pub event: Event,
+    pub payments: u64,
+    pub volume: u64,
+    /// Accounts that paid or were paid at the event.
+    pub participants: u64,
+    /// Payees by what they received, the busiest first.
+    pub stalls: Vec<EventStall>,
+}
+
+/// A member on the leaderboard, see `Domain::top_traders`.
+#[derive(Debug, Serialize)]
+pub struct TopTrader {
+    pub id: i64,
+    pub member_number: u64,
+    pub name: String,
+    pub display_name: String,
+    /// Regular payments sent and received in the period.
+    pub payments: u64,
+}
+
+/// Consistency check of the whole domain, see `Domain::health_report`.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub users: u64,
+    /// Sum of all balances, zero unless the ledger is broken.
+    pub credit_sum: i64,
+    /// Members holding more credit than they could receive.
+    pub over_limit: Vec<OverLimit>,
+    /// Payments whose payer or payee doesn't exist.
+    pub orphaned_payments: Vec<u64>,
+    /// Users whose stored counters don't match their payment rows.
+    pub mismatches: Vec<LedgerMismatch>,
+    pub chain: ChainStatus,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.credit_sum == 0 && self.over_limit.is_empty() && self.orphaned_payments.is_empty() && self.mismatches.is_empty()
+            && self.chain.is_intact()
+    }
+}
+
+/// Fewest members who must have traded in the last 30 days for `PublicStats` to show the
+/// figures, with fewer the volume tells too much about the few who did.
+pub const PUBLIC_STATS_MIN_TRADERS: u64 = 5;
+
+/// What anyone may learn about the domain without logging in, see `Domain::public_stats`.
+/// Only counts and sums over many members, never a name or a balance.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PublicStats {
+    pub name: String,
+    pub description: String,
+    /// How to become a member, as the administrator wrote it.
+    pub join_info: String,
+    /// Active member accounts.
+    pub members: u64,
+    /// Regular payments of the last 30 days, `None` when under `PUBLIC_STATS_MIN_TRADERS` traded.
+    pub monthly_payments: Option<u64>,
+    /// Their sum.
+    pub monthly_volume: Option<u64>,
+}
+
+/// Quick check whether the domain can serve requests, see `Domain::readiness`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Readiness {
+    /// `PRAGMA user_version` of the database.
+    pub schema_version: i64,
+    /// Sum of all balances, if it was asked for.
+    pub credit_sum: Option<i64>,
+}
+
+impl Readiness {
+    /// The schema is the one this build expects and the balances, if summed, are zero.
+    pub fn is_ready(&self) -> bool {
+        self.schema_version == SCHEMA_VERSION && self.credit_sum.is_none_or(|sum| sum == 0)
+    }
+}
+
+/// Result of `Domain::verify_chain`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ChainStatus {
+    /// Payments checked, every payment when the chain is intact.
+    pub entries: u64,
+    /// First payment whose row or link to the previous payment doesn't match its hash.
+    pub broken_at: Option<u64>,
+}
+
+impl ChainStatus {
+    pub fn is_intact(&self) -> bool {
+        self.broken_at.is_none()
+    }
+}
+
+/// `prev_hash` of the first payment.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Hash of the payment's fields and the hash of the payment before it, stored as its
+/// `entry_hash`. Changing, removing or reordering a payment breaks every later link.
+pub fn entry_hash(payment: &Payment, prev_hash: &str) -> String {
+    // a JSON array keeps the fields apart whatever the message contains
+    let fields = serde_json::json!([payment.id, payment.payer, payment.payee, payment.amount, payment.created,
+        payment.message, payment.kind, payment.parent, payment.category, prev_hash]);
+    hex::encode(Sha256::digest(fields.to_string().as_bytes()))
+}
+
+#[derive(Debug, Serialize)]
+pub struct OverLimit {
+    pub id: i64,
+    pub name: String,
+    pub credit: i64,
+    pub receive_limit: i64,
+}
+
+/// Stored user counters next to the values replayed from the payment table.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LedgerMismatch {
+    pub id: i64,
+    pub name: String,
+    pub credit: i64,
+    pub actual_credit: i64,
+    pub payments_in: u64,
+    pub actual_in: u64,
+    pub payments_out: u64,
+    pub actual_out: u64,
+}
+
+/// Payment prefilled for the payer, written as `simplets:<payee>?domain=..&amount=..&message=..`.
+/// Unset parts are left out, so equal payments always give the same URI.
+#[derive(Debug, PartialEq)]
+pub struct PaymentUri {
+    pub payee: i64,
+    pub domain: Option<String>,
+    pub amount: Option<u64>,
+    pub message: Option<String>,
+}
+
+impl PaymentUri {
+    pub fn parse(uri: &str) -> Option<PaymentUri> {
+        let rest = uri.strip_prefix("simplets:")?;
+        let (payee, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut parsed = PaymentUri { payee: payee.parse().ok()?, domain: None, amount: None, message: None };
+        for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            match key {
+                "domain" => parsed.domain = Some(percent_decode(value)?),
+                "amount" => parsed.amount = Some(value.parse().ok()?),
+                "message" => parsed.message = Some(percent_decode(value)?),
+                _ => {}
+            }
+        }
+        Some(parsed)
+    }
+}
+
+impl std::fmt::Display for PaymentUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut query = Vec::new();
+        if let Some(d) = &self.domain { query.push(format!("domain={}", percent_encode(d))) }
+        if let Some(a) = self.amount { query.push(format!("amount={}", a)) }
+        if let Some(m) = self.message.as_ref().filter(|m| !m.is_empty()) { query.push(format!("message={}", percent_encode(m))) }
+        write!(f, "simplets:{}", self.payee)?;
+        if !query.is_empty() { write!(f, "?{}", query.join("&"))? }
+        Ok(())
+    }
+}
+
+/// Someone the user keeps at hand for payments, see `Domain::add_contact`.
+#[derive(Debug, Serialize)]
+pub struct Contact {
+    pub id: i64,
+    pub member_number: u64,
+    pub name: String,
+}
+
+/// A member the user paid before, see `Domain::recent_payees`.
+#[derive(Debug, Serialize)]
+pub struct RecentPayee {
+    pub id: i64,
+    pub member_number: u64,
+    pub name: String,
+    /// Amount of the last payment.
+    pub amount: u64,
+    pub paid: String,
+}
+
+/// A message saved for reuse in payments, see `Domain::add_message_template`.
+#[derive(Debug, Serialize)]
+pub struct MessageTemplate {
+    pub id: u64,
+    pub text: String,
+}
+
+/// Prepaid transfer the payer hands out as a token, see `Domain::issue_voucher`.
+#[derive(Debug, Serialize)]
+pub struct Voucher {
+    pub id: u64,
+    pub payer: i64,
+    pub amount: u64,
+    pub created: String,
+    pub expiry: String,
+    pub redeemed: Option<String>,
+    pub payee: Option<i64>,
+    pub cancelled: Option<String>,
+    pub token: String,
+}
+
+pub const REQUEST_PENDING: i64 = 0;
+pub const REQUEST_ACCEPTED: i64 = 1;
+pub const REQUEST_DECLINED: i64 = 2;
+
+#[derive(Debug, Serialize)]
+pub struct PaymentRequest {
+    pub id: u64,
+    pub payer: i64,
+    pub payee: i64,
+    pub amount: u64,
+    pub created: String,
+    pub message: String,
+    pub status: i64,
+}
+
+pub const PENDING_OPEN: i64 = 0;
+pub const PENDING_CONFIRMED: i64 = 1;
+pub const PENDING_REJECTED: i64 = 2;
+pub const PENDING_EXPIRED: i64 = 3;
+
+/// A payment over the domain's confirmation threshold, booked only once the payee confirms it.
+#[derive(Debug, Serialize)]
+pub struct PendingPayment {
+    pub id: u64,
+    pub payer: i64,
+    pub payee: i64,
+    pub amount: u64,
+    pub message: String,
+    pub category: Option<String>,
+    pub created: String,
+    pub expiry: String,
+    /// One of the `PENDING_*` states.
+    pub status: i64,
+    /// The booked payment once confirmed.
+    pub payment: Option<u64>,
+    /// The event the payment is made at, see `Domain::add_event_payment`.
+    pub event: Option<u64>,
+}
+
+/// A payment as its receipt shows it, see `Domain::get_receipt`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Receipt {
+    pub id: u64,
+    pub payer_name: String,
+    pub payer_number: u64,
+    pub payee_name: String,
+    pub payee_number: u64,
+    pub amount: u64,
+    pub created: String,
+}
+
+/// What became of a payment passed to `Domain::add_payment`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Submitted {
+    /// Booked as the payment with this id.
+    Booked(u64),
+    /// Waits for the payee's confirmation as the pending payment with this id.
+    Pending(u64),
+}
+
+/// What a payment would come to, see `Domain::preview_payment`.
+#[derive(Debug, PartialEq)]
+pub struct PaymentPreview {
+    pub limit: Limit,
+    pub fee: u64,
+    pub payer_balance: i64,
+    pub payee_balance: i64,
+    /// Why the payment would be refused, `None` if it would go through.
+    pub error: Option<SimpletsError>,
+}
+
+pub const ESCROW_OPEN: i64 = 0;
+pub const ESCROW_RELEASED: i64 = 1;
+pub const ESCROW_CANCELLED: i64 = 2;
+/// One of the sides disputes the escrow, an admin releases or cancels it.
+pub const ESCROW_DISPUTED: i64 = 3;
+
+/// An amount set aside from the payer's send limit for the payee, paid out once the payer
+/// releases it, see `Domain::open_escrow`.
+#[derive(Debug, Serialize)]
+pub struct Escrow {
+    pub id: u64,
+    pub payer: i64,
+    pub payee: i64,
+    pub amount: u64,
+    pub message: String,
+    pub created: String,
+    /// One of the `ESCROW_*` states.
+    pub status: i64,
+    pub settled: Option<String>,
+    /// The booked payment once released.
+    pub payment: Option<u64>,
+}
+
+pub const LISTING_OFFER: i64 = 0;
+pub const LISTING_WANT: i64 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Listing {
+    pub id: u64,
+    pub owner: i64,
+    pub kind: i64,
+    pub category: String,
+    pub title: String,
+    pub description: String,
+    pub created: String,
+    pub closed: bool,
+    #[serde(default)]
+    pub owner_name: String,
+}
+
+/// Public information a member shares about themselves. Users who never saved a profile
+/// get empty fields.
+#[derive(Debug, Serialize)]
+pub struct Profile {
+    pub user: i64,
+    /// Account name.
+    pub name: String,
+    pub display_name: String,
+    pub contact: String,
+    pub bio: String,
+    /// What the member offers, comma separated keywords.
+    pub skills: String,
+}
+
+/// Entry of the member directory.
+#[derive(Debug, Serialize)]
+pub struct MemberSummary {
+    pub id: i64,
+    pub member_number: u64,
+    pub name: String,
+    pub display_name: String,
+    pub skills: String,
+    /// Only filled in when the privacy policy lets the viewer see it.
+    pub credit: Option<i64>,
+    /// Payments made or received in the last 90 days.
+    pub recent_payments: u64,
+    pub last_payment: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Message {
+    pub id: u64,
+    pub sender: i64,
+    pub recipient: i64,
+    pub body: String,
+    pub created: String,
+    pub seen: bool,
+}
+
+/// The latest message exchanged with one member.
+#[derive(Debug, Serialize)]
+pub struct Conversation {
+    pub other: i64,
+    pub other_name: String,
+    pub body: String,
+    pub created: String,
+    pub unread: u64,
+}
+
+/// Queued email, see `notify::Mailer` for delivery.
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    pub id: u64,
+    pub user: i64,
+    pub email: String,
+    pub subject: String,
+    pub body: String,
+    pub created: String,
+    pub sent: Option<String>,
+    pub attempts: u64,
+}
+
+pub const EVENT_PAYMENT_CREATED: &str = "payment.created";
+pub const EVENT_PAYMENT_REVERSED: &str = "payment.reversed";
+
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    pub id: u64,
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    /// Comma separated event names.
+    pub events: String,
+    pub created: String,
+}
+
+/// Queued webhook call together with where to send it.
+#[derive(Debug)]
+pub struct WebhookDelivery {
+    pub id: u64,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: u64,
+}
+
+/// Another simplets server members can pay to. Transfers either way go through `bridge`, a
+/// system account whose balance mirrors the peer's bridge account for this domain.
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteDomain {
+    pub id: u64,
+    pub name: String,
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    pub bridge: i64,
+    pub created: String,
+    /// Units of the peer's currency one local unit is worth, applied to outgoing transfers.
+    pub rate: f64,
+}
+
+pub const TRANSFER_PENDING: i64 = 0;
+pub const TRANSFER_DELIVERED: i64 = 1;
+/// The peer refused or never took the transfer and the payer got the amount back.
+pub const TRANSFER_REFUNDED: i64 = 2;
+
+/// Payment to or from a member of a remote domain, booked locally as `payment` between the
+/// local account and the bridge.
+#[derive(Debug, Clone, Serialize)]
+pub struct FederatedTransfer {
+    pub id: u64,
+    pub remote: u64,
+    /// Random id shared with the peer, so a repeated delivery isn't booked twice.
+    pub reference: String,
+    pub outgoing: bool,
+    pub local_account: i64,
+    pub remote_account: i64,
+    pub amount: u64,
+    pub message: String,
+    pub payment: u64,
+    pub status: i64,
+    pub created: String,
+    pub attempts: u64,
+    /// The amount in the peer's units, converted by the sender's rate.
+    pub remote_amount: u64,
+}
+
+/// Body of a transfer posted to the peer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferRequest {
+    /// The sending domain, the name it is registered under at the peer.
+    pub domain: String,
+    pub reference: String,
+    pub payer: i64,
+    pub payee: i64,
+    /// In the receiver's units.
+    pub amount: u64,
+    /// In the sender's units, before conversion. Peers not converting leave it out.
+    #[serde(default)]
+    pub original_amount: Option<u64>,
+    pub message: String,
+}
+
+/// Credit of a bridge account and the outgoing transfers through it the peer hasn't booked yet.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BridgePosition {
+    pub credit: i64,
+    pub pending: u64,
+    /// The settled credit in the peer's units, summed from the converted amounts of the
+    /// transfers. Equal to the settled credit while the rate is 1.
+    #[serde(default)]
+    pub mirrored: i64,
+}
+
+impl BridgePosition {
+    /// Credit once the pending transfers are booked at the peer.
+    pub fn settled(&self) -> i64 {
+        self.credit - self.pending as i64
+    }
+}
+
+/// Both ends of a peering. The bridge accounts mirror each other, so each side's settled
+/// credit and the other side's mirrored one add up to zero unless a transfer was lost or
+/// booked twice.
+#[derive(Debug, Serialize)]
+pub struct Reconciliation {
+    pub local: BridgePosition,
+    pub remote: BridgePosition,
+}
+
+impl Reconciliation {
+    pub fn is_balanced(&self) -> bool {
+        self.local.settled() + self.remote.mirrored == 0 && self.remote.settled() + self.local.mirrored == 0
+    }
+}
+
+/// Web login, identified by a random token kept in the user's cookie.
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub id: u64,
+    pub user: i64,
+    pub created: String,
+    pub expiry: String,
+    pub user_agent: Option<String>,
+    pub last_seen: String,
+}
+
+/// Limits an admin raised for one member, e.g. a shop, on top of those given by turnover.
+/// Without an `expiry` it holds until removed.
+#[derive(Debug, Serialize)]
+pub struct LimitOverride {
+    pub user: i64,
+    /// Negative for limits lowered by `Domain::decay_limits`.
+    pub send_bonus: i64,
+    pub receive_bonus: i64,
+    pub expiry: Option<String>,
+    pub created: String,
+}
+
+/// A hold an admin put on an account under review. Its payments out are blocked, and with
+/// `incoming` also those to it.
+#[derive(Debug, Serialize)]
+pub struct AccountFreeze {
+    pub user: i64,
+    pub reason: String,
+    pub incoming: bool,
+    pub created: String,
+}
+
+/// A member leaving the domain. Until the account is `closed` they can only spend their balance
+/// down or pay off their debt, then it is closed at zero. The open counts are what else is left
+/// to settle.
+#[derive(Debug, Serialize)]
+pub struct AccountClosure {
+    pub user: i64,
+    pub member_number: u64,
+    pub name: String,
+    pub credit: i64,
+    pub started: String,
+    pub closed: Option<String>,
+    pub open_escrows: u64,
+    pub open_pending: u64,
+    pub open_requests: u64,
+}
+
+/// Record of an administrative or security relevant action. `actor` is `None` for actions
+/// done from the command line tools.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub actor: Option<i64>,
+    pub action: String,
+    pub target: Option<i64>,
+    pub detail: String,
+    pub created: String,
+}
+
+/// Extra limit between the user and `partner`. It only applies once both have offered one,
+/// and then it is the smaller of the two offers.
+#[derive(Debug, Serialize)]
+pub struct Trustline {
+    pub partner: i64,
+    pub partner_name: String,
+    pub offered: u64,
+    pub received: u64,
+    pub extra: u64,
+}
+
+/// Why a domain operation failed. `Db` and `Internal` are failures of the server, every other
+/// variant is a validation error the user can fix, see `is_validation`.
+#[derive(Debug, PartialEq)]
+pub enum SimpletsError {
+    Db(Error),
+    PaymentLessMin { minimum: u64 },
+    PaymentOverMax { maximum: u64 },
+    /// The payment would take the payer over the domain's daily limit, `remaining` is left today.
+    PaymentDailyLimit { remaining: u64 },
+    PaymentSidesEq,
+    PaymentReceiveLimit { limit: i64 },
+    PaymentSendLimit { limit: i64 },
+    RequestNotFound,
+    RequestClosed,
+    PendingNotFound,
+    /// The pending payment was already confirmed, rejected or has expired.
+    PendingClosed,
+    EscrowNotFound,
+    /// The escrow was already released or cancelled, or is disputed and waits for an admin.
+    EscrowClosed,
+    UserInactive,
+    /// Another account already has the name.
+    UsernameTaken,
+    /// The name doesn't pass the domain's `UsernamePolicy`.
+    UsernameInvalid,
+    /// The new password is shorter than `validation::MIN_PASSWORD_LENGTH`.
+    PasswordTooShort { minimum: u64 },
+    /// The new password contains the member's name or repeats one character.
+    PasswordWeak,
+    /// An admin froze the payer's account, or the payee's also for incoming payments.
+    AccountFrozen,
+    /// The payee blocked the payer, see `Domain::block_user`.
+    Blocked,
+    /// The message is longer than the domain's `MessageRules` allow.
+    MessageTooLong { maximum: u64 },
+    /// The message has a banned word or matches a banned pattern, or a plugged in
+    /// `MessagePolicy` refused it.
+    MessageRefused,
+    /// Not something mail can be sent to, see `validation::email`.
+    EmailInvalid,
+    /// The verification link is unknown, expired, or for an address the user changed since.
+    EmailTokenInvalid,
+    DemurrageNotConfigured,
+    /// The demurrage rate isn't above 0 and at most 1.
+    DemurrageRateInvalid,
+    /// The fee percent isn't between 0 and 100.
+    FeePercentInvalid,
+    AdjustmentNotConfigured,
+    /// The user's role doesn't have the capability, see `Domain::can`.
+    NotPermitted(Capability),
+    /// The user's role only looks, see `Role::is_read_only`.
+    ReadOnly,
+    AttachmentTooLarge { maximum: u64 },
+    /// Not a kind of file kept, see `validation::attachment`.
+    AttachmentType,
+    /// The domain has no `attachment_dir`.
+    AttachmentsDisabled,
+    /// The event doesn't exist or was closed.
+    EventClosed,
+    ListingNotFound,
+    ResetTokenInvalid,
+    TotpInvalid,
+    CategoryInvalid,
+    PrivacyDenied,
+    VoucherInvalid,
+    VoucherRedeemed,
+    VoucherExpired,
+    NotManager,
+    RemoteDomainUnknown,
+    /// The exchange rate of a remote domain isn't a positive number.
+    ExchangeRateInvalid,
+    /// A call from a peer that isn't registered, isn't signed with its secret or is malformed.
+    FederationDenied,
+    /// The amount or a balance it would lead to doesn't fit the ledger's 64-bit integers.
+    AmountOutOfRange,
+    /// The server is shutting down and takes no more payments, see `Domain::close`.
+    ShuttingDown,
+    /// Only a regular payment that wasn't reversed yet can be reversed.
+    PaymentNotReversible,
+    /// A state the ledger should never get into, e.g. fees without a fee account.
+    Internal,
+}
+
+/// Former name of `SimpletsError`.
+#[deprecated(note = "renamed to `SimpletsError`")]
+pub type Outcome = SimpletsError;
+
+impl SimpletsError {
+    /// Stable machine-readable code, part of the API.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SimpletsError::Db(_) => "database_error",
+            SimpletsError::PaymentLessMin { .. } => "payment_less_min",
+            SimpletsError::PaymentOverMax { .. } => "payment_over_max",
+            SimpletsError::PaymentDailyLimit { .. } => "payment_daily_limit",
+            SimpletsError::PaymentSidesEq => "payment_sides_eq",
+            SimpletsError::PaymentReceiveLimit { .. } => "payment_receive_limit",
+            SimpletsError::PaymentSendLimit { .. } => "payment_send_limit",
+            SimpletsError::RequestNotFound => "request_not_found",
+            SimpletsError::RequestClosed => "request_closed",
+            SimpletsError::PendingNotFound => "pending_not_found",
+            SimpletsError::PendingClosed => "pending_closed",
+            SimpletsError::EscrowNotFound => "escrow_not_found",
+            SimpletsError::EscrowClosed => "escrow_closed",
+            SimpletsError::UserInactive => "user_inactive",
+            SimpletsError::UsernameTaken => "username_taken",
+            SimpletsError::UsernameInvalid => "username_invalid",
+            SimpletsError::PasswordTooShort { .. } => "password_too_short",
+            SimpletsError::PasswordWeak => "password_weak",
+            SimpletsError::AccountFrozen => "account_frozen",
+            SimpletsError::Blocked => "blocked",
+            SimpletsError::MessageTooLong { .. } => "message_too_long",
+            SimpletsError::MessageRefused => "message_refused",
+            SimpletsError::EmailInvalid => "email_invalid",
+            SimpletsError::EmailTokenInvalid => "email_token_invalid",
+            SimpletsError::DemurrageNotConfigured => "demurrage_not_configured",
+            SimpletsError::DemurrageRateInvalid => "demurrage_rate_invalid",
+            SimpletsError::FeePercentInvalid => "fee_percent_invalid",
+            SimpletsError::AdjustmentNotConfigured => "adjustment_not_configured",
+            SimpletsError::NotPermitted(_) => "not_permitted",
+            SimpletsError::ReadOnly => "read_only",
+            SimpletsError::AttachmentTooLarge { .. } => "attachment_too_large",
+            SimpletsError::AttachmentType => "attachment_type",
+            SimpletsError::AttachmentsDisabled => "attachments_disabled",
+            SimpletsError::EventClosed => "event_closed",
+            SimpletsError::ListingNotFound => "listing_not_found",
+            SimpletsError::ResetTokenInvalid => "reset_token_invalid",
+            SimpletsError::TotpInvalid => "totp_invalid",
+            SimpletsError::CategoryInvalid => "category_invalid",
+            SimpletsError::PrivacyDenied => "privacy_denied",
+            SimpletsError::VoucherInvalid => "voucher_invalid",
+            SimpletsError::VoucherRedeemed => "voucher_redeemed",
+            SimpletsError::VoucherExpired => "voucher_expired",
+            SimpletsError::NotManager => "not_manager",
+            SimpletsError::RemoteDomainUnknown => "remote_domain_unknown",
+            SimpletsError::ExchangeRateInvalid => "exchange_rate_invalid",
+            SimpletsError::FederationDenied => "federation_denied",
+            SimpletsError::AmountOutOfRange => "amount_out_of_range",
+            SimpletsError::ShuttingDown => "shutting_down",
+            SimpletsError::PaymentNotReversible => "payment_not_reversible",
+            SimpletsError::Internal => "internal_error",
+        }
+    }
+
+    /// Whether the request was refused as invalid, as opposed to the server failing.
+    pub fn is_validation(&self) -> bool {
+        !matches!(self, SimpletsError::Db(_) | SimpletsError::Internal)
+    }
+}
+
+impl std::fmt::Display for SimpletsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimpletsError::Db(e) => write!(f, "database error: {}", e),
+            SimpletsError::PaymentLessMin { minimum } => write!(f, "payment is less than the minimum of {}", minimum),
+            SimpletsError::PaymentOverMax { maximum } => write!(f, "payment is more than the maximum of {}", maximum),
+            SimpletsError::PaymentDailyLimit { remaining } => write!(f, "payer can send only {} more today", remaining),
+            SimpletsError::PaymentSidesEq => write!(f, "payer and payee are the same account"),
+            SimpletsError::PaymentReceiveLimit { limit } => write!(f, "payee can receive at most {}", limit),
+            SimpletsError::PaymentSendLimit { limit } => write!(f, "payer can send at most {}", limit),
+            SimpletsError::RequestNotFound => write!(f, "payment request not found"),
+            SimpletsError::RequestClosed => write!(f, "payment request is already closed"),
+            SimpletsError::PendingNotFound => write!(f, "pending payment not found"),
+            SimpletsError::PendingClosed => write!(f, "pending payment is already closed"),
+            SimpletsError::EscrowNotFound => write!(f, "escrow not found"),
+            SimpletsError::EscrowClosed => write!(f, "escrow is already settled or disputed"),
+            SimpletsError::UserInactive => write!(f, "account is deactivated"),
+            SimpletsError::UsernameTaken => write!(f, "user name is already taken"),
+            SimpletsError::UsernameInvalid => write!(f, "user name isn't allowed"),
+            SimpletsError::PasswordTooShort { minimum } => write!(f, "password must have at least {} characters", minimum),
+            SimpletsError::PasswordWeak => write!(f, "password is too easy to guess"),
+            SimpletsError::AccountFrozen => write!(f, "account is frozen"),
+            SimpletsError::Blocked => write!(f, "payee doesn't accept payments from the payer"),
+            SimpletsError::MessageTooLong { maximum } => write!(f, "message is longer than {} characters", maximum),
+            SimpletsError::MessageRefused => write!(f, "message isn't allowed"),
+            SimpletsError::EmailInvalid => write!(f, "email address is invalid"),
+            SimpletsError::EmailTokenInvalid => write!(f, "email verification link is invalid or expired"),
+            SimpletsError::DemurrageNotConfigured => write!(f, "no demurrage account is set"),
+            SimpletsError::DemurrageRateInvalid => write!(f, "the demurrage rate must be above 0 and at most 1"),
+            SimpletsError::FeePercentInvalid => write!(f, "the fee percent must be between 0 and 100"),
+            SimpletsError::AdjustmentNotConfigured => write!(f, "no adjustment account is set"),
+            SimpletsError::NotPermitted(capability) => write!(f, "the role doesn't allow {}", capability),
+            SimpletsError::ReadOnly => write!(f, "the role is read-only"),
+            SimpletsError::AttachmentTooLarge { maximum } => write!(f, "attachment is larger than {} bytes", maximum),
+            SimpletsError::AttachmentType => write!(f, "attachment is not a photo, PDF or text"),
+            SimpletsError::AttachmentsDisabled => write!(f, "the domain keeps no attachments"),
+            SimpletsError::EventClosed => write!(f, "event is not open"),
+            SimpletsError::ListingNotFound => write!(f, "listing not found"),
+            SimpletsError::ResetTokenInvalid => write!(f, "password reset link is invalid or expired"),
+            SimpletsError::TotpInvalid => write!(f, "wrong one-time code"),
+            SimpletsError::CategoryInvalid => write!(f, "unknown payment category"),
+            SimpletsError::PrivacyDenied => write!(f, "not allowed by the domain's privacy policy"),
+            SimpletsError::VoucherInvalid => write!(f, "invalid voucher"),
+            SimpletsError::VoucherRedeemed => write!(f, "voucher was already redeemed"),
+            SimpletsError::VoucherExpired => write!(f, "voucher has expired or was cancelled"),
+            SimpletsError::NotManager => write!(f, "not a manager of the account"),
+            SimpletsError::RemoteDomainUnknown => write!(f, "unknown remote domain"),
+            SimpletsError::ExchangeRateInvalid => write!(f, "the exchange rate must be a positive number"),
+            SimpletsError::FederationDenied => write!(f, "call of a remote domain refused"),
+            SimpletsError::AmountOutOfRange => write!(f, "amount is out of range"),
+            SimpletsError::ShuttingDown => write!(f, "server is shutting down"),
+            SimpletsError::PaymentNotReversible => write!(f, "payment can't be reversed"),
+            SimpletsError::Internal => write!(f, "internal error"),
+        }
+    }
+}
+
+impl std::error::Error for SimpletsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SimpletsError::Db(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Serializes as `{"code": ..}` plus `limit` for limit errors and `message` for database errors.
+impl Serialize for SimpletsError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("SimpletsError", 2)?;
+        state.serialize_field("code", self.code())?;
+        match self {
+            SimpletsError::PaymentLessMin { minimum: limit } | SimpletsError::PaymentOverMax { maximum: limit }
+            | SimpletsError::PaymentDailyLimit { remaining: limit } | SimpletsError::MessageTooLong { maximum: limit }
+            | SimpletsError::PasswordTooShort { minimum: limit } => state.serialize_field("limit", &(*limit as i64))?,
+            SimpletsError::PaymentReceiveLimit { limit } | SimpletsError::PaymentSendLimit { limit } => state.serialize_field("limit", limit)?,
+            SimpletsError::Db(e) => state.serialize_field("message", &e.to_string())?,
+            _ => state.skip_field("limit")?
+        }
+        state.end()
+    }
+}
+
+impl From<Error> for SimpletsError {
+    fn from(e: Error) -> Self {
+        SimpletsError::Db(e)
+    }
+}
+
+/// Anyone, even without logging in, sees balances and payments of every member.
+pub const PRIVACY_PUBLIC: i64 = 0;
+/// Logged in members see each other's balances and payments.
+pub const PRIVACY_MEMBERS: i64 = 1;
+/// Members only see their own, admins see everyone's.
+pub const PRIVACY_PRIVATE: i64 = 2;
+
+pub struct Domain {
+    pub name: String,
+    pub description: String,
+    /// How to become a member, shown on the public page.
+    pub join_info: String,
+    pool: Pool<SqliteConnectionManager>,
+    writer: Mutex<Connection>,
+    pub minimal_amount: u64,
+    /// Largest single payment a member may make, 0 for no maximum.
+    pub maximal_amount: u64,
+    /// Most a member may send in a day, 0 for no limit.
+    pub daily_limit: u64,
+    pub probation: Probation,
+    pub username_policy: UsernamePolicy,
+    /// Applied to payment messages, see `Domain::check_message`.
+    pub message_rules: MessageRules,
+    /// Applied after the rules, see `Domain::set_message_policy`.
+    message_policy: Option<Box<dyn MessagePolicy>>,
+    /// Set by `Domain::close`, payments are refused from then on.
+    closing: AtomicBool,
+    /// Months of payments the limits grow with, so that they decay on inactive accounts. 0 counts
+    /// all payments ever made.
+    pub limit_window: u64,
+    /// Payments over this amount wait for the payee's confirmation, 0 books all right away.
+    pub confirmation_threshold: u64,
+    /// Days a payment waits for confirmation before it expires.
+    pub confirmation_days: u64,
+    pub currency: CurrencyFormat,
+    pub catalog: Catalog,
+    pub fee_flat: u64,
+    /// Percent of the amount, added to the flat fee.
+    pub fee_percent: f64,
+    /// Fees are only charged when the domain has an account to collect them.
+    pub fee_account: Option<i64>,
+    /// Who may see other members' balances and payments, one of the `PRIVACY_*` levels.
+    pub privacy: i64,
+    /// Zone times are shown in and days counted by, the database keeps UTC.
+    pub timezone: Timezone,
+    /// Whether members see the most active traders, see `Domain::top_traders`.
+    pub leaderboard: bool,
+    pub branding: Branding,
+    /// Where the content of payment attachments is kept, see `DomainConfig::attachment_dir`.
+    /// `None` refuses attachments, as in-memory domains do.
+    pub attachment_dir: Option<PathBuf>,
+}
+
+/// Database schema version this build migrates to, the `user_version` of the last migration
+/// in `Domain::init_database`.
+pub const SCHEMA_VERSION: i64 = 48;
+
+/// Why a domain couldn't be opened.
+#[derive(Debug)]
+pub enum DomainError {
+    /// The data directory couldn't be created.
+    Io(std::io::Error),
+    Sqlite(Error),
+    /// The read-only connection pool couldn't be set up.
+    Pool(r2d2::Error),
+    /// The database was migrated by a newer version of simplets.
+    SchemaVersionTooNew { found: i64, supported: i64 },
+    /// A backup to restore failed the integrity check or isn't a simplets database.
+    Corrupt(String),
+    /// The payment hash chain is broken at `payment`, found before a migration that rebuilds it.
+    ChainBroken { payment: u64 },
+}
+
+impl std::fmt::Display for DomainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DomainError::Io(e) => write!(f, "data directory: {}", e),
+            DomainError::Sqlite(e) => write!(f, "database: {}", e),
+            DomainError::Pool(e) => write!(f, "connection pool: {}", e),
+            DomainError::SchemaVersionTooNew { found, supported } =>
+                write!(f, "database schema version {} is newer than the supported {}", found, supported),
+            DomainError::Corrupt(reason) => write!(f, "backup is not usable: {}", reason),
+            DomainError::ChainBroken { payment } =>
+                write!(f, "payment hash chain broken at payment {}, the database was not upgraded", payment),
+        }
+    }
+}
+
+impl std::error::Error for DomainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DomainError::Io(e) => Some(e),
+            DomainError::Sqlite(e) => Some(e),
+            DomainError::Pool(e) => Some(e),
+            DomainError::SchemaVersionTooNew { .. } | DomainError::Corrupt(_) | DomainError::ChainBroken { .. } => None,
+        }
+    }
+}
+
+/// Version of the `Archive` format `Domain::export_archive` writes.
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Portable dump of a domain, independent of the database schema. It holds the password
+/// hashes and all settings including the voucher key, so keep it as safe as a backup.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Archive {
+    pub version: u32,
+    pub domain: String,
+    pub exported: String,
+    pub users: Vec<User>,
+    pub payments: Vec<Payment>,
+    pub settings: Vec<(String, String)>,
+    pub listings: Vec<Listing>,
+}
+
+/// Why an import was rejected. Nothing is imported then.
+#[derive(Debug)]
+pub enum ImportError {
+    Read(std::io::Error),
+    /// 1-based line number of a malformed record.
+    Line(usize),
+    /// A member of that name already exists or is listed twice.
+    NameTaken(String),
+    /// An imported payment refers to a member that isn't in the domain.
+    UnknownMember(String),
+    /// An archive written by a newer version of simplets.
+    ArchiveVersion(u32),
+    /// An archive can only be imported into a domain without members.
+    NotEmpty,
+    Db(Error),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Read(e) => write!(f, "reading the import: {}", e),
+            ImportError::Line(line) => write!(f, "malformed record on line {}", line),
+            ImportError::NameTaken(name) => write!(f, "member {} already exists", name),
+            ImportError::UnknownMember(member) => write!(f, "unknown member {}", member),
+            ImportError::ArchiveVersion(version) => write!(f, "archive version {} is newer than the supported {}", version, ARCHIVE_VERSION),
+            ImportError::NotEmpty => write!(f, "the domain already has members"),
+            ImportError::Db(e) => write!(f, "database: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<Error> for ImportError {
+    fn from(e: Error) -> Self {
+        ImportError::Db(e)
+    }
+}
+
+impl From<std::io::Error> for DomainError {
+    fn from(e: std::io::Error) -> Self {
+        DomainError::Io(e)
+    }
+}
+
+impl From<Error> for DomainError {
+    fn from(e: Error) -> Self {
+        DomainError::Sqlite(e)
+    }
+}
+
+impl From<r2d2::Error> for DomainError {
+    fn from(e: r2d2::Error) -> Self {
+        DomainError::Pool(e)
+    }
+}
+
+/// Prefix of the environment variables the server and `DomainConfig::from_env` read, e.g.
+/// `SIMPLETS_DATA_DIR`.
+pub const ENV_PREFIX: &str = "SIMPLETS_";
+
+/// Setting `key` from the environment: `SIMPLETS_{key}`, or else the contents of the file
+/// `SIMPLETS_{key}_FILE` names without the trailing newline, as container secrets are mounted.
+/// Empty values count as unset.
+pub fn env_setting(key: &str) -> Option<String> {
+    let key = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+    let value = match std::env::var(&key) {
+        Ok(value) => value,
+        Err(_) => std::fs::read_to_string(std::env::var_os(format!("{}_FILE", key))?).ok()?.trim_end_matches(['\r', '\n']).to_string(),
+    };
+    Some(value).filter(|v| !v.is_empty())
+}
+
+/// Where and how a domain is opened. The database is `{data_dir}/{name}.sqlite` unless
+/// `db_path` points elsewhere.
+///
+/// ```no_run
+/// let domain = simplets::DomainConfig::new("lets").data_dir("/var/lib/simplets").load().expect("load domain");
+/// ```
+#[derive(Debug, Clone)]
+pub struct DomainConfig {
+    name: String,
+    data_dir: PathBuf,
+    db_path: Option<PathBuf>,
+    attachment_dir: Option<PathBuf>,
+    description: String,
+    minimal_amount: u64,
+}
+
+impl DomainConfig {
+    /// Configuration keeping the database in the working directory, as `Domain::new` does, with
+    /// a minimal amount of 10 until the domain's settings say otherwise.
+    pub fn new(name: &str) -> Self {
+        DomainConfig { name: name.to_string(), data_dir: PathBuf::from("."), db_path: None, attachment_dir: None, description: String::new(),
+            minimal_amount: 10 }
+    }
+
+    /// Configuration from the environment, for containers without a config file: the domain
+    /// `SIMPLETS_DOMAIN` (`lets` if unset) in `SIMPLETS_DATA_DIR`, or the database
+    /// `SIMPLETS_DB_PATH`, with attachments in `SIMPLETS_ATTACHMENT_DIR`. See `env_setting` for
+    /// reading them from files.
+    pub fn from_env() -> Self {
+        let mut config = DomainConfig::new(&env_setting("domain").unwrap_or_else(|| "lets".to_string()));
+        if let Some(dir) = env_setting("data_dir") { config = config.data_dir(dir) }
+        if let Some(path) = env_setting("db_path") { config = config.db_path(path) }
+        if let Some(dir) = env_setting("attachment_dir") { config = config.attachment_dir(dir) }
+        config
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = dir.into();
+        self
+    }
+
+    /// Database file to use instead of the one in `data_dir`.
+    pub fn db_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.db_path = Some(path.into());
+        self
+    }
+
+    /// Directory for the content of payment attachments instead of `{database}.attachments`
+    /// next to the database. Backups and archives only have the attachments' metadata, the
+    /// directory is backed up on its own.
+    pub fn attachment_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.attachment_dir = Some(dir.into());
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = description.to_string();
+        self
+    }
+
+    pub fn minimal_amount(mut self, minimal_amount: u64) -> Self {
+        self.minimal_amount = minimal_amount;
+        self
+    }
+
+    /// The database file the domain will use.
+    pub fn path(&self) -> PathBuf {
+        self.db_path.clone().unwrap_or_else(|| self.data_dir.join(format!("{}.sqlite", self.name)))
+    }
+
+    /// Opens the domain with the description and minimal amount given here, creating the
+    /// directory of the database and migrating it as needed.
+    pub fn try_open(&self) -> std::result::Result<Domain, DomainError> {
+        let path = self.path();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = Domain::init_database(Connection::open(&path)?)?;
+        let attachment_dir = self.attachment_dir.clone().unwrap_or_else(|| path.with_extension("attachments"));
+        let manager = SqliteConnectionManager::file(path)
+            .with_init(|c| c.execute_batch("PRAGMA query_only = ON"));
+        let mut domain = Domain::with_connections(&self.name, &self.description, self.minimal_amount, conn, manager)?;
+        domain.attachment_dir = Some(attachment_dir);
+        Ok(domain)
+    }
+
+    #[deprecated(note = "panics if the database can't be opened, use `try_open`")]
+    pub fn open(&self) -> Domain {
+        self.try_open().expect("open domain")
+    }
+
+    /// Opens the domain with the settings stored in its database, like `Domain::load`. Those
+    /// not stored keep the values given here.
+    pub fn load(&self) -> std::result::Result<Domain, DomainError> {
+        let mut domain = self.try_open()?;
+        domain.load_settings()?;
+        Ok(domain)
+    }
+}
+
+impl Domain {
+    #[deprecated(note = "panics if the database can't be opened, use `Domain::try_new`")]
+    pub fn new(name: &str, description: &str, minimal_amount: u64) -> Self {
+        Domain::try_new(name, description, minimal_amount).expect("open domain")
+    }
+
+    /// Opens `{name}.sqlite` in the working directory, see `DomainConfig` for other locations.
+    pub fn try_new(name: &str, description: &str, minimal_amount: u64) -> std::result::Result<Self, DomainError> {
+        DomainConfig::new(name).description(description).minimal_amount(minimal_amount).try_open()
+    }
+
+    /// Domain kept in memory only, gone when it is dropped. Meant for tests, together with
+    /// `seed_users` and `seed_payments`.
+    pub fn new_in_memory(name: &str, minimal_amount: u64) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        // readers need a shared cache to see the writer's database, and a name of their own
+        // so that domains of parallel tests don't share it
+        let uri = format!("file:{}-{}-{}?mode=memory&cache=shared", name, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_URI;
+        let conn = Domain::init_database(Connection::open_with_flags(&uri, flags).expect("in-memory database")).expect("migrate database");
+        let manager = SqliteConnectionManager::file(uri).with_flags(flags)
+            .with_init(|c| c.execute_batch("PRAGMA query_only = ON"));
+        Domain::with_connections(name, "", minimal_amount, conn, manager).expect("db pool")
+    }
+
+    fn with_connections(name: &str, description: &str, minimal_amount: u64, conn: Connection, manager: SqliteConnectionManager)
+        -> std::result::Result<Self, DomainError> {
+        let pool = Pool::new(manager)?;
+        Ok(Domain {name: name.to_string(), description: description.to_string(), join_info: String::new(), pool, writer: Mutex::new(conn), minimal_amount, maximal_amount: 0, daily_limit: 0,
+            probation: Probation::default(), username_policy: UsernamePolicy::default(),
+            message_rules: MessageRules::default(), message_policy: None, closing: AtomicBool::new(false), limit_window: 0, confirmation_threshold: 0, confirmation_days: 7,
+            currency: CurrencyFormat::default(), catalog: Catalog::default(), fee_flat: 0, fee_percent: 0.0, fee_account: None,
+            privacy: PRIVACY_PRIVATE, timezone: Timezone::Local, leaderboard: false,
+            branding: Branding::default(), attachment_dir: None})
+    }
+
+    /// Creates members with the given names and the password "pw" and returns their ids. A
+    /// fixture for tests: ids and member numbers are given like to any account, see
+    /// `insert_account`, so in a new domain both run from 1 in order.
+    pub fn seed_users(&self, names: &[&str]) -> Result<Vec<i64>> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        let mut ids = Vec::new();
+        for name in names {
+            tx.execute("INSERT INTO user (name, credit, payments_in, payments_out, password, created, account_type, member_number)\
+            VALUES (?1, 0, 0, 0, ?2, datetime('now'), ?3, (SELECT COALESCE(MAX(member_number), 0) + 1 FROM user))",
+                       params![name, hash("pw"), ACCOUNT_MEMBER])?;
+            ids.push(tx.last_insert_rowid());
+        }
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    /// Books `(payer, payee, amount)` payments without checking any limits, so tests can start from
+    /// balances and turnover new members couldn't reach on their own. A fixture for tests.
+    pub fn seed_payments(&self, payments: &[(i64, i64, u64)]) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (payer, payee, amount) in payments {
+            tx.book(&Booking {
+                payer: *payer, payee: *payee, amount: sql_amount(*amount)?, message: "seed", kind: PAYMENT_REGULAR, parent: None, category: None, counted: true, created: None,
+            })?;
+        }
+        tx.commit()
+    }
+
+    /// Read-only connection from the pool. Readers don't block each other or the writer.
+    pub fn reader(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        self.pool.get().map_err(|e| Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_BUSY), Some(e.to_string())))
+    }
+
+    /// The single connection used for all writes.
+    pub fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.writer.lock().unwrap()
+    }
+
+    /// Opens the domain with the settings stored in its database, using defaults for those never set.
+    pub fn load(name: &str) -> std::result::Result<Self, DomainError> {
+        DomainConfig::new(name).load()
+    }
+
+    fn load_settings(&mut self) -> Result<()> {
+        if let Some(d) = self.get_setting("description")? { self.description = d; }
+        if let Some(j) = self.get_setting("join_info")? { self.join_info = j; }
+        if let Some(c) = self.get_setting("currency")? {
+            self.currency.singular = c.clone();
+            self.currency.plural = c;
+        }
+        if let Some(c) = self.get_setting("currency_singular")? { self.currency.singular = c; }
+        if let Some(d) = self.get_setting("currency_decimals")? { self.currency.decimals = d.parse().unwrap_or(0); }
+        if let Some(s) = self.get_setting("currency_decimal_separator")? { self.currency.decimal_separator = s; }
+        if let Some(s) = self.get_setting("currency_thousands_separator")? { self.currency.thousands_separator = s; }
+        if let Some(t) = self.get_setting("currency_time")? { self.currency.time = t == "1"; }
+        if let Some(m) = self.get_setting("minimal_amount")? { self.minimal_amount = m.parse().unwrap_or(self.minimal_amount); }
+        if let Some(m) = self.get_setting("maximal_amount")? { self.maximal_amount = m.parse().unwrap_or(0); }
+        if let Some(d) = self.get_setting("daily_limit")? { self.daily_limit = d.parse().unwrap_or(0); }
+        if let Some(d) = self.get_setting("probation_days")? { self.probation.days = d.parse().unwrap_or(0); }
+        if let Some(p) = self.get_setting("probation_payments")? { self.probation.payments = p.parse().unwrap_or(0); }
+        if let Some(p) = self.get_setting("probation_percent")? { self.probation.percent = p.parse().unwrap_or(0); }
+        self.probation.email = self.get_setting("probation_email")?.as_deref() == Some("1");
+        if let Some(m) = self.get_setting("username_min_length")? { self.username_policy.min_length = m.parse().unwrap_or(3); }
+        if let Some(m) = self.get_setting("username_max_length")? { self.username_policy.max_length = m.parse().unwrap_or(32); }
+        if let Some(c) = self.get_setting("username_extra_chars")? { self.username_policy.extra_chars = c; }
+        if let Some(r) = self.get_setting("username_reserved")? {
+            self.username_policy.reserved = r.split(',').map(str::trim).filter(|r| !r.is_empty()).map(str::to_string).collect();
+        }
+        if let Some(m) = self.get_setting("message_max_length")? { self.message_rules.max_length = m.parse().unwrap_or(140); }
+        if let Some(w) = self.get_setting("message_banned_words")? {
+            self.message_rules.banned_words = w.split(',').map(str::trim).filter(|w| !w.is_empty()).map(str::to_string).collect();
+        }
+        if let Some(p) = self.get_setting("message_banned_patterns")? {
+            self.message_rules.banned_patterns = p.lines().map(str::trim).filter(|p| !p.is_empty()).map(str::to_string).collect();
+        }
+        if let Some(s) = self.get_setting("message_strip_urls")? { self.message_rules.strip_urls = s == "1"; }
+        if let Some(w) = self.get_setting("limit_window")? { self.limit_window = w.parse().unwrap_or(0); }
+        if let Some(t) = self.get_setting("confirmation_threshold")? { self.confirmation_threshold = t.parse().unwrap_or(0); }
+        if let Some(d) = self.get_setting("confirmation_days")? { self.confirmation_days = d.parse().unwrap_or(7); }
+        if let Some(f) = self.get_setting("fee_flat")? { self.fee_flat = f.parse().unwrap_or(0); }
+        if let Some(f) = self.get_setting("fee_percent")? { self.fee_percent = f.parse().unwrap_or(0.0); }
+        self.fee_account = self.get_setting("fee_account")?.and_then(|a| a.parse().ok());
+        if let Some(p) = self.get_setting("privacy")? { self.privacy = p.parse().unwrap_or(PRIVACY_PRIVATE); }
+        self.leaderboard = self.get_setting("leaderboard")?.as_deref() == Some("1");
+        if let Some(t) = self.get_setting("theme")? { self.branding.theme = t; }
+        if let Some(t) = self.get_setting("title")? { self.branding.title = t; }
+        if let Some(l) = self.get_setting("logo")? { self.branding.logo = l; }
+        if let Some(c) = self.get_setting("primary_color")? { self.branding.primary_color = c; }
+        if let Some(c) = self.get_setting("background_color")? { self.branding.background_color = c; }
+        Ok(())
+    }
+
+    pub fn update_settings(&mut self, description: &str, join_info: &str, minimal_amount: u64) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("description", description.to_string()), ("join_info", join_info.to_string()),
+            ("minimal_amount", minimal_amount.to_string())] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.description = description.to_string();
+        self.join_info = join_info.to_string();
+        self.minimal_amount = minimal_amount;
+        Ok(())
+    }
+
+    /// Sets the currency names and how amounts are written. The plural is kept in the `currency`
+    /// setting the single name used to be in.
+    pub fn update_currency(&mut self, format: CurrencyFormat) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("currency", format.plural.clone()), ("currency_singular", format.singular.clone()),
+            ("currency_decimals", format.decimals.to_string()), ("currency_decimal_separator", format.decimal_separator.clone()),
+            ("currency_thousands_separator", format.thousands_separator.clone()), ("currency_time", (format.time as u8).to_string())] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.currency = format;
+        Ok(())
+    }
+
+    /// `amount` the way the domain writes amounts, see `CurrencyFormat::format`.
+    pub fn format_amount(&self, amount: i64) -> String {
+        self.currency.format(amount)
+    }
+
+    /// A non-negative amount typed in whole units, in the smallest units, see `CurrencyFormat::parse`.
+    pub fn parse_amount(&self, text: &str) -> Option<u64> {
+        self.currency.parse(text).and_then(|a| u64::try_from(a).ok())
+    }
+
+    /// Sets the largest single payment and the most a member may send in a day, 0 for none.
+    pub fn update_payment_limits(&mut self, maximal_amount: u64, daily_limit: u64) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("maximal_amount", maximal_amount), ("daily_limit", daily_limit)] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value.to_string()])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.maximal_amount = maximal_amount;
+        self.daily_limit = daily_limit;
+        Ok(())
+    }
+
+    /// Bases the limits on the payments of the last `months` months, 0 on all of them.
+    pub fn update_limit_window(&mut self, months: u64) -> Result<()> {
+        self.set_setting("limit_window", &months.to_string())?;
+        self.limit_window = months;
+        Ok(())
+    }
+
+    /// Makes payments over `threshold` wait up to `days` for the payee's confirmation, see
+    /// `confirm_payment`. A threshold of 0 turns confirmations off.
+    pub fn update_confirmation(&mut self, threshold: u64, days: u64) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("confirmation_threshold", threshold), ("confirmation_days", days)] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value.to_string()])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.confirmation_threshold = threshold;
+        self.confirmation_days = days;
+        Ok(())
+    }
+
+    /// Sets the probation of new members, see `Probation`.
+    pub fn update_probation(&mut self, probation: Probation) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("probation_days", probation.days), ("probation_payments", probation.payments),
+            ("probation_percent", probation.percent), ("probation_email", probation.email as u64)] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value.to_string()])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.probation = probation;
+        Ok(())
+    }
+
+    /// Sets which names new members may take, see `UsernamePolicy`. Existing names stay.
+    pub fn update_username_policy(&mut self, policy: UsernamePolicy) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("username_min_length", policy.min_length.to_string()), ("username_max_length", policy.max_length.to_string()),
+            ("username_extra_chars", policy.extra_chars.clone()), ("username_reserved", policy.reserved.join(","))] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.username_policy = policy;
+        Ok(())
+    }
+
+    /// Sets the rules payment messages are checked with from now on, see `MessageRules`. Check
+    /// the patterns with `MessageRules::is_valid` first, an invalid one refuses every message.
+    pub fn update_message_rules(&mut self, rules: MessageRules) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("message_max_length", rules.max_length.to_string()), ("message_banned_words", rules.banned_words.join(",")),
+            ("message_banned_patterns", rules.banned_patterns.join("\n")), ("message_strip_urls", if rules.strip_urls { "1" } else { "0" }.to_string())] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.message_rules = rules;
+        Ok(())
+    }
+
+    /// Checks payment messages with `policy` after the domain's rules. It isn't stored, set it
+    /// each time the domain is opened.
+    pub fn set_message_policy(&mut self, policy: impl MessagePolicy + 'static) {
+        self.message_policy = Some(Box::new(policy));
+    }
+
+    /// Refuses payments from now on with `SimpletsError::ShuttingDown`, the first step of
+    /// shutting the server down, see `Domain::drain`.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Dispatches everything left in the outbox and checkpoints the WAL into the database file,
+    /// returning how many outbox entries it dispatched. Call it after `close` with exclusive
+    /// access, so no transaction is running.
+    pub fn drain(&mut self) -> Result<usize> {
+        let mut dispatched = 0;
+        loop {
+            match self.dispatch_outbox(100)? {
+                0 => break,
+                n => dispatched += n,
+            }
+        }
+        let _: (i64, i64, i64) = self.writer().query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(dispatched)
+    }
+
+    /// The message as it may be booked, see `MessagePolicy`.
+    pub fn check_message(&self, message: &str) -> Result<String, SimpletsError> {
+        validation::message(&self.message_rules, self.message_policy.as_deref(), message)
+    }
+
+    /// Sets the fee charged on top of payments, `FeePercentInvalid` unless the percent is
+    /// between 0 and 100.
+    pub fn update_fees(&mut self, flat: u64, percent: f64, account: Option<i64>) -> Result<(), SimpletsError> {
+        // also refuses NaN
+        if !(0.0..=100.0).contains(&percent) { return Err(SimpletsError::FeePercentInvalid) }
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("fee_flat", flat.to_string()), ("fee_percent", percent.to_string()),
+            ("fee_account", account.map(|a| a.to_string()).unwrap_or_default())] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.fee_flat = flat;
+        self.fee_percent = percent;
+        self.fee_account = account;
+        Ok(())
+    }
+
+    pub fn update_branding(&mut self, branding: Branding) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        for (key, value) in [("theme", &branding.theme), ("title", &branding.title), ("logo", &branding.logo),
+            ("primary_color", &branding.primary_color), ("background_color", &branding.background_color)] {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.branding = branding;
+        Ok(())
+    }
+
+    pub fn update_leaderboard(&mut self, enabled: bool) -> Result<()> {
+        self.set_setting("leaderboard", if enabled { "1" } else { "0" })?;
+        self.leaderboard = enabled;
+        Ok(())
+    }
+
+    pub fn update_privacy(&mut self, privacy: i64) -> Result<()> {
+        self.set_setting("privacy", &privacy.to_string())?;
+        self.privacy = privacy;
+        Ok(())
+    }
+
+    /// Whether `viewer` (`None` when not logged in) may see the balance and payments of `owner`
+    /// under the domain's privacy policy.
+    pub fn may_view_finances(&self, viewer: Option<i64>, owner: i64) -> Result<bool> {
+        Ok(viewer == Some(owner) || self.sees_others_finances(viewer)?)
+    }
+
+    fn sees_others_finances(&self, viewer: Option<i64>) -> Result<bool> {
+        if self.privacy == PRIVACY_PUBLIC { return Ok(true) }
+        let viewer = match viewer {
+            Some(v) => self.get_user(v)?,
+            None => return Ok(false)
+        };
+        Ok((self.privacy == PRIVACY_MEMBERS && viewer.active) || self.can(viewer.id, Capability::ViewAllBalances)?)
+    }
+
+    /// Balance of `owner` as far as the privacy policy lets `viewer` see it.
+    pub fn get_balance_for(&self, viewer: Option<i64>, owner: i64) -> Result<i64, SimpletsError> {
+        if !self.may_view_finances(viewer, owner)? { return Err(SimpletsError::PrivacyDenied) }
+        Ok(self.get_user(owner)?.credit)
+    }
+
+    /// Payment history of `owner` as far as the privacy policy lets `viewer` see it.
+    pub fn get_payments_for(&self, viewer: Option<i64>, owner: i64, filter: &PaymentFilter) -> Result<Vec<Payment>, SimpletsError> {
+        if !self.may_view_finances(viewer, owner)? { return Err(SimpletsError::PrivacyDenied) }
+        Ok(self.get_payments_by_user(owner, filter)?)
+    }
+
+    /// Canonical `simplets:` URI of a payment to `payee` in this domain, see `PaymentUri`.
+    pub fn payment_uri(&self, payee: i64, amount: Option<u64>, message: Option<&str>) -> String {
+        PaymentUri { payee, domain: Some(self.name.clone()), amount, message: message.map(|m| m.to_string()) }.to_string()
+    }
+
+    /// Fee the payer is charged on top of `amount`, `AmountOutOfRange` if it doesn't fit.
+    pub fn fee(&self, amount: u64) -> Result<Amount, SimpletsError> {
+        if self.fee_account.is_none() { return Ok(Amount::ZERO) }
+        let percent = Amount::try_from((amount as f64 * self.fee_percent / 100.0).round() as u64)?;
+        Amount::try_from(self.fee_flat)?.checked_add(percent).ok_or(SimpletsError::AmountOutOfRange)
+    }
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<usize> {
+        self.writer().execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value])
+    }
+
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        match self.reader()?.query_row("SELECT value FROM domain_settings WHERE key = ?", [key], |row| row.get(0)) {
+            Ok(v) => Ok(Some(v)),
+            Err(Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn get_user(&self, id: i64) -> Result<User> {
+        self.reader()?.user(id)
+    }
+
+    pub fn get_user_by_number(&self, member_number: u64) -> Result<User> {
+        self.reader()?.query_row("SELECT * FROM user WHERE member_number = ?", [member_number], user_from_row)
+    }
+
+    pub fn get_user_by_name(&self, name: &str) -> Result<User> {
+        self.reader()?.user_by_name(name)
+    }
+
+    pub fn get_user_by_email(&self, email: &str) -> Result<User> {
+        self.reader()?.query_row("SELECT * FROM user WHERE email = ? COLLATE NOCASE", [email], user_from_row)
+    }
+
+    pub fn get_users(&self) -> Result<Vec<User>> {
+        self.reader()?.users()
+    }
+
+    /// Active users whose name starts with `prefix`, the contacts of `viewer` first.
+    pub fn find_users_by_prefix(&self, prefix: &str, viewer: Option<i64>, limit: u32) -> Result<Vec<User>> {
+        let pattern = format!("{}%", prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM user WHERE active = 1 AND name LIKE ?1 ESCAPE '\\' \
+        ORDER BY id IN (SELECT contact FROM contact WHERE user = ?3) DESC, name LIMIT ?2")?;
+        let iter = stmt.query_map(params![pattern, limit, viewer], user_from_row)?;
+        let mut vec = Vec::new();
+        for person in iter {
+            match person {
+                Ok(u) => vec.push(u),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(vec)
+    }
+
+    /// Creates a member account, the name checked against the domain's `UsernamePolicy`.
+    pub fn add_user(&self, name: &str, password: &str) -> Result<u64, SimpletsError> {
+        validation::username(&self.username_policy, name)?;
+        let conn = self.writer();
+        if conn.user_by_name(name).optional()?.is_some() { return Err(SimpletsError::UsernameTaken) }
+        Ok(insert_account(&conn, name, &hash(password), ACCOUNT_MEMBER)?)
+    }
+
+    /// Creates a community account exempt from limits. It has no usable password,
+    /// payments from it are made by admins.
+    pub fn add_system_account(&self, name: &str) -> Result<u64> {
+        self.insert_user(name, "!", ACCOUNT_SYSTEM)
+    }
+
+    pub fn get_system_accounts(&self) -> Result<Vec<User>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM user WHERE account_type = ?1 ORDER BY name")?;
+        let iter = stmt.query_map([ACCOUNT_SYSTEM], user_from_row)?;
+        let mut vec = Vec::new();
+        for person in iter {
+            match person {
+                Ok(u) => vec.push(u),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(vec)
+    }
+
+    /// Creates a shared account with `manager` as its first manager.
+    pub fn create_group_account(&self, name: &str, manager: i64) -> Result<u64> {
+        let id = self.insert_user(name, "!", ACCOUNT_GROUP)?;
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        tx.execute("INSERT INTO group_account (id, created, created_by) VALUES (?1, datetime('now'), ?2)",
+                   params![id, manager])?;
+        tx.execute("INSERT INTO account_manager (account, manager, added) VALUES (?1, ?2, datetime('now'))",
+                   params![id, manager])?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Lets `manager` pay from the group account. Returns 0 if they already manage it.
+    pub fn add_manager(&self, account: i64, manager: i64) -> Result<usize> {
+        self.writer().execute("INSERT OR IGNORE INTO account_manager (account, manager, added) \
+        SELECT id, ?2, datetime('now') FROM group_account WHERE id = ?1", params![account, manager])
+    }
+
+    /// Removes a manager unless they are the last one, so the account never ends up orphaned.
+    pub fn remove_manager(&self, account: i64, manager: i64) -> Result<usize> {
+        self.writer().execute("DELETE FROM account_manager WHERE account = ?1 AND manager = ?2 \
+        AND (SELECT COUNT(*) FROM account_manager WHERE account = ?1) > 1", params![account, manager])
+    }
+
+    pub fn get_managers(&self, account: i64) -> Result<Vec<User>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT user.* FROM account_manager JOIN user ON user.id = account_manager.manager \
+        WHERE account_manager.account = ?1 ORDER BY user.name")?;
+        let iter = stmt.query_map([account], user_from_row)?;
+        iter.collect()
+    }
+
+    /// Group accounts `manager` may pay from.
+    pub fn get_managed_accounts(&self, manager: i64) -> Result<Vec<User>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT user.* FROM account_manager JOIN user ON user.id = account_manager.account \
+        WHERE account_manager.manager = ?1 ORDER BY user.name")?;
+        let iter = stmt.query_map([manager], user_from_row)?;
+        iter.collect()
+    }
+
+    fn insert_user(&self, name: &str, password_hash: &str, account_type: i64) -> Result<u64> {
+        insert_account(&self.writer(), name, password_hash, account_type)
+    }
+
+    /// Creates members from a `name,email,balance` CSV (see `parse_users_csv`) in one transaction.
+    /// Opening balances are booked against the migration account, created by the first import,
+    /// so the domain still sums to zero. The members get no password, they set one through a
+    /// reset link sent to their email or an admin sets it. Returns their account numbers.
+    pub fn import_users_csv(&self, mut reader: impl Read) -> std::result::Result<Vec<u64>, ImportError> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map_err(ImportError::Read)?;
+        let users = parse_users_csv(&text).map_err(ImportError::Line)?;
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let migration = tx.query_row("SELECT value FROM domain_settings WHERE key = 'migration_account'", [],
+                                     |row| row.get::<_, String>(0)).optional()?.and_then(|a| a.parse().ok());
+        let migration = match migration {
+            Some(id) => id,
+            None => {
+                let id = insert_account(&tx, "migration", "!", ACCOUNT_SYSTEM)? as i64;
+                tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES ('migration_account', ?1)", [id.to_string()])?;
+                id
+            }
+        };
+        let mut ids = Vec::new();
+        for (i, user) in users.iter().enumerate() {
+            if users[..i].iter().any(|u| u.name == user.name) || tx.user_by_name(&user.name).optional()?.is_some() {
+                return Err(ImportError::NameTaken(user.name.clone()))
+            }
+            let id = insert_account(&tx, &user.name, "!", ACCOUNT_MEMBER)?;
+            tx.execute("UPDATE user SET email = ?1 WHERE id = ?2", params![user.email, id])?;
+            if let Some(legacy) = &user.legacy_id {
+                tx.execute("INSERT INTO legacy_account (legacy_id, user) VALUES (?1, ?2)", params![legacy, id])
+                    .map_err(|_| ImportError::NameTaken(legacy.clone()))?;
+            }
+            if user.balance != 0 {
+                let (payer, payee) = if user.balance > 0 { (migration, id as i64) } else { (id as i64, migration) };
+                tx.book(&Booking {
+                    payer, payee, amount: sql_amount(user.balance.unsigned_abs())?, message: "opening balance", kind: PAYMENT_OPENING, parent: None, category: None, counted: false, created: None,
+                })?;
+            }
+            ids.push(id);
+        }
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    /// Books the payment history of another system in one transaction, oldest first and with
+    /// the original times, taken as local times of the domain. Members are looked up by the id they had there (see `ImportedUser`),
+    /// then by account number or name. The limits aren't checked, the history happened, but
+    /// every payment must be between two members and in the past. The balances and turnover come
+    /// out of the history, so import the members with zero opening balances. On error nothing is
+    /// booked and `ImportError::Line` has the 1-based position of the bad payment.
+    pub fn import_payments(&self, payments: Vec<ImportedPayment>) -> std::result::Result<usize, ImportError> {
+        let now = self.timezone.now();
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let resolve = |key: &str| -> std::result::Result<i64, ImportError> {
+            let key = key.trim();
+            let mapped = tx.query_row("SELECT user FROM legacy_account WHERE legacy_id = ?", [key], |row| row.get(0)).optional()?;
+            let found = match mapped {
+                Some(id) => Some(id),
+                None => match key.parse().ok().map(|id| tx.user(id).optional()).transpose()?.flatten() {
+                    Some(u) => Some(u.id),
+                    None => tx.user_by_name(key).optional()?.map(|u| u.id),
+                },
+            };
+            found.ok_or_else(|| ImportError::UnknownMember(key.to_string()))
+        };
+        let mut resolved = Vec::new();
+        for (i, p) in payments.iter().enumerate() {
+            let (payer, payee) = (resolve(&p.payer)?, resolve(&p.payee)?);
+            if p.amount == 0 || payer == payee || p.created > now { return Err(ImportError::Line(i + 1)) }
+            resolved.push((payer, payee, i));
+        }
+        resolved.sort_by_key(|(_, _, i)| payments[*i].created);
+        for (payer, payee, i) in resolved.iter() {
+            let p = &payments[*i];
+            let created = self.timezone.to_utc(p.created).format(TIME_FORMAT).to_string();
+            tx.book(&Booking {
+                payer: *payer, payee: *payee, amount: sql_amount(p.amount)?, message: &p.message, kind: PAYMENT_REGULAR, parent: None, category: None, counted: true,
+                created: Some(&created),
+            })?;
+        }
+        tx.commit()?;
+        Ok(payments.len())
+    }
+
+    /// The system account imported opening balances are booked against.
+    pub fn get_migration_account(&self) -> Result<Option<i64>> {
+        Ok(self.get_setting("migration_account")?.and_then(|a| a.parse().ok()))
+    }
+
+    /// Sets a password the member picked, see `validation::password`. 0 if there's no such member.
+    pub fn set_password(&self, user_id: i64, new_password: &str) -> Result<usize, SimpletsError> {
+        let conn = self.writer();
+        let Some(user) = conn.user(user_id).optional()? else { return Ok(0) };
+        validation::password(&user.name, new_password)?;
+        let updated = conn.execute("UPDATE user SET password = ?1 WHERE id = ?2",
+                          params![hash(new_password), user_id])?;
+        self.notify(&conn, &user, NOTIFY_PASSWORD, "notify-password-subject", self.catalog.get(self.catalog.default_locale(), "notify-password-body"))?;
+        Ok(updated)
+    }
+
+    pub fn set_email(&self, user_id: i64, email: Option<&str>, notify: i64) -> Result<usize> {
+        self.writer().execute("UPDATE user SET email_verified = email_verified AND email IS ?1, email = ?1, notify = ?2 WHERE id = ?3",
+                              params![email, notify, user_id])
+    }
+
+    /// Queues an email for `user` if they have an address and opted in to `event`.
+    fn notify(&self, conn: &Connection, user: &User, event: i64, subject: &str, body: &str) -> Result<()> {
+        let email = match &user.email {
+            Some(e) if user.notify & event != 0 => e,
+            _ => return Ok(())
+        };
+        let subject = self.catalog.get(self.catalog.default_locale(), subject);
+        Domain::enqueue_email(conn, user.id, email, subject, body)
+    }
+
+    /// Queues an email regardless of the user's preferences, e.g. a password reset link.
+    pub fn queue_email(&self, user_id: i64, email: &str, subject: &str, body: &str) -> Result<()> {
+        Domain::enqueue_email(&self.writer(), user_id, email, subject, body)
+    }
+
+    fn enqueue_email(conn: &Connection, user_id: i64, email: &str, subject: &str, body: &str) -> Result<()> {
+        conn.execute("INSERT INTO notification (user, email, subject, body, created, sent, attempts)\
+        VALUES (?1, ?2, ?3, ?4, datetime('now'), NULL, 0)", params![user_id, email, subject, body])?;
+        Ok(())
+    }
+
+    /// Sets the user's email address and mails them a link to `{base_url}/verify/{token}`
+    /// confirming it, valid for a day. Returns the token. Earlier links stop working.
+    pub fn start_email_verification(&self, user_id: i64, email: &str, base_url: &str) -> Result<String, SimpletsError> {
+        validation::email(email)?;
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute("UPDATE user SET email_verified = email_verified AND email IS ?1, email = ?1 WHERE id = ?2", params![email, user_id])?;
+        tx.execute("DELETE FROM email_verification WHERE user = ?1", [user_id])?;
+        tx.execute("INSERT INTO email_verification (hash, user, email, created, expiry) \
+        VALUES (?1, ?2, ?3, datetime('now'), datetime('now', '+1 day'))", params![hash(&token), user_id, email])?;
+        let locale = self.catalog.default_locale();
+        let body = self.catalog.format(locale, "verify-email-body", &[&format!("{}/verify/{}", base_url.trim_end_matches('/'), token)]);
+        Domain::enqueue_email(&tx, user_id, email, self.catalog.get(locale, "verify-email-subject"), &body)?;
+        tx.commit()?;
+        Ok(token)
+    }
+
+    /// Marks the address the token was sent to as verified and returns its user.
+    pub fn verify_email(&self, token: &str) -> Result<i64, SimpletsError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let (user_id, email): (i64, String) = match tx.query_row("SELECT user, email FROM email_verification WHERE hash = ?1 \
+        AND expiry > datetime('now')", [hash(token)], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(found) => found,
+            Err(Error::QueryReturnedNoRows) => return Err(SimpletsError::EmailTokenInvalid),
+            Err(e) => return Err(SimpletsError::Db(e))
+        };
+        if tx.execute("UPDATE user SET email_verified = 1 WHERE id = ?1 AND email = ?2", params![user_id, email])? == 0 {
+            return Err(SimpletsError::EmailTokenInvalid)
+        }
+        tx.execute("DELETE FROM email_verification WHERE user = ?1", [user_id])?;
+        tx.commit()?;
+        info!(user = user_id, "email verified");
+        Ok(user_id)
+    }
+
+    /// Creates a one-time password reset token valid for an hour and returns it.
+    /// Like API tokens, only its hash is stored.
+    pub fn create_password_reset(&self, user_id: i64) -> Result<String> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        self.writer().execute("INSERT INTO password_reset (user, hash, created, expiry)\
+        VALUES (?1, ?2, datetime('now'), datetime('now', '+1 hour'))", params![user_id, hash(&token)])?;
+        Ok(token)
+    }
+
+    /// The user a reset token belongs to, if the token is still valid.
+    pub fn check_password_reset(&self, token: &str) -> Result<i64, SimpletsError> {
+        match self.reader()?.query_row("SELECT user FROM password_reset WHERE hash = ?1 \
+        AND expiry > datetime('now')", [hash(token)], |row| row.get(0)) {
+            Ok(user) => Ok(user),
+            Err(Error::QueryReturnedNoRows) => Err(SimpletsError::ResetTokenInvalid),
+            Err(e) => Err(SimpletsError::Db(e))
+        }
+    }
+
+    /// Sets a new password using a reset token. All outstanding tokens of the user are
+    /// invalidated, so a leaked link can't be reused.
+    pub fn reset_password(&self, token: &str, new_password: &str) -> Result<i64, SimpletsError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let user_id: i64 = match tx.query_row("SELECT user FROM password_reset WHERE hash = ?1 \
+        AND expiry > datetime('now')", [hash(token)], |row| row.get(0)) {
+            Ok(user) => user,
+            Err(Error::QueryReturnedNoRows) => return Err(SimpletsError::ResetTokenInvalid),
+            Err(e) => return Err(SimpletsError::Db(e))
+        };
+        let user = tx.user(user_id)?;
+        validation::password(&user.name, new_password)?;
+        tx.execute("DELETE FROM password_reset WHERE user = ?", [user_id])?;
+        tx.execute("DELETE FROM session WHERE user = ?", [user_id])?;
+        tx.execute("UPDATE user SET password = ?1 WHERE id = ?2", params![hash(new_password), user_id])?;
+        self.notify(&tx, &user, NOTIFY_PASSWORD, "notify-password-subject", self.catalog.get(self.catalog.default_locale(), "notify-password-body"))?;
+        tx.commit()?;
+        Ok(user_id)
+    }
+
+    /// Unsent notifications that haven't failed too often, oldest first.
+    pub fn pending_notifications(&self, limit: u32) -> Result<Vec<Notification>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM notification WHERE sent IS NULL AND attempts < 5 ORDER BY id LIMIT ?")?;
+        let iter = stmt.query_map([limit], |row| {
+            Ok(Notification {
+                id: row.get(0)?,
+                user: row.get(1)?,
+                email: row.get(2)?,
+                subject: row.get(3)?,
+                body: row.get(4)?,
+                created: row.get(5)?,
+                sent: row.get(6)?,
+                attempts: row.get(7)?,
+            })
+        })?;
+        iter.collect()
+    }
+
+    pub fn mark_notification_sent(&self, id: u64) -> Result<usize> {
+        self.writer().execute("UPDATE notification SET sent = datetime('now'), attempts = attempts + 1 WHERE id = ?", [id])
+    }
+
+    pub fn mark_notification_failed(&self, id: u64) -> Result<usize> {
+        self.writer().execute("UPDATE notification SET attempts = attempts + 1 WHERE id = ?", [id])
+    }
+
+    /// Gives the user `role`. Returns 0 when that would leave nobody to manage the settings,
+    /// the last admin can't be demoted.
+    pub fn set_role(&self, user_id: i64, role: Role) -> Result<usize> {
+        const MANAGED: &str = "SELECT EXISTS(SELECT 1 FROM user JOIN role ON role.name = user.role WHERE role.capability = ?1)";
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let managed: bool = tx.query_row(MANAGED, [Capability::ManageSettings], |row| row.get(0))?;
+        let changed = tx.execute("UPDATE user SET role = ?1 WHERE id = ?2", params![role, user_id])?;
+        if managed && !tx.query_row(MANAGED, [Capability::ManageSettings], |row| row.get::<_, bool>(0))? { return Ok(0) }
+        tx.commit()?;
+        info!(user = user_id, %role, "role set");
+        Ok(changed)
+    }
+
+    /// Whether the user's role has the capability, see `roles`.
+    pub fn can(&self, user: i64, capability: Capability) -> Result<bool> {
+        let conn = self.reader()?;
+        Domain::has_capability(&conn, user, capability)
+    }
+
+    /// The capabilities of `role`, in the order of `Capability::ALL`.
+    pub fn role_capabilities(&self, role: Role) -> Result<Vec<Capability>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT capability FROM role WHERE name = ?1")?;
+        let granted = stmt.query_map([role], |row| row.get(0))?.collect::<Result<Vec<Capability>>>()?;
+        Ok(Capability::ALL.into_iter().filter(|c| granted.contains(c)).collect())
+    }
+
+    /// Grants or takes away a capability of all users with `role`. Admins always manage the
+    /// settings, so someone can undo a mistake, and read-only roles only get viewing ones.
+    pub fn set_role_capability(&self, role: Role, capability: Capability, granted: bool) -> Result<usize> {
+        if role == Role::Admin && capability == Capability::ManageSettings { return Ok(0) }
+        if granted && role.is_read_only() && !capability.is_viewing() { return Ok(0) }
+        if granted { self.writer().execute("INSERT OR IGNORE INTO role (name, capability) VALUES (?1, ?2)", params![role, capability]) }
+        else { self.writer().execute("DELETE FROM role WHERE name = ?1 AND capability = ?2", params![role, capability]) }
+    }
+
+    fn has_capability(conn: &Connection, user: i64, capability: Capability) -> Result<bool> {
+        let granted = conn.query_row("SELECT role, EXISTS(SELECT 1 FROM role WHERE name = user.role AND capability = ?2) FROM user WHERE id = ?1",
+                                     params![user, capability], |row| Ok((row.get::<_, Role>(0)?, row.get::<_, bool>(1)?))).optional()?;
+        // a role table edited by hand still can't let a read-only role change anything
+        Ok(matches!(granted, Some((role, true)) if capability.is_viewing() || !role.is_read_only()))
+    }
+
+    /// `NotPermitted` unless the user acting in a method may, checked inside its transaction.
+    fn require(conn: &Connection, user: i64, capability: Capability) -> Result<(), SimpletsError> {
+        if Domain::has_capability(conn, user, capability)? { Ok(()) } else { Err(SimpletsError::NotPermitted(capability)) }
+    }
+
+    /// `ReadOnly` if the user acting in a method only looks, see `Role::is_read_only`.
+    fn require_writable(user: &User) -> Result<(), SimpletsError> {
+        if user.role.is_read_only() { Err(SimpletsError::ReadOnly) } else { Ok(()) }
+    }
+
+    pub fn deactivate_user(&self, user_id: i64) -> Result<usize> {
+        self.writer().execute("UPDATE user SET active = 0 WHERE id = ?1", [user_id])
+    }
+
+    pub fn reactivate_user(&self, user_id: i64) -> Result<usize> {
+        self.writer().execute("UPDATE user SET active = 1 WHERE id = ?1", [user_id])
+    }
+
+    /// Everything stored about the user, one JSON array of rows per kind of record, read in one
+    /// transaction. Password, session and token hashes and the second factor secret are left out.
+    pub fn export_user_data(&self, user_id: i64) -> Result<serde_json::Value> {
+        let mut conn = self.reader()?;
+        let tx = conn.transaction()?;
+        tx.user(user_id)?;
+        let mut data = serde_json::Map::new();
+        for (section, sql) in [
+            ("account", "SELECT id, member_number, name, credit, payments_in, payments_out, created, role, active, account_type, email, notify \
+            FROM user WHERE id = ?1"),
+            ("profile", "SELECT display_name, contact, bio, skills, updated FROM profile WHERE user = ?1"),
+            ("payments", "SELECT id, payer, payee, amount, created, message, kind, parent, category FROM payment \
+            WHERE payer = ?1 OR payee = ?1 ORDER BY id"),
+            ("attachments", "SELECT a.id, a.payment, a.uploader, a.name, a.media_type, a.size, a.created FROM attachment a \
+            JOIN payment p ON p.id = a.payment WHERE a.uploader = ?1 OR ?1 IN (p.payer, p.payee) ORDER BY a.id"),
+            ("payment_requests", "SELECT * FROM payment_request WHERE payer = ?1 OR payee = ?1 ORDER BY id"),
+            ("pending_payments", "SELECT * FROM pending_payment WHERE payer = ?1 OR payee = ?1 ORDER BY id"),
+            ("escrows", "SELECT * FROM escrow WHERE payer = ?1 OR payee = ?1 ORDER BY id"),
+            ("vouchers", "SELECT * FROM voucher WHERE payer = ?1 OR payee = ?1 ORDER BY id"),
+            ("trustlines", "SELECT * FROM trustline WHERE truster = ?1 OR trustee = ?1"),
+            ("limit_override", "SELECT send_bonus, receive_bonus, expiry, created FROM limit_override WHERE user = ?1"),
+            ("leaderboard_opt_out", "SELECT created FROM leaderboard_opt_out WHERE user = ?1"),
+            ("message_templates", "SELECT text, created FROM message_template WHERE user = ?1 ORDER BY id"),
+            ("contacts", "SELECT contact, created FROM contact WHERE user = ?1 ORDER BY contact"),
+            ("terms_acceptance", "SELECT version, accepted FROM terms_acceptance WHERE user = ?1 ORDER BY version"),
+            ("federated_identities", "SELECT issuer, subject, created FROM federated_identity WHERE user = ?1"),
+            ("blocked_users", "SELECT blocked, created FROM user_block WHERE blocker = ?1 ORDER BY blocked"),
+            ("account_freeze", "SELECT reason, incoming, created FROM account_freeze WHERE user = ?1"),
+            ("account_closure", "SELECT started, closed FROM account_closure WHERE user = ?1"),
+            ("listings", "SELECT * FROM listing WHERE owner = ?1 ORDER BY id"),
+            ("messages", "SELECT * FROM message WHERE sender = ?1 OR recipient = ?1 ORDER BY id"),
+            ("notifications", "SELECT id, email, subject, body, created, sent FROM notification WHERE user = ?1 ORDER BY id"),
+            ("sessions", "SELECT id, created, expiry, user_agent, last_seen FROM session WHERE user = ?1 ORDER BY id"),
+            ("tokens", "SELECT id, label, created, expiry FROM token WHERE user = ?1 ORDER BY id"),
+            ("second_factor", "SELECT enabled, created FROM totp WHERE user = ?1"),
+            ("managed_accounts", "SELECT account, added FROM account_manager WHERE manager = ?1"),
+            ("federated_transfers", "SELECT * FROM federation_transfer WHERE local_account = ?1 ORDER BY id"),
+            ("legacy_ids", "SELECT legacy_id FROM legacy_account WHERE user = ?1"),
+            ("audit_log", "SELECT * FROM audit_log WHERE actor = ?1 OR target = ?1 ORDER BY id"),
+        ] {
+            data.insert(section.to_string(), serde_json::Value::Array(rows_as_json(&tx, sql, user_id)?));
+        }
+        Ok(serde_json::Value::Object(data))
+    }
+
+    /// Erases the personal data of a member: the name becomes a pseudonym, the email, password,
+    /// profile, listings, messages, sessions, second factor and the files they attached are
+    /// removed and the account is deactivated. The payments stay, with their messages, under the account number, so the
+    /// ledger still sums to zero and the payment chain stays intact. Returns 0 if `user_id` isn't
+    /// a member, system and group accounts aren't personal.
+    pub fn anonymize_user(&self, user_id: i64) -> Result<usize> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let name = match tx.user(user_id).optional()? {
+            Some(u) if u.account_type == ACCOUNT_MEMBER => u.name,
+            _ => return Ok(0),
+        };
+        tx.execute("UPDATE user SET name = ?2, password = '!', email = NULL, notify = 0, active = 0 WHERE id = ?1",
+                   params![user_id, format!("anonymized {}", user_id)])?;
+        for sql in ["DELETE FROM profile WHERE user = ?1", "DELETE FROM listing WHERE owner = ?1",
+            "DELETE FROM message WHERE sender = ?1 OR recipient = ?1", "DELETE FROM notification WHERE user = ?1",
+            "DELETE FROM session WHERE user = ?1", "DELETE FROM token WHERE user = ?1", "DELETE FROM totp WHERE user = ?1",
+            "DELETE FROM recovery_code WHERE user = ?1", "DELETE FROM password_reset WHERE user = ?1", "DELETE FROM email_verification WHERE user = ?1",
+            "DELETE FROM trustline WHERE truster = ?1 OR trustee = ?1", "DELETE FROM account_manager WHERE manager = ?1",
+            "DELETE FROM payment_request WHERE payer = ?1 OR payee = ?1", "DELETE FROM legacy_account WHERE user = ?1",
+            "DELETE FROM limit_override WHERE user = ?1", "DELETE FROM account_freeze WHERE user = ?1", "DELETE FROM leaderboard_opt_out WHERE user = ?1",
+            "DELETE FROM message_template WHERE user = ?1", "DELETE FROM contact WHERE user = ?1 OR contact = ?1",
+            "DELETE FROM user_block WHERE blocker = ?1", "DELETE FROM terms_acceptance WHERE user = ?1",
+            "DELETE FROM federated_identity WHERE user = ?1", "DELETE FROM pending_payment WHERE payer = ?1 OR payee = ?1",
+            "DELETE FROM escrow WHERE (payer = ?1 OR payee = ?1) AND payment IS NULL"] {
+            tx.execute(sql, [user_id])?;
+        }
+        tx.execute("DELETE FROM login_failure WHERE key = ?1", [format!("user:{}", name)])?;
+        let attachments = tx.prepare("SELECT id FROM attachment WHERE uploader = ?1")?
+            .query_map([user_id], |row| row.get::<_, u64>(0))?.collect::<Result<Vec<_>>>()?;
+        tx.execute("DELETE FROM attachment WHERE uploader = ?1", [user_id])?;
+        tx.commit()?;
+        // the files go once their rows are gone, one left behind is no longer reachable
+        if let Some(dir) = &self.attachment_dir {
+            for id in attachments {
+                if let Err(e) = std::fs::remove_file(dir.join(id.to_string())) {
+                    warn!(error = %e, attachment = id, "attachment file not removed");
+                }
+            }
+        }
+        info!(user = user_id, "user anonymized");
+        Ok(1)
+    }
+
+    pub fn get_payments(&self) -> Result<Vec<Payment>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM payment")?;
+        let iter = stmt.query_map([], payment_from_row)?;
+        let mut vec = Vec::new();
+        for person in iter {
+            match person {
+                Ok(u) => vec.push(u),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(vec)
+    }
+
+    pub fn get_payments_by_user(&self, user: i64, filter: &PaymentFilter) -> Result<Vec<Payment>> {
+        self.reader()?.payments(user, &PaymentFilter { timezone: self.timezone, ..filter.clone() })
+    }
+
+    /// Refuses payments from `blocked` to the user, e.g. one harassing them with payment
+    /// messages. Returns 0 for the user themselves or an unknown account.
+    pub fn block_user(&self, blocker: i64, blocked: i64) -> Result<usize> {
+        let blocked = self.writer().execute("INSERT OR IGNORE INTO user_block (blocker, blocked, created) \
+        SELECT ?1, id, datetime('now') FROM user WHERE id = ?2 AND id != ?1", params![blocker, blocked])?;
+        if blocked > 0 { info!(blocker, "user blocked") }
+        Ok(blocked)
+    }
+
+    pub fn unblock_user(&self, blocker: i64, blocked: i64) -> Result<usize> {
+        self.writer().execute("DELETE FROM user_block WHERE blocker = ?1 AND blocked = ?2", params![blocker, blocked])
+    }
+
+    pub fn is_blocked(&self, blocker: i64, blocked: i64) -> Result<bool> {
+        self.reader()?.query_row("SELECT EXISTS(SELECT 1 FROM user_block WHERE blocker = ?1 AND blocked = ?2)", params![blocker, blocked], |row| row.get(0))
+    }
+
+    fn check_blocked(conn: &Connection, payer: i64, payee: i64) -> Result<(), SimpletsError> {
+        let blocked: bool = conn.query_row("SELECT EXISTS(SELECT 1 FROM user_block WHERE blocker = ?1 AND blocked = ?2)", params![payee, payer], |row| row.get(0))?;
+        if blocked { Err(SimpletsError::Blocked) } else { Ok(()) }
+    }
+
+    /// Adds `contact` to the user's contacts, offered first when paying. Returns 0 for the user
+    /// themselves or an unknown account.
+    pub fn add_contact(&self, user: i64, contact: i64) -> Result<usize> {
+        self.writer().execute("INSERT OR IGNORE INTO contact (user, contact, created) \
+        SELECT ?1, id, datetime('now') FROM user WHERE id = ?2 AND id != ?1", params![user, contact])
+    }
+
+    pub fn remove_contact(&self, user: i64, contact: i64) -> Result<usize> {
+        self.writer().execute("DELETE FROM contact WHERE user = ?1 AND contact = ?2", params![user, contact])
+    }
+
+    /// The user's active contacts by name.
+    pub fn get_contacts(&self, user: i64) -> Result<Vec<Contact>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT u.id, u.member_number, u.name FROM contact c JOIN user u ON u.id = c.contact \
+        WHERE c.user = ?1 AND u.active = 1 ORDER BY u.name")?;
+        let iter = stmt.query_map([user], |row| Ok(Contact { id: row.get(0)?, member_number: row.get(1)?, name: row.get(2)? }))?;
+        iter.collect()
+    }
+
+    pub fn is_contact(&self, user: i64, contact: i64) -> Result<bool> {
+        self.reader()?.query_row("SELECT EXISTS(SELECT 1 FROM contact WHERE user = ?1 AND contact = ?2)", params![user, contact], |row| row.get(0))
+    }
+
+    /// The last `n` members the user paid, most recent first, with the amount paid last.
+    pub fn recent_payees(&self, user: i64, n: u32) -> Result<Vec<RecentPayee>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT u.id, u.member_number, u.name, p.amount, p.created FROM payment p JOIN user u ON u.id = p.payee \
+        WHERE p.id IN (SELECT MAX(id) FROM payment WHERE payer = ?1 AND kind = ?2 GROUP BY payee) AND u.active = 1 \
+        ORDER BY p.id DESC LIMIT ?3")?;
+        let iter = stmt.query_map(params![user, PAYMENT_REGULAR, n], |row| Ok(RecentPayee {
+            id: row.get(0)?, member_number: row.get(1)?, name: row.get(2)?, amount: row.get(3)?, paid: row.get(4)?,
+        }))?;
+        iter.collect()
+    }
+
+    /// Saves a message the user can pick when paying. Saving the same text again keeps one.
+    pub fn add_message_template(&self, user: i64, text: &str) -> Result<usize> {
+        self.writer().execute("INSERT OR IGNORE INTO message_template (user, text, created) VALUES (?1, ?2, datetime('now'))",
+                              params![user, text.trim()])
+    }
+
+    pub fn delete_message_template(&self, id: u64, user: i64) -> Result<usize> {
+        self.writer().execute("DELETE FROM message_template WHERE id = ?1 AND user = ?2", params![id, user])
+    }
+
+    /// The user's saved messages, alphabetically.
+    pub fn get_message_templates(&self, user: i64) -> Result<Vec<MessageTemplate>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT id, text FROM message_template WHERE user = ?1 ORDER BY text")?;
+        let iter = stmt.query_map([user], |row| Ok(MessageTemplate { id: row.get(0)?, text: row.get(1)? }))?;
+        iter.collect()
+    }
+
+    /// Returns the balance before `from` and the user's payments in the inclusive date range,
+    /// each with the running balance after it.
+    pub fn statement(&self, user_id: i64, from: Option<NaiveDate>, to: Option<NaiveDate>) -> Result<(i64, Vec<StatementLine>)> {
+        let from = from.map(|d| self.timezone.day_start(d)).unwrap_or_else(|| "0000-01-01".to_string());
+        let to = to.map(|d| self.timezone.day_start(d.succ())).unwrap_or_else(|| "9999-12-31".to_string());
+        let conn = self.reader()?;
+        let opening: i64 = conn.query_row("SELECT COALESCE(SUM(CASE WHEN payee = ?1 THEN amount ELSE -amount END), 0) \
+        FROM payment WHERE (payer = ?1 OR payee = ?1) AND created < ?2", params![user_id, from], |row| row.get(0))?;
+        let mut stmt = conn.prepare("SELECT p.created, u.name, CASE WHEN p.payee = ?1 THEN p.amount ELSE -p.amount END, p.message, p.kind \
+        FROM payment p JOIN user u ON u.id = CASE WHEN p.payee = ?1 THEN p.payer ELSE p.payee END \
+        WHERE (p.payer = ?1 OR p.payee = ?1) AND p.created >= ?2 AND p.created < ?3 ORDER BY p.created, p.id")?;
+        let mut rows = stmt.query(params![user_id, from, to])?;
+        let mut balance = opening;
+        let mut lines = Vec::new();
+        while let Some(row) = rows.next()? {
+            let amount: i64 = row.get(2)?;
+            balance += amount;
+            let created: String = row.get(0)?;
+            lines.push(StatementLine { created: self.timezone.render(&created), counterparty: row.get(1)?, amount, message: row.get(3)?, balance, kind: row.get(4)? });
+        }
+        Ok((opening, lines))
+    }
+
+    /// Returns the user's statement for the given inclusive date range as CSV. The running
+    /// balance starts from the sum of all payments made before `from`. Amounts are in whole
+    /// units, see `CurrencyFormat::plain`.
+    pub fn export_payments_csv(&self, user_id: i64, from: Option<NaiveDate>, to: Option<NaiveDate>) -> Result<String> {
+        let (_, lines) = self.statement(user_id, from, to)?;
+        let mut csv = String::from("date,counterparty,amount,message,balance\n");
+        for l in lines.iter() {
+            let message = if l.kind == PAYMENT_ADJUSTMENT { format!("[adjustment] {}", l.message) } else { l.message.clone() };
+            csv.push_str(&format!("{},{},{},{},{}\n", l.created, csv_field(&l.counterparty), self.currency.plain(l.amount),
+                                   csv_field(&message), self.currency.plain(l.balance)));
+        }
+        Ok(csv)
+    }
+
+    /// The statement of `statement` as a printable A4 PDF with the totals and the user's current
+    /// limits, labelled in `locale`. Both dates are required, as the period is printed.
+    pub fn render_statement_pdf(&self, user_id: i64, from: NaiveDate, to: NaiveDate, locale: &str) -> Result<Vec<u8>> {
+        use pdf::{Document, Font, PAGE_HEIGHT, PAGE_WIDTH};
+        let user = self.get_user(user_id)?;
+        let (send_limit, receive_limit) = self.get_limits(user_id)?;
+        let (opening, lines) = self.statement(user_id, Some(from), Some(to))?;
+        let closing = lines.last().map(|l| l.balance).unwrap_or(opening);
+        let received: i64 = lines.iter().filter(|l| l.amount > 0).map(|l| l.amount).sum();
+        let sent: i64 = lines.iter().filter(|l| l.amount < 0).map(|l| -l.amount).sum();
+        let text = |key: &str| self.catalog.get(locale, key).to_string();
+        let money = |amount: i64| self.format_amount(amount);
+        let (left, right) = (50.0, PAGE_WIDTH - 50.0);
+        let mut doc = Document::default();
+        let mut y = PAGE_HEIGHT - 60.0;
+        let title = if self.description.is_empty() { &self.name } else { &self.description };
+        doc.text(left, y, Font::Bold, 16.0, title);
+        y -= 22.0;
+        doc.text(left, y, Font::Regular, 11.0, &self.catalog.format(locale, "statement-title", &[&user.name, &user.member_number]));
+        y -= 15.0;
+        doc.text(left, y, Font::Regular, 10.0, &self.catalog.format(locale, "statement-period", &[&from, &to]));
+        y -= 25.0;
+        for (key, amount) in [("statement-opening", opening), ("statement-received", received), ("statement-sent", sent),
+            ("statement-closing", closing), ("statement-send-limit", send_limit), ("statement-receive-limit", receive_limit)] {
+            doc.text(left, y, Font::Regular, 10.0, &text(key));
+            doc.text_right(300.0, y, 10.0, &money(amount));
+            y -= 14.0;
+        }
+        y -= 12.0;
+        // date, counterparty, amount and balance right aligned, message
+        let columns = [left, 105.0, 270.0, 330.0, 340.0];
+        let header = |doc: &mut Document, y: f64| {
+            doc.text(columns[0], y, Font::Bold, 9.0, &text("statement-date"));
+            doc.text(columns[1], y, Font::Bold, 9.0, &text("statement-counterparty"));
+            doc.text_right(columns[2], y, 9.0, &text("statement-amount"));
+            doc.text_right(columns[3], y, 9.0, &text("statement-balance"));
+            doc.text(columns[4], y, Font::Bold, 9.0, &text("statement-message"));
+            doc.line(left, y - 4.0, right, y - 4.0);
+        };
+        header(&mut doc, y);
+        y -= 16.0;
+        for l in lines.iter() {
+            if y < 60.0 {
+                doc.add_page();
+                y = PAGE_HEIGHT - 60.0;
+                header(&mut doc, y);
+                y -= 16.0;
+            }
+            doc.text(columns[0], y, Font::Mono, 8.0, l.created.get(..10).unwrap_or(&l.created));
+            doc.text(columns[1], y, Font::Mono, 8.0, &l.counterparty.chars().take(20).collect::<String>());
+            let sign = if l.amount > 0 { "+" } else { "" };
+            doc.text_right(columns[2], y, 8.0, &format!("{}{}", sign, money(l.amount)));
+            doc.text_right(columns[3], y, 8.0, &money(l.balance));
+            if l.kind == PAYMENT_ADJUSTMENT {
+                let message = format!("{}: {}", text("statement-adjustment"), l.message);
+                doc.text(columns[4], y, Font::Bold, 8.0, &message.chars().take(38).collect::<String>());
+            } else {
+                doc.text(columns[4], y, Font::Mono, 8.0, &l.message.chars().take(42).collect::<String>());
+            }
+            y -= 11.0;
+        }
+        let generated = self.timezone.now().format("%Y-%m-%d %H:%M").to_string();
+        let pages = doc.page_count();
+        for page in 0..pages {
+            doc.select_page(page);
+            doc.text(left, 30.0, Font::Regular, 8.0, &self.catalog.format(locale, "statement-footer", &[&generated, &(page + 1), &pages]));
+        }
+        Ok(doc.finish())
+    }
+
+    /// Totals, monthly turnover and the ten most frequent counterparties of the user. Fees and
+    /// demurrage are counted like any other payment, since they leave the account too.
+    pub fn user_stats(&self, user_id: i64) -> Result<UserStats> {
+        let conn = self.reader()?;
+        let (sent, payments_sent, received, payments_received): (u64, u64, u64, u64) = conn.query_row("SELECT \
+        COALESCE(SUM(CASE WHEN payer = ?1 THEN amount END), 0), COUNT(CASE WHEN payer = ?1 THEN 1 END), \
+        COALESCE(SUM(CASE WHEN payee = ?1 THEN amount END), 0), COUNT(CASE WHEN payee = ?1 THEN 1 END) \
+        FROM payment WHERE payer = ?1 OR payee = ?1", [user_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+        let count = payments_sent + payments_received;
+        let mut stmt = conn.prepare("SELECT strftime('%Y-%m', created, ?2) AS month, \
+        COALESCE(SUM(CASE WHEN payer = ?1 THEN amount END), 0), COALESCE(SUM(CASE WHEN payee = ?1 THEN amount END), 0) \
+        FROM payment WHERE payer = ?1 OR payee = ?1 GROUP BY month ORDER BY month DESC")?;
+        let months = stmt.query_map(params![user_id, self.timezone.sql_modifier()], |row| Ok(MonthTurnover {
+            month: row.get(0)?,
+            sent: row.get(1)?,
+            received: row.get(2)?,
+        }))?.collect::<Result<_>>()?;
+        let mut stmt = conn.prepare("SELECT u.id, u.name, COUNT(*) AS n, SUM(p.amount) \
+        FROM payment p JOIN user u ON u.id = CASE WHEN p.payer = ?1 THEN p.payee ELSE p.payer END \
+        WHERE p.payer = ?1 OR p.payee = ?1 GROUP BY u.id ORDER BY n DESC, SUM(p.amount) DESC LIMIT 10")?;
+        let counterparties = stmt.query_map([user_id], |row| Ok(Counterparty {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            payments: row.get(2)?,
+            turnover: row.get(3)?,
+        }))?.collect::<Result<_>>()?;
+        let mut stmt = conn.prepare("SELECT category, \
+        COALESCE(SUM(CASE WHEN payer = ?1 THEN amount END), 0) AS sent, COALESCE(SUM(CASE WHEN payee = ?1 THEN amount END), 0) AS received \
+        FROM payment WHERE (payer = ?1 OR payee = ?1) AND category IS NOT NULL GROUP BY category ORDER BY sent + received DESC")?;
+        let categories = stmt.query_map([user_id], |row| Ok(CategoryTurnover {
+            category: row.get(0)?,
+            sent: row.get(1)?,
+            received: row.get(2)?,
+        }))?.collect::<Result<_>>()?;
+        Ok(UserStats {
+            sent,
+            received,
+            payments_sent,
+            payments_received,
+            average: (sent + received).checked_div(count).unwrap_or(0),
+            months,
+            counterparties,
+            categories,
+        })
+    }
+
+    /// Writes a consistent snapshot of the database to `path` using SQLite's online backup,
+    /// payments keep being booked meanwhile.
+    pub fn backup_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.reader()?.backup(DatabaseName::Main, path, None)
+    }
+
+    /// Replaces the database with the backup at `path`, migrating it if it is older, and
+    /// reloads the settings. The backup is checked before anything is overwritten.
+    pub fn restore_from(&mut self, path: impl AsRef<Path>) -> std::result::Result<(), DomainError> {
+        let path = path.as_ref();
+        {
+            let backup = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            let check: String = backup.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+            if check != "ok" {
+                return Err(DomainError::Corrupt(check))
+            }
+            let version: i64 = backup.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            if version == 0 {
+                return Err(DomainError::Corrupt("no simplets schema".to_string()))
+            }
+            if version > SCHEMA_VERSION {
+                return Err(DomainError::SchemaVersionTooNew { found: version, supported: SCHEMA_VERSION })
+            }
+        }
+        let mut writer = self.writer();
+        writer.restore(DatabaseName::Main, path, None::<fn(rusqlite::backup::Progress)>)?;
+        let restored = std::mem::replace(&mut *writer, Connection::open_in_memory()?);
+        *writer = Domain::init_database(restored)?;
+        drop(writer);
+        self.load_settings()?;
+        info!(domain = %self.name, path = %path.display(), "database restored");
+        Ok(())
+    }
+
+    /// Dumps the members, payments, settings and listings, read in one transaction so they
+    /// are consistent with each other. Sessions, tokens, second factors, the audit log and
+    /// federation peers stay with the server.
+    pub fn export_archive(&self) -> Result<Archive> {
+        let mut conn = self.reader()?;
+        let tx = conn.transaction()?;
+        let users = {
+            let mut stmt = tx.prepare("SELECT * FROM user ORDER BY id")?;
+            let iter = stmt.query_map([], user_from_row)?;
+            iter.collect::<Result<Vec<User>>>()?
+        };
+        let payments = {
+            let mut stmt = tx.prepare("SELECT * FROM payment ORDER BY id")?;
+            let iter = stmt.query_map([], payment_from_row)?;
+            iter.collect::<Result<Vec<Payment>>>()?
+        };
+        let settings = {
+            let mut stmt = tx.prepare("SELECT key, value FROM domain_settings ORDER BY key")?;
+            let iter = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            iter.collect::<Result<Vec<(String, String)>>>()?
+        };
+        let listings = {
+            let mut stmt = tx.prepare("SELECT listing.*, user.name FROM listing JOIN user ON user.id = listing.owner ORDER BY listing.id")?;
+            let iter = stmt.query_map([], listing_from_row)?;
+            iter.collect::<Result<Vec<Listing>>>()?
+        };
+        Ok(Archive { version: ARCHIVE_VERSION, domain: self.name.clone(), exported: Utc::now().format(TIME_FORMAT).to_string(),
+            users, payments, settings, listings })
+    }
+
+    /// Loads an `export_archive` dump into this domain, which must have no members yet, in one
+    /// transaction. Account and payment numbers are kept, the payments are chained anew.
+    pub fn import_archive(&mut self, archive: &Archive) -> std::result::Result<(), ImportError> {
+        if archive.version > ARCHIVE_VERSION { return Err(ImportError::ArchiveVersion(archive.version)) }
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if tx.query_row("SELECT COUNT(*) FROM user", [], |row| row.get::<_, i64>(0))? > 0 { return Err(ImportError::NotEmpty) }
+        for u in archive.users.iter() {
+            // archives from before member numbers have none, the account keeps its id as the number
+            let number = if u.member_number > 0 { u.member_number as i64 } else { u.id };
+            tx.execute("INSERT INTO user (id, name, credit, payments_in, payments_out, password, created, role, active, \
+            account_type, email, notify, member_number, email_verified) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+                       params![u.id, u.name, u.credit, u.payments_in, u.payments_out, u.password, u.created, u.role, u.active,
+                           u.account_type, u.email, u.notify, number, u.email_verified])?;
+        }
+        let mut prev = GENESIS_HASH.to_string();
+        for p in archive.payments.iter() {
+            let hash = entry_hash(p, &prev);
+            tx.execute("INSERT INTO payment (id, payer, payee, amount, created, message, kind, parent, category, prev_hash, entry_hash) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                       params![p.id, p.payer, p.payee, p.amount, p.created, p.message, p.kind, p.parent, p.category, prev, hash])?;
+            prev = hash;
+        }
+        for (key, value) in archive.settings.iter() {
+            tx.execute("INSERT OR REPLACE INTO domain_settings (key, value) VALUES (?1, ?2)", params![key, value])?;
+        }
+        for l in archive.listings.iter() {
+            tx.execute("INSERT INTO listing (id, owner, kind, category, title, description, created, closed) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                       params![l.id, l.owner, l.kind, l.category, l.title, l.description, l.created, l.closed])?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.load_settings()?;
+        info!(domain = %self.name, from = %archive.domain, users = archive.users.len(), payments = archive.payments.len(), "archive imported");
+        Ok(())
+    }
+
+    /// The twelve months of `year` in the domain's timezone, months without activity included.
+    pub fn turnover_report(&self, year: i32) -> Result<Vec<DomainMonth>> {
+        let conn = self.reader()?;
+        let (year, modifier) = (year.to_string(), self.timezone.sql_modifier());
+        let mut months: Vec<DomainMonth> = (1..=12).map(|m| DomainMonth {
+            month: format!("{}-{:02}", year, m), payments: 0, volume: 0, active_members: 0, new_members: 0,
+        }).collect();
+        let index = |month: String| month.get(5..).and_then(|m| m.parse::<usize>().ok()).filter(|m| (1..=12).contains(m)).map(|m| m - 1);
+        let mut stmt = conn.prepare("SELECT strftime('%Y-%m', created, ?2) AS month, COUNT(*), SUM(amount) FROM payment \
+        WHERE kind = ?3 AND strftime('%Y', created, ?2) = ?1 GROUP BY month")?;
+        let mut rows = stmt.query(params![year, modifier, PAYMENT_REGULAR])?;
+        while let Some(row) = rows.next()? {
+            if let Some(i) = index(row.get(0)?) { (months[i].payments, months[i].volume) = (row.get(1)?, row.get(2)?); }
+        }
+        let mut stmt = conn.prepare("SELECT month, COUNT(DISTINCT account) FROM (\
+        SELECT strftime('%Y-%m', created, ?2) AS month, payer AS account FROM payment WHERE kind = ?3 AND strftime('%Y', created, ?2) = ?1 \
+        UNION ALL SELECT strftime('%Y-%m', created, ?2), payee FROM payment WHERE kind = ?3 AND strftime('%Y', created, ?2) = ?1) \
+        JOIN user u ON u.id = account WHERE u.account_type = ?4 GROUP BY month")?;
+        let mut rows = stmt.query(params![year, modifier, PAYMENT_REGULAR, ACCOUNT_MEMBER])?;
+        while let Some(row) = rows.next()? {
+            if let Some(i) = index(row.get(0)?) { months[i].active_members = row.get(1)?; }
+        }
+        let mut stmt = conn.prepare("SELECT strftime('%Y-%m', created, ?2) AS month, COUNT(*) FROM user \
+        WHERE account_type = ?3 AND strftime('%Y', created, ?2) = ?1 GROUP BY month")?;
+        let mut rows = stmt.query(params![year, modifier, ACCOUNT_MEMBER])?;
+        while let Some(row) = rows.next()? {
+            if let Some(i) = index(row.get(0)?) { months[i].new_members = row.get(1)?; }
+        }
+        Ok(months)
+    }
+
+    /// Balance distribution and trading concentration of the active members, for spotting
+    /// hoarding or a community that stopped trading.
+    pub fn participation_metrics(&self) -> Result<ParticipationMetrics> {
+        let conn = self.reader()?;
+        let mut balances = {
+            let mut stmt = conn.prepare("SELECT credit FROM user WHERE account_type = ?1 AND active = 1 ORDER BY credit")?;
+            let iter = stmt.query_map([ACCOUNT_MEMBER], |row| row.get(0))?;
+            iter.collect::<Result<Vec<i64>>>()?
+        };
+        let members = balances.len();
+        let median_balance = match members {
+            0 => 0,
+            n if n % 2 == 0 => (balances[n / 2 - 1] + balances[n / 2]) / 2,
+            n => balances[n / 2],
+        };
+        let (min_balance, max_balance) = (balances.first().copied().unwrap_or(0), balances.last().copied().unwrap_or(0));
+        let (in_debit, in_credit) = (balances.iter().filter(|b| **b < 0).count(), balances.iter().filter(|b| **b > 0).count());
+        balances.iter_mut().for_each(|b| *b = (*b).max(0));
+        let total: i64 = balances.iter().sum();
+        let credit_gini = if total == 0 { 0.0 } else {
+            let weighted: i64 = balances.iter().enumerate().map(|(i, b)| (i as i64 + 1) * b).sum();
+            let n = members as f64;
+            2.0 * weighted as f64 / (n * total as f64) - (n + 1.0) / n
+        };
+        let volumes = {
+            let mut stmt = conn.prepare("SELECT SUM(amount) AS volume FROM (\
+            SELECT payer AS account, amount FROM payment WHERE kind = ?1 AND created >= datetime('now', '-365 days') \
+            UNION ALL SELECT payee, amount FROM payment WHERE kind = ?1 AND created >= datetime('now', '-365 days')) \
+            JOIN user u ON u.id = account WHERE u.account_type = ?2 AND u.active = 1 GROUP BY account ORDER BY volume DESC")?;
+            let iter = stmt.query_map(params![PAYMENT_REGULAR, ACCOUNT_MEMBER], |row| row.get(0))?;
+            iter.collect::<Result<Vec<u64>>>()?
+        };
+        let volume: u64 = volumes.iter().sum();
+        let top_decile_share = if volume == 0 { 0.0 } else {
+            volumes.iter().take(members.div_ceil(10)).sum::<u64>() as f64 / volume as f64
+        };
+        let dormant = conn.query_row("SELECT COUNT(*) FROM user u WHERE u.account_type = ?1 AND u.active = 1 AND NOT EXISTS (\
+        SELECT 1 FROM payment p WHERE (p.payer = u.id OR p.payee = u.id) AND p.kind = ?2 AND p.created >= datetime('now', ?3))",
+                                     params![ACCOUNT_MEMBER, PAYMENT_REGULAR, format!("-{} days", DORMANT_DAYS)], |row| row.get(0))?;
+        Ok(ParticipationMetrics {
+            members: members as u64, in_debit: in_debit as u64, in_credit: in_credit as u64, min_balance, median_balance, max_balance,
+            credit_gini, top_decile_share, dormant,
+        })
+    }
+
+    /// Active members without a regular payment from `since` on, longest idle first. Those who
+    /// joined later are new, not dormant.
+    pub fn dormant_users(&self, since: NaiveDate) -> Result<Vec<DormantUser>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT u.id, u.member_number, u.name, u.email, u.credit, \
+        (SELECT MAX(created) FROM payment WHERE (payer = u.id OR payee = u.id) AND kind = ?2) AS last \
+        FROM user u WHERE u.account_type = ?1 AND u.active = 1 AND u.created < ?3 AND NOT EXISTS (\
+        SELECT 1 FROM payment p WHERE (p.payer = u.id OR p.payee = u.id) AND p.kind = ?2 AND p.created >= ?3) \
+        ORDER BY last IS NOT NULL, last, u.id")?;
+        let iter = stmt.query_map(params![ACCOUNT_MEMBER, PAYMENT_REGULAR, self.timezone.day_start(since)], |row| Ok(DormantUser {
+            id: row.get(0)?, member_number: row.get(1)?, name: row.get(2)?, email: row.get(3)?, credit: row.get(4)?, last_payment: row.get(5)?,
+        }))?;
+        iter.collect()
+    }
+
+    /// Emails the dormant members who have an address that their community misses them, and
+    /// returns how many were reminded.
+    pub fn remind_dormant_users(&self, since: NaiveDate) -> Result<usize> {
+        let dormant = self.dormant_users(since)?;
+        let locale = self.catalog.default_locale();
+        let subject = self.catalog.get(locale, "dormant-reminder-subject");
+        let conn = self.writer();
+        let mut reminded = 0;
+        for user in dormant.iter() {
+            let email = match &user.email { Some(e) => e, None => continue };
+            let body = self.catalog.format(locale, "dormant-reminder-body", &[&user.name, &self.name, &since]);
+            Domain::enqueue_email(&conn, user.id, email, subject, &body)?;
+            reminded += 1;
+        }
+        info!(since = %since, reminded, "dormant members reminded");
+        Ok(reminded)
+    }
+
+    /// Opens an event, e.g. a market day, and returns its id. Payments made with
+    /// `add_event_payment` are tagged with it until `close_event`.
+    pub fn open_event(&self, name: &str) -> Result<u64> {
+        let conn = self.writer();
+        conn.execute("INSERT INTO event (name, created) VALUES (?1, datetime('now'))", [name])?;
+        let id = conn.last_insert_rowid() as u64;
+        info!(event = id, name, "event opened");
+        Ok(id)
+    }
+
+    pub fn close_event(&self, id: u64) -> Result<usize> {
+        self.writer().execute("UPDATE event SET closed = datetime('now') WHERE id = ?1 AND closed IS NULL", [id])
+    }
+
+    /// All events, the newest first.
+    pub fn get_events(&self) -> Result<Vec<Event>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT id, name, created, closed FROM event ORDER BY id DESC")?;
+        let iter = stmt.query_map([], event_from_row)?;
+        iter.collect()
+    }
+
+    pub fn get_open_events(&self) -> Result<Vec<Event>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT id, name, created, closed FROM event WHERE closed IS NULL ORDER BY id")?;
+        let iter = stmt.query_map([], event_from_row)?;
+        iter.collect()
+    }
+
+    /// Posts news for all members, e.g. a meeting or the next market day. Only those managing the
+    /// settings may.
+    pub fn post_announcement(&self, author: i64, title: &str, body: &str) -> Result<u64, SimpletsError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        Domain::require(&tx, author, Capability::ManageSettings)?;
+        tx.execute("INSERT INTO announcement (author, title, body, created) VALUES (?1, ?2, ?3, datetime('now'))",
+                   params![author, title, body])?;
+        let id = tx.last_insert_rowid() as u64;
+        tx.commit()?;
+        info!(announcement = id, author, "announcement posted");
+        Ok(id)
+    }
+
+    pub fn delete_announcement(&self, id: u64) -> Result<usize> {
+        self.writer().execute("DELETE FROM announcement WHERE id = ?1", [id])
+    }
+
+    /// The `limit` newest announcements, the newest first.
+    pub fn recent_announcements(&self, limit: u32) -> Result<Vec<Announcement>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT a.id, a.author, u.name, a.title, a.body, a.created FROM announcement a \
+        JOIN user u ON u.id = a.author ORDER BY a.id DESC LIMIT ?1")?;
+        let iter = stmt.query_map([limit], |row| Ok(Announcement {
+            id: row.get(0)?,
+            author: row.get(1)?,
+            author_name: row.get(2)?,
+            title: row.get(3)?,
+            body: row.get(4)?,
+            created: row.get(5)?,
+        }))?;
+        iter.collect()
+    }
+
+    /// Publishes a new version of the terms of use and returns its number. Members accept it
+    /// when they next log in, see `needs_terms_acceptance`.
+    pub fn publish_terms(&self, text: &str) -> Result<u32> {
+        let conn = self.writer();
+        conn.execute("INSERT INTO terms (text, published) VALUES (?1, datetime('now'))", [text])?;
+        let version = conn.last_insert_rowid() as u32;
+        info!(version, "terms published");
+        Ok(version)
+    }
+
+    /// The newest terms, `None` until any are published.
+    pub fn current_terms(&self) -> Result<Option<Terms>> {
+        match self.reader()?.query_row("SELECT version, text, published FROM terms ORDER BY version DESC LIMIT 1", [],
+                                       |row| Ok(Terms { version: row.get(0)?, text: row.get(1)?, published: row.get(2)? })) {
+            Ok(t) => Ok(Some(t)),
+            Err(Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Whether the user hasn't accepted the newest terms yet.
+    pub fn needs_terms_acceptance(&self, user: i64) -> Result<bool> {
+        self.reader()?.query_row("SELECT EXISTS(SELECT 1 FROM terms WHERE version = (SELECT MAX(version) FROM terms) \
+        AND version NOT IN (SELECT version FROM terms_acceptance WHERE user = ?1))", [user], |row| row.get(0))
+    }
+
+    /// Records that the user accepted `version`. Returns 0 when it isn't the newest any more,
+    /// the terms changed while the user read them.
+    pub fn accept_terms(&self, user: i64, version: u32) -> Result<usize> {
+        self.writer().execute("INSERT OR IGNORE INTO terms_acceptance (user, version, accepted) \
+        SELECT ?1, ?2, datetime('now') WHERE ?2 = (SELECT MAX(version) FROM terms)", params![user, version])
+    }
+
+    /// Links the `subject` an OpenID Connect provider `issuer` knows to the user, see
+    /// `simplets::oidc`. Returns 0 if the subject is already linked, to this or another user.
+    pub fn link_identity(&self, user: i64, issuer: &str, subject: &str) -> Result<usize> {
+        let linked = self.writer().execute("INSERT OR IGNORE INTO federated_identity (issuer, subject, user, created) \
+        VALUES (?1, ?2, ?3, datetime('now'))", params![issuer, subject, user])?;
+        if linked > 0 { info!(user, issuer, "identity linked") }
+        Ok(linked)
+    }
+
+    /// The user the provider's subject is linked to.
+    pub fn user_by_identity(&self, issuer: &str, subject: &str) -> Result<Option<i64>> {
+        self.reader()?.query_row("SELECT user FROM federated_identity WHERE issuer = ?1 AND subject = ?2",
+                                 [issuer, subject], |row| row.get(0)).optional()
+    }
+
+    /// The providers the user can log in through.
+    pub fn linked_identities(&self, user: i64) -> Result<Vec<FederatedIdentity>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT issuer, subject, created FROM federated_identity WHERE user = ?1 ORDER BY created")?;
+        let iter = stmt.query_map([user], |row| Ok(FederatedIdentity { issuer: row.get(0)?, subject: row.get(1)?, created: row.get(2)? }))?;
+        iter.collect()
+    }
+
+    /// Removes the user's links to the provider `issuer`, the password still logs in.
+    pub fn unlink_identity(&self, user: i64, issuer: &str) -> Result<usize> {
+        self.writer().execute("DELETE FROM federated_identity WHERE user = ?1 AND issuer = ?2", params![user, issuer])
+    }
+
+    fn check_event_open(conn: &Connection, event: u64) -> Result<(), SimpletsError> {
+        let open: bool = conn.query_row("SELECT COUNT(*) > 0 FROM event WHERE id = ?1 AND closed IS NULL", [event], |row| row.get(0))?;
+        if open { Ok(()) } else { Err(SimpletsError::EventClosed) }
+    }
+
+    fn tag_event(conn: &Connection, event: u64, payment: u64) -> Result<()> {
+        conn.execute("INSERT INTO event_payment (payment, event) VALUES (?1, ?2)", params![payment, event])?;
+        Ok(())
+    }
+
+    /// Volume, participants and what each stall took in at the event, also while it's open.
+    pub fn event_summary(&self, event_id: u64) -> Result<EventSummary> {
+        let conn = self.reader()?;
+        let event = conn.query_row("SELECT id, name, created, closed FROM event WHERE id = ?1", [event_id], event_from_row)?;
+        let (payments, volume) = conn.query_row("SELECT COUNT(*), COALESCE(SUM(p.amount), 0) FROM event_payment e \
+        JOIN payment p ON p.id = e.payment WHERE e.event = ?1", [event_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let participants = conn.query_row("SELECT COUNT(DISTINCT account) FROM (\
+        SELECT p.payer AS account FROM event_payment e JOIN payment p ON p.id = e.payment WHERE e.event = ?1 \
+        UNION ALL SELECT p.payee FROM event_payment e JOIN payment p ON p.id = e.payment WHERE e.event = ?1)", [event_id], |row| row.get(0))?;
+        let mut stmt = conn.prepare("SELECT u.id, u.member_number, u.name, SUM(p.amount) AS received, COUNT(*) FROM event_payment e \
+        JOIN payment p ON p.id = e.payment JOIN user u ON u.id = p.payee WHERE e.event = ?1 \
+        GROUP BY u.id ORDER BY received DESC, u.name")?;
+        let stalls = stmt.query_map([event_id], |row| Ok(EventStall {
+            id: row.get(0)?, member_number: row.get(1)?, name: row.get(2)?, received: row.get(3)?, payments: row.get(4)?,
+        }))?.collect::<Result<_>>()?;
+        Ok(EventSummary { event, payments, volume, participants, stalls })
+    }
+
+    /// The `n` members with the most regular payments in `period`, a `YYYY-MM` month of the
+    /// domain's timezone. Counting payments rather than balances rewards circulation, members who
+    /// opted out with `set_leaderboard_opt_out` are left out.
+    pub fn top_traders(&self, period: &str, n: u32) -> Result<Vec<TopTrader>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT u.id, u.member_number, u.name, COALESCE(pr.display_name, ''), COUNT(*) AS n FROM (\
+        SELECT payer AS account FROM payment WHERE kind = ?1 AND strftime('%Y-%m', created, ?2) = ?3 \
+        UNION ALL SELECT payee FROM payment WHERE kind = ?1 AND strftime('%Y-%m', created, ?2) = ?3) \
+        JOIN user u ON u.id = account LEFT JOIN profile pr ON pr.user = u.id \
+        WHERE u.account_type = ?4 AND u.active = 1 AND u.id NOT IN (SELECT user FROM leaderboard_opt_out) \
+        GROUP BY u.id ORDER BY n DESC, u.name LIMIT ?5")?;
+        let iter = stmt.query_map(params![PAYMENT_REGULAR, self.timezone.sql_modifier(), period, ACCOUNT_MEMBER, n], |row| Ok(TopTrader {
+            id: row.get(0)?, member_number: row.get(1)?, name: row.get(2)?, display_name: row.get(3)?, payments: row.get(4)?,
+        }))?;
+        iter.collect()
+    }
+
+    pub fn set_leaderboard_opt_out(&self, user: i64, opt_out: bool) -> Result<usize> {
+        if opt_out {
+            self.writer().execute("INSERT OR IGNORE INTO leaderboard_opt_out (user, created) VALUES (?1, datetime('now'))", [user])
+        } else {
+            self.writer().execute("DELETE FROM leaderboard_opt_out WHERE user = ?1", [user])
+        }
+    }
+
+    pub fn leaderboard_opt_out(&self, user: i64) -> Result<bool> {
+        self.reader()?.query_row("SELECT COUNT(*) > 0 FROM leaderboard_opt_out WHERE user = ?1", [user], |row| row.get(0))
+    }
+
+    pub fn health_report(&self) -> Result<HealthReport> {
+        let users = self.get_users()?;
+        let over_limit = users.iter()
+            .filter(|u| !u.is_system() && u.receive_limit_in(self.currency.limit_unit()) < 0)
+            .map(|u| OverLimit { id: u.id, name: u.name.clone(), credit: u.credit, receive_limit: u.receive_limit_in(self.currency.limit_unit()) })
+            .collect();
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT id FROM payment \
+        WHERE payer NOT IN (SELECT id FROM user) OR payee NOT IN (SELECT id FROM user) ORDER BY id")?;
+        let orphaned_payments = stmt.query_map([], |row| row.get(0))?.collect::<Result<_>>()?;
+        Ok(HealthReport {
+            users: users.len() as u64,
+            credit_sum: users.iter().map(|u| u.credit).sum(),
+            over_limit,
+            orphaned_payments,
+            mismatches: ledger_mismatches(&conn)?,
+            chain: self.verify_chain()?,
+        })
+    }
+
+    /// The numbers for the public page. Payments of system accounts and fees aren't trading, and
+    /// the monthly figures are left out while too few members trade to hide among each other.
+    pub fn public_stats(&self) -> Result<PublicStats> {
+        let conn = self.reader()?;
+        let members = conn.query_row("SELECT COUNT(*) FROM user WHERE account_type = ?1 AND active = 1", [ACCOUNT_MEMBER], |row| row.get(0))?;
+        let (payments, volume): (u64, u64) = conn.query_row("SELECT COUNT(*), COALESCE(SUM(amount), 0) FROM payment \
+        WHERE kind = ?1 AND created >= datetime('now', '-30 days')", [PAYMENT_REGULAR], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let traders: u64 = conn.query_row("SELECT COUNT(DISTINCT account) FROM (\
+        SELECT payer AS account FROM payment WHERE kind = ?1 AND created >= datetime('now', '-30 days') \
+        UNION ALL SELECT payee FROM payment WHERE kind = ?1 AND created >= datetime('now', '-30 days')) \
+        JOIN user u ON u.id = account WHERE u.account_type = ?2", params![PAYMENT_REGULAR, ACCOUNT_MEMBER], |row| row.get(0))?;
+        let shown = traders >= PUBLIC_STATS_MIN_TRADERS;
+        Ok(PublicStats {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            join_info: self.join_info.clone(),
+            members,
+            monthly_payments: Some(payments).filter(|_| shown),
+            monthly_volume: Some(volume).filter(|_| shown),
+        })
+    }
+
+    /// Reads the schema version through the reader pool and, with `ledger`, sums the balances.
+    /// Cheap enough for a readiness probe, unlike `health_report`.
+    pub fn readiness(&self, ledger: bool) -> Result<Readiness> {
+        let conn = self.reader()?;
+        let schema_version = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let credit_sum = if ledger {
+            Some(conn.query_row("SELECT COALESCE(SUM(credit), 0) FROM user", [], |row| row.get(0))?)
+        } else { None };
+        Ok(Readiness { schema_version, credit_sum })
+    }
+
+    /// Walks the payments in booking order checking each `entry_hash` and its link to the
+    /// previous payment.
+    pub fn verify_chain(&self) -> Result<ChainStatus> {
+        chain_status(&*self.reader()?)
+    }
+
+    /// Rewrites `credit` and `payments_in/out` of every user from the payment table and returns
+    /// what was changed. Runs in one immediate transaction, so no payment can slip in between.
+    pub fn recompute_balances(&self) -> Result<Vec<LedgerMismatch>> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mismatches = ledger_mismatches(&tx)?;
+        for m in mismatches.iter() {
+            tx.execute("UPDATE user SET credit = ?1, payments_in = ?2, payments_out = ?3 WHERE id = ?4",
+                       params![m.actual_credit, m.actual_in, m.actual_out, m.id])?;
+        }
+        tx.commit()?;
+        Ok(mismatches)
+    }
+
+    /// Transfers `amount` from payer to payee. Both accounts are read inside an immediate
+    /// transaction, so the limits are checked against balances no other writer can change meanwhile.
+    /// Payments over the domain's `confirmation_threshold` are checked the same way, but wait
+    /// for the payee as a pending payment instead of being booked.
+    ///
+    /// A payer's retry with the `idempotency_key` of a payment that went through or is pending
+    /// returns it without paying again. Keys of refused payments aren't kept, their retry is
+    /// checked anew.
+    pub fn add_payment(&self, payer: i64, payee: i64, amount: u64, message: &str, category: Option<&str>,
+                       idempotency_key: Option<&str>) -> Result<Submitted, SimpletsError> {
+        self.submit_payment(payer, payee, amount, message, category, idempotency_key, None)
+    }
+
+    /// Like `add_payment`, with the payment tagged with the open `event`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_event_payment(&self, event: u64, payer: i64, payee: i64, amount: u64, message: &str, category: Option<&str>,
+                             idempotency_key: Option<&str>) -> Result<Submitted, SimpletsError> {
+        self.submit_payment(payer, payee, amount, message, category, idempotency_key, Some(event))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn submit_payment(&self, payer: i64, payee: i64, amount: u64, message: &str, category: Option<&str>,
+                      idempotency_key: Option<&str>, event: Option<u64>) -> Result<Submitted, SimpletsError> {
+        if self.is_closing() { return Err(SimpletsError::ShuttingDown) }
+        if let Some(c) = category {
+            if !self.get_categories()?.iter().any(|k| k == c) { return Err(SimpletsError::CategoryInvalid) }
+        }
+        let message = &self.check_message(message)?;
+        let mut conn = self.writer();
+        let mut tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if let Some(event) = event { Domain::check_event_open(&tx, event)? }
+        if let Some(key) = idempotency_key {
+            let paid = tx.query_row("SELECT payment, pending FROM idempotency_key WHERE payer = ?1 AND key = ?2", params![payer, key],
+                                    |row| Ok((row.get::<_, Option<u64>>(0)?, row.get::<_, Option<u64>>(1)?))).optional()?;
+            match paid {
+                Some((Some(payment), _)) => {
+                    info!(payment, payer, "payment retried");
+                    return Ok(Submitted::Booked(payment))
+                }
+                Some((None, Some(pending))) => {
+                    info!(pending, payer, "pending payment retried");
+                    return Ok(Submitted::Pending(pending))
+                }
+                _ => {}
+            }
+        }
+        if self.confirmation_threshold > 0 && amount > self.confirmation_threshold && !tx.user(payer)?.is_system() {
+            {
+                // try the transfer to refuse it now if it wouldn't go through, then roll it back
+                Domain::check_blocked(&tx, payer, payee)?;
+                let trial = tx.savepoint()?;
+                self.check_and_book(&*trial, payer, payee, Amount::try_from(amount)?, message, category)?;
+            }
+            let id = self.create_pending(&tx, payer, payee, amount, message, category)?;
+            tx.execute("UPDATE pending_payment SET event = ?1 WHERE id = ?2", params![event, id])?;
+            if let Some(key) = idempotency_key {
+                tx.execute("INSERT INTO idempotency_key (payer, key, pending, created) VALUES (?1, ?2, ?3, datetime('now'))",
+                           params![payer, key, id])?;
+            }
+            tx.commit()?;
+            return Ok(Submitted::Pending(id))
+        }
+        let payment = self.transfer(&tx, payer, payee, amount, message, category)?;
+        if let Some(event) = event { Domain::tag_event(&tx, event, payment.id)? }
+        if let Some(key) = idempotency_key {
+            tx.execute("INSERT INTO idempotency_key (payer, key, payment, created) VALUES (?1, ?2, ?3, datetime('now'))",
+                       params![payer, key, payment.id])?;
+        }
+        tx.commit()?;
+        Ok(Submitted::Booked(payment.id))
+    }
+
+    fn create_pending(&self, conn: &Connection, payer: i64, payee: i64, amount: u64, message: &str, category: Option<&str>) -> Result<u64> {
+        conn.execute("INSERT INTO pending_payment (payer, payee, amount, message, category, created, expiry, status) \
+        VALUES (?1, ?2, ?3, ?4, ?5, datetime('now'), datetime('now', ?6), ?7)",
+                     params![payer, payee, amount, message, category, format!("+{} days", self.confirmation_days), PENDING_OPEN])?;
+        let id = conn.last_insert_rowid() as u64;
+        let (payer, payee) = (conn.user(payer)?, conn.user(payee)?);
+        let body = self.catalog.format(self.catalog.default_locale(), "notify-pending-body", &[&payer.name, &self.format_amount(amount as i64), &message]);
+        self.notify(conn, &payee, NOTIFY_PAYMENT, "notify-pending-subject", &body)?;
+        info!(pending = id, payer = payer.id, payee = payee.id, amount, "payment waits for confirmation");
+        Ok(id)
+    }
+
+    pub fn get_pending_payment(&self, id: u64) -> Result<PendingPayment> {
+        self.reader()?.query_row("SELECT * FROM pending_payment WHERE id = ?", [id], pending_from_row)
+    }
+
+    /// Pending payments the user received, newest first.
+    pub fn get_pending_incoming(&self, user: i64) -> Result<Vec<PendingPayment>> {
+        self.get_pending_where("payee", user)
+    }
+
+    pub fn get_pending_outgoing(&self, user: i64) -> Result<Vec<PendingPayment>> {
+        self.get_pending_where("payer", user)
+    }
+
+    fn get_pending_where(&self, column: &str, user: i64) -> Result<Vec<PendingPayment>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(&format!("SELECT * FROM pending_payment WHERE {} = ?1 ORDER BY id DESC", column))?;
+        let iter = stmt.query_map([user], pending_from_row)?;
+        iter.collect()
+    }
+
+    /// Books a pending payment addressed to `payee`. The limits are checked again, against the
+    /// balances at the time of confirmation.
+    pub fn confirm_payment(&self, id: u64, payee: i64) -> Result<Payment, SimpletsError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let pending = Domain::open_pending(&tx, id, payee)?;
+        let payment = self.transfer(&tx, pending.payer, pending.payee, pending.amount, &pending.message, pending.category.as_deref())?;
+        tx.execute("UPDATE pending_payment SET status = ?1, payment = ?2 WHERE id = ?3", params![PENDING_CONFIRMED, payment.id, id])?;
+        // the event may have closed meanwhile, the payment still was made at it
+        if let Some(event) = pending.event { Domain::tag_event(&tx, event, payment.id)? }
+        tx.commit()?;
+        Ok(payment)
+    }
+
+    pub fn reject_payment(&self, id: u64, payee: i64) -> Result<(), SimpletsError> {
+        let conn = self.writer();
+        Domain::open_pending(&conn, id, payee)?;
+        conn.execute("UPDATE pending_payment SET status = ?1 WHERE id = ?2", params![PENDING_REJECTED, id])?;
+        Ok(())
+    }
+
+    fn open_pending(conn: &Connection, id: u64, payee: i64) -> Result<PendingPayment, SimpletsError> {
+        let pending = match conn.query_row("SELECT * FROM pending_payment WHERE id = ?", [id], pending_from_row) {
+            Ok(p) if p.payee == payee => p,
+            Ok(_) | Err(Error::QueryReturnedNoRows) => return Err(SimpletsError::PendingNotFound),
+            Err(e) => return Err(SimpletsError::Db(e))
+        };
+        let expired: bool = conn.query_row("SELECT ?1 <= datetime('now')", [&pending.expiry], |row| row.get(0))?;
+        if pending.status != PENDING_OPEN || expired { return Err(SimpletsError::PendingClosed) }
+        Ok(pending)
+    }
+
+    /// Sets `amount` aside for `payee`, reserved against the payer's send limit until the payer
+    /// releases it to the payee or the payee cancels it. The transfer is checked as if it were
+    /// made now, and again on release.
+    pub fn open_escrow(&self, payer: i64, payee: i64, amount: u64, message: &str) -> Result<u64, SimpletsError> {
+        let message = &self.check_message(message)?;
+        let mut conn = self.writer();
+        let mut tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        {
+            let trial = tx.savepoint()?;
+            self.check_and_book(&*trial, payer, payee, Amount::try_from(amount)?, message, None)?;
+        }
+        tx.execute("INSERT INTO escrow (payer, payee, amount, message, created, status) \
+        VALUES (?1, ?2, ?3, ?4, datetime('now'), ?5)", params![payer, payee, amount, message, ESCROW_OPEN])?;
+        let id = tx.last_insert_rowid() as u64;
+        tx.commit()?;
+        info!(escrow = id, payer, payee, amount, "escrow opened");
+        Ok(id)
+    }
+
+    pub fn get_escrow(&self, id: u64) -> Result<Escrow> {
+        self.reader()?.query_row("SELECT * FROM escrow WHERE id = ?", [id], escrow_from_row)
+    }
+
+    /// Escrows the user pays or receives, newest first.
+    pub fn get_escrows(&self, user: i64) -> Result<Vec<Escrow>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM escrow WHERE payer = ?1 OR payee = ?1 ORDER BY id DESC")?;
+        let iter = stmt.query_map([user], escrow_from_row)?;
+        iter.collect()
+    }
+
+    /// Escrows waiting for an admin, oldest first.
+    pub fn get_disputed_escrows(&self) -> Result<Vec<Escrow>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM escrow WHERE status = ? ORDER BY id")?;
+        let iter = stmt.query_map([ESCROW_DISPUTED], escrow_from_row)?;
+        iter.collect()
+    }
+
+    /// Pays an open escrow out to the payee, on the payer's word that the service was delivered.
+    pub fn release_escrow(&self, id: u64, payer: i64) -> Result<Payment, SimpletsError> {
+        self.settle_escrow(id, |e| e.payer == payer, ESCROW_OPEN, true)?.ok_or(SimpletsError::Internal)
+    }
+
+    /// Returns an open escrow to the payer, the payee giving up on it.
+    pub fn cancel_escrow(&self, id: u64, payee: i64) -> Result<(), SimpletsError> {
+        self.settle_escrow(id, |e| e.payee == payee, ESCROW_OPEN, false)?;
+        Ok(())
+    }
+
+    /// Flags an open escrow of `user`, payer or payee, for an admin to settle.
+    pub fn dispute_escrow(&self, id: u64, user: i64) -> Result<(), SimpletsError> {
+        let conn = self.writer();
+        let escrow = Domain::find_escrow(&conn, id, |e| e.payer == user || e.payee == user)?;
+        if escrow.status != ESCROW_OPEN { return Err(SimpletsError::EscrowClosed) }
+        conn.execute("UPDATE escrow SET status = ?1 WHERE id = ?2", params![ESCROW_DISPUTED, id])?;
+        Ok(())
+    }
+
+    /// Settles a disputed escrow by an admin's decision, paying it out if `release`.
+    pub fn resolve_escrow(&self, id: u64, release: bool) -> Result<Option<Payment>, SimpletsError> {
+        self.settle_escrow(id, |_| true, ESCROW_DISPUTED, release)
+    }
+
+    fn settle_escrow(&self, id: u64, visible: impl Fn(&Escrow) -> bool, from: i64, release: bool) -> Result<Option<Payment>, SimpletsError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let escrow = Domain::find_escrow(&tx, id, visible)?;
+        if escrow.status != from { return Err(SimpletsError::EscrowClosed) }
+        let status = if release { ESCROW_RELEASED } else { ESCROW_CANCELLED };
+        // settled first, so the transfer doesn't count the escrow against the payer's limit
+        tx.execute("UPDATE escrow SET status = ?1, settled = datetime('now') WHERE id = ?2", params![status, id])?;
+        let payment = if release {
+            let payment = self.transfer(&tx, escrow.payer, escrow.payee, escrow.amount, &escrow.message, None)?;
+            tx.execute("UPDATE escrow SET payment = ?1 WHERE id = ?2", params![payment.id, id])?;
+            Some(payment)
+        } else { None };
+        tx.commit()?;
+        info!(escrow = id, released = release, "escrow settled");
+        Ok(payment)
+    }
+
+    /// The escrow if `visible` to the caller, `EscrowNotFound` otherwise.
+    fn find_escrow(conn: &Connection, id: u64, visible: impl Fn(&Escrow) -> bool) -> Result<Escrow, SimpletsError> {
+        match conn.query_row("SELECT * FROM escrow WHERE id = ?", [id], escrow_from_row) {
+            Ok(e) if visible(&e) => Ok(e),
+            Ok(_) | Err(Error::QueryReturnedNoRows) => Err(SimpletsError::EscrowNotFound),
+            Err(e) => Err(SimpletsError::Db(e))
+        }
+    }
+
+    /// Marks the pending payments nobody confirmed in time as expired, returns how many.
+    pub fn expire_pending_payments(&self) -> Result<usize> {
+        let expired = self.writer().execute("UPDATE pending_payment SET status = ?1 \
+        WHERE status = ?2 AND expiry <= datetime('now')", params![PENDING_EXPIRED, PENDING_OPEN])?;
+        if expired > 0 { info!(expired, "pending payments expired") }
+        Ok(expired)
+    }
+
+    /// Pays from a group account on behalf of one of its managers. The manager check runs in the
+    /// same transaction as the transfer, so a manager removed meanwhile can't slip a payment in.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_group_payment(&self, manager: i64, account: i64, payee: i64, amount: u64, message: &str, category: Option<&str>,
+                             event: Option<u64>) -> Result<u64, SimpletsError> {
+        if let Some(c) = category {
+            if !self.get_categories()?.iter().any(|k| k == c) { return Err(SimpletsError::CategoryInvalid) }
+        }
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let managed: bool = tx.query_row("SELECT EXISTS(SELECT 1 FROM account_manager WHERE account = ?1 AND manager = ?2)",
+                                         params![account, manager], |row| row.get(0))?;
+        if !managed { return Err(SimpletsError::NotManager) }
+        Domain::require_writable(&tx.user(manager)?)?;
+        if let Some(event) = event { Domain::check_event_open(&tx, event)? }
+        let payment = self.transfer(&tx, account, payee, amount, message, category)?;
+        if let Some(event) = event { Domain::tag_event(&tx, event, payment.id)? }
+        tx.commit()?;
+        Ok(payment.id)
+    }
+
+    /// Pays every `(payee, amount, message)` from one payer in a single transaction. Each transfer
+    /// is checked against the balances left by the previous ones; if any fails, nothing is applied
+    /// and the index of the failing transfer is returned with the reason.
+    pub fn add_payments_bulk(&self, payer: i64, payments: Vec<(i64, u64, String)>) -> Result<(), (usize, SimpletsError)> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate).map_err(|e| (0, e.into()))?;
+        for (i, (payee, amount, message)) in payments.iter().enumerate() {
+            self.transfer(&tx, payer, *payee, *amount, message, None).map_err(|e| (i, e))?;
+        }
+        tx.commit().map_err(|e| (payments.len(), e.into()))
+    }
+
+    /// Pays several payees from one payer with a shared message, in a single transaction. The
+    /// total with its fees is checked against the payer's send limit up front and each share
+    /// against its payee's receive limit; if any share fails, nothing is applied and its index
+    /// is returned.
+    pub fn add_split_payment(&self, payer: i64, payments: Vec<(i64, u64)>, message: &str) -> Result<(), (Option<usize>, SimpletsError)> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate).map_err(|e| (None, e.into()))?;
+        let user = tx.user(payer).map_err(|e| (None, e.into()))?;
+        // the shares with their fees, none of which may exceed what an amount can hold
+        let total = payments.iter().try_fold(Amount::ZERO, |total, (_, amount)| {
+            total.checked_add(Amount::try_from(*amount)?).zip(self.fee(*amount).ok())
+                .and_then(|(total, fee)| total.checked_add(fee)).ok_or(SimpletsError::AmountOutOfRange)
+        }).map_err(|e| (None, e))?;
+        let (send_limit, _) = self.member_limits(&*tx, &user).map_err(|e| (None, e.into()))?;
+        if total.balance() > Balance::new(send_limit) {
+            return Err((None, SimpletsError::PaymentSendLimit { limit: send_limit }));
+        }
+        for (i, (payee, amount)) in payments.iter().enumerate() {
+            self.transfer(&tx, payer, *payee, *amount, message, None).map_err(|e| (Some(i), e))?;
+        }
+        tx.commit().map_err(|e| (None, e.into()))
+    }
+
+    /// Books the transfer and records it in the outbox within the caller's transaction, so its
+    /// side effects fire once it commits and never for a rolled back one, see `dispatch_outbox`.
+    fn transfer(&self, conn: &Connection, payer: i64, payee: i64, amount: u64, message: &str, category: Option<&str>) -> Result<Payment, SimpletsError> {
+        if self.is_closing() { return Err(SimpletsError::ShuttingDown) }
+        Domain::check_blocked(conn, payer, payee)?;
+        let message = self.check_message(message)?;
+        let payment = self.book_transfer(conn, payer, payee, Amount::try_from(amount)?, &message, category)?;
+        Domain::finish_closure(conn, payer)?;
+        Domain::finish_closure(conn, payee)?;
+        conn.execute("INSERT INTO outbox (event, payment, created) VALUES (?1, ?2, datetime('now'))",
+                     params![EVENT_PAYMENT_CREATED, payment.id])?;
+        Ok(payment)
+    }
+
+    /// Turns up to `limit` outbox entries into webhook calls and email notifications, oldest
+    /// first, and returns how many it processed. The calls and emails are queued in the same
+    /// transaction that marks the entries processed, so each entry fires exactly once.
+    pub fn dispatch_outbox(&self, limit: u32) -> Result<usize> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let entries = {
+            let mut stmt = tx.prepare("SELECT id, event, payment FROM outbox WHERE processed IS NULL ORDER BY id LIMIT ?")?;
+            let iter = stmt.query_map([limit], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)))?;
+            iter.collect::<Result<Vec<_>>>()?
+        };
+        for (id, event, payment) in entries.iter() {
+            let payment = tx.query_row("SELECT * FROM payment WHERE id = ?", [payment], payment_from_row)?;
+            self.queue_webhooks(&tx, event, &payment)?;
+            if event == EVENT_PAYMENT_CREATED {
+                let (payer, payee) = (tx.user(payment.payer as i64)?, tx.user(payment.payee as i64)?);
+                let body = self.catalog.format(self.catalog.default_locale(), "notify-payment-body",
+                                               &[&payer.name, &self.format_amount(payment.amount as i64), &payment.message]);
+                self.notify(&tx, &payee, NOTIFY_PAYMENT, "notify-payment-subject", &body)?;
+            }
+            tx.execute("UPDATE outbox SET processed = datetime('now') WHERE id = ?", [id])?;
+        }
+        tx.commit()?;
+        Ok(entries.len())
+    }
+
+    /// The payment logic: checks the minimum, the limits (raised by a trustline) and the fee,
+    /// then books the payment and its fee in `ledger`. Run it inside a transaction of the ledger.
+    pub fn book_transfer(&self, ledger: &dyn Ledger, payer: i64, payee: i64, amount: Amount, message: &str, category: Option<&str>) -> Result<Payment, SimpletsError> {
+        let result = self.check_and_book(ledger, payer, payee, amount, message, category);
+        let amount = amount.get();
+        match &result {
+            Ok(payment) => info!(payment = payment.id, payer, payee, amount, "payment booked"),
+            Err(e) if e.is_validation() => info!(payer, payee, amount, error = e.code(), "payment refused"),
+            Err(e) => error!(payer, payee, amount, error = %e, "payment failed"),
+        }
+        result
+    }
+
+    fn check_and_book(&self, ledger: &dyn Ledger, payer: i64, payee: i64, amount: Amount, message: &str, category: Option<&str>) -> Result<Payment, SimpletsError> {
+        let payer = ledger.user(payer)?;
+        let payee = ledger.user(payee)?;
+        let fee = self.check_payment(ledger, &payer, &payee, amount)?;
+        let payment = ledger.book(&Booking {
+            payer: payer.id, payee: payee.id, amount, message, kind: PAYMENT_REGULAR, parent: None, category, counted: true, created: None,
+        })?;
+        if fee > Amount::ZERO {
+            let account = self.fee_account.ok_or(SimpletsError::Internal)?;
+            ledger.book(&Booking {
+                payer: payer.id, payee: account, amount: fee, message: "fee", kind: PAYMENT_FEE, parent: Some(payment.id as i64), category: None, counted: false, created: None,
+            })?;
+        }
+        Ok(payment)
+    }
+
+    /// Everything `check_and_book` checks before booking, returns the fee on top of `amount`.
+    fn check_payment(&self, ledger: &dyn Ledger, payer: &User, payee: &User, amount: Amount) -> Result<Amount, SimpletsError> {
+        validation::amount(amount.get(), self.minimal_amount, if payer.is_system() { 0 } else { self.maximal_amount })?;
+        if payer.id == payee.id { return Err(SimpletsError::PaymentSidesEq); }
+        if !payer.active || !payee.active { return Err(SimpletsError::UserInactive); }
+        Domain::require_writable(payer)?;
+        Domain::require_writable(payee)?;
+        if ledger.frozen(payer.id)?.is_some() || ledger.frozen(payee.id)? == Some(true) { return Err(SimpletsError::AccountFrozen) }
+        if !payer.is_system() && self.daily_limit > 0 {
+            let sent = ledger.sent_since(payer.id, &self.timezone.day_start(self.timezone.today()))?;
+            if sent.checked_add(amount).is_none_or(|total| total.get() > self.daily_limit) {
+                return Err(SimpletsError::PaymentDailyLimit { remaining: self.daily_limit.saturating_sub(sent.get()) })
+            }
+        }
+        let (send_limit, receive_limit) = self.trusted_limits(ledger, payer, payee)?;
+        let limit = Limit::lower(send_limit, receive_limit);
+        if amount.balance().get() > limit.amount() { return Err(limit.exceeded()) }
+        let fee = self.payment_fee(payer, payee, amount)?;
+        if fee > Amount::ZERO && amount.checked_add(fee).is_none_or(|total| total.balance().get() > send_limit) {
+            return Err(SimpletsError::PaymentSendLimit { limit: Balance::new(send_limit).saturating_sub(fee).get() });
+        }
+        let total = amount.checked_add(fee).ok_or(SimpletsError::AmountOutOfRange)?;
+        if Balance::new(payer.credit).checked_sub(total).is_none() || Balance::new(payee.credit).checked_add(amount).is_none() {
+            return Err(SimpletsError::AmountOutOfRange)
+        }
+        Ok(fee)
+    }
+
+    /// The payer's send and the payee's receive limit, both raised by their trustline.
+    fn trusted_limits(&self, ledger: &dyn Ledger, payer: &User, payee: &User) -> Result<(i64, i64), SimpletsError> {
+        let extra = Amount::try_from(ledger.trustline(payer.id, payee.id)?)?.balance().get();
+        Ok((self.member_limits(ledger, payer)?.0.saturating_add(extra), self.member_limits(ledger, payee)?.1.saturating_add(extra)))
+    }
+
+    /// The fee the payer pays to the fee account on top of `amount`, none between it and system
+    /// accounts.
+    fn payment_fee(&self, payer: &User, payee: &User, amount: Amount) -> Result<Amount, SimpletsError> {
+        match self.fee_account {
+            Some(account) if account != payer.id && account != payee.id && !payer.is_system() => self.fee(amount.get()),
+            _ => Ok(Amount::ZERO),
+        }
+    }
+
+    /// What paying `amount` from `payer` to `payee` would come to, without booking anything: the
+    /// lower of the limits, the fee and the balances after it, with the reason if the payment
+    /// would be refused.
+    pub fn preview_payment(&self, payer: i64, payee: i64, amount: u64) -> Result<PaymentPreview, SimpletsError> {
+        let conn = self.reader()?;
+        let (payer, payee) = (conn.user(payer)?, conn.user(payee)?);
+        let amount = Amount::try_from(amount)?;
+        let (send_limit, receive_limit) = self.trusted_limits(&*conn, &payer, &payee)?;
+        let fee = self.payment_fee(&payer, &payee, amount)?;
+        Ok(PaymentPreview {
+            limit: Limit::lower(send_limit, receive_limit),
+            fee: fee.get(),
+            payer_balance: Balance::new(payer.credit).saturating_sub(amount).saturating_sub(fee).get(),
+            payee_balance: payee.credit.saturating_add(amount.balance().get()),
+            error: Domain::check_blocked(&conn, payer.id, payee.id).and_then(|_| self.check_payment(&*conn, &payer, &payee, amount)).err(),
+        })
+    }
+
+    /// Raises the member's send and receive limits by the bonuses until `expiry`, a local time of
+    /// the domain, replacing an
+    /// earlier override. Both bonuses 0 remove it.
+    pub fn set_limit_override(&self, user: i64, send_bonus: u64, receive_bonus: u64, expiry: Option<NaiveDateTime>) -> Result<usize> {
+        if send_bonus == 0 && receive_bonus == 0 {
+            return self.writer().execute("DELETE FROM limit_override WHERE user = ?1", [user])
+        }
+        self.writer().execute("INSERT OR REPLACE INTO limit_override (user, send_bonus, receive_bonus, expiry, created) \
+        SELECT id, ?2, ?3, ?4, datetime('now') FROM user WHERE id = ?1",
+                              params![user, send_bonus, receive_bonus, expiry.map(|e| self.timezone.to_utc(e).format(TIME_FORMAT).to_string())])
+    }
+
+    /// Lowers the member's credit and receive limits by `percent`, e.g. of an account that stopped
+    /// trading. It replaces an earlier override, `set_limit_override` with zero bonuses undoes it.
+    pub fn decay_limits(&self, user: i64, percent: u8) -> Result<usize> {
+        let conn = self.writer();
+        let account = conn.user(user)?;
+        let percent = percent.min(100) as i64;
+        let unit = self.currency.limit_unit();
+        let send = account.credit_limit_in(unit).max(0).saturating_mul(percent) / 100;
+        let receive = account.receive_limit_in(unit).saturating_add(account.credit).max(0).saturating_mul(percent) / 100;
+        conn.execute("INSERT OR REPLACE INTO limit_override (user, send_bonus, receive_bonus, expiry, created) \
+        VALUES (?1, ?2, ?3, NULL, datetime('now'))", params![user, -send, -receive])
+    }
+
+    /// The member's override, also when it has expired.
+    pub fn get_limit_override(&self, user: i64) -> Result<Option<LimitOverride>> {
+        self.reader()?.query_row("SELECT user, send_bonus, receive_bonus, expiry, created FROM limit_override WHERE user = ?1", [user],
+                                 |row| Ok(LimitOverride {
+                                     user: row.get(0)?, send_bonus: row.get(1)?, receive_bonus: row.get(2)?, expiry: row.get(3)?, created: row.get(4)?,
+                                 })).optional()
+    }
+
+    /// Blocks the account's payments out, and with `incoming` also those to it, until
+    /// `unfreeze_user`. Freezing again replaces the reason.
+    pub fn freeze_user(&self, user: i64, reason: &str, incoming: bool) -> Result<usize> {
+        let frozen = self.writer().execute("INSERT OR REPLACE INTO account_freeze (user, reason, incoming, created) \
+        SELECT id, ?2, ?3, datetime('now') FROM user WHERE id = ?1", params![user, reason, incoming])?;
+        if frozen > 0 { info!(user, incoming, "account frozen") }
+        Ok(frozen)
+    }
+
+    pub fn unfreeze_user(&self, user: i64) -> Result<usize> {
+        self.writer().execute("DELETE FROM account_freeze WHERE user = ?1", [user])
+    }
+
+    pub fn get_freeze(&self, user: i64) -> Result<Option<AccountFreeze>> {
+        self.reader()?.query_row("SELECT user, reason, incoming, created FROM account_freeze WHERE user = ?1", [user],
+                                 |row| Ok(AccountFreeze { user: row.get(0)?, reason: row.get(1)?, incoming: row.get(2)?, created: row.get(3)? })).optional()
+    }
+
+    /// Starts closing the member's account: from now on they can only settle their balance to
+    /// zero, at which point the account is deactivated. Returns whether it was closed at once,
+    /// the balance being zero already.
+    pub fn begin_account_closure(&self, user: i64) -> Result<bool> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute("INSERT OR IGNORE INTO account_closure (user, started) SELECT id, datetime('now') FROM user WHERE id = ?1", [user])?;
+        let closed = Domain::finish_closure(&tx, user)?;
+        tx.commit()?;
+        info!(user, closed, "account closure started");
+        Ok(closed)
+    }
+
+    /// Stops a closure that hasn't finished yet, the member stays.
+    pub fn cancel_account_closure(&self, user: i64) -> Result<usize> {
+        self.writer().execute("DELETE FROM account_closure WHERE user = ?1 AND closed IS NULL", [user])
+    }
+
+    /// Closes the account if its closure was started and the balance is zero.
+    fn finish_closure(conn: &Connection, user: i64) -> Result<bool> {
+        let closed = conn.execute("UPDATE account_closure SET closed = datetime('now') \
+        WHERE user = ?1 AND closed IS NULL AND (SELECT credit FROM user WHERE id = ?1) = 0", [user])? > 0;
+        if closed {
+            conn.execute("UPDATE user SET active = 0 WHERE id = ?1", [user])?;
+            info!(user, "account closed");
+        }
+        Ok(closed)
+    }
+
+    /// All closures with what is left to settle, unfinished ones first.
+    pub fn get_account_closures(&self) -> Result<Vec<AccountClosure>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT c.user, u.name, u.credit, c.started, c.closed, u.member_number, \
+        (SELECT COUNT(*) FROM escrow WHERE (payer = c.user OR payee = c.user) AND status IN (?1, ?2)), \
+        (SELECT COUNT(*) FROM pending_payment WHERE (payer = c.user OR payee = c.user) AND status = ?3), \
+        (SELECT COUNT(*) FROM payment_request WHERE (payer = c.user OR payee = c.user) AND status = ?4) \
+        FROM account_closure c JOIN user u ON u.id = c.user ORDER BY c.closed IS NOT NULL, c.started")?;
+        let iter = stmt.query_map(params![ESCROW_OPEN, ESCROW_DISPUTED, PENDING_OPEN, REQUEST_PENDING], |row| Ok(AccountClosure {
+            user: row.get(0)?, member_number: row.get(5)?, name: row.get(1)?, credit: row.get(2)?, started: row.get(3)?, closed: row.get(4)?,
+            open_escrows: row.get(6)?, open_pending: row.get(7)?, open_requests: row.get(8)?,
+        }))?;
+        iter.collect()
+    }
+
+    /// The member's send and receive limits, reduced on probation and raised by an override in
+    /// effect, trustlines aside.
+    pub fn get_limits(&self, user: i64) -> Result<(i64, i64)> {
+        let conn = self.reader()?;
+        let user = conn.user(user)?;
+        self.member_limits(&*conn, &user)
+    }
+
+    fn member_limits(&self, ledger: &dyn Ledger, user: &User) -> Result<(i64, i64)> {
+        if ledger.closing(user.id)? {
+            return Ok((Balance::new(user.credit.max(0)).saturating_sub(ledger.escrowed(user.id)?).get(), (-user.credit).max(0)))
+        }
+        let (mut send_limit, mut receive_limit) = if self.limit_window > 0 && !user.is_system() {
+            let (payments_in, payments_out) = ledger.turnover(user.id, self.limit_window)?;
+            let recent = User { payments_in, payments_out, ..user.clone() };
+            (recent.send_limit_in(self.currency.limit_unit()), recent.receive_limit_in(self.currency.limit_unit()))
+        } else { (user.send_limit_in(self.currency.limit_unit()), user.receive_limit_in(self.currency.limit_unit())) };
+        if self.probation.applies(user, self.timezone.today()) {
+            send_limit = self.probation.reduce(send_limit);
+            receive_limit = self.probation.reduce(receive_limit);
+        }
+        let (send_bonus, receive_bonus) = ledger.limit_bonus(user.id)?;
+        let escrowed = ledger.escrowed(user.id)?;
+        Ok((Balance::new(send_limit.saturating_add(send_bonus)).saturating_sub(escrowed).get(), receive_limit.saturating_add(receive_bonus)))
+    }
+
+    /// Offers `partner` an extra limit on payments between the two of them, on top of the
+    /// limits given by turnover. An offer of 0 withdraws it.
+    pub fn set_trustline(&self, user: i64, partner: i64, extra_limit: u64) -> Result<usize> {
+        if extra_limit == 0 {
+            return self.writer().execute("DELETE FROM trustline WHERE truster = ?1 AND trustee = ?2", params![user, partner])
+        }
+        self.writer().execute("INSERT INTO trustline (truster, trustee, extra_limit, created) \
+        VALUES (?1, ?2, ?3, datetime('now')) \
+        ON CONFLICT(truster, trustee) DO UPDATE SET extra_limit = ?3, created = datetime('now')",
+                              params![user, partner, extra_limit])
+    }
+
+    /// Extra limit `a` and `b` both agreed on, 0 if either hasn't offered one.
+    pub fn get_trustline(&self, a: i64, b: i64) -> Result<u64> {
+        self.reader()?.trustline(a, b)
+    }
+
+    /// Trustlines the user offered or was offered, by partner name.
+    pub fn get_trustlines(&self, user: i64) -> Result<Vec<Trustline>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT partner, user.name, \
+        COALESCE(SUM(CASE WHEN truster = ?1 THEN extra_limit END), 0), COALESCE(SUM(CASE WHEN trustee = ?1 THEN extra_limit END), 0) \
+        FROM (SELECT truster, trustee, extra_limit, CASE WHEN truster = ?1 THEN trustee ELSE truster END AS partner \
+        FROM trustline WHERE truster = ?1 OR trustee = ?1) JOIN user ON user.id = partner \
+        GROUP BY partner ORDER BY user.name")?;
+        let iter = stmt.query_map([user], |row| {
+            let (offered, received): (u64, u64) = (row.get(2)?, row.get(3)?);
+            Ok(Trustline { partner: row.get(0)?, partner_name: row.get(1)?, offered, received, extra: offered.min(received) })
+        })?;
+        iter.collect()
+    }
+
+    /// Categories members can tag their payments with, in the order the admin listed them.
+    pub fn get_categories(&self) -> Result<Vec<String>> {
+        Ok(self.get_setting("payment_categories")?
+            .map(|c| c.lines().map(|l| l.to_string()).collect())
+            .unwrap_or_default())
+    }
+
+    /// Replaces the category list. Blank and repeated names are dropped, payments keep the
+    /// category they were made with even if it is removed.
+    pub fn set_categories(&self, categories: &[&str]) -> Result<usize> {
+        let mut list: Vec<&str> = Vec::new();
+        for c in categories.iter().map(|c| c.trim()).filter(|c| !c.is_empty()) {
+            if !list.contains(&c) { list.push(c) }
+        }
+        self.set_setting("payment_categories", &list.join("\n"))
+    }
+
+    /// Sets the community account that collects demurrage.
+    pub fn set_demurrage_account(&self, account: i64) -> Result<usize> {
+        self.set_setting("demurrage_account", &account.to_string())
+    }
+
+    pub fn get_demurrage_account(&self) -> Result<Option<i64>> {
+        Ok(self.get_setting("demurrage_account")?.and_then(|a| a.parse().ok()))
+    }
+
+    /// Sets the community account manual adjustments are booked against.
+    pub fn set_adjustment_account(&self, account: i64) -> Result<usize> {
+        self.set_setting("adjustment_account", &account.to_string())
+    }
+
+    pub fn get_adjustment_account(&self) -> Result<Option<i64>> {
+        Ok(self.get_setting("adjustment_account")?.and_then(|a| a.parse().ok()))
+    }
+
+    /// Grants `user` the amount from the adjustment account, or levies it from them when
+    /// negative, e.g. a correction decided by the committee. Only `Capability::BookAdjustments`
+    /// allows it, the limits don't
+    /// apply and the entry, with its reason, goes to both the ledger and the audit log.
+    pub fn add_adjustment(&self, admin: i64, user: i64, amount: i64, reason: &str) -> Result<Payment, SimpletsError> {
+        let account = self.get_adjustment_account()?.ok_or(SimpletsError::AdjustmentNotConfigured)?;
+        if amount == 0 { return Err(SimpletsError::PaymentLessMin { minimum: 1 }) }
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        Domain::require(&tx, admin, Capability::BookAdjustments)?;
+        let user = tx.user(user)?;
+        if user.id == account { return Err(SimpletsError::PaymentSidesEq) }
+        let (payer, payee) = if amount > 0 { (account, user.id) } else { (user.id, account) };
+        let payment = tx.book(&Booking {
+            payer, payee, amount: Amount::try_from(amount.unsigned_abs())?, message: reason, kind: PAYMENT_ADJUSTMENT, parent: None, category: None, counted: false, created: None,
+        })?;
+        Domain::finish_closure(&tx, user.id)?;
+        tx.execute("INSERT INTO audit_log (actor, action, target, detail, created) VALUES (?1, 'ledger.adjustment', ?2, ?3, datetime('now'))",
+                   params![admin, user.id, format!("{:+} payment {}: {}", amount, payment.id, reason)])?;
+        tx.commit()?;
+        info!(payment = payment.id, admin, user = user.id, amount, "adjustment booked");
+        Ok(payment)
+    }
+
+    /// Books the regular payment `id` and its fee back to the payer, regardless of the limits, and
+    /// returns the reversal of the payment. The payments still count into the limits as before.
+    /// `admin` needs `Capability::ReversePayment`, `None` is the operator on the command line.
+    /// The reversal fires `EVENT_PAYMENT_REVERSED` through the outbox.
+    pub fn reverse_payment(&self, admin: Option<i64>, id: u64, reason: &str) -> Result<Payment, SimpletsError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if let Some(admin) = admin { Domain::require(&tx, admin, Capability::ReversePayment)? }
+        let payment = tx.query_row("SELECT * FROM payment WHERE id = ?", [id], payment_from_row)?;
+        let reversed: bool = tx.query_row("SELECT COUNT(*) > 0 FROM payment WHERE parent = ?1 AND kind = ?2",
+                                          params![id, PAYMENT_REVERSAL], |row| row.get(0))?;
+        if payment.kind != PAYMENT_REGULAR || reversed { return Err(SimpletsError::PaymentNotReversible) }
+        let fees = {
+            let mut stmt = tx.prepare("SELECT * FROM payment WHERE parent = ?1 AND kind = ?2")?;
+            let iter = stmt.query_map(params![id, PAYMENT_FEE], payment_from_row)?;
+            iter.collect::<Result<Vec<_>>>()?
+        };
+        let message = format!("reversal of {}: {}", id, reason);
+        let mut reversal = None;
+        for original in std::iter::once(&payment).chain(fees.iter()) {
+            let booked = tx.book(&Booking {
+                payer: original.payee as i64, payee: original.payer as i64, amount: sql_amount(original.amount)?, message: &message,
+                kind: PAYMENT_REVERSAL, parent: Some(id as i64), category: None, counted: false, created: None,
+            })?;
+            reversal.get_or_insert(booked);
+        }
+        let reversal = reversal.ok_or(SimpletsError::Internal)?;
+        Domain::finish_closure(&tx, payment.payer as i64)?;
+        Domain::finish_closure(&tx, payment.payee as i64)?;
+        tx.execute("INSERT INTO outbox (event, payment, created) VALUES (?1, ?2, datetime('now'))",
+                   params![EVENT_PAYMENT_REVERSED, reversal.id])?;
+        tx.execute("INSERT INTO audit_log (actor, action, target, detail, created) VALUES (?1, 'payment.reverse', ?2, ?3, datetime('now'))",
+                   params![admin, payment.payer, format!("payment {}: {}", id, reason)])?;
+        tx.commit()?;
+        info!(payment = id, admin, "payment reversed");
+        Ok(reversal)
+    }
+
+    /// Charges `rate` (e.g. 0.01 for 1 %) of every positive member balance to the demurrage account
+    /// and books each charge as a demurrage payment. A period (e.g. "2026-10") is only charged once,
+    /// so repeated runs return an empty list. Charges don't count as payments for the limits.
+    /// A rate outside (0, 1] is `DemurrageRateInvalid` and doesn't use up the period.
+    pub fn apply_demurrage(&self, rate: f64, period: &str) -> Result<Vec<(i64, u64)>, SimpletsError> {
+        // also refuses NaN
+        if !(0.0 < rate && rate <= 1.0) { return Err(SimpletsError::DemurrageRateInvalid) }
+        let account = self.get_demurrage_account()?.ok_or(SimpletsError::DemurrageNotConfigured)?;
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if tx.execute("INSERT OR IGNORE INTO demurrage_run (period, rate, created)\
+        VALUES (?1, ?2, datetime('now'))", params![period, rate])? == 0 {
+            return Ok(Vec::new());
+        }
+        let mut charges = Vec::new();
+        {
+            let mut stmt = tx.prepare("SELECT id, credit FROM user \
+            WHERE credit > 0 AND account_type = ?1 AND id != ?2")?;
+            let mut rows = stmt.query(params![ACCOUNT_MEMBER, account])?;
+            while let Some(row) = rows.next()? {
+                let (user, credit): (i64, i64) = (row.get(0)?, row.get(1)?);
+                let charge = (credit as f64 * rate) as u64;
+                if charge > 0 { charges.push((user, charge)); }
+            }
+        }
+        let message = format!("demurrage {}", period);
+        for (user, charge) in charges.iter() {
+            tx.book(&Booking {
+                payer: *user, payee: account, amount: Amount::try_from(*charge)?, message: &message, kind: PAYMENT_DEMURRAGE, parent: None, category: None, counted: false, created: None,
+            })?;
+        }
+        tx.commit()?;
+        info!(period, rate, charges = charges.len(), "demurrage applied");
+        Ok(charges)
+    }
+
+    pub fn create_request(&self, payee: i64, payer: i64, amount: u64, message: &str) -> Result<u64, SimpletsError> {
+        validation::amount(amount, self.minimal_amount, self.maximal_amount)?;
+        if payer == payee { return Err(SimpletsError::PaymentSidesEq); }
+        let message = &self.check_message(message)?;
+        let payer = self.get_user(payer)?;
+        let payee = self.get_user(payee)?;
+        Domain::require_writable(&payer)?;
+        Domain::require_writable(&payee)?;
+        let conn = self.writer();
+        conn.execute("INSERT INTO payment_request (payer, payee, amount, created, message, status)\
+        VALUES (?1, ?2, ?3, datetime('now'), ?4, ?5)", params![payer.id, payee.id, amount, message, REQUEST_PENDING])?;
+        let id = conn.last_insert_rowid() as u64;
+        let body = self.catalog.format(self.catalog.default_locale(), "notify-request-body", &[&payee.name, &self.format_amount(amount as i64), &message]);
+        self.notify(&conn, &payer, NOTIFY_REQUEST, "notify-request-subject", &body)?;
+        Ok(id)
+    }
+
+    pub fn get_request(&self, id: u64) -> Result<PaymentRequest> {
+        self.reader()?.query_row("SELECT * FROM payment_request WHERE id = ?", [id],
+                            |row| {
+                                Ok(PaymentRequest {
+                                    id: row.get(0)?,
+                                    payer: row.get(1)?,
+                                    payee: row.get(2)?,
+                                    amount: row.get(3)?,
+                                    created: row.get(4)?,
+                                    message: row.get(5)?,
+                                    status: row.get(6)?,
+                                })
+                            })
+    }
+
+    pub fn get_requests_incoming(&self, user: i64) -> Result<Vec<PaymentRequest>> {
+        self.get_requests_where("payer", user)
+    }
+
+    pub fn get_requests_outgoing(&self, user: i64) -> Result<Vec<PaymentRequest>> {
+        self.get_requests_where("payee", user)
+    }
+
+    fn get_requests_where(&self, column: &str, user: i64) -> Result<Vec<PaymentRequest>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(&format!("SELECT * FROM payment_request \
+        WHERE {} = ?1 ORDER BY created DESC", column))?;
+        let iter = stmt.query_map([&user], |row| {
+            Ok(PaymentRequest {
+                id: row.get(0)?,
+                payer: row.get(1)?,
+                payee: row.get(2)?,
+                amount: row.get(3)?,
+                created: row.get(4)?,
+                message: row.get(5)?,
+                status: row.get(6)?,
+            })
+        })?;
+        let mut vec = Vec::new();
+        for request in iter {
+            match request {
+                Ok(r) => vec.push(r),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(vec)
+    }
+
+    /// Pays a pending request addressed to `payer`, with the usual minimum and limit checks at the
+    /// time of acceptance. The request is closed in the payment's transaction, so accepting it
+    /// twice at once pays once and the second gets `RequestClosed`.
+    pub fn accept_request(&self, id: u64, payer: i64) -> Result<(), SimpletsError> {
+        let request = self.pending_request(id, payer)?;
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        Domain::close_request(&tx, id, payer, REQUEST_ACCEPTED)?;
+        self.transfer(&tx, request.payer, request.payee, request.amount, &request.message, None)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn decline_request(&self, id: u64, payer: i64) -> Result<(), SimpletsError> {
+        self.pending_request(id, payer)?;
+        Domain::close_request(&self.writer(), id, payer, REQUEST_DECLINED)
+    }
+
+    /// Sets the status of the payer's request unless another accept or decline closed it since
+    /// `pending_request` looked.
+    fn close_request(conn: &Connection, id: u64, payer: i64, status: i64) -> Result<(), SimpletsError> {
+        let closed = conn.execute("UPDATE payment_request SET status = ?1 WHERE id = ?2 AND payer = ?3 AND status = ?4",
+                                  params![status, id, payer, REQUEST_PENDING])?;
+        if closed == 1 { Ok(()) } else { Err(SimpletsError::RequestClosed) }
+    }
+
+    fn pending_request(&self, id: u64, payer: i64) -> Result<PaymentRequest, SimpletsError> {
+        let request = match self.get_request(id) {
+            Ok(r) if r.payer == payer => r,
+            Ok(_) | Err(Error::QueryReturnedNoRows) => return Err(SimpletsError::RequestNotFound),
+            Err(e) => return Err(SimpletsError::Db(e))
+        };
+        if request.status != REQUEST_PENDING { return Err(SimpletsError::RequestClosed); }
+        Ok(request)
+    }
+
+    /// Creates a single-use voucher over `amount` and returns its token, `<id>.<amount>.<signature>`.
+    /// The token can be written down or printed and redeemed by anyone who holds it until `expiry`,
+    /// a local time of the domain.
+    /// Nothing is reserved, the payer's limits are checked when the voucher is redeemed.
+    pub fn issue_voucher(&self, payer: i64, amount: u64, expiry: NaiveDateTime) -> Result<String, SimpletsError> {
+        validation::amount(amount, self.minimal_amount, self.maximal_amount)?;
+        let user = self.get_user(payer)?;
+        if !user.active { return Err(SimpletsError::UserInactive); }
+        Domain::require_writable(&user)?;
+        let key = self.domain_secret("voucher_key")?;
+        let expiry = self.timezone.to_utc(expiry).format(TIME_FORMAT).to_string();
+        let conn = self.writer();
+        conn.execute("INSERT INTO voucher (payer, amount, created, expiry) \
+        VALUES (?1, ?2, datetime('now'), ?3)", params![payer, amount, expiry])?;
+        let id = conn.last_insert_rowid() as u64;
+        Ok(voucher_token(&key, id, payer, amount, &expiry))
+    }
+
+    /// Pays the voucher to `payee`. The voucher is marked redeemed in the same transaction as the
+    /// transfer, so it can't be spent twice.
+    pub fn redeem_voucher(&self, token: &str, payee: i64) -> Result<u64, SimpletsError> {
+        let key = self.domain_secret("voucher_key")?;
+        let id: u64 = token.trim().split('.').next().and_then(|i| i.parse().ok()).ok_or(SimpletsError::VoucherInvalid)?;
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let (payer, amount, expiry, redeemed, expired): (i64, u64, String, Option<String>, bool) = match tx.query_row(
+            "SELECT payer, amount, expiry, redeemed, cancelled IS NOT NULL OR expiry < datetime('now') FROM voucher WHERE id = ?1",
+            [id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))) {
+            Ok(v) => v,
+            Err(Error::QueryReturnedNoRows) => return Err(SimpletsError::VoucherInvalid),
+            Err(e) => return Err(e.into())
+        };
+        if token.trim() != voucher_token(&key, id, payer, amount, &expiry) { return Err(SimpletsError::VoucherInvalid) }
+        if redeemed.is_some() { return Err(SimpletsError::VoucherRedeemed) }
+        if expired { return Err(SimpletsError::VoucherExpired) }
+        self.transfer(&tx, payer, payee, amount, &format!("voucher {}", id), None)?;
+        tx.execute("UPDATE voucher SET redeemed = datetime('now'), payee = ?1 WHERE id = ?2", params![payee, id])?;
+        tx.commit()?;
+        Ok(amount)
+    }
+
+    /// Vouchers issued by the user, newest first.
+    pub fn get_vouchers(&self, payer: i64) -> Result<Vec<Voucher>> {
+        let key = self.domain_secret("voucher_key")?;
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT id, payer, amount, created, expiry, redeemed, payee, cancelled FROM voucher \
+        WHERE payer = ?1 ORDER BY id DESC")?;
+        let iter = stmt.query_map([payer], |row| {
+            let (id, amount, expiry): (u64, u64, String) = (row.get(0)?, row.get(2)?, row.get(4)?);
+            Ok(Voucher {
+                id,
+                payer,
+                amount,
+                created: row.get(3)?,
+                token: voucher_token(&key, id, payer, amount, &expiry),
+                expiry,
+                redeemed: row.get(5)?,
+                payee: row.get(6)?,
+                cancelled: row.get(7)?,
+            })
+        })?;
+        iter.collect()
+    }
+
+    /// Invalidates an unredeemed voucher, e.g. when its token was lost. Redeeming it then fails
+    /// as if it had expired.
+    pub fn cancel_voucher(&self, id: u64, payer: i64) -> Result<usize> {
+        self.writer().execute("UPDATE voucher SET cancelled = datetime('now') \
+        WHERE id = ?1 AND payer = ?2 AND redeemed IS NULL AND cancelled IS NULL", params![id, payer])
+    }
+
+    /// Domain secret stored as setting `name`, e.g. the one voucher tokens are signed with,
+    /// created on first use.
+    fn domain_secret(&self, name: &str) -> Result<String> {
+        if let Some(key) = self.get_setting(name)? { return Ok(key) }
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        self.writer().execute("INSERT OR IGNORE INTO domain_settings (key, value) VALUES (?1, ?2)", params![name, hex::encode(bytes)])?;
+        Ok(self.get_setting(name)?.unwrap_or_default())
+    }
+
+    /// The token of the payment's receipt link, for its payer, payee or a manager of either
+    /// account. `None` for anyone else.
+    pub fn receipt_token(&self, payment: u64, user: i64) -> Result<Option<String>> {
+        let key = self.domain_secret("receipt_key")?;
+        let receipt = self.reader()?.query_row("SELECT p.id, p.payer, p.payee, p.amount, p.created FROM payment p \
+        WHERE p.id = ?1 AND (?2 IN (p.payer, p.payee) OR EXISTS(SELECT 1 FROM account_manager \
+        WHERE account IN (p.payer, p.payee) AND manager = ?2))", params![payment, user],
+                                               |row| Ok(receipt_token(&key, row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, &row.get::<_, String>(4)?))).optional()?;
+        Ok(receipt)
+    }
+
+    /// The payment's receipt, shown to anyone with its link. `None` if the token doesn't match.
+    pub fn get_receipt(&self, payment: u64, token: &str) -> Result<Option<Receipt>> {
+        let key = self.domain_secret("receipt_key")?;
+        let receipt = self.reader()?.query_row("SELECT p.id, p.payer, p.payee, p.amount, p.created, \
+        payer.name, payer.member_number, payee.name, payee.member_number FROM payment p \
+        JOIN user payer ON payer.id = p.payer JOIN user payee ON payee.id = p.payee WHERE p.id = ?1", [payment], |row| {
+            let (payer, payee, created): (i64, i64, String) = (row.get(1)?, row.get(2)?, row.get(4)?);
+            Ok((receipt_token(&key, row.get(0)?, payer, payee, row.get(3)?, &created), Receipt {
+                id: row.get(0)?,
+                payer_name: row.get(5)?,
+                payer_number: row.get(6)?,
+                payee_name: row.get(7)?,
+                payee_number: row.get(8)?,
+                amount: row.get(3)?,
+                created,
+            }))
+        }).optional()?;
+        Ok(receipt.filter(|(expected, _)| expected == token.trim()).map(|(_, receipt)| receipt))
+    }
+
+    /// Keeps `content` as a file documenting the payment, such as a photo of what was traded or
+    /// a receipt, see `validation::attachment` for what is kept. The payer, the payee and
+    /// managers of either account may attach files, `QueryReturnedNoRows` for anyone else.
+    pub fn attach_file(&self, payment: u64, user: i64, name: &str, content: &[u8]) -> Result<u64, SimpletsError> {
+        let dir = self.attachment_dir.as_ref().ok_or(SimpletsError::AttachmentsDisabled)?;
+        let media_type = validation::attachment(content)?;
+        Domain::require_writable(&self.get_user(user)?)?;
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if !Domain::is_party(&tx, payment, user)? { return Err(Error::QueryReturnedNoRows.into()) }
+        tx.execute("INSERT INTO attachment (payment, uploader, name, media_type, size, created) \
+        VALUES (?1, ?2, ?3, ?4, ?5, datetime('now'))", params![payment, user, attachment_name(name), media_type, content.len()])?;
+        let id = tx.last_insert_rowid() as u64;
+        // the row is committed only once the content is on disk
+        std::fs::create_dir_all(dir).and_then(|_| std::fs::write(dir.join(id.to_string()), content)).map_err(|e| {
+            error!(error = %e, dir = %dir.display(), "attachment not stored");
+            SimpletsError::Internal
+        })?;
+        tx.commit()?;
+        info!(attachment = id, payment, user, size = content.len(), "file attached");
+        Ok(id)
+    }
+
+    /// The files attached to the payment. Only its payer, payee, their managers and roles that
+    /// view all balances see them, whatever the privacy policy, `PrivacyDenied` for others.
+    pub fn get_attachments(&self, viewer: i64, payment: u64) -> Result<Vec<Attachment>, SimpletsError> {
+        let conn = self.reader()?;
+        if !Domain::may_view_payment(&conn, payment, viewer)? { return Err(SimpletsError::PrivacyDenied) }
+        let mut stmt = conn.prepare("SELECT * FROM attachment WHERE payment = ?1 ORDER BY id")?;
+        let iter = stmt.query_map([payment], attachment_from_row)?;
+        Ok(iter.collect::<Result<_>>()?)
+    }
+
+    /// The files attached to payments from and to the user, for their payment history.
+    pub fn get_attachments_by_user(&self, user: i64) -> Result<Vec<Attachment>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT a.* FROM attachment a JOIN payment p ON p.id = a.payment \
+        WHERE ?1 IN (p.payer, p.payee) ORDER BY a.id")?;
+        let iter = stmt.query_map([user], attachment_from_row)?;
+        iter.collect()
+    }
+
+    /// An attached file with its content, for those `get_attachments` shows it to.
+    pub fn read_attachment(&self, viewer: i64, id: u64) -> Result<(Attachment, Vec<u8>), SimpletsError> {
+        let dir = self.attachment_dir.as_ref().ok_or(SimpletsError::AttachmentsDisabled)?;
+        let attachment = {
+            let conn = self.reader()?;
+            let attachment = conn.query_row("SELECT * FROM attachment WHERE id = ?1", [id], attachment_from_row)?;
+            if !Domain::may_view_payment(&conn, attachment.payment, viewer)? { return Err(SimpletsError::PrivacyDenied) }
+            attachment
+        };
+        let content = std::fs::read(dir.join(id.to_string())).map_err(|e| {
+            error!(error = %e, attachment = id, "attachment not readable");
+            SimpletsError::Internal
+        })?;
+        Ok((attachment, content))
+    }
+
+    /// Whether the user paid or received the payment, or manages an account that did.
+    fn is_party(conn: &Connection, payment: u64, user: i64) -> Result<bool> {
+        conn.query_row("SELECT EXISTS(SELECT 1 FROM payment p WHERE p.id = ?1 AND (?2 IN (p.payer, p.payee) \
+        OR EXISTS(SELECT 1 FROM account_manager WHERE account IN (p.payer, p.payee) AND manager = ?2)))",
+                       params![payment, user], |row| row.get(0))
+    }
+
+    fn may_view_payment(conn: &Connection, payment: u64, viewer: i64) -> Result<bool> {
+        Ok(Domain::is_party(conn, payment, viewer)? || Domain::has_capability(conn, viewer, Capability::ViewAllBalances)?)
+    }
+
+    pub fn create_listing(&self, owner: i64, kind: i64, category: &str, title: &str, description: &str) -> Result<u64, SimpletsError> {
+        Domain::require_writable(&self.get_user(owner)?)?;
+        let conn = self.writer();
+        conn.execute("INSERT INTO listing (owner, kind, category, title, description, created, closed)\
+        VALUES (?1, ?2, ?3, ?4, ?5, datetime('now'), 0)", params![owner, kind, category, title, description])?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    pub fn update_listing(&self, id: u64, owner: i64, category: &str, title: &str, description: &str) -> Result<(), SimpletsError> {
+        self.open_listing(id, owner)?;
+        self.writer().execute("UPDATE listing SET category = ?1, title = ?2, description = ?3 WHERE id = ?4",
+                              params![category, title, description, id])?;
+        Ok(())
+    }
+
+    pub fn close_listing(&self, id: u64, owner: i64) -> Result<(), SimpletsError> {
+        self.open_listing(id, owner)?;
+        self.writer().execute("UPDATE listing SET closed = 1 WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn open_listing(&self, id: u64, owner: i64) -> Result<Listing, SimpletsError> {
+        match self.get_listing(id) {
+            Ok(l) if l.owner == owner && !l.closed => Ok(l),
+            Ok(_) | Err(Error::QueryReturnedNoRows) => Err(SimpletsError::ListingNotFound),
+            Err(e) => Err(SimpletsError::Db(e))
+        }
+    }
+
+    pub fn get_listing(&self, id: u64) -> Result<Listing> {
+        self.reader()?.query_row("SELECT listing.*, user.name FROM listing JOIN user ON user.id = listing.owner \
+        WHERE listing.id = ?", [id], listing_from_row)
+    }
+
+    /// Open listings, newest first, optionally only of one category and kind.
+    pub fn get_listings(&self, category: Option<&str>, kind: Option<i64>) -> Result<Vec<Listing>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT listing.*, user.name FROM listing JOIN user ON user.id = listing.owner \
+        WHERE closed = 0 AND (?1 IS NULL OR category = ?1) AND (?2 IS NULL OR kind = ?2) ORDER BY created DESC")?;
+        let iter = stmt.query_map(params![category, kind], listing_from_row)?;
+        iter.collect()
+    }
+
+    pub fn get_listing_categories(&self) -> Result<Vec<String>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT DISTINCT category FROM listing WHERE closed = 0 ORDER BY category")?;
+        let iter = stmt.query_map([], |row| row.get(0))?;
+        iter.collect()
+    }
+
+    pub fn update_profile(&self, user_id: i64, display_name: &str, contact: &str, bio: &str, skills: &str) -> Result<usize> {
+        self.writer().execute("INSERT OR REPLACE INTO profile (user, display_name, contact, bio, skills, updated)\
+        VALUES (?1, ?2, ?3, ?4, ?5, datetime('now'))", params![user_id, display_name, contact, bio, skills])
+    }
+
+    pub fn get_profile(&self, user_id: i64) -> Result<Profile> {
+        self.reader()?.query_row("SELECT u.id, u.name, COALESCE(p.display_name, ''), COALESCE(p.contact, ''), \
+        COALESCE(p.bio, ''), COALESCE(p.skills, '') FROM user u LEFT JOIN profile p ON p.user = u.id WHERE u.id = ?1",
+            [user_id], |row| Ok(Profile {
+                user: row.get(0)?,
+                name: row.get(1)?,
+                display_name: row.get(2)?,
+                contact: row.get(3)?,
+                bio: row.get(4)?,
+                skills: row.get(5)?,
+            }))
+    }
+
+    /// Active members with their offers and recent activity as seen by `viewer`, most active first.
+    pub fn get_users_with_stats(&self, viewer: Option<i64>) -> Result<Vec<MemberSummary>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT u.id, u.name, COALESCE(p.display_name, ''), COALESCE(p.skills, ''), \
+        (SELECT COUNT(*) FROM payment WHERE (payer = u.id OR payee = u.id) AND kind = ?1 \
+        AND created > datetime('now', '-90 days')) AS recent, \
+        (SELECT MAX(created) FROM payment WHERE (payer = u.id OR payee = u.id) AND kind = ?1), u.credit, u.member_number \
+        FROM user u LEFT JOIN profile p ON p.user = u.id WHERE u.active AND u.account_type = ?2 \
+        ORDER BY recent DESC, u.name")?;
+        let iter = stmt.query_map(params![PAYMENT_REGULAR, ACCOUNT_MEMBER], |row| Ok(MemberSummary {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            display_name: row.get(2)?,
+            skills: row.get(3)?,
+            credit: row.get(6)?,
+            recent_payments: row.get(4)?,
+            last_payment: row.get(5)?,
+            member_number: row.get(7)?,
+        }))?;
+        let mut members = iter.collect::<Result<Vec<_>>>()?;
+        if !self.sees_others_finances(viewer)? {
+            members.iter_mut().filter(|m| viewer != Some(m.id)).for_each(|m| m.credit = None);
+        }
+        Ok(members)
+    }
+
+    pub fn send_message(&self, sender: i64, recipient: i64, body: &str) -> Result<u64, SimpletsError> {
+        if !self.get_user(recipient)?.active { return Err(SimpletsError::UserInactive); }
+        Domain::require_writable(&self.get_user(sender)?)?;
+        let conn = self.writer();
+        conn.execute("INSERT INTO message (sender, recipient, body, created, seen)\
+        VALUES (?1, ?2, ?3, datetime('now'), 0)", params![sender, recipient, body])?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    /// All messages between two members, oldest first.
+    pub fn get_conversation(&self, user: i64, other: i64) -> Result<Vec<Message>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM message WHERE (sender = ?1 AND recipient = ?2) \
+        OR (sender = ?2 AND recipient = ?1) ORDER BY id")?;
+        let iter = stmt.query_map([user, other], message_from_row)?;
+        iter.collect()
+    }
+
+    pub fn mark_conversation_read(&self, user: i64, other: i64) -> Result<usize> {
+        self.writer().execute("UPDATE message SET seen = 1 WHERE recipient = ?1 AND sender = ?2 AND seen = 0", [user, other])
+    }
+
+    /// One entry per counterpart with the latest message, most recent first.
+    pub fn get_inbox(&self, user: i64) -> Result<Vec<Conversation>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT m.other, user.name, m.body, m.created, \
+        (SELECT COUNT(*) FROM message WHERE sender = m.other AND recipient = ?1 AND seen = 0) \
+        FROM (SELECT CASE WHEN sender = ?1 THEN recipient ELSE sender END AS other, body, created, MAX(id) AS last \
+        FROM message WHERE sender = ?1 OR recipient = ?1 GROUP BY other) m \
+        JOIN user ON user.id = m.other ORDER BY m.last DESC")?;
+        let iter = stmt.query_map([user], |row| {
+            Ok(Conversation {
+                other: row.get(0)?,
+                other_name: row.get(1)?,
+                body: row.get(2)?,
+                created: row.get(3)?,
+                unread: row.get(4)?,
+            })
+        })?;
+        iter.collect()
+    }
+
+    pub fn get_unread_count(&self, user: i64) -> Result<u64> {
+        self.reader()?.query_row("SELECT COUNT(*) FROM message WHERE recipient = ? AND seen = 0", [user], |row| row.get(0))
+    }
+
+    /// Subscribes `url` to `events`. Every call is signed with `secret`, see `webhook::sign`.
+    pub fn register_webhook(&self, url: &str, secret: &str, events: &[&str]) -> Result<u64> {
+        let conn = self.writer();
+        conn.execute("INSERT INTO webhook (url, secret, events, created) VALUES (?1, ?2, ?3, datetime('now'))",
+                     params![url, secret, events.join(",")])?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    pub fn get_webhooks(&self) -> Result<Vec<Webhook>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM webhook ORDER BY id")?;
+        let iter = stmt.query_map([], |row| {
+            Ok(Webhook {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                secret: row.get(2)?,
+                events: row.get(3)?,
+                created: row.get(4)?,
+            })
+        })?;
+        iter.collect()
+    }
+
+    pub fn delete_webhook(&self, id: u64) -> Result<usize> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM webhook_delivery WHERE webhook = ?", [id])?;
+        let deleted = tx.execute("DELETE FROM webhook WHERE id = ?", [id])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn queue_webhooks(&self, conn: &Connection, event: &str, payment: &Payment) -> Result<()> {
+        let payload = serde_json::json!({ "event": event, "domain": self.name, "payment": payment }).to_string();
+        conn.execute("INSERT INTO webhook_delivery (webhook, event, payload, created, attempts, next_attempt) \
+        SELECT id, ?1, ?2, datetime('now'), 0, datetime('now') FROM webhook \
+        WHERE ',' || events || ',' LIKE '%,' || ?1 || ',%'", params![event, payload])?;
+        Ok(())
+    }
+
+    /// Undelivered calls that are due, oldest first.
+    pub fn pending_webhook_deliveries(&self, limit: u32) -> Result<Vec<WebhookDelivery>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT d.id, w.url, w.secret, d.event, d.payload, d.attempts \
+        FROM webhook_delivery d JOIN webhook w ON w.id = d.webhook \
+        WHERE d.delivered IS NULL AND d.attempts < 10 AND d.next_attempt <= datetime('now') ORDER BY d.id LIMIT ?")?;
+        let iter = stmt.query_map([limit], |row| {
+            Ok(WebhookDelivery {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                secret: row.get(2)?,
+                event: row.get(3)?,
+                payload: row.get(4)?,
+                attempts: row.get(5)?,
+            })
+        })?;
+        iter.collect()
+    }
+
+    pub fn mark_webhook_delivered(&self, id: u64) -> Result<usize> {
+        self.writer().execute("UPDATE webhook_delivery SET delivered = datetime('now'), \
+        attempts = attempts + 1, last_error = NULL WHERE id = ?", [id])
+    }
+
+    /// Records the failure and backs off exponentially: 1, 2, 4, .. minutes.
+    pub fn mark_webhook_failed(&self, id: u64, error: &str) -> Result<usize> {
+        self.writer().execute("UPDATE webhook_delivery SET attempts = attempts + 1, last_error = ?1, \
+        next_attempt = datetime('now', '+' || (1 << attempts) || ' minutes') WHERE id = ?2", params![error, id])
+    }
+
+    /// Peers with the domain `name` served at `url`, e.g. `https://lets.example.org`. Both sides
+    /// register each other under the same secret. Creates the bridge account for the peer.
+    pub fn add_remote_domain(&self, name: &str, url: &str, secret: &str) -> Result<RemoteDomain> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        let bridge = insert_account(&tx, &format!("{} (federation)", name), "!", ACCOUNT_SYSTEM)?;
+        tx.execute("INSERT INTO remote_domain (name, url, secret, bridge, created) VALUES (?1, ?2, ?3, ?4, datetime('now'))",
+                   params![name, url.trim_end_matches('/'), secret, bridge])?;
+        tx.commit()?;
+        drop(conn);
+        self.get_remote_domain(name)
+    }
+
+    pub fn get_remote_domain(&self, name: &str) -> Result<RemoteDomain> {
+        self.reader()?.query_row("SELECT * FROM remote_domain WHERE name = ?", [name], remote_domain_from_row)
+    }
+
+    pub fn get_remote_domains(&self) -> Result<Vec<RemoteDomain>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM remote_domain ORDER BY name")?;
+        let iter = stmt.query_map([], remote_domain_from_row)?;
+        iter.collect()
+    }
+
+    /// Pays `remote_payee` at a peer: books the amount from the payer to the peer's bridge
+    /// account with the usual checks and queues the transfer, converted by the peer's rate, for
+    /// `federation::Client`. If the peer refuses it, the payer is refunded.
+    pub fn add_cross_domain_payment(&self, local_payer: i64, remote_domain: &str, remote_payee: i64, amount: u64, message: &str) -> Result<FederatedTransfer, SimpletsError> {
+        let remote = match self.get_remote_domain(remote_domain) {
+            Err(Error::QueryReturnedNoRows) => return Err(SimpletsError::RemoteDomainUnknown),
+            result => result?
+        };
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let payment = self.transfer(&tx, local_payer, remote.bridge, amount, message, None)?;
+        let mut reference = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut reference);
+        let remote_amount = convert(amount, remote.rate);
+        tx.execute("INSERT INTO federation_transfer (remote, reference, outgoing, local_account, remote_account, amount, message, \
+        payment, status, created, attempts, next_attempt, remote_amount) \
+        VALUES (?1, ?2, 1, ?3, ?4, ?5, ?6, ?7, ?8, datetime('now'), 0, datetime('now'), ?9)",
+                   params![remote.id, hex::encode(reference), local_payer, remote_payee, amount, message, payment.id, TRANSFER_PENDING, remote_amount])?;
+        let transfer = tx.query_row("SELECT * FROM federation_transfer WHERE id = ?", [tx.last_insert_rowid()], transfer_from_row)?;
+        tx.commit()?;
+        info!(transfer = transfer.id, remote = %remote.name, payer = local_payer, payee = remote_payee, amount, "cross-domain payment queued");
+        Ok(transfer)
+    }
+
+    /// Transfers of the user to and from other domains, newest first.
+    pub fn get_federated_transfers(&self, user: i64) -> Result<Vec<FederatedTransfer>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT * FROM federation_transfer WHERE local_account = ? ORDER BY id DESC")?;
+        let iter = stmt.query_map([user], transfer_from_row)?;
+        iter.collect()
+    }
+
+    /// Outgoing transfers due to be posted, oldest first, with their peer.
+    pub fn pending_transfers(&self, limit: u32) -> Result<Vec<(RemoteDomain, FederatedTransfer)>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT r.*, t.* FROM federation_transfer t JOIN remote_domain r ON r.id = t.remote \
+        WHERE t.outgoing = 1 AND t.status = ?1 AND t.next_attempt <= datetime('now') ORDER BY t.id LIMIT ?2")?;
+        let iter = stmt.query_map(params![TRANSFER_PENDING, limit], |row| {
+            Ok((remote_domain_from_row(row)?, transfer_from_row_at(row, 7)?))
+        })?;
+        iter.collect()
+    }
+
+    pub fn mark_transfer_delivered(&self, id: u64) -> Result<usize> {
+        self.writer().execute("UPDATE federation_transfer SET status = ?1, attempts = attempts + 1, last_error = NULL \
+        WHERE id = ?2 AND status = ?3", params![TRANSFER_DELIVERED, id, TRANSFER_PENDING])
+    }
+
+    /// Records the failure and backs off exponentially like webhook calls.
+    pub fn mark_transfer_failed(&self, id: u64, error: &str) -> Result<usize> {
+        self.writer().execute("UPDATE federation_transfer SET attempts = attempts + 1, last_error = ?1, \
+        next_attempt = datetime('now', '+' || (1 << attempts) || ' minutes') WHERE id = ?2", params![error, id])
+    }
+
+    /// Gives the payer of a pending outgoing transfer the amount back from the bridge account.
+    /// The refund doesn't count into the limits, as the original payment did.
+    pub fn refund_transfer(&self, id: u64, reason: &str) -> Result<Payment> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let (transfer, bridge) = tx.query_row("SELECT t.*, r.bridge FROM federation_transfer t JOIN remote_domain r ON r.id = t.remote \
+        WHERE t.id = ?1 AND t.outgoing = 1 AND t.status = ?2", params![id, TRANSFER_PENDING],
+                                              |row| Ok((transfer_from_row(row)?, row.get::<_, i64>("bridge")?)))?;
+        let payment = tx.book(&Booking {
+            payer: bridge, payee: transfer.local_account, amount: sql_amount(transfer.amount)?, message: &format!("refund: {}", reason),
+            kind: PAYMENT_REGULAR, parent: Some(transfer.payment as i64), category: None, counted: false, created: None,
+        })?;
+        tx.execute("UPDATE federation_transfer SET status = ?1, last_error = ?2 WHERE id = ?3", params![TRANSFER_REFUNDED, reason, id])?;
+        tx.commit()?;
+        warn!(transfer = id, payer = transfer.local_account, amount = transfer.amount, reason, "cross-domain payment refunded");
+        Ok(payment)
+    }
+
+    /// Returns the peer that sent `body` if `signature` (the `X-Simplets-Signature` header) is
+    /// its signature of the body.
+    pub fn verify_peer(&self, peer: &str, signature: &str, body: &str) -> Result<RemoteDomain, SimpletsError> {
+        let remote = match self.get_remote_domain(peer) {
+            Err(Error::QueryReturnedNoRows) => return Err(SimpletsError::FederationDenied),
+            result => result?
+        };
+        if !webhook::verify(&remote.secret, body, signature) { return Err(SimpletsError::FederationDenied) }
+        Ok(remote)
+    }
+
+    /// Books a `TransferRequest` posted by a peer from its bridge account to the payee, with
+    /// the payee's usual receive limit. A reference booked before returns the same payment.
+    pub fn receive_transfer(&self, peer: &str, signature: &str, body: &str) -> Result<Payment, SimpletsError> {
+        let remote = self.verify_peer(peer, signature, body)?;
+        let request: TransferRequest = serde_json::from_str(body).map_err(|_| SimpletsError::FederationDenied)?;
+        if request.domain != remote.name { return Err(SimpletsError::FederationDenied) }
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let booked: Option<u64> = tx.query_row("SELECT payment FROM federation_transfer WHERE remote = ?1 AND reference = ?2 AND outgoing = 0",
+                                               params![remote.id, request.reference], |row| row.get(0)).optional()?;
+        if let Some(id) = booked {
+            return Ok(tx.query_row("SELECT * FROM payment WHERE id = ?", [id], payment_from_row)?)
+        }
+        let payment = self.transfer(&tx, remote.bridge, request.payee, request.amount, &request.message, None)?;
+        tx.execute("INSERT INTO federation_transfer (remote, reference, outgoing, local_account, remote_account, amount, message, \
+        payment, status, created, attempts, remote_amount) VALUES (?1, ?2, 0, ?3, ?4, ?5, ?6, ?7, ?8, datetime('now'), 1, ?9)",
+                   params![remote.id, request.reference, request.payee, request.payer, request.amount, request.message, payment.id, TRANSFER_DELIVERED,
+                       request.original_amount.unwrap_or(request.amount)])?;
+        tx.commit()?;
+        Ok(payment)
+    }
+
+    pub fn bridge_position(&self, remote: &RemoteDomain) -> Result<BridgePosition> {
+        self.reader()?.query_row("SELECT credit, \
+        (SELECT COALESCE(SUM(amount), 0) FROM federation_transfer WHERE remote = ?1 AND outgoing = 1 AND status = ?2), \
+        (SELECT COALESCE(SUM(CASE WHEN outgoing = 1 THEN remote_amount ELSE -remote_amount END), 0) FROM federation_transfer \
+        WHERE remote = ?1 AND (outgoing = 0 OR status = ?3)) FROM user WHERE id = ?4",
+                                 params![remote.id, TRANSFER_PENDING, TRANSFER_DELIVERED, remote.bridge],
+                                 |row| Ok(BridgePosition { credit: row.get(0)?, pending: row.get(1)?, mirrored: row.get(2)? }))
+    }
+
+    /// Sets how many units of the peer's currency one local unit is worth, `ExchangeRateInvalid`
+    /// unless it's a positive number.
+    pub fn set_exchange_rate(&self, remote: &str, rate: f64) -> Result<usize, SimpletsError> {
+        // also refuses NaN
+        if !(rate.is_finite() && rate > 0.0) { return Err(SimpletsError::ExchangeRateInvalid) }
+        Ok(self.writer().execute("UPDATE remote_domain SET rate = ?1 WHERE name = ?2", params![rate, remote])?)
+    }
+
+    /// Starts two-factor enrolment with a new secret and returns the `otpauth://` URI for the
+    /// authenticator app. Login doesn't ask for codes until `confirm_totp` succeeds.
+    pub fn enable_totp(&self, user_id: i64) -> Result<String> {
+        let user = self.get_user(user_id)?;
+        let mut secret = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut secret);
+        self.writer().execute("INSERT OR REPLACE INTO totp (user, secret, enabled, last_step, created)\
+        VALUES (?1, ?2, 0, 0, datetime('now'))", params![user_id, totp::base32_encode(&secret)])?;
+        let issuer = if self.description.is_empty() { &self.name } else { &self.description };
+        Ok(totp::uri(issuer, &user.name, &secret))
+    }
+
+    /// Finishes enrolment with a first code from the app and returns fresh recovery codes.
+    /// Only their hashes are kept, so they are shown this once.
+    pub fn confirm_totp(&self, user_id: i64, code: &str) -> Result<Vec<String>, SimpletsError> {
+        self.check_totp(user_id, code, false)?;
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        tx.execute("UPDATE totp SET enabled = 1 WHERE user = ?", [user_id])?;
+        tx.execute("DELETE FROM recovery_code WHERE user = ?", [user_id])?;
+        let mut codes = Vec::new();
+        for _ in 0..8 {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = hex::encode(bytes);
+            tx.execute("INSERT INTO recovery_code (user, hash, used) VALUES (?1, ?2, 0)", params![user_id, hash(&code)])?;
+            codes.push(code);
+        }
+        tx.commit()?;
+        Ok(codes)
+    }
+
+    pub fn disable_totp(&self, user_id: i64) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM totp WHERE user = ?", [user_id])?;
+        tx.execute("DELETE FROM recovery_code WHERE user = ?", [user_id])?;
+        tx.commit()
+    }
+
+    pub fn totp_enabled(&self, user_id: i64) -> Result<bool> {
+        self.reader()?.query_row("SELECT EXISTS(SELECT 1 FROM totp WHERE user = ? AND enabled = 1)", [user_id], |row| row.get(0))
+    }
+
+    /// Second login step: accepts a current code or an unused recovery code, each only once.
+    pub fn verify_totp(&self, user_id: i64, code: &str) -> Result<(), SimpletsError> {
+        match self.check_totp(user_id, code, true) {
+            Err(SimpletsError::TotpInvalid) => {
+                let used = self.writer().execute("UPDATE recovery_code SET used = 1 WHERE user = ?1 AND hash = ?2 AND used = 0",
+                                                 params![user_id, hash(code.trim())])?;
+                if used == 1 { Ok(()) } else { Err(SimpletsError::TotpInvalid) }
+            }
+            result => result
+        }
+    }
+
+    fn check_totp(&self, user_id: i64, code: &str, enabled: bool) -> Result<(), SimpletsError> {
+        let (secret, last_step): (String, u64) = match self.reader()?.query_row("SELECT secret, last_step FROM totp \
+        WHERE user = ?1 AND enabled = ?2", params![user_id, enabled], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(r) => r,
+            Err(Error::QueryReturnedNoRows) => return Err(SimpletsError::TotpInvalid),
+            Err(e) => return Err(SimpletsError::Db(e))
+        };
+        let secret = totp::base32_decode(&secret).ok_or(SimpletsError::Internal)?;
+        let now = Utc::now().timestamp() as u64;
+        match totp::verify(&secret, code, now) {
+            // a code can't be replayed within its validity window, not even by a login checking
+            // it at the same time, so the step is only taken if no one took it first
+            Some(step) if step > last_step => {
+                let taken = self.writer().execute("UPDATE totp SET last_step = ?1 WHERE user = ?2 AND enabled = ?3 AND last_step < ?1",
+                                                  params![step, user_id, enabled])?;
+                if taken == 1 { Ok(()) } else { Err(SimpletsError::TotpInvalid) }
+            }
+            _ => Err(SimpletsError::TotpInvalid)
+        }
+    }
+
+    /// Starts a 30 day web session and returns its token. Only the hash is stored.
+    pub fn create_session(&self, user_id: i64, user_agent: Option<&str>) -> Result<String> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        self.writer().execute("INSERT INTO session (user, hash, created, expiry, user_agent, last_seen)\
+        VALUES (?1, ?2, datetime('now'), datetime('now', '+30 days'), ?3, datetime('now'))",
+                              params![user_id, hash(&token), user_agent])?;
+        Ok(token)
+    }
+
+    /// Looks up a live session by token. Last seen is refreshed at most once a minute
+    /// to spare the writer.
+    pub fn authenticate_session(&self, token: &str) -> Result<Session> {
+        let session = self.reader()?.query_row("SELECT id, user, created, expiry, user_agent, last_seen FROM session \
+        WHERE hash = ?1 AND expiry > datetime('now')", [hash(token)], session_from_row)?;
+        self.writer().execute("UPDATE session SET last_seen = datetime('now') \
+        WHERE id = ?1 AND last_seen < datetime('now', '-1 minute')", [session.id])?;
+        Ok(session)
+    }
+
+    pub fn get_sessions(&self, user_id: i64) -> Result<Vec<Session>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT id, user, created, expiry, user_agent, last_seen FROM session \
+        WHERE user = ?1 AND expiry > datetime('now') ORDER BY last_seen DESC")?;
+        let iter = stmt.query_map([user_id], session_from_row)?;
+        iter.collect()
+    }
+
+    pub fn end_session(&self, token: &str) -> Result<usize> {
+        self.writer().execute("DELETE FROM session WHERE hash = ?", [hash(token)])
+    }
+
+    pub fn revoke_session(&self, user_id: i64, id: u64) -> Result<usize> {
+        self.writer().execute("DELETE FROM session WHERE user = ?1 AND id = ?2", params![user_id, id])
+    }
+
+    /// Logs the user out everywhere except the session `keep`.
+    pub fn revoke_other_sessions(&self, user_id: i64, keep: u64) -> Result<usize> {
+        self.writer().execute("DELETE FROM session WHERE user = ?1 AND id != ?2", params![user_id, keep])
+    }
+
+    /// Counts a failed login for `key` (e.g. `user:alice` or `ip:10.0.0.1`) and locks it
+    /// out with exponential backoff. The counter starts over after a day without failures.
+    pub fn record_failed_login(&self, key: &str) -> Result<()> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let failures: u64 = match tx.query_row("SELECT failures FROM login_failure WHERE key = ?1 \
+        AND last_failure > datetime('now', '-1 day')", [key], |row| row.get(0)) {
+            Ok(f) => f,
+            Err(Error::QueryReturnedNoRows) => 0,
+            Err(e) => return Err(e)
+        } + 1;
+        let locked_until = lockout_seconds(failures).map(|s| (Utc::now() + chrono::Duration::seconds(s as i64))
+            .format(TIME_FORMAT).to_string());
+        tx.execute("INSERT OR REPLACE INTO login_failure (key, failures, last_failure, locked_until)\
+        VALUES (?1, ?2, datetime('now'), ?3)", params![key, failures, locked_until])?;
+        tx.commit()?;
+        warn!(key, failures, locked_until = locked_until.as_deref(), "login failed");
+        Ok(())
+    }
+
+    pub fn is_locked(&self, key: &str) -> Result<bool> {
+        self.reader()?.query_row("SELECT EXISTS(SELECT 1 FROM login_failure WHERE key = ?1 \
+        AND locked_until > datetime('now'))", [key], |row| row.get(0))
+    }
+
+    pub fn clear_failed_logins(&self, key: &str) -> Result<usize> {
+        self.writer().execute("DELETE FROM login_failure WHERE key = ?", [key])
+    }
+
+    /// Appends an entry to the audit log. Actions are dotted names like `password.change`,
+    /// `target` is the account the action was done to, if any.
+    pub fn audit(&self, actor: Option<i64>, action: &str, target: Option<i64>, detail: &str) -> Result<()> {
+        self.writer().execute("INSERT INTO audit_log (actor, action, target, detail, created)\
+        VALUES (?1, ?2, ?3, ?4, datetime('now'))", params![actor, action, target, detail])?;
+        info!(target: "simplets::audit", actor, action, account = target, detail, "audited action");
+        Ok(())
+    }
+
+    /// Returns the newest audit entries in the inclusive date range, optionally only those
+    /// where `user` is the actor or the target.
+    pub fn get_audit_log(&self, user: Option<i64>, from: Option<NaiveDate>, to: Option<NaiveDate>, limit: u32) -> Result<Vec<AuditEntry>> {
+        let from = from.map(|d| self.timezone.day_start(d)).unwrap_or_else(|| "0000-01-01".to_string());
+        let to = to.map(|d| self.timezone.day_start(d.succ())).unwrap_or_else(|| "9999-12-31".to_string());
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT id, actor, action, target, detail, created FROM audit_log \
+        WHERE (?1 IS NULL OR actor = ?1 OR target = ?1) AND created >= ?2 AND created < ?3 \
+        ORDER BY id DESC LIMIT ?4")?;
+        let iter = stmt.query_map(params![user, from, to, limit], audit_from_row)?;
+        iter.collect()
+    }
+
+    /// Issues a new API token for the user and returns it. Only its hash is stored,
+    /// so the token can't be shown again later.
+    pub fn issue_token(&self, user_id: i64, label: &str, expiry: Option<NaiveDateTime>) -> Result<String> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        let expiry = expiry.map(|e| self.timezone.to_utc(e).format(TIME_FORMAT).to_string());
+        self.writer().execute("INSERT INTO token (user, label, hash, created, expiry)\
+        VALUES (?1, ?2, ?3, datetime('now'), ?4)", params![user_id, label, hash(&token), expiry])?;
+        Ok(token)
+    }
+
+    pub fn authenticate_token(&self, token: &str) -> Result<User> {
+        let user_id: i64 = self.reader()?.query_row("SELECT user FROM token WHERE hash = ?1 \
+        AND (expiry IS NULL OR expiry > datetime('now'))", [hash(token)], |row| row.get(0))?;
+        self.get_user(user_id)
+    }
+
+    fn init_database(conn: Connection) -> std::result::Result<Connection, DomainError> {
+        let db_version: i64 = conn.query_row("PRAGMA user_version",[], |row| {row.get(0)})?;
+        if db_version > SCHEMA_VERSION {
+            return Err(DomainError::SchemaVersionTooNew { found: db_version, supported: SCHEMA_VERSION });
+        }
+        let _: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        if db_version == 0 {
+            conn.execute("PRAGMA user_version = 1", [])?;
+            conn.execute("PRAGMA foreign_keys = ON", [])?;
+            conn.execute("CREATE TABLE user (
+                    id              INTEGER PRIMARY KEY,
+                    name            TEXT,
+                    credit          INTEGER NOT NULL,
+                    payments_in     INTEGER NOT NULL,
+                    payments_out    INTEGER NOT NULL,
+                    password        TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    permission      INTEGER NOT NULL
+                    )", [])?;
+            conn.execute("CREATE TABLE payment (
+                    id              INTEGER PRIMARY KEY,
+                    payer           INTEGER NOT NULL,
+                    payee           INTEGER NOT NULL,
+                    amount          INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    message         TEXT NOT NULL,
+                    FOREIGN KEY(payer) REFERENCES user(id),
+                    FOREIGN KEY(payee) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 2 {
+            conn.execute("PRAGMA user_version = 2", [])?;
+            conn.execute("CREATE TABLE payment_request (
+                    id              INTEGER PRIMARY KEY,
+                    payer           INTEGER NOT NULL,
+                    payee           INTEGER NOT NULL,
+                    amount          INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    message         TEXT NOT NULL,
+                    status          INTEGER NOT NULL,
+                    FOREIGN KEY(payer) REFERENCES user(id),
+                    FOREIGN KEY(payee) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 3 {
+            conn.execute("PRAGMA user_version = 3", [])?;
+            conn.execute("CREATE TABLE token (
+                    id              INTEGER PRIMARY KEY,
+                    user            INTEGER NOT NULL,
+                    label           TEXT NOT NULL,
+                    hash            TEXT NOT NULL UNIQUE,
+                    created         TEXT NOT NULL,
+                    expiry          TEXT,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 4 {
+            conn.execute("PRAGMA user_version = 4", [])?;
+            conn.execute("ALTER TABLE user ADD COLUMN active INTEGER NOT NULL DEFAULT 1", [])?;
+        }
+        if db_version < 5 {
+            conn.execute("PRAGMA user_version = 5", [])?;
+            conn.execute("CREATE TABLE domain_settings (
+                    key             TEXT PRIMARY KEY,
+                    value           TEXT NOT NULL
+                    )", [])?;
+        }
+        if db_version < 6 {
+            conn.execute("PRAGMA user_version = 6", [])?;
+            conn.execute("ALTER TABLE user ADD COLUMN account_type INTEGER NOT NULL DEFAULT 0", [])?;
+        }
+        if db_version < 7 {
+            conn.execute("PRAGMA user_version = 7", [])?;
+            conn.execute("ALTER TABLE payment ADD COLUMN kind INTEGER NOT NULL DEFAULT 0", [])?;
+            conn.execute("CREATE TABLE demurrage_run (
+                    period          TEXT PRIMARY KEY,
+                    rate            REAL NOT NULL,
+                    created         TEXT NOT NULL
+                    )", [])?;
+        }
+        if db_version < 8 {
+            conn.execute("PRAGMA user_version = 8", [])?;
+            conn.execute("ALTER TABLE payment ADD COLUMN parent INTEGER REFERENCES payment(id)", [])?;
+        }
+        if db_version < 9 {
+            conn.execute("PRAGMA user_version = 9", [])?;
+            conn.execute("CREATE TABLE listing (
+                    id              INTEGER PRIMARY KEY,
+                    owner           INTEGER NOT NULL,
+                    kind            INTEGER NOT NULL,
+                    category        TEXT NOT NULL,
+                    title           TEXT NOT NULL,
+                    description     TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    closed          INTEGER NOT NULL,
+                    FOREIGN KEY(owner) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 10 {
+            conn.execute("PRAGMA user_version = 10", [])?;
+            conn.execute("CREATE TABLE message (
+                    id              INTEGER PRIMARY KEY,
+                    sender          INTEGER NOT NULL,
+                    recipient       INTEGER NOT NULL,
+                    body            TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    seen            INTEGER NOT NULL,
+                    FOREIGN KEY(sender) REFERENCES user(id),
+                    FOREIGN KEY(recipient) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 11 {
+            conn.execute("PRAGMA user_version = 11", [])?;
+            conn.execute("ALTER TABLE user ADD COLUMN email TEXT", [])?;
+            conn.execute("ALTER TABLE user ADD COLUMN notify INTEGER NOT NULL DEFAULT 0", [])?;
+            conn.execute("CREATE TABLE notification (
+                    id              INTEGER PRIMARY KEY,
+                    user            INTEGER NOT NULL,
+                    email           TEXT NOT NULL,
+                    subject         TEXT NOT NULL,
+                    body            TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    sent            TEXT,
+                    attempts        INTEGER NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 12 {
+            conn.execute("PRAGMA user_version = 12", [])?;
+            conn.execute("CREATE TABLE webhook (
+                    id              INTEGER PRIMARY KEY,
+                    url             TEXT NOT NULL,
+                    secret          TEXT NOT NULL,
+                    events          TEXT NOT NULL,
+                    created         TEXT NOT NULL
+                    )", [])?;
+            conn.execute("CREATE TABLE webhook_delivery (
+                    id              INTEGER PRIMARY KEY,
+                    webhook         INTEGER NOT NULL,
+                    event           TEXT NOT NULL,
+                    payload         TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    attempts        INTEGER NOT NULL,
+                    next_attempt    TEXT NOT NULL,
+                    delivered       TEXT,
+                    last_error      TEXT,
+                    FOREIGN KEY(webhook) REFERENCES webhook(id)
+                    )", [])?;
+        }
+        if db_version < 13 {
+            conn.execute("PRAGMA user_version = 13", [])?;
+            conn.execute("CREATE TABLE password_reset (
+                    id              INTEGER PRIMARY KEY,
+                    user            INTEGER NOT NULL,
+                    hash            TEXT NOT NULL UNIQUE,
+                    created         TEXT NOT NULL,
+                    expiry          TEXT NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 14 {
+            conn.execute("PRAGMA user_version = 14", [])?;
+            conn.execute("CREATE TABLE totp (
+                    user            INTEGER PRIMARY KEY,
+                    secret          TEXT NOT NULL,
+                    enabled         INTEGER NOT NULL,
+                    last_step       INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+            conn.execute("CREATE TABLE recovery_code (
+                    id              INTEGER PRIMARY KEY,
+                    user            INTEGER NOT NULL,
+                    hash            TEXT NOT NULL,
+                    used            INTEGER NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 15 {
+            conn.execute("PRAGMA user_version = 15", [])?;
+            conn.execute("CREATE TABLE session (
+                    id              INTEGER PRIMARY KEY,
+                    user            INTEGER NOT NULL,
+                    hash            TEXT NOT NULL UNIQUE,
+                    created         TEXT NOT NULL,
+                    expiry          TEXT NOT NULL,
+                    user_agent      TEXT,
+                    last_seen       TEXT NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 16 {
+            conn.execute("PRAGMA user_version = 16", [])?;
+            conn.execute("CREATE TABLE login_failure (
+                    key             TEXT PRIMARY KEY,
+                    failures        INTEGER NOT NULL,
+                    last_failure    TEXT NOT NULL,
+                    locked_until    TEXT
+                    )", [])?;
+        }
+        if db_version < 17 {
+            conn.execute("PRAGMA user_version = 17", [])?;
+            conn.execute("CREATE TABLE audit_log (
+                    id              INTEGER PRIMARY KEY,
+                    actor           INTEGER,
+                    action          TEXT NOT NULL,
+                    target          INTEGER,
+                    detail          TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(actor) REFERENCES user(id),
+                    FOREIGN KEY(target) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 18 {
+            conn.execute("PRAGMA user_version = 18", [])?;
+            conn.execute("ALTER TABLE payment ADD COLUMN category TEXT", [])?;
+        }
+        if db_version < 19 {
+            conn.execute("PRAGMA user_version = 19", [])?;
+            conn.execute("CREATE TABLE profile (
+                    user            INTEGER PRIMARY KEY,
+                    display_name    TEXT NOT NULL,
+                    contact         TEXT NOT NULL,
+                    bio             TEXT NOT NULL,
+                    skills          TEXT NOT NULL,
+                    updated         TEXT NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 20 {
+            conn.execute("PRAGMA user_version = 20", [])?;
+            conn.execute("CREATE TABLE voucher (
+                    id              INTEGER PRIMARY KEY,
+                    payer           INTEGER NOT NULL,
+                    amount          INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    expiry          TEXT NOT NULL,
+                    redeemed        TEXT,
+                    payee           INTEGER,
+                    cancelled       TEXT,
+                    FOREIGN KEY(payer) REFERENCES user(id),
+                    FOREIGN KEY(payee) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 21 {
+            conn.execute("PRAGMA user_version = 21", [])?;
+            conn.execute("CREATE TABLE group_account (
+                    id              INTEGER PRIMARY KEY,
+                    created         TEXT NOT NULL,
+                    created_by      INTEGER NOT NULL,
+                    FOREIGN KEY(id) REFERENCES user(id),
+                    FOREIGN KEY(created_by) REFERENCES user(id)
+                    )", [])?;
+            conn.execute("CREATE TABLE account_manager (
+                    account         INTEGER NOT NULL,
+                    manager         INTEGER NOT NULL,
+                    added           TEXT NOT NULL,
+                    PRIMARY KEY(account, manager),
+                    FOREIGN KEY(account) REFERENCES group_account(id),
+                    FOREIGN KEY(manager) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 22 {
+            conn.execute("PRAGMA user_version = 22", [])?;
+            conn.execute("CREATE TABLE trustline (
+                    truster         INTEGER NOT NULL,
+                    trustee         INTEGER NOT NULL,
+                    extra_limit     INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    PRIMARY KEY(truster, trustee),
+                    FOREIGN KEY(truster) REFERENCES user(id),
+                    FOREIGN KEY(trustee) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 23 {
+            conn.execute("PRAGMA user_version = 23", [])?;
+            conn.execute("ALTER TABLE payment ADD prev_hash TEXT", [])?;
+            conn.execute("ALTER TABLE payment ADD entry_hash TEXT", [])?;
+            let payments = {
+                let mut stmt = conn.prepare("SELECT * FROM payment ORDER BY id")?;
+                let iter = stmt.query_map([], payment_from_row)?;
+                iter.collect::<Result<Vec<_>>>()?
+            };
+            let mut prev = GENESIS_HASH.to_string();
+            for p in payments.iter() {
+                let hash = entry_hash(p, &prev);
+                conn.execute("UPDATE payment SET prev_hash = ?1, entry_hash = ?2 WHERE id = ?3", params![prev, hash, p.id])?;
+                prev = hash;
+            }
+            // booked payments stay as they are, corrections are new payments
+            conn.execute("CREATE TRIGGER payment_append_only_update BEFORE UPDATE ON payment WHEN OLD.entry_hash IS NOT NULL
+                BEGIN SELECT RAISE(ABORT, 'payments are append-only'); END", [])?;
+            conn.execute("CREATE TRIGGER payment_append_only_delete BEFORE DELETE ON payment
+                BEGIN SELECT RAISE(ABORT, 'payments are append-only'); END", [])?;
+        }
+        if db_version < 24 {
+            conn.execute("PRAGMA user_version = 24", [])?;
+            conn.execute("CREATE TABLE remote_domain (
+                    id              INTEGER PRIMARY KEY,
+                    name            TEXT NOT NULL UNIQUE,
+                    url             TEXT NOT NULL,
+                    secret          TEXT NOT NULL,
+                    bridge          INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(bridge) REFERENCES user(id)
+                    )", [])?;
+            conn.execute("CREATE TABLE federation_transfer (
+                    id              INTEGER PRIMARY KEY,
+                    remote          INTEGER NOT NULL,
+                    reference       TEXT NOT NULL,
+                    outgoing        INTEGER NOT NULL,
+                    local_account   INTEGER NOT NULL,
+                    remote_account  INTEGER NOT NULL,
+                    amount          INTEGER NOT NULL,
+                    message         TEXT NOT NULL,
+                    payment         INTEGER NOT NULL,
+                    status          INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    attempts        INTEGER NOT NULL,
+                    next_attempt    TEXT,
+                    last_error      TEXT,
+                    UNIQUE(remote, reference, outgoing),
+                    FOREIGN KEY(remote) REFERENCES remote_domain(id),
+                    FOREIGN KEY(local_account) REFERENCES user(id),
+                    FOREIGN KEY(payment) REFERENCES payment(id)
+                    )", [])?;
+        }
+        if db_version < 25 {
+            conn.execute("PRAGMA user_version = 25", [])?;
+            conn.execute("ALTER TABLE remote_domain ADD rate REAL NOT NULL DEFAULT 1.0", [])?;
+            conn.execute("ALTER TABLE federation_transfer ADD remote_amount INTEGER NOT NULL DEFAULT 0", [])?;
+            conn.execute("UPDATE federation_transfer SET remote_amount = amount", [])?;
+        }
+        if db_version < 26 {
+            conn.execute("PRAGMA user_version = 26", [])?;
+            conn.execute("CREATE TABLE legacy_account (
+                  legacy_id       TEXT PRIMARY KEY,
+                  user            INTEGER NOT NULL REFERENCES user(id)
+                  )", [])?;
+        }
+        if db_version < 27 {
+            conn.execute("PRAGMA user_version = 27", [])?;
+            conn.execute("CREATE TABLE limit_override (
+                    user            INTEGER PRIMARY KEY,
+                    send_bonus      INTEGER NOT NULL,
+                    receive_bonus   INTEGER NOT NULL,
+                    expiry          TEXT,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 28 {
+            conn.execute("PRAGMA user_version = 28", [])?;
+            conn.execute("CREATE TABLE outbox (
+                    id              INTEGER PRIMARY KEY,
+                    event           TEXT NOT NULL,
+                    payment         INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    processed       TEXT,
+                    FOREIGN KEY(payment) REFERENCES payment(id)
+                    )", [])?;
+            conn.execute("CREATE INDEX outbox_pending ON outbox (processed, id)", [])?;
+        }
+        if db_version < 29 {
+            conn.execute("PRAGMA user_version = 29", [])?;
+            conn.execute("CREATE TABLE idempotency_key (
+                    payer           INTEGER NOT NULL,
+                    key             TEXT NOT NULL,
+                    payment         INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(payer) REFERENCES user(id),
+                    FOREIGN KEY(payment) REFERENCES payment(id)
+                    )", [])?;
+            conn.execute("CREATE UNIQUE INDEX idempotency_key_payer ON idempotency_key (payer, key)", [])?;
+        }
+        if db_version < 30 {
+            conn.execute("PRAGMA user_version = 30", [])?;
+            conn.execute("CREATE TABLE pending_payment (
+                    id              INTEGER PRIMARY KEY,
+                    payer           INTEGER NOT NULL,
+                    payee           INTEGER NOT NULL,
+                    amount          INTEGER NOT NULL,
+                    message         TEXT NOT NULL,
+                    category        TEXT,
+                    created         TEXT NOT NULL,
+                    expiry          TEXT NOT NULL,
+                    status          INTEGER NOT NULL,
+                    payment         INTEGER,
+                    FOREIGN KEY(payer) REFERENCES user(id),
+                    FOREIGN KEY(payee) REFERENCES user(id),
+                    FOREIGN KEY(payment) REFERENCES payment(id)
+                    )", [])?;
+        }
+        if db_version < 31 {
+            conn.execute("PRAGMA user_version = 31", [])?;
+            conn.execute("CREATE TABLE escrow (
+                    id              INTEGER PRIMARY KEY,
+                    payer           INTEGER NOT NULL,
+                    payee           INTEGER NOT NULL,
+                    amount          INTEGER NOT NULL,
+                    message         TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    status          INTEGER NOT NULL,
+                    settled         TEXT,
+                    payment         INTEGER,
+                    FOREIGN KEY(payer) REFERENCES user(id),
+                    FOREIGN KEY(payee) REFERENCES user(id),
+                    FOREIGN KEY(payment) REFERENCES payment(id)
+                    )", [])?;
+        }
+        if db_version < 32 {
+            conn.execute("PRAGMA user_version = 32", [])?;
+            conn.execute("CREATE TABLE account_freeze (
+                    user            INTEGER PRIMARY KEY,
+                    reason          TEXT NOT NULL,
+                    incoming        INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 33 {
+            conn.execute("PRAGMA user_version = 33", [])?;
+            conn.execute("CREATE TABLE account_closure (
+                    user            INTEGER PRIMARY KEY,
+                    started         TEXT NOT NULL,
+                    closed          TEXT,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 34 {
+            conn.execute("PRAGMA user_version = 34", [])?;
+            // older versions allowed duplicates, later accounts of a name get their number appended
+            conn.execute("UPDATE user SET name = name || ' ' || id WHERE id NOT IN (SELECT MIN(id) FROM user GROUP BY name)", [])?;
+            conn.execute("CREATE UNIQUE INDEX user_name ON user(name)", [])?;
+        }
+        if db_version < 35 {
+            conn.execute("PRAGMA user_version = 35", [])?;
+            // ids used to be creation timestamps. AUTOINCREMENT needs the table rebuilt, the ids
+            // stay and become the member numbers of the existing accounts.
+            let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+            conn.execute("PRAGMA foreign_keys = OFF", [])?;
+            conn.execute("CREATE TABLE user_new (
+                    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name            TEXT,
+                    credit          INTEGER NOT NULL,
+                    payments_in     INTEGER NOT NULL,
+                    payments_out    INTEGER NOT NULL,
+                    password        TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    permission      INTEGER NOT NULL,
+                    active          INTEGER NOT NULL DEFAULT 1,
+                    account_type    INTEGER NOT NULL DEFAULT 0,
+                    email           TEXT,
+                    notify          INTEGER NOT NULL DEFAULT 0,
+                    member_number   INTEGER NOT NULL
+                    )", [])?;
+            conn.execute("INSERT INTO user_new SELECT id, name, credit, payments_in, payments_out, password, created, permission, \
+            active, account_type, email, notify, id FROM user", [])?;
+            conn.execute("DROP TABLE user", [])?;
+            conn.execute("ALTER TABLE user_new RENAME TO user", [])?;
+            conn.execute("CREATE UNIQUE INDEX user_name ON user(name)", [])?;
+            conn.execute("CREATE UNIQUE INDEX user_member_number ON user(member_number)", [])?;
+            if foreign_keys { conn.execute("PRAGMA foreign_keys = ON", [])?; }
+        }
+        if db_version < 36 {
+            // the chain is rebuilt below and would hide a break, so a broken one stops the upgrade
+            if let Some(payment) = chain_status(&conn)?.broken_at {
+                return Err(DomainError::ChainBroken { payment });
+            }
+            conn.execute("PRAGMA user_version = 36", [])?;
+            // times were stored in the server's zone, they are UTC from now on. Voucher expiries
+            // are signed into the printed tokens and stay as they are.
+            const TIME_COLUMNS: [&str; 16] = ["created", "expiry", "sent", "next_attempt", "delivered", "last_seen", "last_failure",
+                "locked_until", "updated", "redeemed", "cancelled", "added", "processed", "settled", "started", "closed"];
+            let tables = {
+                let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'")?;
+                let iter = stmt.query_map([], |row| row.get(0))?;
+                iter.collect::<Result<Vec<String>>>()?
+            };
+            conn.execute("DROP TRIGGER payment_append_only_update", [])?;
+            conn.execute("DROP TRIGGER payment_append_only_delete", [])?;
+            for table in tables.iter() {
+                let columns = {
+                    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+                    let iter = stmt.query_map([], |row| row.get(1))?;
+                    iter.collect::<Result<Vec<String>>>()?
+                };
+                for column in columns.iter().filter(|c| TIME_COLUMNS.contains(&c.as_str())) {
+                    if table == "voucher" && column == "expiry" { continue }
+                    conn.execute(&format!("UPDATE {0} SET {1} = datetime({1}, 'utc') WHERE datetime({1}) IS NOT NULL", table, column), [])?;
+                }
+            }
+            // the times are part of the hashes, the chain is built anew like in version 23
+            let payments = {
+                let mut stmt = conn.prepare("SELECT * FROM payment ORDER BY id")?;
+                let iter = stmt.query_map([], payment_from_row)?;
+                iter.collect::<Result<Vec<_>>>()?
+            };
+            let mut prev = GENESIS_HASH.to_string();
+            for p in payments.iter() {
+                let hash = entry_hash(p, &prev);
+                conn.execute("UPDATE payment SET prev_hash = ?1, entry_hash = ?2 WHERE id = ?3", params![prev, hash, p.id])?;
+                prev = hash;
+            }
+            conn.execute("CREATE TRIGGER payment_append_only_update BEFORE UPDATE ON payment WHEN OLD.entry_hash IS NOT NULL
+                BEGIN SELECT RAISE(ABORT, 'payments are append-only'); END", [])?;
+            conn.execute("CREATE TRIGGER payment_append_only_delete BEFORE DELETE ON payment
+                BEGIN SELECT RAISE(ABORT, 'payments are append-only'); END", [])?;
+        }
+        if db_version < 37 {
+            conn.execute("PRAGMA user_version = 37", [])?;
+            conn.execute("CREATE TABLE leaderboard_opt_out (
+                    user            INTEGER PRIMARY KEY,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 38 {
+            conn.execute("PRAGMA user_version = 38", [])?;
+            conn.execute("CREATE TABLE event (
+                    id              INTEGER PRIMARY KEY,
+                    name            TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    closed          TEXT
+                    )", [])?;
+            conn.execute("CREATE TABLE event_payment (
+                    payment         INTEGER PRIMARY KEY,
+                    event           INTEGER NOT NULL,
+                    FOREIGN KEY(payment) REFERENCES payment(id),
+                    FOREIGN KEY(event) REFERENCES event(id)
+                    )", [])?;
+            conn.execute("CREATE INDEX event_payment_event ON event_payment(event)", [])?;
+            conn.execute("ALTER TABLE pending_payment ADD COLUMN event INTEGER REFERENCES event(id)", [])?;
+        }
+        if db_version < 39 {
+            conn.execute("PRAGMA user_version = 39", [])?;
+            conn.execute("CREATE TABLE message_template (
+                    id              INTEGER PRIMARY KEY,
+                    user            INTEGER NOT NULL,
+                    text            TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    UNIQUE(user, text),
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 40 {
+            conn.execute("PRAGMA user_version = 40", [])?;
+            conn.execute("CREATE TABLE contact (
+                    user            INTEGER NOT NULL,
+                    contact         INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    PRIMARY KEY(user, contact),
+                    FOREIGN KEY(user) REFERENCES user(id),
+                    FOREIGN KEY(contact) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 41 {
+            conn.execute("PRAGMA user_version = 41", [])?;
+            conn.execute("CREATE TABLE user_block (
+                    blocker         INTEGER NOT NULL,
+                    blocked         INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    PRIMARY KEY(blocker, blocked),
+                    FOREIGN KEY(blocker) REFERENCES user(id),
+                    FOREIGN KEY(blocked) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 42 {
+            conn.execute("PRAGMA user_version = 42", [])?;
+            conn.execute("CREATE TABLE announcement (
+                    id              INTEGER PRIMARY KEY,
+                    author          INTEGER NOT NULL,
+                    title           TEXT NOT NULL,
+                    body            TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(author) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 43 {
+            conn.execute("PRAGMA user_version = 43", [])?;
+            conn.execute("CREATE TABLE terms (
+                    version         INTEGER PRIMARY KEY,
+                    text            TEXT NOT NULL,
+                    published       TEXT NOT NULL
+                    )", [])?;
+            conn.execute("CREATE TABLE terms_acceptance (
+                    user            INTEGER NOT NULL,
+                    version         INTEGER NOT NULL,
+                    accepted        TEXT NOT NULL,
+                    PRIMARY KEY(user, version),
+                    FOREIGN KEY(user) REFERENCES user(id),
+                    FOREIGN KEY(version) REFERENCES terms(version)
+                    )", [])?;
+        }
+        if db_version < 44 {
+            conn.execute("PRAGMA user_version = 44", [])?;
+            conn.execute("ALTER TABLE user ADD COLUMN email_verified INTEGER NOT NULL DEFAULT 0", [])?;
+            conn.execute("CREATE TABLE email_verification (
+                    hash            TEXT PRIMARY KEY,
+                    user            INTEGER NOT NULL,
+                    email           TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    expiry          TEXT NOT NULL,
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 45 {
+            conn.execute("PRAGMA user_version = 45", [])?;
+            conn.execute("CREATE TABLE federated_identity (
+                    issuer          TEXT NOT NULL,
+                    subject         TEXT NOT NULL,
+                    user            INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    PRIMARY KEY(issuer, subject),
+                    FOREIGN KEY(user) REFERENCES user(id)
+                    )", [])?;
+        }
+        if db_version < 46 {
+            conn.execute("PRAGMA user_version = 46", [])?;
+            conn.execute("CREATE TABLE role (
+                    name            TEXT NOT NULL,
+                    capability      TEXT NOT NULL,
+                    PRIMARY KEY(name, capability)
+                    )", [])?;
+            for role in Role::ALL {
+                for capability in role.default_capabilities() {
+                    conn.execute("INSERT INTO role (name, capability) VALUES (?1, ?2)", params![role, capability])?;
+                }
+            }
+            // the permission number becomes a role in its place, 2 and above were admins
+            let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+            conn.execute("PRAGMA foreign_keys = OFF", [])?;
+            conn.execute("CREATE TABLE user_new (
+                    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name            TEXT,
+                    credit          INTEGER NOT NULL,
+                    payments_in     INTEGER NOT NULL,
+                    payments_out    INTEGER NOT NULL,
+                    password        TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    role            TEXT NOT NULL DEFAULT 'member',
+                    active          INTEGER NOT NULL DEFAULT 1,
+                    account_type    INTEGER NOT NULL DEFAULT 0,
+                    email           TEXT,
+                    notify          INTEGER NOT NULL DEFAULT 0,
+                    member_number   INTEGER NOT NULL,
+                    email_verified  INTEGER NOT NULL DEFAULT 0
+                    )", [])?;
+            conn.execute("INSERT INTO user_new SELECT id, name, credit, payments_in, payments_out, password, created, \
+            CASE WHEN permission >= 2 THEN 'admin' ELSE 'member' END, active, account_type, email, notify, member_number, email_verified FROM user", [])?;
+            conn.execute("DROP TABLE user", [])?;
+            conn.execute("ALTER TABLE user_new RENAME TO user", [])?;
+            conn.execute("CREATE UNIQUE INDEX user_name ON user(name)", [])?;
+            conn.execute("CREATE UNIQUE INDEX user_member_number ON user(member_number)", [])?;
+            if foreign_keys { conn.execute("PRAGMA foreign_keys = ON", [])?; }
+        }
+        if db_version < 47 {
+            conn.execute("PRAGMA user_version = 47", [])?;
+            conn.execute("CREATE TABLE attachment (
+                    id              INTEGER PRIMARY KEY,
+                    payment         INTEGER NOT NULL,
+                    uploader        INTEGER NOT NULL,
+                    name            TEXT NOT NULL,
+                    media_type      TEXT NOT NULL,
+                    size            INTEGER NOT NULL,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(payment) REFERENCES payment(id),
+                    FOREIGN KEY(uploader) REFERENCES user(id)
+                    )", [])?;
+            conn.execute("CREATE INDEX attachment_payment ON attachment(payment)", [])?;
+        }
+        if db_version < 48 {
+            conn.execute("PRAGMA user_version = 48", [])?;
+            // a key belongs to a booked or to a pending payment, the payment column needs NULL
+            conn.execute("CREATE TABLE idempotency_key_new (
+                    payer           INTEGER NOT NULL,
+                    key             TEXT NOT NULL,
+                    payment         INTEGER,
+                    pending         INTEGER,
+                    created         TEXT NOT NULL,
+                    FOREIGN KEY(payer) REFERENCES user(id),
+                    FOREIGN KEY(payment) REFERENCES payment(id),
+                    FOREIGN KEY(pending) REFERENCES pending_payment(id)
+                    )", [])?;
+            conn.execute("INSERT INTO idempotency_key_new (payer, key, payment, created) SELECT payer, key, payment, created FROM idempotency_key", [])?;
+            conn.execute("DROP TABLE idempotency_key", [])?;
+            conn.execute("ALTER TABLE idempotency_key_new RENAME TO idempotency_key", [])?;
+            conn.execute("CREATE UNIQUE INDEX idempotency_key_payer ON idempotency_key (payer, key)", [])?;
+        }
+        Ok(conn)
+    }
+}
+
+/// Walks the payment hash chain up to the first payment that doesn't match, see `Domain::verify_chain`.
+fn chain_status(conn: &Connection) -> Result<ChainStatus> {
+    let mut stmt = conn.prepare("SELECT *, prev_hash, entry_hash FROM payment ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    let mut prev = GENESIS_HASH.to_string();
+    let mut entries = 0;
+    while let Some(row) = rows.next()? {
+        let payment = payment_from_row(row)?;
+        let (prev_hash, hash): (Option<String>, Option<String>) = (row.get("prev_hash")?, row.get("entry_hash")?);
+        if prev_hash.as_deref() != Some(prev.as_str()) || hash.as_deref() != Some(entry_hash(&payment, &prev).as_str()) {
+            return Ok(ChainStatus { entries, broken_at: Some(payment.id) })
+        }
+        prev = hash.unwrap_or_default();
+        entries += 1;
+    }
+    Ok(ChainStatus { entries, broken_at: None })
+}
+
+fn attachment_from_row(row: &Row) -> Result<Attachment> {
+    Ok(Attachment {
+        id: row.get(0)?,
+        payment: row.get(1)?,
+        uploader: row.get(2)?,
+        name: row.get(3)?,
+        media_type: row.get(4)?,
+        size: row.get(5)?,
+        created: row.get(6)?,
+    })
+}
+
+/// The last component of an uploaded file's name without control characters and quotes,
+/// `attachment` if nothing is left.
+fn attachment_name(name: &str) -> String {
+    let name: String = name.rsplit(['/', '\\']).next().unwrap_or_default().chars()
+        .filter(|c| !c.is_control() && *c != '"').take(100).collect();
+    if name.trim().is_empty() { "attachment".to_string() } else { name.trim().to_string() }
+}
+
+fn user_from_row(row: &Row) -> Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        credit: row.get(2)?,
+        payments_in: row.get(3)?,
+        payments_out: row.get(4)?,
+        password: row.get(5)?,
+        created: row.get(6)?,
+        role: row.get(7)?,
+        active: row.get(8)?,
+        account_type: row.get(9)?,
+        email: row.get(10)?,
+        notify: row.get(11)?,
+        member_number: row.get(12)?,
+        email_verified: row.get(13)?,
+    })
+}
+
+/// How long a login key stays locked after `failures` consecutive failures: the first
+/// few are free, then the lockout doubles from 30 seconds up to an hour.
+pub fn lockout_seconds(failures: u64) -> Option<u64> {
+    if failures < LOGIN_FREE_ATTEMPTS { return None }
+    Some((30u64 << (failures - LOGIN_FREE_ATTEMPTS).min(7)).min(3600))
+}
+
+/// Parses `payee,amount[,message]` lines, skipping empty ones. The payee is left as text
+/// so it can be an account number or a name. On error returns the 1-based line number.
+pub fn parse_payments_csv(text: &str) -> std::result::Result<Vec<(String, u64, String)>, usize> {
+    let mut payments = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() { continue }
+        let fields = split_csv_line(line);
+        let amount = fields.get(1).and_then(|a| a.trim().parse().ok()).ok_or(i + 1)?;
+        let message = fields.get(2).cloned().unwrap_or_default();
+        payments.push((fields[0].trim().to_string(), amount, message));
+    }
+    Ok(payments)
+}
+
+/// Member of an import, see `Domain::import_users_csv`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedUser {
+    pub name: String,
+    pub email: Option<String>,
+    /// Balance in the old system, negative when the member owes.
+    pub balance: i64,
+    /// Id in the old system, for mapping its payment history in `Domain::import_payments`.
+    pub legacy_id: Option<String>,
+}
+
+/// Payment of another system's history, see `Domain::import_payments`. The members are given
+/// by their id there, their account number or name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedPayment {
+    pub payer: String,
+    pub payee: String,
+    pub amount: u64,
+    pub created: NaiveDateTime,
+    pub message: String,
+}
+
+/// Parses `name[,email[,balance[,legacy id]]]` lines, skipping empty ones and a `name,...`
+/// header line spreadsheets add. On error returns the 1-based line number.
+pub fn parse_users_csv(text: &str) -> std::result::Result<Vec<ImportedUser>, usize> {
+    let mut users = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || (i == 0 && line.trim().to_lowercase().starts_with("name,")) { continue }
+        let fields = split_csv_line(line);
+        let name = fields[0].trim().to_string();
+        let email = fields.get(1).map(|e| e.trim().to_string()).filter(|e| !e.is_empty());
+        let balance = match fields.get(2).map(|b| b.trim()).filter(|b| !b.is_empty()) {
+            Some(b) => b.parse().map_err(|_| i + 1)?,
+            None => 0,
+        };
+        if name.is_empty() || email.as_ref().is_some_and(|e| !e.contains('@')) { return Err(i + 1) }
+        let legacy_id = fields.get(3).map(|l| l.trim().to_string()).filter(|l| !l.is_empty());
+        users.push(ImportedUser { name, email, balance, legacy_id });
+    }
+    Ok(users)
+}
+
+/// Parses `date,payer,payee,amount[,message]` lines of a payment history, skipping empty ones
+/// and a header line. The date is `YYYY-MM-DD` or with the time `YYYY-MM-DD HH:MM[:SS]`, or
+/// `T` instead of the space. On error returns the 1-based line number.
+pub fn parse_history_csv(text: &str) -> std::result::Result<Vec<ImportedPayment>, usize> {
+    let mut payments = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || (i == 0 && line.trim().to_lowercase().starts_with("date,")) { continue }
+        let fields = split_csv_line(line);
+        if fields.len() < 4 { return Err(i + 1) }
+        let date = fields[0].trim().replace('T', " ");
+        let created = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"].iter().find_map(|f| NaiveDateTime::parse_from_str(&date, f).ok())
+            .or_else(|| NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)))
+            .ok_or(i + 1)?;
+        let amount = fields[3].trim().parse().map_err(|_| i + 1)?;
+        let message = fields.get(4).cloned().unwrap_or_default();
+        payments.push(ImportedPayment { payer: fields[1].trim().to_string(), payee: fields[2].trim().to_string(), amount, created, message });
+    }
+    Ok(payments)
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => { chars.next(); fields.last_mut().unwrap().push('"'); }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c)
+        }
+    }
+    fields
+}
+
+fn session_from_row(row: &Row) -> Result<Session> {
+    Ok(Session {
+        id: row.get(0)?,
+        user: row.get(1)?,
+        created: row.get(2)?,
+        expiry: row.get(3)?,
+        user_agent: row.get(4)?,
+        last_seen: row.get(5)?,
+    })
+}
+
+/// Replays the payment table and returns every user whose balance or payment counters differ.
+/// Only regular payments count towards `payments_in/out`, fees and demurrage just move credit.
+fn ledger_mismatches(conn: &Connection) -> Result<Vec<LedgerMismatch>> {
+    let mut stmt = conn.prepare("SELECT u.id, u.name, u.credit, \
+    COALESCE(SUM(CASE WHEN p.payee = u.id THEN p.amount ELSE -p.amount END), 0) AS actual_credit, \
+    u.payments_in, COUNT(CASE WHEN p.payee = u.id AND p.kind = ?1 THEN 1 END) AS actual_in, \
+    u.payments_out, COUNT(CASE WHEN p.payer = u.id AND p.kind = ?1 THEN 1 END) AS actual_out \
+    FROM user u LEFT JOIN payment p ON p.payer = u.id OR p.payee = u.id GROUP BY u.id \
+    HAVING u.credit != actual_credit OR u.payments_in != actual_in OR u.payments_out != actual_out ORDER BY u.id")?;
+    let iter = stmt.query_map([PAYMENT_REGULAR], |row| Ok(LedgerMismatch {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        credit: row.get(2)?,
+        actual_credit: row.get(3)?,
+        payments_in: row.get(4)?,
+        actual_in: row.get(5)?,
+        payments_out: row.get(6)?,
+        actual_out: row.get(7)?,
+    }))?;
+    iter.collect()
+}
+
+/// The rows of a query for one user as JSON objects keyed by column name.
+fn rows_as_json(conn: &Connection, sql: &str, user_id: i64) -> Result<Vec<serde_json::Value>> {
+    use rusqlite::types::ValueRef;
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut rows = stmt.query([user_id])?;
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut object = serde_json::Map::new();
+        for (i, column) in columns.iter().enumerate() {
+            let value = match row.get_ref(i)? {
+                ValueRef::Null => serde_json::Value::Null,
+                ValueRef::Integer(n) => n.into(),
+                ValueRef::Real(x) => x.into(),
+                ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+                ValueRef::Blob(b) => hex::encode(b).into(),
+            };
+            object.insert(column.clone(), value);
+        }
+        result.push(serde_json::Value::Object(object));
+    }
+    Ok(result)
+}
+
+/// `amount` converted at `rate` peer units per local unit, rounded to whole units.
+fn convert(amount: u64, rate: f64) -> u64 {
+    (amount as f64 * rate).round() as u64
+}
+
+fn remote_domain_from_row(row: &Row) -> Result<RemoteDomain> {
+    Ok(RemoteDomain {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        url: row.get(2)?,
+        secret: row.get(3)?,
+        bridge: row.get(4)?,
+        created: row.get(5)?,
+        rate: row.get(6)?,
+    })
+}
+
+fn transfer_from_row(row: &Row) -> Result<FederatedTransfer> {
+    transfer_from_row_at(row, 0)
+}
+
+/// Reads the transfer columns starting at `offset`, for joins putting other tables first.
+fn transfer_from_row_at(row: &Row, offset: usize) -> Result<FederatedTransfer> {
+    Ok(FederatedTransfer {
+        id: row.get(offset)?,
+        remote: row.get(offset + 1)?,
+        reference: row.get(offset + 2)?,
+        outgoing: row.get(offset + 3)?,
+        local_account: row.get(offset + 4)?,
+        remote_account: row.get(offset + 5)?,
+        amount: row.get(offset + 6)?,
+        message: row.get(offset + 7)?,
+        payment: row.get(offset + 8)?,
+        status: row.get(offset + 9)?,
+        created: row.get(offset + 10)?,
+        attempts: row.get(offset + 11)?,
+        remote_amount: row.get(offset + 14)?,
+    })
+}
+
+fn audit_from_row(row: &Row) -> Result<AuditEntry> {
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        actor: row.get(1)?,
+        action: row.get(2)?,
+        target: row.get(3)?,
+        detail: row.get(4)?,
+        created: row.get(5)?,
+    })
+}
+
+fn message_from_row(row: &Row) -> Result<Message> {
+    Ok(Message {
+        id: row.get(0)?,
+        sender: row.get(1)?,
+        recipient: row.get(2)?,
+        body: row.get(3)?,
+        created: row.get(4)?,
+        seen: row.get(5)?,
+    })
+}
+
+fn listing_from_row(row: &Row) -> Result<Listing> {
+    Ok(Listing {
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        kind: row.get(2)?,
+        category: row.get(3)?,
+        title: row.get(4)?,
+        description: row.get(5)?,
+        created: row.get(6)?,
+        closed: row.get(7)?,
+        owner_name: row.get(8)?,
+    })
+}
+
+/// Creates the account under the next AUTOINCREMENT id, so an id is never reused, and gives it
+/// the member number after the highest one in use.
+fn insert_account(conn: &Connection, name: &str, password_hash: &str, account_type: i64) -> Result<u64> {
+    conn.execute("INSERT INTO user (name, credit, payments_in, payments_out, password, created, account_type, member_number)\
+    VALUES (?1, 0, 0, 0, ?2, datetime('now'), ?3, (SELECT COALESCE(MAX(member_number), 0) + 1 FROM user))",
+                 params![name, password_hash, account_type])?;
+    let id = conn.last_insert_rowid();
+    info!(user = id, name, account_type, "account created");
+    Ok(id as u64) // AUTOINCREMENT ids start at 1
+}
+
+fn escrow_from_row(row: &Row) -> Result<Escrow> {
+    Ok(Escrow {
+        id: row.get(0)?,
+        payer: row.get(1)?,
+        payee: row.get(2)?,
+        amount: row.get(3)?,
+        message: row.get(4)?,
+        created: row.get(5)?,
+        status: row.get(6)?,
+        settled: row.get(7)?,
+        payment: row.get(8)?,
+    })
+}
+
+fn event_from_row(row: &Row) -> Result<Event> {
+    Ok(Event {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created: row.get(2)?,
+        closed: row.get(3)?,
+    })
+}
+
+fn pending_from_row(row: &Row) -> Result<PendingPayment> {
+    Ok(PendingPayment {
+        id: row.get(0)?,
+        payer: row.get(1)?,
+        payee: row.get(2)?,
+        amount: row.get(3)?,
+        message: row.get(4)?,
+        category: row.get(5)?,
+        created: row.get(6)?,
+        expiry: row.get(7)?,
+        status: row.get(8)?,
+        payment: row.get(9)?,
+        event: row.get(10)?,
+    })
+}
+
+fn payment_from_row(row: &Row) -> Result<Payment> {
+    Ok(Payment {
+        id: row.get(0)?,
+        payer: row.get(1)?,
+        payee: row.get(2)?,
+        amount: row.get(3)?,
+        created: row.get(4)?,
+        message: row.get(5)?,
+        kind: row.get(6)?,
+        parent: row.get(7)?,
+        category: row.get(8)?,
+    })
+}
+
+/// Percent-encodes everything except the unreserved characters of RFC 3986.
+pub fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Reverses `percent_encode`, also accepting `+` for a space. `None` on malformed input.
+pub fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut iter = text.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn voucher_token(key: &str, id: u64, payer: i64, amount: u64, expiry: &str) -> String {
+    let signature = webhook::sign(key, &format!("voucher:{}:{}:{}:{}", id, payer, amount, expiry));
+    format!("{}.{}.{}", id, amount, &signature[..20])
+}
+
+fn receipt_token(key: &str, id: u64, payer: i64, payee: i64, amount: u64, created: &str) -> String {
+    let signature = webhook::sign(key, &format!("receipt:{}:{}:{}:{}:{}", id, payer, payee, amount, created));
+    signature[..20].to_string()
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else { field.to_string() }
+}
+
 pub fn hash(data: impl AsRef<[u8]>) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
@@ -0,0 +1,57 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! The turnover formulas behind `User::send_limit` and `User::receive_limit`, safe for any
+//! counters and balance.
+//!
+//! A member may receive `2500 * sqrt(payments_out + 1)` units minus what they hold and owe up to
+//! `1000 * (sqrt(payments_in + 1) - 1)` units. `unit` is the smallest units in a unit of the
+//! formulas, see `CurrencyFormat::limit_unit`. Pathological values behave like this:
+//!
+//! - the counters may be anything up to `u64::MAX`, the limits only grow with them;
+//! - a `unit` that isn't a positive finite number gives the formulas nothing, as with 0;
+//! - results saturate at `i64::MIN` and `i64::MAX` instead of overflowing;
+//! - a receive limit below zero is how far the member holds more than they may, they can't
+//!   receive anything, see `Limit::lower`.
+
+/// `2500 * sqrt(payments_out + 1)` units.
+const RECEIVE_BASE: f64 = 2500.0;
+/// `1000 * (sqrt(payments_in + 1) - 1)` units.
+const CREDIT_BASE: f64 = 1000.0;
+
+/// `base * sqrt(count + 1) * unit` in smallest units, rounded down. Casting a float saturates
+/// and gives 0 for NaN.
+fn formula(base: f64, count: u64, unit: f64) -> i64 {
+    if !unit.is_finite() || unit <= 0.0 { return 0 }
+    ((count as f64 + 1.0).sqrt() * base * unit) as i64
+}
+
+/// Most a member holding `credit` may receive, negative when they hold more than that.
+pub fn receive_limit(payments_out: u64, credit: i64, unit: f64) -> i64 {
+    formula(RECEIVE_BASE, payments_out, unit).saturating_sub(credit)
+}
+
+/// Most a member may owe, 0 before they were paid anything.
+pub fn credit_limit(payments_in: u64, unit: f64) -> i64 {
+    formula(CREDIT_BASE, payments_in, unit).saturating_sub(formula(CREDIT_BASE, 0, unit)).max(0)
+}
+
+/// Most a member holding `credit` may send, their credit limit on top of the balance. Negative
+/// when they owe more than the credit limit.
+pub fn send_limit(payments_in: u64, credit: i64, unit: f64) -> i64 {
+    credit_limit(payments_in, unit).saturating_add(credit)
+}
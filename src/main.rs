@@ -15,27 +15,44 @@
 * along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+// Rocket's codegen still emits `#[allow(private_in_public)]`, which rustc reports as removed.
+#![allow(renamed_and_removed_lints)]
+
 #[macro_use] extern crate rocket;
 
-//use rocket::tokio::sync::Mutex;
-use std::sync::Mutex;
 use rocket::serde::Deserialize;
-use rocket::{figment, State};
-use simplets::Domain;
+use rocket::{figment, Data, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use simplets::{AsyncDomain, Capability, Domain, DomainConfig, ImportError, Limit, MessageRules, Probation, SimpletsError, Submitted, Timezone, UsernamePolicy};
 use rocket::outcome::IntoOutcome;
 use rocket::request::{self, FlashMessage, FromRequest, Request};
-use rocket::response::{Redirect, Flash};
-use rocket::http::{Cookie, CookieJar};
-use rocket::form::Form;
-use rocket::response::content::RawHtml;
-use rocket_dyn_templates::{Template, context};
+use rocket::response::{status, Redirect, Flash};
+use rocket::http::{ContentType, Cookie, CookieJar, Header, Status};
+use rocket::data::{self, FromData, ToByteUnit};
+use rocket::form::{self, Form, FromForm, FromFormField};
+use rocket::serde::{Serialize, json::Json};
+use rocket_dyn_templates::{Template, Metadata, context};
+use rocket_dyn_templates::handlebars::{html_escape, Context, Handlebars, Helper, HelperResult, Output, RenderContext};
+use rand::RngCore;
 use rusqlite::Error;
+use chrono::{Datelike, NaiveDate};
 
-pub type Domains = Mutex<Domain>;
+pub type Domains = AsyncDomain;
 
+/// Whether the `template_dir` config key is set.
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
-pub struct TemplateDir(bool);
+pub struct TemplateDir(pub bool);
+
+/// Key encrypting downloaded backups, the `backup_key` config key (64 hex digits).
+pub struct BackupKey(Option<[u8; 32]>);
+
+/// Base address of the site used in emailed links, the `public_url` config key.
+pub struct PublicUrl(String);
+
+/// The OpenID Connect provider members may log in through, the `oidc_issuer` config key.
+#[cfg(feature = "oidc")]
+struct Oidc(std::sync::Arc<simplets::oidc::Provider>);
 
 #[derive(FromForm)]
 struct Login<'r> {
@@ -43,6 +60,74 @@ struct Login<'r> {
     password: &'r str
 }
 
+/// Filters of the payment history on the index page, see `simplets::PaymentFilter`.
+#[derive(FromForm, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct History<'r> {
+    from: Option<&'r str>,
+    to: Option<&'r str>,
+    /// Account number or name.
+    counterparty: Option<&'r str>,
+    min: Option<&'r str>,
+    max: Option<&'r str>,
+    direction: Option<&'r str>,
+}
+
+#[derive(FromForm)]
+struct ProfileForm<'r> {
+    display_name: &'r str,
+    contact: &'r str,
+    bio: &'r str,
+    skills: &'r str,
+}
+
+#[derive(FromForm)]
+struct NewVoucher<'r> {
+    amount: &'r str,
+    days: u32,
+}
+
+#[derive(FromForm)]
+struct RedeemVoucher<'r> {
+    token: &'r str,
+}
+
+#[derive(FromForm)]
+struct Privacy {
+    privacy: i64,
+}
+
+#[derive(FromForm)]
+struct Categories<'r> {
+    /// One category per line.
+    categories: &'r str,
+}
+
+#[derive(FromForm)]
+struct TermsAcceptance {
+    version: u32,
+}
+
+#[derive(FromForm)]
+struct TermsForm<'r> {
+    text: &'r str,
+}
+
+#[derive(FromForm)]
+struct RoleForm<'r> {
+    role: &'r str,
+}
+
+#[derive(FromForm)]
+struct IdentityForm<'r> {
+    issuer: &'r str,
+}
+
+#[derive(FromForm)]
+struct TotpCode<'r> {
+    code: &'r str,
+}
+
 #[derive(FromForm)]
 struct Password<'r> {
     old: &'r str,
@@ -51,11 +136,367 @@ struct Password<'r> {
 
 #[derive(FromForm)]
 struct Payment<'r> {
+    payee: &'r str,
+    /// In whole units, see `Domain::parse_amount`, like the other amounts typed in forms.
+    amount: &'r str,
+    message: &'r str,
+    category: Option<&'r str>,
+    from: Option<i64>,
+    /// An open event the payment is made at.
+    event: Option<u64>,
+    /// Fresh for each rendering of the form, so a double submission pays once.
+    idempotency_key: Option<&'r str>,
+    /// Keeps the message for later payments, see `Domain::add_message_template`.
+    save_message: bool,
+}
+
+#[derive(FromForm)]
+struct GroupAccount<'r> {
+    name: &'r str,
+}
+
+#[derive(FromForm)]
+struct TrustlineForm<'r> {
+    partner: &'r str,
+    extra: &'r str,
+}
+
+#[derive(FromForm)]
+struct RemotePayment<'r> {
+    remote: &'r str,
     payee: i64,
-    amount: u64,
+    amount: &'r str,
+    message: &'r str,
+}
+
+#[derive(FromForm)]
+struct Peer<'r> {
+    name: &'r str,
+    url: &'r str,
+    secret: &'r str,
+}
+
+#[derive(FromForm)]
+struct ExchangeRate {
+    rate: f64,
+}
+
+#[derive(FromForm)]
+struct Manager<'r> {
+    manager: &'r str,
+}
+
+#[derive(FromForm)]
+struct SplitPayment<'r> {
+    payee: Vec<&'r str>,
+    amount: Vec<&'r str>,
+    message: &'r str,
+}
+
+#[derive(FromForm)]
+struct Settings<'r> {
+    description: &'r str,
+    join_info: &'r str,
+    minimal_amount: &'r str,
+}
+
+#[derive(FromForm)]
+struct CurrencyForm<'r> {
+    singular: &'r str,
+    plural: &'r str,
+    decimals: u32,
+    decimal_separator: &'r str,
+    thousands_separator: &'r str,
+    time: bool,
+}
+
+#[derive(FromForm)]
+struct SystemAccount<'r> {
+    name: &'r str,
+}
+
+#[derive(FromForm)]
+struct AnnouncementForm<'r> {
+    title: &'r str,
+    body: &'r str,
+}
+
+#[derive(FromForm)]
+struct Webhook<'r> {
+    url: &'r str,
+    secret: &'r str,
+    created: bool,
+    reversed: bool,
+}
+
+#[derive(FromForm)]
+struct Fees<'r> {
+    flat: &'r str,
+    percent: f64,
+    account: Option<i64>,
+}
+
+#[derive(FromForm)]
+struct LimitOverrideForm<'r> {
+    send_bonus: &'r str,
+    receive_bonus: &'r str,
+    expiry: Option<&'r str>,
+}
+
+#[derive(FromForm)]
+struct Freeze<'r> {
+    reason: &'r str,
+    incoming: bool,
+}
+
+#[derive(FromForm)]
+struct PaymentLimits<'r> {
+    maximal_amount: &'r str,
+    daily_limit: &'r str,
+}
+
+#[derive(FromForm)]
+struct LimitWindow {
+    months: u64,
+}
+
+#[derive(FromForm)]
+struct EscrowForm<'r> {
+    payee: &'r str,
+    amount: &'r str,
+    message: &'r str,
+}
+
+#[derive(FromForm)]
+struct Confirmation<'r> {
+    threshold: &'r str,
+    days: u64,
+}
+
+#[derive(FromForm)]
+struct ProbationForm {
+    days: u64,
+    payments: u64,
+    percent: u64,
+    email: bool,
+}
+
+#[derive(FromForm)]
+struct UsernamePolicyForm<'r> {
+    min_length: usize,
+    max_length: usize,
+    extra_chars: &'r str,
+    reserved: &'r str,
+}
+
+#[derive(FromForm)]
+struct MessageRulesForm<'r> {
+    max_length: usize,
+    banned_words: &'r str,
+    banned_patterns: &'r str,
+    strip_urls: bool,
+}
+
+#[derive(FromForm)]
+struct DemurrageAccount {
+    account: i64,
+}
+
+#[derive(FromForm)]
+struct Adjustment<'r> {
+    user: &'r str,
+    /// Negative to levy.
+    amount: &'r str,
+    reason: &'r str,
+}
+
+#[derive(FromForm)]
+struct Erasure<'r> {
+    password: &'r str,
+}
+
+#[derive(FromForm)]
+struct AccountErasure {
+    account: i64,
+}
+
+#[derive(FromForm)]
+struct BulkPayment<'r> {
+    payer: &'r str,
+    csv: &'r str,
+}
+
+#[derive(FromForm)]
+struct UserImport<'r> {
+    csv: &'r str,
+}
+
+#[derive(FromForm)]
+struct Listing<'r> {
+    kind: i64,
+    category: &'r str,
+    title: &'r str,
+    description: &'r str,
+}
+
+#[derive(FromForm)]
+struct Message<'r> {
+    recipient: &'r str,
+    body: &'r str,
+}
+
+#[derive(FromForm)]
+struct Notifications<'r> {
+    email: &'r str,
+    payment: bool,
+    request: bool,
+    password: bool,
+}
+
+#[derive(FromForm)]
+struct DormantAction<'r> {
+    months: u32,
+    /// `remind`, `freeze` or `decay`
+    action: &'r str,
+    percent: Option<u8>,
+}
+
+#[derive(FromForm)]
+struct NewEvent<'r> {
+    name: &'r str,
+}
+
+#[derive(FromForm)]
+struct BrandingForm<'r> {
+    theme: &'r str,
+    title: &'r str,
+    logo: &'r str,
+    primary_color: &'r str,
+    background_color: &'r str,
+}
+
+#[derive(FromForm)]
+struct Leaderboard {
+    enabled: bool,
+}
+
+#[derive(FromForm)]
+struct LeaderboardOptOut {
+    opt_out: bool,
+}
+
+#[derive(FromForm)]
+struct ResetRequest<'r> {
+    account: &'r str,
+}
+
+#[derive(FromForm)]
+struct NewPassword<'r> {
+    new: &'r str,
+}
+
+#[derive(FromForm)]
+struct PaymentRequest<'r> {
+    payer: i64,
+    amount: &'r str,
     message: &'r str,
 }
 
+#[derive(Responder)]
+#[response(content_type = "text/csv")]
+struct Csv(String, Header<'static>);
+
+#[derive(Responder)]
+#[response(content_type = "application/pdf")]
+struct Pdf(Vec<u8>, Header<'static>);
+
+#[derive(Responder)]
+#[response(content_type = "json")]
+struct JsonFile(String, Header<'static>);
+
+#[derive(Responder)]
+#[response(content_type = "application/octet-stream")]
+struct Download(Vec<u8>, Header<'static>);
+
+#[derive(Responder)]
+#[response(content_type = "image/svg+xml")]
+struct Svg(String);
+
+/// A payment attachment with the media type it was stored with.
+#[derive(Responder)]
+struct AttachmentFile(Vec<u8>, ContentType, Header<'static>);
+
+/// A file sent with a multipart form, read up to one byte over the attachment limit so that
+/// `Domain::attach_file` refuses larger ones.
+struct Upload {
+    name: String,
+    content: Vec<u8>,
+}
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for Upload {
+    async fn from_data(field: form::DataField<'v, '_>) -> form::Result<'v, Self> {
+        let name = field.file_name.map(|n| n.dangerous_unsafe_unsanitized_raw().as_str().to_string()).unwrap_or_default();
+        let content = field.data.open((simplets::validation::MAX_ATTACHMENT_SIZE + 1).bytes()).into_bytes().await?;
+        Ok(Upload { name, content: content.into_inner() })
+    }
+}
+
+#[derive(FromForm)]
+struct AttachmentUpload {
+    file: Upload,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Suggestion {
+    id: i64,
+    name: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Preview {
+    limit: i64,
+    /// `send` or `receive`, whose limit is the lower one.
+    limited_by: &'static str,
+    fee: u64,
+    payer_balance: i64,
+    payee_balance: i64,
+    /// Why the payment would be refused, in the user's language.
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Me {
+    id: i64,
+    name: String,
+    credit: i64,
+    send_limit: i64,
+    receive_limit: i64,
+    payments_in: u64,
+    payments_out: u64,
+    minimal_amount: u64,
+    currency: String,
+    /// Places of the amounts, which the API gives in the smallest units.
+    decimals: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ApiPayment {
+    payee: i64,
+    amount: u64,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    category: Option<String>,
+    /// Sent again with a retried request, so that it doesn't pay twice.
+    #[serde(default)]
+    idempotency_key: Option<String>,
+}
+
 #[derive(Debug)]
 struct User(i64);
 
@@ -64,131 +505,3424 @@ impl<'r> FromRequest<'r> for User {
     type Error = std::convert::Infallible;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<User, Self::Error> {
-        request.cookies()
-            .get_private("user_id")
-            .and_then(|cookie| cookie.value().parse().ok())
-            .map(User)
-            .or_forward(())
+        if let request::Outcome::Success(session) = request.guard::<UserSession>().await { return request::Outcome::Success(User(session.0.user)) }
+        request.guard::<ApiUser>().await.map(|u| User(u.0))
     }
 }
 
-#[post("/payment", data = "<payment>")]
-fn payment(user: User, domains: &State<Domains>, payment: Form<Payment<'_>>) -> Option<Flash<Redirect>> {
-    use simplets::Outcome::*;
-    if payment.message.len() > 140 { return Some(Flash::error(Redirect::to(uri!(index)), "Maximální délka zprávy je 140 znaků.")) }
-    let mut domain = domains.lock().unwrap();
-    let user = domain.get_user(user.0).expect("database error: {}");
-    let payee = match domain.get_user(payment.payee) {
-        Ok(u) => u,
-        Err(Error::QueryReturnedNoRows) => return Some(Flash::error(Redirect::to(uri!(index)), "Příjemce nexistuje")),
-        Err(e) => return Some(Flash::error(Redirect::to(uri!(index)), format!("Databázová chyba. Kontaktujte administrátora s podrobnostmi platby<br>{}", e)))
-    };
-    let flash = match domain.add_payment(user, payee, payment.amount, payment.message) {
-        Ok(_) => Flash::success(Redirect::to(uri!(index)), "Platba proběhla úspěšně."),
-        Err(Db(e)) => Flash::error(Redirect::to(uri!(index)), format!("Databázová chyba. Kontaktujte administrátora s podrobnostmi platby<br>{}", e)),
-        Err(PaymentSidesEq) => Flash::error(Redirect::to(uri!(index)), "Nelze poslat sám sobě"),
-        Err(PaymentLessMin(m)) => Flash::error(Redirect::to(uri!(index)), format!("Minimálně lze poslat {} kr.", m)),
-        Err(PaymentSendLimit(_)) => Flash::error(Redirect::to(uri!(index)), "Nedostatek prostředků na účtě"),
-        Err(PaymentReceiveLimit(l)) => Flash::error(Redirect::to(uri!(index)), format!("Příjemce nemůže přijmout více než {} kr.", l)),
-        _ => Flash::error(Redirect::to(uri!(index)), "Neznámá chyba. Kontaktujte administrátora s podrobnostmi platby")
-    };
-    Some(flash)
-}
+/// Live server-side session from the private `session` cookie.
+#[derive(Debug)]
+struct UserSession(simplets::Session);
 
-#[get("/payment")]
-fn no_auth_payment() -> Redirect {
-    Redirect::to(uri!(login_page))
-}
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserSession {
+    type Error = std::convert::Infallible;
 
-#[get("/")]
-fn index(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>) -> Template {
-    let domain = domains.lock().unwrap();
-    let user = domain.get_user(user.0).expect("database error: {}");
-    let payments = domain.get_payments_by_user(user.id).unwrap();
-    Template::render("session", context! {
-        user: &user,
-        receive_limit: user.receive_limit(),
-        send_limit: user.send_limit(),
-        payments,
-        flash: &flash,
-    })
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<UserSession, Self::Error> {
+        let session = request.local_cache_async(async {
+            let token = request.cookies().get_private("session")?.value().to_string();
+            let domains = match request.guard::<&State<Domains>>().await {
+                request::Outcome::Success(d) => d,
+                _ => return None
+            };
+            domains.run(move |d| d.authenticate_session(&token)).await.ok()
+        }).await;
+        session.clone().map(UserSession).or_forward(())
+    }
 }
 
-#[get("/", rank = 2)]
-fn no_auth_index() -> Redirect {
-    Redirect::to(uri!(login_page))
+/// The `User-Agent` header, remembered with sessions so users can tell their devices apart.
+struct UserAgent(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<UserAgent, Self::Error> {
+        request::Outcome::Success(UserAgent(request.headers().get_one("User-Agent").map(|a| a.chars().take(200).collect())))
+    }
 }
 
-#[get("/login")]
-fn login(_user: User) -> Redirect {
-    Redirect::to(uri!(index))
+/// Anti-CSRF token of the visitor, kept in the private `csrf` cookie and created on first use.
+/// Pages pass it to templates as `csrf`, where `{{csrf_field}}` embeds it in forms.
+struct CsrfToken(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<CsrfToken, Self::Error> {
+        let token = request.local_cache(|| match request.cookies().get_private("csrf") {
+            Some(cookie) => cookie.value().to_string(),
+            None => new_csrf_token(request.cookies()),
+        });
+        request::Outcome::Success(CsrfToken(token.clone()))
+    }
 }
 
-#[get("/login", rank = 2)]
-fn login_page(flash: Option<FlashMessage<'_>>) -> Template {
-    Template::render("login", &flash)
+fn new_csrf_token(jar: &CookieJar<'_>) -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    jar.add_private(Cookie::new("csrf", token.clone()));
+    token
 }
 
-#[post("/login", data = "<login>")]
-fn post_login(jar: &CookieJar<'_>, login: Form<Login<'_>>, domains: &State<Domains>) -> Result<Redirect, Flash<Redirect>> {
-    let domain = domains.lock().unwrap();
-    let user = if let Ok(u) = domain.get_user_by_name(login.username) { u }
-    else { return Err(Flash::error(Redirect::to(uri!(login_page)), "Špatné jméno/heslo.")) };
-    drop(domain);
-    let hash = simplets::hash(login.password);
-    if hash == user.password {
-        jar.add_private(Cookie::new("user_id", user.id.to_string()));
-        Ok(Redirect::to(uri!(index)))
-    } else {
-        Err(Flash::error(Redirect::to(uri!(login_page)), "Špatné jméno/heslo."))
+/// Form data guard that accepts the form only with a `csrf` field matching the visitor's
+/// token, otherwise the POST is rejected with 403.
+struct Csrf<T>(T);
+
+impl<T> std::ops::Deref for Csrf<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
     }
 }
 
-#[get("/logout")]
-fn logout(jar: &CookieJar<'_>) -> Flash<Redirect> {
-    jar.remove_private(Cookie::named("user_id"));
-    Flash::success(Redirect::to(uri!(login_page)), "Odhlášení proběhlo úspěšně.")
+/// Form wrapper that picks out the `csrf` field and passes the rest on to `T`.
+struct CsrfForm<'v, T> {
+    token: Option<&'v str>,
+    inner: T,
 }
 
-#[post("/password", data = "<password>")]
-fn password(user: User, domains: &State<Domains>, password: Form<Password<'_>>) -> Option<Flash<Redirect>> {
-    let domain = domains.lock().unwrap();
-    if simplets::hash(password.old) == domain.get_user(user.0).expect("database error: {}").password {
-        if domain.set_password(user.0, password.new).is_ok() {
-            Some(Flash::success(Redirect::to(uri!(index)), "Nové heslo nastaveno."))
-        } else { Some(Flash::error(Redirect::to(uri!(index)), "Chyba při změně hesla.")) }
-    } else { Some(Flash::error(Redirect::to(uri!(index)), "Původní heslo je neplatné.")) }
-}
+#[rocket::async_trait]
+impl<'v, T: FromForm<'v>> FromForm<'v> for CsrfForm<'v, T> {
+    type Context = (Option<&'v str>, T::Context);
 
-#[get("/password")]
-fn password_page(_user: User) -> RawHtml<&'static str> {
-    RawHtml(r#"<form action="/password" method="post" accept-charset="utf-8">
-         <label for="old">Původní heslo</label><br>
-         <input type="password" name="old" id="old" value="" required autofocus /><br>
-         <label for="new">Nové heslo</label><br>
-         <input type="password" name="new" id="new" value="" required /><br>
-         <p><input type="submit" value="Změnit heslo"></p>
-      </form>"#)
+    fn init(opts: form::Options) -> Self::Context {
+        (None, T::init(opts))
+    }
+
+    fn push_value(ctxt: &mut Self::Context, field: form::ValueField<'v>) {
+        if field.name == "csrf" { ctxt.0 = Some(field.value) } else { T::push_value(&mut ctxt.1, field) }
+    }
+
+    async fn push_data(ctxt: &mut Self::Context, field: form::DataField<'v, '_>) {
+        T::push_data(&mut ctxt.1, field).await
+    }
+
+    fn finalize(ctxt: Self::Context) -> form::Result<'v, Self> {
+        Ok(CsrfForm { token: ctxt.0, inner: T::finalize(ctxt.1)? })
+    }
 }
 
-#[get("/password", rank = 2)]
-fn no_auth_password() -> Redirect {
-    Redirect::to(uri!(login_page))
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r>> FromData<'r> for Csrf<T> {
+    type Error = form::Errors<'r>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let expected = request.cookies().get_private("csrf").map(|c| c.value().to_string());
+        match Form::<CsrfForm<'r, T>>::from_data(request, data).await {
+            data::Outcome::Success(form) => {
+                let form = form.into_inner();
+                match expected {
+                    Some(token) if form.token == Some(token.as_str()) => data::Outcome::Success(Csrf(form.inner)),
+                    _ => data::Outcome::Failure((Status::Forbidden, form::Errors::new())),
+                }
+            }
+            data::Outcome::Failure(e) => data::Outcome::Failure(e),
+            data::Outcome::Forward(d) => data::Outcome::Forward(d),
+        }
+    }
 }
 
-#[rocket::main]
-async fn main() -> Result<(), rocket::Error> {
-    let lets = Domain::new("lets", "", 10);
+/// Body of POST forms that have nothing but the CSRF token.
+struct NoFields;
 
-    //let rct = rocket::ignite()
-    let rct = rocket::build()
-        .attach(Template::fairing())
-        .manage(Mutex::new(lets))
-        //.mount("/", routes![no_auth_index])
-        .mount("/", routes![index, no_auth_index, login, login_page, post_login, logout, payment, no_auth_payment, password, no_auth_password, password_page]);
+#[rocket::async_trait]
+impl<'v> FromForm<'v> for NoFields {
+    type Context = ();
+
+    fn init(_: form::Options) -> Self::Context {}
+
+    fn push_value(_: &mut Self::Context, _: form::ValueField<'v>) {}
+
+    async fn push_data(_: &mut Self::Context, _: form::DataField<'v, '_>) {}
 
+    fn finalize(_: Self::Context) -> form::Result<'v, Self> {
+        Ok(NoFields)
+    }
+}
+
+fn csrf_field(_: &Helper, _: &Handlebars, ctx: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let token = ctx.data().get("csrf").and_then(|t| t.as_str()).unwrap_or("");
+    out.write(&format!("<input type=\"hidden\" name=\"csrf\" value=\"{}\" />", token))?;
+    Ok(())
+}
+
+fn idempotency_field(_: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    out.write(&format!("<input type=\"hidden\" name=\"idempotency_key\" value=\"{}\" />", hex::encode(bytes)))?;
+    Ok(())
+}
+
+/// The `currency_format` every page gets, see `page_context`.
+fn currency_format(ctx: &Context) -> simplets::CurrencyFormat {
+    ctx.data().get("currency_format").cloned().and_then(|f| serde_json::from_value(f).ok()).unwrap_or_default()
+}
+
+/// The amount given to a helper, nothing if it's missing or null.
+fn amount_param(h: &Helper) -> Option<i64> {
+    match h.param(0).map(|p| p.value()) {
+        Some(value) if value.is_number() => Some(value.as_i64().unwrap_or_else(|| value.as_f64().unwrap_or(0.0) as i64)),
+        _ => None,
+    }
+}
+
+/// `{{format_amount credit}}` writes an amount the way the page's domain does, see
+/// `Domain::format_amount`.
+fn format_amount(h: &Helper, _: &Handlebars, ctx: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    if let Some(amount) = amount_param(h) {
+        out.write(&html_escape(&currency_format(ctx).format(amount)))?;
+    }
+    Ok(())
+}
+
+/// `{{input_amount minimal_amount}}` writes an amount in whole units for a number input's value
+/// or bounds, `1.50`.
+fn input_amount(h: &Helper, _: &Handlebars, ctx: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    if let Some(amount) = amount_param(h) {
+        out.write(&currency_format(ctx).plain(amount))?;
+    }
+    Ok(())
+}
+
+/// `{{amount_step}}` writes the smallest amount for a number input's `step`, `0.01`.
+fn amount_step(_: &Helper, _: &Handlebars, ctx: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    out.write(&currency_format(ctx).plain(1))?;
+    Ok(())
+}
+
+/// `{{amount_type}}` writes the `type` of an amount input, `text` for `1:30` in time mode.
+fn amount_type(_: &Helper, _: &Handlebars, ctx: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    out.write(if currency_format(ctx).time { "text" } else { "number" })?;
+    Ok(())
+}
+
+/// `{{local created}}` writes a stored UTC time in the domain's timezone, nothing if it's null.
+fn local_time(timezone: Timezone, h: &Helper, out: &mut dyn Output) -> HelperResult {
+    if let Some(stored) = h.param(0).and_then(|p| p.value().as_str()) {
+        out.write(&timezone.render(stored))?;
+    }
+    Ok(())
+}
+
+/// Lockout keys of a login attempt, see `Domain::record_failed_login`. Attempts are
+/// counted both per client address and per attacked account.
+struct LoginThrottle {
+    ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LoginThrottle {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<LoginThrottle, Self::Error> {
+        request::Outcome::Success(LoginThrottle { ip: request.client_ip().map(|ip| format!("ip:{}", ip)) })
+    }
+}
+
+impl LoginThrottle {
+    fn keys(&self, user: &str) -> Vec<String> {
+        self.ip.iter().cloned().chain(std::iter::once(format!("user:{}", user))).collect()
+    }
+
+    async fn is_locked(&self, domains: &Domains, user: &str) -> bool {
+        let keys = self.keys(user);
+        domains.run(move |d| keys.iter().any(|k| d.is_locked(k).unwrap_or(false))).await
+    }
+
+    async fn failed(&self, domains: &Domains, user: &str) {
+        let keys = self.keys(user);
+        domains.run(move |d| for k in keys.iter() { d.record_failed_login(k).ok(); }).await
+    }
+
+    /// Only the account is cleared, one known password mustn't reset the address counter.
+    async fn succeeded(&self, domains: &Domains, user: &str) {
+        let key = format!("user:{}", user);
+        domains.run(move |d| d.clear_failed_logins(&key).ok()).await;
+    }
+}
+
+async fn start_session(jar: &CookieJar<'_>, domains: &Domains, user: i64, agent: UserAgent) -> Result<(), Error> {
+    let token = domains.run(move |d| d.create_session(user, agent.0.as_deref())).await?;
+    jar.add_private(Cookie::new("session", token));
+    new_csrf_token(jar);
+    Ok(())
+}
+
+/// Where a user goes once logged in: to the terms first if there are new ones to accept.
+async fn after_login(domains: &Domains, user: i64) -> Redirect {
+    if domains.run(move |d| d.needs_terms_acceptance(user)).await.unwrap_or(false) { Redirect::to(uri!(terms)) }
+    else { Redirect::to(uri!(index(_))) }
+}
+
+/// Logged in user whose role has the capability `Capability::from_index(C)`, see the aliases
+/// below. The `Domain` methods check again, this keeps the pages away.
+#[derive(Debug)]
+struct Capable<const C: u8>(i64);
+
+/// Changes the domain's settings.
+type Admin = Capable<{ Capability::ManageSettings as u8 }>;
+/// Sees all accounts and the reports.
+type Overseer = Capable<{ Capability::ViewAllBalances as u8 }>;
+type MemberManager = Capable<{ Capability::ManageMembers as u8 }>;
+type Treasurer = Capable<{ Capability::BookAdjustments as u8 }>;
+
+#[rocket::async_trait]
+impl<'r, const C: u8> FromRequest<'r> for Capable<C> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Capable<C>, Self::Error> {
+        let user = match request.guard::<User>().await {
+            request::Outcome::Success(u) => u.0,
+            _ => return request::Outcome::Forward(())
+        };
+        let domains = match request.guard::<&State<Domains>>().await {
+            request::Outcome::Success(d) => d,
+            _ => return request::Outcome::Forward(())
+        };
+        let capability = Capability::from_index(C).expect("capability index");
+        domains.run(move |d| d.can(user, capability)).await.unwrap_or(false)
+            .then_some(Capable(user))
+            .or_forward(())
+    }
+}
+
+/// Locale negotiated for the request by `LocaleFairing`.
+#[derive(Debug)]
+struct Locale(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Locale {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<&'r Locale, Self::Error> {
+        request::Outcome::Success(request.local_cache(|| Locale(String::new())))
+    }
+}
+
+/// Picks the request locale from the `lang` cookie or the `Accept-Language` header.
+struct LocaleFairing;
+
+#[rocket::async_trait]
+impl Fairing for LocaleFairing {
+    fn info(&self) -> Info {
+        Info { name: "Locale negotiation", kind: Kind::Request }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let chosen = request.cookies().get("lang").map(|c| c.value().to_string());
+        let accept_language = request.headers().get_one("Accept-Language");
+        let locale = match request.rocket().state::<Domains>() {
+            Some(domains) => domains.read().catalog.negotiate(chosen.as_deref(), accept_language),
+            None => return
+        };
+        request.local_cache(|| Locale(locale));
+    }
+}
+
+/// Tracing span of the request and when it started, set by `RequestLog`.
+struct RequestSpan(tracing::Span, std::time::Instant);
+
+/// Opens a `request` span for every request and logs its status and duration.
+#[derive(Default)]
+struct RequestLog {
+    next_id: std::sync::atomic::AtomicU64,
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info { name: "Request log", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = self.next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let span = tracing::info_span!("request", id, method = %request.method(), uri = %request.uri());
+        request.local_cache(|| RequestSpan(span, std::time::Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut rocket::Response<'r>) {
+        let RequestSpan(span, start) = request.local_cache(|| RequestSpan(tracing::Span::none(), std::time::Instant::now()));
+        span.in_scope(|| tracing::info!(status = response.status().code, elapsed_ms = start.elapsed().as_millis() as u64, "request handled"));
+    }
+}
+
+/// Drains the domain once shutdown is triggered: new payments are refused, running queries
+/// finish, the outbox is dispatched and the WAL checkpointed before Rocket exits.
+struct Drain;
+
+#[rocket::async_trait]
+impl Fairing for Drain {
+    fn info(&self) -> Info {
+        Info { name: "Domain drain", kind: Kind::Shutdown }
+    }
+
+    async fn on_shutdown(&self, rocket: &rocket::Rocket<rocket::Orbit>) {
+        let Some(domains) = rocket.state::<Domains>() else { return };
+        match domains.shutdown().await {
+            Ok(dispatched) => tracing::info!(dispatched, "domain drained"),
+            Err(e) => tracing::error!(error = %e, "draining the domain failed"),
+        }
+    }
+}
+
+/// Rocket's configuration (`Rocket.toml`, `ROCKET_` variables) overridden by `SIMPLETS_`
+/// variables, so a container needs no config file: `SIMPLETS_SMTP_HOST` sets `smtp_host`,
+/// `SIMPLETS_DOMAINS__LETS__DB_PATH` sets `domains.lets.db_path`. Any of them can be read from
+/// a file instead, e.g. `SIMPLETS_SMTP_PASSWORD_FILE=/run/secrets/smtp`, see
+/// `simplets::env_setting`.
+fn config_figment() -> figment::Figment {
+    let mut figment = rocket::Config::figment()
+        .merge(figment::providers::Env::prefixed(simplets::ENV_PREFIX).split("__").global());
+    for (key, _) in std::env::vars() {
+        let Some(key) = key.strip_prefix(simplets::ENV_PREFIX).and_then(|k| k.strip_suffix("_FILE")) else { continue };
+        if let Some(value) = simplets::env_setting(key) {
+            figment = figment.merge((key.to_lowercase().replace("__", "."), value));
+        }
+    }
+    figment
+}
+
+/// Installs the log subscriber: `RUST_LOG` filters (`info` by default), `log_format = "json"`
+/// in the config switches from human readable lines to one JSON object per event.
+fn init_logging(figment: &figment::Figment) {
+    use tracing_subscriber::EnvFilter;
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match figment.extract_inner::<String>("log_format").as_deref() {
+        Ok("json") => builder.json().try_init(),
+        _ => builder.try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("cannot install log subscriber: {}", e);
+    }
+}
+
+/// Returns the translated variant of the template (`<locale>/<name>`) if there is one.
+fn localized(metadata: &Metadata<'_>, locale: &Locale, name: &str) -> String {
+    let translated = format!("{}/{}", locale.0, name);
+    if metadata.contains_template(&translated) { translated } else { name.to_string() }
+}
+
+/// Template sets a domain can pick with the `theme` setting besides the classic one. A theme's
+/// pages are in its own directory next to the classic ones, pages it lacks come from the classic set.
+const THEMES: [&str; 1] = ["responsive"];
+
+/// Branding every page gets as `brand`, with the theme's defaults for what the domain left empty.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Brand<'a> {
+    name: &'a str,
+    logo: &'a str,
+    primary_color: &'a str,
+    background_color: &'a str,
+}
+
+/// The context a page is rendered with, the handler's `context`, `brand` and the
+/// `currency_format` of `{{format_amount}}`.
+fn page_context(domain: &Domain, context: impl Serialize) -> serde_json::Value {
+    let branding = &domain.branding;
+    fn or<'a>(value: &'a str, default: &'a str) -> &'a str { if value.is_empty() { default } else { value } }
+    let brand = Brand {
+        name: or(&branding.title, &domain.name),
+        logo: &branding.logo,
+        primary_color: or(&branding.primary_color, "#2e6b4f"),
+        background_color: or(&branding.background_color, "#fafaf7"),
+    };
+    let mut value = serde_json::to_value(context).unwrap_or_default();
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert("brand".to_string(), serde_json::to_value(brand).unwrap_or_default());
+        fields.insert("currency_format".to_string(), serde_json::to_value(&domain.currency).unwrap_or_default());
+        fields.insert("message_max_length".to_string(), domain.message_rules.max_length.into());
+    }
+    value
+}
+
+/// Renders page `name` in the domain's theme and the request's locale.
+fn themed(domain: &Domain, metadata: &Metadata<'_>, locale: &Locale, name: &str, context: impl Serialize) -> Template {
+    let theme = &domain.branding.theme;
+    let name = match localized(metadata, locale, &format!("{}/{}", theme, name)) {
+        themed if !theme.is_empty() && metadata.contains_template(&themed) => themed,
+        _ => localized(metadata, locale, name),
+    };
+    Template::render(name, page_context(domain, context))
+}
+
+/// A color the branding may put into a style sheet, `#` and hex digits or a color name.
+fn css_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => [3, 4, 6, 8].contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => !color.is_empty() && color.chars().all(|c| c.is_ascii_alphabetic()),
+    }
+}
+
+/// Domain name and body signature a peer server sends with its calls.
+struct PeerCall {
+    domain: String,
+    signature: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PeerCall {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<PeerCall, Self::Error> {
+        let headers = request.headers();
+        match (headers.get_one(simplets::federation::DOMAIN_HEADER), headers.get_one(simplets::webhook::SIGNATURE_HEADER)) {
+            (Some(domain), Some(signature)) => request::Outcome::Success(PeerCall { domain: domain.to_string(), signature: signature.to_string() }),
+            _ => request::Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// User authenticated by an `Authorization: Bearer <token>` header.
+#[derive(Debug)]
+struct ApiUser(i64);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiUser {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<ApiUser, Self::Error> {
+        let token = match request.headers().get_one("Authorization").and_then(|h| h.strip_prefix("Bearer ")) {
+            Some(t) => t,
+            None => return request::Outcome::Forward(())
+        };
+        let domains = match request.guard::<&State<Domains>>().await {
+            request::Outcome::Success(d) => d,
+            _ => return request::Outcome::Forward(())
+        };
+        domains.authenticate_token(token.trim()).await.ok()
+            .map(|u| ApiUser(u.id))
+            .or_forward(())
+    }
+}
+
+#[post("/payment", data = "<payment>")]
+async fn payment(user: User, domains: &State<Domains>, payment: Csrf<Payment<'_>>, locale: &Locale) -> Option<Flash<Redirect>> {
+    let payee = match payment.payee.trim().parse() {
+        Ok(number) => domains.get_user_by_number(number).await,
+        Err(_) => domains.get_user_by_name(payment.payee.trim()).await
+    };
+    let payee = match payee {
+        Ok(u) => u,
+        Err(Error::QueryReturnedNoRows) => return Some(Flash::error(Redirect::to(uri!(index(_))), domains.read().catalog.get(&locale.0, "payee-not-found"))),
+        Err(e) => return Some(Flash::error(Redirect::to(uri!(index(_))), domains.read().catalog.format(&locale.0, "db-error", &[&e])))
+    };
+    let amount = match domains.read().parse_amount(payment.amount) {
+        Some(amount) => amount,
+        None => return Some(Flash::error(Redirect::to(uri!(index(_))), domains.read().catalog.get(&locale.0, "amount-invalid")))
+    };
+    let category = payment.category.filter(|c| !c.is_empty());
+    let result = match (payment.from.filter(|from| *from != user.0), payment.event) {
+        (Some(account), event) => {
+            let (message, category) = (payment.message.to_string(), category.map(|c| c.to_string()));
+            domains.run(move |d| d.add_group_payment(user.0, account, payee.id, amount, &message, category.as_deref(), event)).await
+                .map(Submitted::Booked)
+        }
+        (None, Some(event)) => domains.add_event_payment(event, user.0, payee.id, amount, payment.message, category, payment.idempotency_key).await,
+        (None, None) => domains.add_payment(user.0, payee.id, amount, payment.message, category, payment.idempotency_key).await
+    };
+    if result.is_ok() && payment.save_message && !payment.message.trim().is_empty() {
+        let message = payment.message.to_string();
+        if let Err(e) = domains.run(move |d| d.add_message_template(user.0, &message)).await {
+            tracing::warn!(user = user.0, error = %e, "message template not saved");
+        }
+    }
+    let receipt = match result {
+        Ok(Submitted::Booked(id)) => domains.run(move |d| d.receipt_token(id, user.0)).await.ok().flatten().map(|token| uri!(receipt(&id, &token))),
+        _ => None,
+    };
+    let domain = domains.read();
+    let flash = match result {
+        Ok(Submitted::Booked(_)) => Flash::success(Redirect::to(receipt.unwrap_or_else(|| uri!(index(_)))), domain.catalog.get(&locale.0, "payment-success")),
+        Ok(Submitted::Pending(_)) => Flash::success(Redirect::to(uri!(index(_))), domain.catalog.get(&locale.0, "payment-pending")),
+        Err(e) => Flash::error(Redirect::to(uri!(index(_))), payment_error(&domain, locale, e)),
+    };
+    Some(flash)
+}
+
+fn payment_error(domain: &Domain, locale: &Locale, error: SimpletsError) -> String {
+    use simplets::SimpletsError::*;
+    let catalog = &domain.catalog;
+    let locale = &locale.0;
+    match error {
+        Db(e) => catalog.format(locale, "db-error", &[&e]),
+        PaymentSidesEq => catalog.get(locale, "payment-sides-eq").to_string(),
+        PaymentLessMin { minimum } => catalog.format(locale, "payment-less-min", &[&domain.format_amount(minimum as i64)]),
+        PaymentOverMax { maximum } => catalog.format(locale, "payment-over-max", &[&domain.format_amount(maximum as i64)]),
+        PaymentDailyLimit { remaining } => catalog.format(locale, "payment-daily-limit", &[&domain.format_amount(remaining as i64)]),
+        PaymentSendLimit { .. } => catalog.get(locale, "payment-send-limit").to_string(),
+        PaymentReceiveLimit { limit } => catalog.format(locale, "payment-receive-limit", &[&domain.format_amount(limit)]),
+        RequestNotFound => catalog.get(locale, "request-not-found").to_string(),
+        RequestClosed => catalog.get(locale, "request-closed").to_string(),
+        PendingNotFound => catalog.get(locale, "pending-not-found").to_string(),
+        PendingClosed => catalog.get(locale, "pending-closed").to_string(),
+        EscrowNotFound => catalog.get(locale, "escrow-not-found").to_string(),
+        EscrowClosed => catalog.get(locale, "escrow-closed").to_string(),
+        UserInactive => catalog.get(locale, "user-inactive").to_string(),
+        UsernameTaken => catalog.get(locale, "username-taken").to_string(),
+        UsernameInvalid => catalog.get(locale, "username-invalid").to_string(),
+        PasswordTooShort { minimum } => catalog.format(locale, "password-too-short", &[&minimum]),
+        PasswordWeak => catalog.get(locale, "password-weak").to_string(),
+        AccountFrozen => catalog.get(locale, "account-frozen").to_string(),
+        Blocked => catalog.get(locale, "payment-blocked").to_string(),
+        EventClosed => catalog.get(locale, "event-not-open").to_string(),
+        DemurrageNotConfigured => catalog.get(locale, "demurrage-not-configured").to_string(),
+        DemurrageRateInvalid => catalog.get(locale, "demurrage-rate-invalid").to_string(),
+        FeePercentInvalid => catalog.get(locale, "fee-percent-invalid").to_string(),
+        AdjustmentNotConfigured => catalog.get(locale, "adjustment-not-configured").to_string(),
+        NotPermitted(_) => catalog.get(locale, "not-permitted").to_string(),
+        ReadOnly => catalog.get(locale, "read-only").to_string(),
+        ListingNotFound => catalog.get(locale, "listing-not-found").to_string(),
+        ResetTokenInvalid => catalog.get(locale, "reset-token-invalid").to_string(),
+        EmailInvalid => catalog.get(locale, "email-invalid").to_string(),
+        EmailTokenInvalid => catalog.get(locale, "email-token-invalid").to_string(),
+        TotpInvalid => catalog.get(locale, "totp-invalid").to_string(),
+        CategoryInvalid => catalog.get(locale, "category-invalid").to_string(),
+        PrivacyDenied => catalog.get(locale, "privacy-denied").to_string(),
+        VoucherInvalid => catalog.get(locale, "voucher-invalid").to_string(),
+        VoucherRedeemed => catalog.get(locale, "voucher-redeemed").to_string(),
+        NotManager => catalog.get(locale, "not-manager").to_string(),
+        RemoteDomainUnknown => catalog.get(locale, "remote-domain-unknown").to_string(),
+        ExchangeRateInvalid => catalog.get(locale, "rate-invalid").to_string(),
+        VoucherExpired => catalog.get(locale, "voucher-expired").to_string(),
+        AmountOutOfRange => catalog.get(locale, "amount-out-of-range").to_string(),
+        ShuttingDown => catalog.get(locale, "shutting-down").to_string(),
+        MessageTooLong { maximum } => catalog.format(locale, "message-too-long", &[&maximum]),
+        MessageRefused => catalog.get(locale, "message-refused").to_string(),
+        AttachmentTooLarge { maximum } => catalog.format(locale, "attachment-too-large", &[&(maximum / 1024)]),
+        AttachmentType => catalog.get(locale, "attachment-type").to_string(),
+        AttachmentsDisabled => catalog.get(locale, "attachments-disabled").to_string(),
+        _ => catalog.get(locale, "unknown-error").to_string()
+    }
+}
+
+#[get("/payment")]
+fn no_auth_payment() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[get("/payment/split")]
+fn split(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "split", context! {
+        user_id: user.0,
+        flash: flash.map(|f| f.into_inner()).map(|(name, message)| context! { name, message }),
+        currency: &domain.currency.plural,
+        minimal_amount: domain.minimal_amount,
+        csrf: &csrf.0,
+    })
+}
+
+#[get("/payment/split", rank = 2)]
+fn no_auth_split() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/payment/split", data = "<split>")]
+async fn post_split(user: User, domains: &State<Domains>, split: Csrf<SplitPayment<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let shares: Option<Vec<(String, u64)>> = split.payee.iter().zip(split.amount.iter())
+        .filter(|(payee, _)| !payee.trim().is_empty())
+        .map(|(payee, amount)| domains.read().parse_amount(amount).map(|amount| (payee.trim().to_string(), amount)))
+        .collect();
+    let shares = shares.unwrap_or_default();
+    if shares.is_empty() || split.payee.len() != split.amount.len() {
+        return Flash::error(Redirect::to(uri!(split)), domains.read().catalog.get(&locale.0, "split-invalid"))
+    }
+    let count = shares.len();
+    let message = split.message.to_string();
+    let result = domains.run(move |d| {
+        let mut resolved = Vec::new();
+        for (i, (payee, amount)) in shares.into_iter().enumerate() {
+            let payee = find_user(d, &payee).map_err(|e| (Some(i), e.into()))?;
+            resolved.push((payee.id, amount));
+        }
+        d.add_split_payment(user.0, resolved, &message)
+    }).await;
+    let domain = domains.read();
+    let catalog = &domain.catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(index(_))), catalog.format(&locale.0, "split-success", &[&count])),
+        Err((None, e)) => Flash::error(Redirect::to(uri!(split)), payment_error(&domain, locale, e)),
+        Err((Some(i), e)) => {
+            let reason = match e {
+                SimpletsError::Db(Error::QueryReturnedNoRows) => catalog.get(&locale.0, "payee-not-found").to_string(),
+                e => payment_error(&domain, locale, e)
+            };
+            Flash::error(Redirect::to(uri!(split)), catalog.format(&locale.0, "split-line-error", &[&(i + 1), &reason]))
+        }
+    }
+}
+
+#[get("/requests")]
+async fn requests(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let id = user.0;
+    let (incoming, outgoing) = domains.run(move |d| (d.get_requests_incoming(id), d.get_requests_outgoing(id))).await;
+    let (incoming, outgoing) = (incoming.unwrap(), outgoing.unwrap());
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "requests", context! {
+        user_id: user.0,
+        minimal_amount: domain.minimal_amount,
+        incoming,
+        outgoing,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/requests", rank = 2)]
+fn no_auth_requests() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/requests", data = "<request>")]
+async fn create_request(user: User, domains: &State<Domains>, request: Csrf<PaymentRequest<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let amount = match domains.read().parse_amount(request.amount) {
+        Some(amount) => amount,
+        None => return Flash::error(Redirect::to(uri!(requests)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let (payer, message) = (request.payer, request.message.to_string());
+    let result = domains.run(move |d| d.create_request(user.0, payer, amount, &message)).await;
+    let domain = domains.read();
+    let catalog = &domain.catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(requests)), catalog.get(&locale.0, "request-sent")),
+        Err(simplets::SimpletsError::Db(Error::QueryReturnedNoRows)) => Flash::error(Redirect::to(uri!(requests)), catalog.get(&locale.0, "payer-not-found")),
+        Err(e) => Flash::error(Redirect::to(uri!(requests)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/requests/<id>/accept", data = "<_csrf>")]
+async fn accept_request(user: User, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.accept_request(id, user.0)).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(requests)), domain.catalog.get(&locale.0, "payment-success")),
+        Err(e) => Flash::error(Redirect::to(uri!(requests)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/requests/<id>/decline", data = "<_csrf>")]
+async fn decline_request(user: User, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.decline_request(id, user.0)).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(requests)), domain.catalog.get(&locale.0, "request-declined")),
+        Err(e) => Flash::error(Redirect::to(uri!(requests)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/pending")]
+async fn pending(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let id = user.0;
+    let (incoming, outgoing) = domains.run(move |d| (d.get_pending_incoming(id), d.get_pending_outgoing(id))).await;
+    let (incoming, outgoing) = (incoming.unwrap(), outgoing.unwrap());
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "pending", context! {
+        user_id: user.0,
+        currency: &domain.currency.plural,
+        incoming,
+        outgoing,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/pending", rank = 2)]
+fn no_auth_pending() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/pending/<id>/confirm", data = "<_csrf>")]
+async fn confirm_payment(user: User, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.confirm_payment(id, user.0)).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(pending)), domain.catalog.get(&locale.0, "payment-success")),
+        Err(e) => Flash::error(Redirect::to(uri!(pending)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/pending/<id>/reject", data = "<_csrf>")]
+async fn reject_payment(user: User, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.reject_payment(id, user.0)).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(pending)), domain.catalog.get(&locale.0, "pending-rejected")),
+        Err(e) => Flash::error(Redirect::to(uri!(pending)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/escrow")]
+async fn escrows(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let escrows = domains.run(move |d| d.get_escrows(user.0)).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "escrow", context! {
+        user_id: user.0,
+        minimal_amount: domain.minimal_amount,
+        currency: &domain.currency.plural,
+        escrows,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/escrow", rank = 2)]
+fn no_auth_escrows() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/escrow", data = "<form>")]
+async fn open_escrow(user: User, domains: &State<Domains>, form: Csrf<EscrowForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let amount = match domains.read().parse_amount(form.amount) {
+        Some(amount) => amount,
+        None => return Flash::error(Redirect::to(uri!(escrows)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let (payee, message) = (form.payee.to_string(), form.message.to_string());
+    let result = domains.run(move |d| {
+        let payee = find_user(d, &payee)?;
+        d.open_escrow(user.0, payee.id, amount, &message)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(escrows)), domain.catalog.get(&locale.0, "escrow-opened")),
+        Err(SimpletsError::Db(Error::QueryReturnedNoRows)) => Flash::error(Redirect::to(uri!(escrows)), domain.catalog.get(&locale.0, "payee-not-found")),
+        Err(e) => Flash::error(Redirect::to(uri!(escrows)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/escrow/<id>/<action>", data = "<_csrf>")]
+async fn settle_escrow(user: User, domains: &State<Domains>, id: u64, action: &str, _csrf: Csrf<NoFields>, locale: &Locale) -> Option<Flash<Redirect>> {
+    let (result, key) = match action {
+        "release" => (domains.run(move |d| d.release_escrow(id, user.0).map(|_| ())).await, "escrow-released"),
+        "cancel" => (domains.run(move |d| d.cancel_escrow(id, user.0)).await, "escrow-cancelled"),
+        "dispute" => (domains.run(move |d| d.dispute_escrow(id, user.0)).await, "escrow-disputed"),
+        _ => return None,
+    };
+    let domain = domains.read();
+    Some(match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(escrows)), domain.catalog.get(&locale.0, key)),
+        Err(e) => Flash::error(Redirect::to(uri!(escrows)), payment_error(&domain, locale, e)),
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/listings?<category>&<kind>")]
+async fn listings(user: User, domains: &State<Domains>, category: Option<&str>, kind: Option<i64>, flash: Option<FlashMessage<'_>>,
+                  csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let category = category.filter(|c| !c.is_empty()).map(|c| c.to_string());
+    let filter = category.clone();
+    let (listings, categories) = domains.run(move |d| (d.get_listings(filter.as_deref(), kind), d.get_listing_categories())).await;
+    let (listings, categories) = (listings.unwrap(), categories.unwrap());
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "listings", context! {
+        user_id: user.0,
+        listings,
+        categories,
+        category,
+        kind,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/listings", rank = 2)]
+fn no_auth_listings() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/listings", data = "<listing>")]
+async fn create_listing(user: User, domains: &State<Domains>, listing: Csrf<Listing<'_>>, locale: &Locale) -> Flash<Redirect> {
+    if let Some(e) = listing_error(&domains.read(), locale, &listing) { return Flash::error(Redirect::to(uri!(listings(_, _))), e) }
+    let (kind, category, title, description) = (listing.kind, listing.category.trim().to_string(), listing.title.trim().to_string(),
+                                                listing.description.to_string());
+    let result = domains.run(move |d| d.create_listing(user.0, kind, &category, &title, &description)).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(listings(_, _))), domain.catalog.get(&locale.0, "listing-posted")),
+        Err(e) => Flash::error(Redirect::to(uri!(listings(_, _))), payment_error(&domain, locale, e)),
+    }
+}
+
+fn listing_error(domain: &Domain, locale: &Locale, listing: &Listing) -> Option<String> {
+    if listing.title.trim().is_empty() || listing.category.trim().is_empty() || listing.title.len() > 140 {
+        Some(domain.catalog.get(&locale.0, "listing-invalid").to_string())
+    } else { None }
+}
+
+#[get("/listings/<id>")]
+async fn listing(user: User, domains: &State<Domains>, id: u64, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Option<Template> {
+    let listing = domains.run(move |d| d.get_listing(id)).await.ok()?;
+    let domain = domains.read();
+    Some(themed(&domain, &metadata, locale, "listing", context! {
+        user_id: user.0,
+        is_owner: listing.owner == user.0,
+        listing,
+        currency: &domain.currency.plural,
+        minimal_amount: domain.minimal_amount,
+        csrf: &csrf.0,
+        flash: &flash,
+    }))
+}
+
+#[post("/listings/<id>", data = "<listing>")]
+async fn update_listing(user: User, domains: &State<Domains>, id: u64, listing: Csrf<Listing<'_>>, locale: &Locale) -> Flash<Redirect> {
+    if let Some(e) = listing_error(&domains.read(), locale, &listing) { return Flash::error(Redirect::to(uri!(listing(&id))), e) }
+    let (category, title, description) = (listing.category.trim().to_string(), listing.title.trim().to_string(), listing.description.to_string());
+    let result = domains.run(move |d| d.update_listing(id, user.0, &category, &title, &description)).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(listing(&id))), domain.catalog.get(&locale.0, "listing-updated")),
+        Err(e) => Flash::error(Redirect::to(uri!(listing(&id))), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/listings/<id>/close", data = "<_csrf>")]
+async fn close_listing(user: User, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.close_listing(id, user.0)).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(listings(_, _))), domain.catalog.get(&locale.0, "listing-closed")),
+        Err(e) => Flash::error(Redirect::to(uri!(listing(&id))), payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/messages")]
+async fn messages(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let inbox = domains.run(move |d| d.get_inbox(user.0)).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "messages", context! {
+        user_id: user.0,
+        inbox,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/messages", rank = 2)]
+fn no_auth_messages() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[get("/messages/<other>")]
+async fn conversation(user: User, domains: &State<Domains>, other: i64, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Option<Template> {
+    let (other, messages) = domains.run(move |d| {
+        let other = d.get_user(other)?;
+        d.mark_conversation_read(user.0, other.id)?;
+        let messages = d.get_conversation(user.0, other.id)?;
+        Ok::<_, Error>((other, messages))
+    }).await.ok()?;
+    let domain = domains.read();
+    Some(themed(&domain, &metadata, locale, "conversation", context! {
+        user_id: user.0,
+        other_id: other.id,
+        other_name: other.name,
+        messages,
+        csrf: &csrf.0,
+        flash: &flash,
+    }))
+}
+
+#[post("/messages", data = "<message>")]
+async fn send_message(user: User, domains: &State<Domains>, message: Csrf<Message<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let key = message.recipient.to_string();
+    let recipient = domains.run(move |d| find_user(d, &key)).await;
+    let recipient = {
+        let catalog = &domains.read().catalog;
+        let recipient = match recipient {
+            Ok(u) => u,
+            Err(Error::QueryReturnedNoRows) => return Flash::error(Redirect::to(uri!(messages)), catalog.get(&locale.0, "recipient-not-found")),
+            Err(e) => return Flash::error(Redirect::to(uri!(messages)), catalog.format(&locale.0, "db-error", &[&e]))
+        };
+        if recipient.id == user.0 { return Flash::error(Redirect::to(uri!(messages)), catalog.get(&locale.0, "message-to-self")) }
+        if message.body.trim().is_empty() || message.body.len() > 2000 {
+            return Flash::error(Redirect::to(uri!(conversation(recipient.id))), catalog.get(&locale.0, "message-body-invalid"))
+        }
+        recipient.id
+    };
+    let body = message.body.trim().to_string();
+    let result = domains.run(move |d| d.send_message(user.0, recipient, &body)).await;
+    let back = Redirect::to(uri!(conversation(recipient)));
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(back, domain.catalog.get(&locale.0, "message-sent")),
+        Err(e) => Flash::error(back, payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/notifications")]
+async fn notifications(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let user = domains.get_user(user.0).await.expect("database error: {}");
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "notifications", context! {
+        user_id: user.id,
+        email: &user.email,
+        email_verified: user.email_verified,
+        payment: user.notify & simplets::NOTIFY_PAYMENT != 0,
+        request: user.notify & simplets::NOTIFY_REQUEST != 0,
+        password: user.notify & simplets::NOTIFY_PASSWORD != 0,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/notifications", rank = 2)]
+fn no_auth_notifications() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/notifications", data = "<settings>")]
+async fn post_notifications(user: User, domains: &State<Domains>, public_url: &State<PublicUrl>, settings: Csrf<Notifications<'_>>,
+                            locale: &Locale) -> Flash<Redirect> {
+    let email = settings.email.trim().to_string();
+    if let Err(e) = Some(email.as_str()).filter(|e| !e.is_empty()).map_or(Ok(()), simplets::validation::email) {
+        return Flash::error(Redirect::to(uri!(notifications)), payment_error(&domains.read(), locale, e))
+    }
+    let mut notify = 0;
+    if settings.payment { notify |= simplets::NOTIFY_PAYMENT }
+    if settings.request { notify |= simplets::NOTIFY_REQUEST }
+    if settings.password { notify |= simplets::NOTIFY_PASSWORD }
+    let base = public_url.0.clone();
+    let result = domains.run(move |d| -> Result<bool, SimpletsError> {
+        d.set_email(user.0, Some(email.as_str()).filter(|e| !e.is_empty()), notify)?;
+        // a new or still unverified address gets a fresh link
+        if email.is_empty() || d.get_user(user.0)?.email_verified { return Ok(false) }
+        d.start_email_verification(user.0, &email, &base)?;
+        Ok(true)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(true) => Flash::success(Redirect::to(uri!(notifications)), domain.catalog.get(&locale.0, "verify-email-sent")),
+        Ok(false) => Flash::success(Redirect::to(uri!(notifications)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(notifications)), payment_error(&domain, locale, e)),
+    }
+}
+
+/// Confirms the address from the link `Domain::start_email_verification` mailed, no login needed.
+#[get("/verify/<token>")]
+async fn verify_email(token: &str, domains: &State<Domains>, locale: &Locale) -> Flash<Redirect> {
+    let token = token.to_string();
+    let result = domains.run(move |d| {
+        let user = d.verify_email(&token)?;
+        d.audit(Some(user), "email.verify", Some(user), "")?;
+        Ok(user)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(index(_))), domain.catalog.get(&locale.0, "email-verified")),
+        Err(e) => Flash::error(Redirect::to(uri!(index(_))), payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/profile")]
+async fn profile(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let (profile, message_templates) = domains.run(move |d| (d.get_profile(user.0), d.get_message_templates(user.0))).await;
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "profile", context! {
+        user_id: user.0,
+        profile: profile.unwrap(),
+        message_templates: message_templates.unwrap(),
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[post("/message-templates/<id>/delete", data = "<_csrf>")]
+async fn delete_message_template(user: User, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.delete_message_template(id, user.0)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(profile)), catalog.get(&locale.0, "message-template-deleted")),
+        Err(e) => Flash::error(Redirect::to(uri!(profile)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+/// The most active traders of the month, if the domain shows them.
+#[get("/leaderboard")]
+async fn leaderboard(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>,
+                     locale: &Locale) -> Option<Template> {
+    if !domains.read().leaderboard { return None }
+    let period = domains.read().timezone.today().format("%Y-%m").to_string();
+    let month = period.clone();
+    let (traders, opt_out) = domains.run(move |d| (d.top_traders(&month, 10), d.leaderboard_opt_out(user.0))).await;
+    let domain = domains.read();
+    Some(themed(&domain, &metadata, locale, "leaderboard", context! {
+        user_id: user.0,
+        period,
+        traders: traders.unwrap(),
+        opt_out: opt_out.unwrap(),
+        csrf: &csrf.0,
+        flash: &flash,
+    }))
+}
+
+#[get("/leaderboard", rank = 2)]
+fn no_auth_leaderboard() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/leaderboard", data = "<form>")]
+async fn post_leaderboard(user: User, domains: &State<Domains>, form: Csrf<LeaderboardOptOut>, locale: &Locale) -> Flash<Redirect> {
+    let opt_out = form.opt_out;
+    let result = domains.run(move |d| d.set_leaderboard_opt_out(user.0, opt_out)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(leaderboard)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(leaderboard)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[get("/profile", rank = 2)]
+fn no_auth_profile() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/profile", data = "<profile>")]
+async fn post_profile(user: User, domains: &State<Domains>, profile: Csrf<ProfileForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let fields = [profile.display_name, profile.contact, profile.skills].map(|f| f.trim().to_string());
+    if fields.iter().any(|f| f.chars().count() > 140) || profile.bio.chars().count() > 2000 {
+        return Flash::error(Redirect::to(uri!(profile)), domains.read().catalog.get(&locale.0, "profile-too-long"))
+    }
+    let [display_name, contact, skills] = fields;
+    let bio = profile.bio.trim().to_string();
+    let result = domains.run(move |d| d.update_profile(user.0, &display_name, &contact, &bio, &skills)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(profile)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(profile)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+/// Everything stored about the user, see `Domain::export_user_data`.
+#[get("/profile/data")]
+async fn profile_data(user: User, domains: &State<Domains>) -> Option<JsonFile> {
+    let data = domains.run(move |d| {
+        let data = d.export_user_data(user.0)?;
+        d.audit(Some(user.0), "user.export", Some(user.0), "")?;
+        Ok::<_, Error>(data)
+    }).await.ok()?;
+    let filename = format!("attachment; filename=\"{}.json\"", user.0);
+    Some(JsonFile(serde_json::to_string_pretty(&data).unwrap_or_default(), Header::new("Content-Disposition", filename)))
+}
+
+#[get("/profile/data", rank = 2)]
+fn no_auth_profile_data() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+/// Erases the user's own account after they confirmed with their password and logs them out.
+#[post("/profile/erase", data = "<erasure>")]
+async fn erase_account(user: User, jar: &CookieJar<'_>, domains: &State<Domains>, erasure: Csrf<Erasure<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let password = simplets::hash(erasure.password);
+    let result = domains.run(move |d| {
+        if d.get_user(user.0)?.password != password { return Ok(None) }
+        let erased = d.anonymize_user(user.0)?;
+        d.audit(Some(user.0), "user.anonymize", Some(user.0), "")?;
+        Ok::<_, Error>(Some(erased))
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(Some(1)) => {
+            jar.remove_private(Cookie::named("session"));
+            Flash::success(Redirect::to(uri!(login_page)), catalog.get(&locale.0, "account-erased"))
+        }
+        Ok(Some(_)) => Flash::error(Redirect::to(uri!(profile)), catalog.get(&locale.0, "account-not-erasable")),
+        Ok(None) => Flash::error(Redirect::to(uri!(profile)), catalog.get(&locale.0, "password-old-invalid")),
+        Err(e) => Flash::error(Redirect::to(uri!(profile)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+/// Member directory. `q` searches names and offers, `sort` is `activity` (default), `name`
+/// or `recent` for the latest payment.
+#[get("/members?<q>&<sort>")]
+async fn members(user: User, domains: &State<Domains>, q: Option<&str>, sort: Option<&str>, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let mut members = domains.run(move |d| d.get_users_with_stats(Some(user.0))).await.unwrap();
+    let query = q.unwrap_or_default().trim().to_lowercase();
+    if !query.is_empty() {
+        members.retain(|m| [&m.name, &m.display_name, &m.skills].iter().any(|f| f.to_lowercase().contains(&query)));
+    }
+    match sort {
+        Some("name") => members.sort_by_key(|m| if m.display_name.is_empty() { m.name.to_lowercase() } else { m.display_name.to_lowercase() }),
+        Some("recent") => members.sort_by(|a, b| b.last_payment.cmp(&a.last_payment)),
+        _ => {}
+    }
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "members", context! {
+        user_id: user.0,
+        balances: members.iter().any(|m| m.credit.is_some() && m.id != user.0),
+        members,
+        q,
+        sort: sort.unwrap_or("activity"),
+    })
+}
+
+#[get("/members", rank = 2)]
+fn no_auth_members() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[get("/member/<id>")]
+async fn member(user: User, domains: &State<Domains>, id: i64, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>,
+                locale: &Locale) -> Option<Template> {
+    member_page(domains, Some(user.0), id, flash, Some(csrf), &metadata, locale).await
+}
+
+/// Profiles are only shown to visitors who aren't logged in when the domain's balances are public.
+#[get("/member/<id>", rank = 2)]
+async fn no_auth_member(domains: &State<Domains>, id: i64, metadata: Metadata<'_>, locale: &Locale) -> Result<Option<Template>, Redirect> {
+    if domains.read().privacy != simplets::PRIVACY_PUBLIC { return Err(Redirect::to(uri!(login_page))) }
+    Ok(member_page(domains, None, id, None, None, &metadata, locale).await)
+}
+
+async fn member_page(domains: &Domains, viewer: Option<i64>, id: i64, flash: Option<FlashMessage<'_>>, csrf: Option<CsrfToken>,
+                     metadata: &Metadata<'_>, locale: &Locale) -> Option<Template> {
+    let (profile, credit, payments, (contact, blocked)) = domains.run(move |d| {
+        let profile = d.get_profile(id)?;
+        let credit = match d.get_balance_for(viewer, id) {
+            Ok(c) => Some(c),
+            Err(SimpletsError::PrivacyDenied) => None,
+            Err(e) => return Err(e)
+        };
+        let mut payments = match credit {
+            Some(_) => d.get_payments_for(viewer, id, &simplets::PaymentFilter::default())?,
+            None => Vec::new()
+        };
+        payments.truncate(20);
+        let relation = match viewer {
+            Some(viewer) => (d.is_contact(viewer, id)?, d.is_blocked(viewer, id)?),
+            None => (false, false)
+        };
+        Ok((profile, credit, payments, relation))
+    }).await.ok()?;
+    let domain = domains.read();
+    Some(themed(&domain, metadata, locale, "member", context! {
+        user_id: viewer,
+        own: viewer == Some(id),
+        contact,
+        blocked,
+        profile,
+        finances: credit.is_some(),
+        credit,
+        payments,
+        currency: &domain.currency.plural,
+        csrf: csrf.map(|c| c.0),
+        flash: &flash,
+    }))
+}
+
+#[post("/contacts/<id>", data = "<_csrf>")]
+async fn add_contact(user: User, domains: &State<Domains>, id: i64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.add_contact(user.0, id)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(member(&id))), catalog.get(&locale.0, "contact-added")),
+        Err(e) => Flash::error(Redirect::to(uri!(member(&id))), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/contacts/<id>/delete", data = "<_csrf>")]
+async fn remove_contact(user: User, domains: &State<Domains>, id: i64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.remove_contact(user.0, id)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(member(&id))), catalog.get(&locale.0, "contact-removed")),
+        Err(e) => Flash::error(Redirect::to(uri!(member(&id))), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/blocks/<id>", data = "<_csrf>")]
+async fn block_user(user: User, domains: &State<Domains>, id: i64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.block_user(user.0, id)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(member(&id))), catalog.get(&locale.0, "user-blocked")),
+        Err(e) => Flash::error(Redirect::to(uri!(member(&id))), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/blocks/<id>/delete", data = "<_csrf>")]
+async fn unblock_user(user: User, domains: &State<Domains>, id: i64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.unblock_user(user.0, id)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(member(&id))), catalog.get(&locale.0, "user-unblocked")),
+        Err(e) => Flash::error(Redirect::to(uri!(member(&id))), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+/// QR code of a payment link. Scanning it opens `/pay` with the payment prefilled.
+#[get("/qr/<payee>?<amount>&<message>")]
+async fn qr(domains: &State<Domains>, public_url: &State<PublicUrl>, payee: i64, amount: Option<&str>, message: Option<&str>) -> Option<Svg> {
+    let amount = amount.filter(|a| !a.trim().is_empty()).and_then(|a| domains.read().parse_amount(a));
+    let message = message.filter(|m| !m.is_empty()).map(|m| m.to_string());
+    let uri = domains.run(move |d| d.get_user(payee).map(|_| d.payment_uri(payee, amount, message.as_deref()))).await.ok()?;
+    let link = format!("{}/pay?uri={}", public_url.0.trim_end_matches('/'), simplets::percent_encode(&uri));
+    let code = qrcode::QrCode::new(link).ok()?;
+    Some(Svg(code.render::<qrcode::render::svg::Color>().min_dimensions(200, 200).build()))
+}
+
+#[get("/pay?<uri>")]
+async fn pay(user: User, domains: &State<Domains>, uri: &str, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Result<Template, Flash<Redirect>> {
+    let parsed = simplets::PaymentUri::parse(uri).filter(|p| p.domain.as_ref().is_none_or(|d| *d == domains.read().name));
+    let parsed = match parsed {
+        Some(p) => p,
+        None => return Err(Flash::error(Redirect::to(uri!(index(_))), domains.read().catalog.get(&locale.0, "payment-uri-invalid")))
+    };
+    let payee = parsed.payee;
+    let payee = match domains.get_user(payee).await {
+        Ok(u) => u,
+        Err(_) => return Err(Flash::error(Redirect::to(uri!(index(_))), domains.read().catalog.get(&locale.0, "payee-not-found")))
+    };
+    let domain = domains.read();
+    Ok(themed(&domain, &metadata, locale, "pay", context! {
+        user_id: user.0,
+        payee: payee.id,
+        payee_name: &payee.name,
+        amount: parsed.amount,
+        message: parsed.message,
+        currency: &domain.currency.plural,
+        minimal_amount: domain.minimal_amount,
+        csrf: &csrf.0,
+    }))
+}
+
+#[get("/pay", rank = 2)]
+fn no_auth_pay() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+/// What the domain is and how to join, for anyone thinking about it.
+#[get("/about")]
+async fn about(domains: &State<Domains>, metadata: Metadata<'_>, locale: &Locale) -> Option<Template> {
+    let (stats, terms) = domains.run(|d| (d.public_stats(), d.current_terms())).await;
+    let (stats, terms) = (stats.ok()?, terms.ok()?.is_some());
+    let domain = domains.read();
+    Some(themed(&domain, &metadata, locale, "about", context! { stats, terms }))
+}
+
+/// Proof of a payment for anyone with its link, no login needed.
+#[get("/receipt/<id>/<token>")]
+async fn receipt(domains: &State<Domains>, public_url: &State<PublicUrl>, id: u64, token: &str, flash: Option<FlashMessage<'_>>,
+                 metadata: Metadata<'_>, locale: &Locale) -> Option<Template> {
+    let link = format!("{}/receipt/{}/{}", public_url.0.trim_end_matches('/'), id, token);
+    let token = token.to_string();
+    let receipt = domains.run(move |d| d.get_receipt(id, &token)).await.ok()??;
+    let domain = domains.read();
+    Some(themed(&domain, &metadata, locale, "receipt", context! {
+        receipt,
+        link,
+        flash: &flash,
+    }))
+}
+
+/// The files attached to a payment, with a form to attach more.
+#[get("/payment/<id>/attachments")]
+async fn attachments(user: User, domains: &State<Domains>, id: u64, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>,
+                     locale: &Locale) -> Option<Template> {
+    let attachments = domains.run(move |d| d.get_attachments(user.0, id)).await.ok()?;
+    let domain = domains.read();
+    Some(themed(&domain, &metadata, locale, "attachments", context! {
+        payment: id,
+        attachments,
+        enabled: domain.attachment_dir.is_some(),
+        max_size: simplets::validation::MAX_ATTACHMENT_SIZE / 1024,
+        csrf: &csrf.0,
+        flash: &flash,
+    }))
+}
+
+#[get("/payment/<_id>/attachments", rank = 2)]
+fn no_auth_attachments(_id: u64) -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/payment/<id>/attachments", data = "<upload>")]
+async fn attach_file(user: User, domains: &State<Domains>, id: u64, upload: Csrf<AttachmentUpload>, locale: &Locale) -> Flash<Redirect> {
+    let file = upload.0.file;
+    let result = domains.run(move |d| d.attach_file(id, user.0, &file.name, &file.content)).await;
+    let domain = domains.read();
+    let back = Redirect::to(uri!(attachments(&id)));
+    match result {
+        Ok(_) => Flash::success(back, domain.catalog.get(&locale.0, "attachment-added")),
+        Err(SimpletsError::Db(Error::QueryReturnedNoRows)) => Flash::error(Redirect::to(uri!(index(_))), domain.catalog.get(&locale.0, "payment-not-found")),
+        Err(e) => Flash::error(back, payment_error(&domain, locale, e)),
+    }
+}
+
+/// An attached file, photos shown in the browser and anything else downloaded.
+#[get("/attachment/<id>")]
+async fn attachment(user: User, domains: &State<Domains>, id: u64) -> Option<AttachmentFile> {
+    let (attachment, content) = domains.run(move |d| d.read_attachment(user.0, id)).await.ok()?;
+    let disposition = if attachment.media_type.starts_with("image/") { "inline" } else { "attachment" };
+    let content_type = ContentType::parse_flexible(&attachment.media_type).unwrap_or(ContentType::Binary);
+    Some(AttachmentFile(content, content_type, Header::new("Content-Disposition",
+        format!("{}; filename*=UTF-8''{}", disposition, simplets::percent_encode(&attachment.name)))))
+}
+
+/// The receipt of the user's own payment, linked from the history.
+#[get("/receipt/<id>")]
+async fn my_receipt(user: User, domains: &State<Domains>, id: u64) -> Option<Redirect> {
+    let token = domains.run(move |d| d.receipt_token(id, user.0)).await.ok()??;
+    Some(Redirect::to(uri!(receipt(&id, &token))))
+}
+
+#[get("/groups")]
+async fn groups(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let groups = domains.run(move |d| -> Result<Vec<_>, Error> {
+        let mut groups = Vec::new();
+        for account in d.get_managed_accounts(user.0)? {
+            let managers = d.get_managers(account.id)?;
+            groups.push(context! { account, managers });
+        }
+        Ok(groups)
+    }).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "groups", context! {
+        user_id: user.0,
+        groups,
+        currency: &domain.currency.plural,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/groups", rank = 2)]
+fn no_auth_groups() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/groups", data = "<group>")]
+async fn create_group(user: User, domains: &State<Domains>, group: Csrf<GroupAccount<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let name = group.name.trim().to_string();
+    if name.is_empty() { return Flash::error(Redirect::to(uri!(groups)), domains.read().catalog.get(&locale.0, "group-name-empty")) }
+    let result = domains.run(move |d| {
+        let id = d.create_group_account(&name, user.0)?;
+        d.audit(Some(user.0), "group.create", Some(id as i64), &name)
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(groups)), catalog.get(&locale.0, "group-created")),
+        Err(e) => Flash::error(Redirect::to(uri!(groups)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/groups/<account>/managers", data = "<manager>")]
+async fn add_manager(user: User, domains: &State<Domains>, account: i64, manager: Csrf<Manager<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let key = manager.manager.trim().to_string();
+    let result = domains.run(move |d| {
+        if !d.get_managers(account)?.iter().any(|m| m.id == user.0) { return Err(SimpletsError::NotManager) }
+        let manager = find_user(d, &key)?;
+        if manager.account_type != simplets::ACCOUNT_MEMBER { return Err(Error::QueryReturnedNoRows.into()) }
+        d.add_manager(account, manager.id)?;
+        d.audit(Some(user.0), "group.manager.add", Some(account), &manager.id.to_string())?;
+        Ok(())
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(groups)), domain.catalog.get(&locale.0, "manager-added")),
+        Err(SimpletsError::Db(Error::QueryReturnedNoRows)) => Flash::error(Redirect::to(uri!(groups)), domain.catalog.get(&locale.0, "user-not-found")),
+        Err(e) => Flash::error(Redirect::to(uri!(groups)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/groups/<account>/managers/<manager>/delete", data = "<_csrf>")]
+async fn remove_manager(user: User, domains: &State<Domains>, account: i64, manager: i64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| {
+        if !d.get_managers(account)?.iter().any(|m| m.id == user.0) { return Err(SimpletsError::NotManager) }
+        if d.remove_manager(account, manager)? == 0 { return Ok(false) }
+        d.audit(Some(user.0), "group.manager.remove", Some(account), &manager.to_string())?;
+        Ok(true)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(true) => Flash::success(Redirect::to(uri!(groups)), domain.catalog.get(&locale.0, "manager-removed")),
+        Ok(false) => Flash::error(Redirect::to(uri!(groups)), domain.catalog.get(&locale.0, "manager-last")),
+        Err(e) => Flash::error(Redirect::to(uri!(groups)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/trustlines")]
+async fn trustlines(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let trustlines = domains.run(move |d| d.get_trustlines(user.0)).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "trustlines", context! {
+        user_id: user.0,
+        trustlines,
+        currency: &domain.currency.plural,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/trustlines", rank = 2)]
+fn no_auth_trustlines() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/trustlines", data = "<trustline>")]
+async fn set_trustline(user: User, domains: &State<Domains>, trustline: Csrf<TrustlineForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let extra = match domains.read().parse_amount(trustline.extra) {
+        Some(extra) => extra,
+        None => return Flash::error(Redirect::to(uri!(trustlines)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let key = trustline.partner.trim().to_string();
+    let result = domains.run(move |d| {
+        let partner = find_user(d, &key)?;
+        if partner.id == user.0 { return Err(SimpletsError::PaymentSidesEq) }
+        d.set_trustline(user.0, partner.id, extra)?;
+        d.audit(Some(user.0), "trustline.set", Some(partner.id), &extra.to_string())?;
+        Ok(())
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(trustlines)), domain.catalog.get(&locale.0, "trustline-saved")),
+        Err(SimpletsError::Db(Error::QueryReturnedNoRows)) => Flash::error(Redirect::to(uri!(trustlines)), domain.catalog.get(&locale.0, "user-not-found")),
+        Err(e) => Flash::error(Redirect::to(uri!(trustlines)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/api/federation/transfers", data = "<body>")]
+async fn federation_transfer(peer: PeerCall, domains: &State<Domains>, body: String) -> Result<Json<simplets::Payment>, status::Custom<Json<SimpletsError>>> {
+    domains.run(move |d| d.receive_transfer(&peer.domain, &peer.signature, &body)).await.map(Json).map_err(api_error)
+}
+
+#[post("/api/federation/balance", data = "<body>")]
+async fn federation_balance(peer: PeerCall, domains: &State<Domains>, body: String) -> Result<Json<simplets::BridgePosition>, status::Custom<Json<SimpletsError>>> {
+    domains.run(move |d| {
+        let remote = d.verify_peer(&peer.domain, &peer.signature, &body)?;
+        Ok::<_, SimpletsError>(d.bridge_position(&remote)?)
+    }).await.map(Json).map_err(api_error)
+}
+
+/// Posts the queued cross-domain transfers without waiting for the next round of the
+/// delivery task.
+fn deliver_transfers(domains: &Domains) {
+    #[cfg(feature = "federation")]
+    {
+        let domains = domains.clone();
+        rocket::tokio::spawn(async move {
+            if let Err(e) = domains.run(|d| simplets::federation::Client::default().deliver(d, 10)).await {
+                tracing::error!(error = %e, "federation delivery failed");
+            }
+        });
+    }
+    #[cfg(not(feature = "federation"))]
+    let _ = domains;
+}
+
+#[get("/federation")]
+async fn federation(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let (remotes, transfers) = domains.run(move |d| (d.get_remote_domains(), d.get_federated_transfers(user.0))).await;
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "federation", context! {
+        user_id: user.0,
+        remotes: remotes.unwrap(),
+        transfers: transfers.unwrap(),
+        currency: &domain.currency.plural,
+        minimal_amount: domain.minimal_amount,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/federation", rank = 2)]
+fn no_auth_federation() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/federation", data = "<payment>")]
+async fn post_federation(user: User, domains: &State<Domains>, payment: Csrf<RemotePayment<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let amount = match domains.read().parse_amount(payment.amount) {
+        Some(amount) => amount,
+        None => return Flash::error(Redirect::to(uri!(federation)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let (remote, payee, message) = (payment.remote.to_string(), payment.payee, payment.message.to_string());
+    let result = domains.run(move |d| d.add_cross_domain_payment(user.0, &remote, payee, amount, &message)).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => {
+            deliver_transfers(domains);
+            Flash::success(Redirect::to(uri!(federation)), domain.catalog.get(&locale.0, "federation-sent"))
+        }
+        Err(e) => Flash::error(Redirect::to(uri!(federation)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/vouchers")]
+async fn vouchers(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let vouchers = domains.run(move |d| d.get_vouchers(user.0)).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "vouchers", context! {
+        user_id: user.0,
+        vouchers,
+        currency: &domain.currency.plural,
+        minimal_amount: domain.minimal_amount,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/vouchers", rank = 2)]
+fn no_auth_vouchers() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/vouchers", data = "<voucher>")]
+async fn issue_voucher(user: User, domains: &State<Domains>, voucher: Csrf<NewVoucher<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let amount = match domains.read().parse_amount(voucher.amount) {
+        Some(amount) => amount,
+        None => return Flash::error(Redirect::to(uri!(vouchers)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let days = voucher.days.clamp(1, 365);
+    let expiry = domains.read().timezone.now() + chrono::Duration::days(days as i64);
+    let result = domains.run(move |d| d.issue_voucher(user.0, amount, expiry)).await;
+    let domain = domains.read();
+    match result {
+        Ok(token) => Flash::success(Redirect::to(uri!(vouchers)), domain.catalog.format(&locale.0, "voucher-issued", &[&token])),
+        Err(e) => Flash::error(Redirect::to(uri!(vouchers)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/vouchers/redeem", data = "<voucher>")]
+async fn redeem_voucher(user: User, domains: &State<Domains>, voucher: Csrf<RedeemVoucher<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let token = voucher.token.to_string();
+    let result = domains.run(move |d| d.redeem_voucher(&token, user.0)).await;
+    let domain = domains.read();
+    match result {
+        Ok(amount) => Flash::success(Redirect::to(uri!(vouchers)), domain.catalog.format(&locale.0, "voucher-paid", &[&domain.format_amount(amount as i64)])),
+        Err(e) => Flash::error(Redirect::to(uri!(vouchers)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/vouchers/<id>/cancel", data = "<_csrf>")]
+async fn cancel_voucher(user: User, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| d.cancel_voucher(id, user.0)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(vouchers)), catalog.get(&locale.0, "voucher-cancelled")),
+        Err(e) => Flash::error(Redirect::to(uri!(vouchers)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[get("/?<history..>")]
+async fn index(user: User, domains: &State<Domains>, history: Option<History<'_>>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken,
+               metadata: Metadata<'_>, locale: &Locale) -> Result<Template, Redirect> {
+    let id = user.0;
+    // sessions from before new terms were published meet them here
+    if domains.run(move |d| d.needs_terms_acceptance(id)).await.unwrap_or(false) { return Err(Redirect::to(uri!(terms))) }
+    let user = domains.get_user(user.0).await.expect("database error: {}");
+    let date = |d: Option<&str>| d.filter(|d| !d.is_empty()).and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+    let mut filter = simplets::PaymentFilter::default();
+    let mut counterparty = None;
+    if let Some(h) = &history {
+        filter.from = date(h.from);
+        filter.to = date(h.to);
+        let amount = |a: Option<&str>| a.filter(|a| !a.trim().is_empty()).and_then(|a| domains.read().parse_amount(a));
+        filter.min_amount = amount(h.min);
+        filter.max_amount = amount(h.max);
+        filter.direction = match h.direction {
+            Some("in") => Some(simplets::Direction::Incoming),
+            Some("out") => Some(simplets::Direction::Outgoing),
+            _ => None
+        };
+        counterparty = h.counterparty.map(str::trim).filter(|c| !c.is_empty()).map(|c| c.to_string());
+    }
+    let id = user.id;
+    let payments = domains.run(move |d| {
+        if let Some(key) = counterparty {
+            match find_user(d, &key) {
+                Ok(u) => filter.counterparty = Some(u.id),
+                Err(Error::QueryReturnedNoRows) => return Ok(Vec::new()),
+                Err(e) => return Err(e)
+            }
+        }
+        d.get_payments_by_user(id, &filter)
+    }).await.unwrap();
+    let (unread, categories, groups, limits, freeze, events) = domains.run(move |d| (d.get_unread_count(id), d.get_categories(),
+        d.get_managed_accounts(id), d.get_limits(id), d.get_freeze(id), d.get_open_events())).await;
+    let (unread, categories, groups, (send_limit, receive_limit), freeze, events) = (unread.unwrap(), categories.unwrap(), groups.unwrap(),
+        limits.unwrap(), freeze.unwrap(), events.unwrap());
+    let (recent_payees, message_templates, contacts, announcements, administration, attachments) = domains.run(move |d| (d.recent_payees(id, 5),
+        d.get_message_templates(id), d.get_contacts(id), d.recent_announcements(3), d.can(id, Capability::ViewAllBalances),
+        d.get_attachments_by_user(id))).await;
+    let (recent_payees, message_templates, contacts, announcements, administration, attachments) = (recent_payees.unwrap(),
+        message_templates.unwrap(), contacts.unwrap(), announcements.unwrap(), administration.unwrap(), attachments.unwrap());
+    let domain = domains.read();
+    Ok(themed(&domain, &metadata, locale, "session", context! {
+        unread,
+        freeze,
+        events,
+        announcements,
+        user: &user,
+        receive_limit,
+        send_limit,
+        administration,
+        leaderboard: domain.leaderboard,
+        currency: &domain.currency.plural,
+        minimal_amount: domain.minimal_amount,
+        fee_flat: domain.fee_flat,
+        fee_percent: domain.fee_percent,
+        fee_enabled: domain.fee_account.is_some(),
+        payments,
+        attachments,
+        categories,
+        groups,
+        recent_payees,
+        message_templates,
+        contacts,
+        history,
+        csrf: &csrf.0,
+        flash: &flash,
+    }))
+}
+
+#[get("/activity")]
+async fn activity(user: User, domains: &State<Domains>, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let stats = domains.run(move |d| d.user_stats(user.0)).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "activity", context! {
+        user_id: user.0,
+        currency: &domain.currency.plural,
+        stats,
+    })
+}
+
+#[get("/activity", rank = 2)]
+fn no_auth_activity() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[get("/statement.csv?<from>&<to>")]
+async fn statement(user: User, domains: &State<Domains>, from: Option<&str>, to: Option<&str>) -> Option<Csv> {
+    let from = from.filter(|d| !d.is_empty()).and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+    let to = to.filter(|d| !d.is_empty()).and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+    let csv = domains.run(move |d| d.export_payments_csv(user.0, from, to)).await.ok()?;
+    Some(Csv(csv, Header::new("Content-Disposition", "attachment; filename=\"statement.csv\"")))
+}
+
+#[get("/statement.csv", rank = 2)]
+fn no_auth_statement() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+/// Printable statement, by default of the current month.
+#[get("/statement.pdf?<from>&<to>")]
+async fn statement_pdf(user: User, domains: &State<Domains>, locale: &Locale, from: Option<&str>, to: Option<&str>) -> Option<Pdf> {
+    let today = domains.read().timezone.today();
+    let from = from.and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok()).unwrap_or_else(|| today.with_day(1).unwrap_or(today));
+    let to = to.and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok()).unwrap_or(today);
+    let locale = locale.0.clone();
+    let pdf = domains.run(move |d| d.render_statement_pdf(user.0, from, to, &locale)).await.ok()?;
+    Some(Pdf(pdf, Header::new("Content-Disposition", format!("attachment; filename=\"statement-{}.pdf\"", from.format("%Y-%m")))))
+}
+
+#[get("/statement.pdf", rank = 2)]
+fn no_auth_statement_pdf() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[get("/users/suggest?<q>")]
+async fn suggest_users(user: User, domains: &State<Domains>, q: &str) -> Option<Json<Vec<Suggestion>>> {
+    if q.is_empty() { return Some(Json(Vec::new())) }
+    let q = q.to_string();
+    let users = domains.run(move |d| d.find_users_by_prefix(&q, Some(user.0), 10)).await.ok()?;
+    Some(Json(users.into_iter().map(|u| Suggestion { id: u.id, name: u.name }).collect()))
+}
+
+/// Warns the payment form of what a payment would come to before it is sent, in the smallest units.
+#[get("/payment/preview?<payee>&<amount>")]
+async fn payment_preview(user: User, domains: &State<Domains>, payee: &str, amount: &str, locale: &Locale) -> Option<Json<Preview>> {
+    let amount = domains.read().parse_amount(amount)?;
+    let payee = payee.to_string();
+    let preview = domains.run(move |d| find_user(d, &payee).map(|payee| d.preview_payment(user.0, payee.id, amount))).await;
+    let domain = domains.read();
+    let refused = |message| Preview { limit: 0, limited_by: "send", fee: 0, payer_balance: 0, payee_balance: 0, message: Some(message) };
+    Some(Json(match preview {
+        Ok(Ok(p)) => Preview {
+            limit: p.limit.amount(),
+            limited_by: match p.limit { Limit::Send(_) => "send", Limit::Receive(_) => "receive" },
+            fee: p.fee,
+            payer_balance: p.payer_balance,
+            payee_balance: p.payee_balance,
+            message: p.error.map(|e| payment_error(&domain, locale, e)),
+        },
+        Ok(Err(e)) => refused(payment_error(&domain, locale, e)),
+        Err(Error::QueryReturnedNoRows) => refused(domain.catalog.get(&locale.0, "payee-not-found").to_string()),
+        Err(e) => refused(domain.catalog.format(&locale.0, "db-error", &[&e])),
+    }))
+}
+
+#[get("/api/v1/me")]
+async fn api_me(user: User, domains: &State<Domains>) -> Result<Json<Me>, status::Custom<Json<SimpletsError>>> {
+    let (user, limits) = domains.run(move |d| Ok::<_, Error>((d.get_user(user.0)?, d.get_limits(user.0)?))).await.map_err(api_error)?;
+    Ok(Json(me(&domains.read(), user, limits)))
+}
+
+#[get("/api/v1/stats")]
+async fn api_stats(user: User, domains: &State<Domains>) -> Result<Json<simplets::UserStats>, status::Custom<Json<SimpletsError>>> {
+    domains.run(move |d| d.user_stats(user.0)).await.map(Json).map_err(api_error)
+}
+
+#[post("/api/v1/payments", format = "json", data = "<payment>")]
+async fn api_payment(user: User, domains: &State<Domains>, payment: Json<ApiPayment>) -> Result<Json<Me>, status::Custom<Json<SimpletsError>>> {
+    domains.add_payment(user.0, payment.payee, payment.amount, &payment.message, payment.category.as_deref(),
+                        payment.idempotency_key.as_deref()).await.map_err(api_error)?;
+    let (user, limits) = domains.run(move |d| Ok::<_, Error>((d.get_user(user.0)?, d.get_limits(user.0)?))).await.map_err(api_error)?;
+    Ok(Json(me(&domains.read(), user, limits)))
+}
+
+fn me(domain: &Domain, user: simplets::User, (send_limit, receive_limit): (i64, i64)) -> Me {
+    Me {
+        id: user.id,
+        send_limit,
+        receive_limit,
+        name: user.name,
+        credit: user.credit,
+        payments_in: user.payments_in,
+        payments_out: user.payments_out,
+        minimal_amount: domain.minimal_amount,
+        currency: domain.currency.plural.clone(),
+        decimals: domain.currency.decimals,
+    }
+}
+
+fn api_error(e: impl Into<SimpletsError>) -> status::Custom<Json<SimpletsError>> {
+    let error = e.into();
+    let status = match error {
+        SimpletsError::Db(Error::QueryReturnedNoRows) => Status::NotFound,
+        SimpletsError::FederationDenied => Status::Unauthorized,
+        SimpletsError::ShuttingDown => Status::ServiceUnavailable,
+        ref e if e.is_validation() => Status::UnprocessableEntity,
+        _ => Status::InternalServerError
+    };
+    status::Custom(status, Json(error))
+}
+
+#[get("/", rank = 2)]
+fn no_auth_index() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[get("/login")]
+fn login(_user: User) -> Redirect {
+    Redirect::to(uri!(index(_)))
+}
+
+#[get("/login", rank = 2)]
+fn login_page(domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale,
+              oidc: OidcEnabled) -> Template {
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "login", context! { message: flash.as_ref().map(|f| f.message()), csrf: &csrf.0, oidc: oidc.0 })
+}
+
+/// Whether members may log in through an OpenID Connect provider, see `oidc_login`.
+struct OidcEnabled(bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OidcEnabled {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(_request: &'r Request<'_>) -> request::Outcome<OidcEnabled, Self::Error> {
+        #[cfg(feature = "oidc")]
+        let enabled = _request.rocket().state::<Oidc>().is_some();
+        #[cfg(not(feature = "oidc"))]
+        let enabled = false;
+        request::Outcome::Success(OidcEnabled(enabled))
+    }
+}
+
+/// Sends the member to the provider to log in. A member logged in already comes back with the
+/// provider's account linked to theirs, see `oidc_callback`.
+#[cfg(feature = "oidc")]
+#[get("/login/oidc")]
+async fn oidc_login(jar: &CookieJar<'_>, oidc: &State<Oidc>, domains: &State<Domains>, locale: &Locale) -> Result<Redirect, Flash<Redirect>> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let (state, nonce) = (hex::encode(&bytes[..16]), hex::encode(&bytes[16..]));
+    // the state and nonce come back with the provider's answer, checked within ten minutes
+    jar.add_private(Cookie::new("oidc", format!("{}:{}:{}", state, nonce, chrono::Local::now().timestamp())));
+    let provider = oidc.0.clone();
+    match rocket::tokio::task::spawn_blocking(move || provider.authorization_url(&state, &nonce)).await.expect("discovery panicked") {
+        Ok(url) => Ok(Redirect::to(url)),
+        Err(e) => {
+            tracing::warn!(error = %e, "OpenID Connect discovery failed");
+            Err(Flash::error(Redirect::to(uri!(login_page)), domains.read().catalog.get(&locale.0, "oidc-failed")))
+        }
+    }
+}
+
+/// Where the provider sends the member back. The provider's account logs in the user it's linked
+/// to, or one whose address both sides verified. A member logged in already gets it linked.
+#[allow(clippy::too_many_arguments)]
+#[cfg(feature = "oidc")]
+#[get("/login/oidc/callback?<code>&<state>")]
+async fn oidc_callback(jar: &CookieJar<'_>, session: Option<UserSession>, oidc: &State<Oidc>, domains: &State<Domains>, agent: UserAgent,
+                       code: Option<&str>, state: Option<&str>, locale: &Locale) -> Result<Redirect, Flash<Redirect>> {
+    let failed = |key| Flash::error(Redirect::to(uri!(login_page)), domains.read().catalog.get(&locale.0, key));
+    let pending = jar.get_private("oidc").map(|c| c.value().to_string());
+    jar.remove_private(Cookie::named("oidc"));
+    let nonce = pending.as_deref().and_then(|p| {
+        let mut parts = p.splitn(3, ':');
+        let (expected, nonce, at) = (parts.next()?, parts.next()?, parts.next()?.parse::<i64>().ok()?);
+        (Some(expected) == state && chrono::Local::now().timestamp() - at <= 600).then(|| nonce.to_string())
+    });
+    let (Some(code), Some(nonce)) = (code.map(str::to_string), nonce) else { return Err(failed("login-failed")) };
+    let provider = oidc.0.clone();
+    let claims = match rocket::tokio::task::spawn_blocking(move || provider.exchange(&code, &nonce)).await.expect("token exchange panicked") {
+        Ok(claims) => claims,
+        Err(e) => {
+            tracing::warn!(error = %e, "OpenID Connect login failed");
+            return Err(failed("oidc-failed"))
+        }
+    };
+    let db_error = |e: Error| Flash::error(Redirect::to(uri!(login_page)), domains.read().catalog.format(&locale.0, "db-error", &[&e]));
+    if let Some(session) = session {
+        let user = session.0.user;
+        let linked = domains.run(move |d| {
+            d.link_identity(user, &claims.issuer, &claims.subject)?;
+            d.user_by_identity(&claims.issuer, &claims.subject)
+        }).await.map_err(db_error)?;
+        // the sessions page lists the linked providers
+        return if linked == Some(user) { Ok(Redirect::to(uri!(sessions))) }
+        else { Err(Flash::error(Redirect::to(uri!(sessions)), domains.read().catalog.get(&locale.0, "oidc-taken"))) }
+    }
+    let user = domains.run(move |d| -> Result<Option<i64>, Error> {
+        if let Some(user) = d.user_by_identity(&claims.issuer, &claims.subject)? { return Ok(Some(user)) }
+        let Some(email) = &claims.email else { return Ok(None) };
+        match d.get_user_by_email(email) {
+            Ok(u) if u.email_verified => {
+                d.link_identity(u.id, &claims.issuer, &claims.subject)?;
+                Ok(Some(u.id))
+            }
+            Ok(_) | Err(Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }).await.map_err(db_error)?;
+    let Some(user) = user else { return Err(failed("oidc-unknown")) };
+    start_session(jar, domains, user, agent).await.map_err(db_error)?;
+    Ok(after_login(domains, user).await)
+}
+
+#[post("/login", data = "<login>")]
+async fn post_login(jar: &CookieJar<'_>, login: Csrf<Login<'_>>, domains: &State<Domains>, agent: UserAgent, throttle: LoginThrottle,
+                    locale: &Locale) -> Result<Redirect, Flash<Redirect>> {
+    if throttle.is_locked(domains, login.username).await {
+        return Err(Flash::error(Redirect::to(uri!(login_page)), domains.read().catalog.get(&locale.0, "login-locked")))
+    }
+    let failed = domains.read().catalog.get(&locale.0, "login-failed").to_string();
+    let user = if let Ok(u) = domains.get_user_by_name(login.username).await { u }
+    else {
+        throttle.failed(domains, login.username).await;
+        return Err(Flash::error(Redirect::to(uri!(login_page)), failed))
+    };
+    let hash = simplets::hash(login.password);
+    if hash == user.password {
+        throttle.succeeded(domains, &user.name).await;
+        let id = user.id;
+        if domains.run(move |d| d.totp_enabled(id)).await.unwrap_or(true) {
+            // the password is right, the code is asked for next; the cookie is only good for a few minutes
+            jar.add_private(Cookie::new("totp_user", format!("{}:{}", user.id, chrono::Local::now().timestamp())));
+            return Ok(Redirect::to(uri!(login_totp_page)))
+        }
+        start_session(jar, domains, user.id, agent).await
+            .map_err(|e| Flash::error(Redirect::to(uri!(login_page)), domains.read().catalog.format(&locale.0, "db-error", &[&e])))?;
+        Ok(after_login(domains, user.id).await)
+    } else {
+        throttle.failed(domains, login.username).await;
+        Err(Flash::error(Redirect::to(uri!(login_page)), failed))
+    }
+}
+
+#[get("/logout")]
+async fn logout(jar: &CookieJar<'_>, domains: &State<Domains>, locale: &Locale) -> Flash<Redirect> {
+    if let Some(token) = jar.get_private("session").map(|c| c.value().to_string()) {
+        domains.run(move |d| d.end_session(&token)).await.ok();
+    }
+    jar.remove_private(Cookie::named("session"));
+    Flash::success(Redirect::to(uri!(login_page)), domains.read().catalog.get(&locale.0, "logout-success"))
+}
+
+/// The user who passed the password step within the last five minutes.
+fn totp_pending(jar: &CookieJar<'_>) -> Option<i64> {
+    let cookie = jar.get_private("totp_user")?;
+    let (id, at) = cookie.value().split_once(':')?;
+    let at: i64 = at.parse().ok()?;
+    if chrono::Local::now().timestamp() - at > 300 { return None }
+    id.parse().ok()
+}
+
+/// The current terms of use, to be accepted by members who haven't yet. Anyone may read them.
+#[get("/terms")]
+async fn terms(user: Option<User>, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>,
+               locale: &Locale) -> Option<Template> {
+    let id = user.map(|u| u.0);
+    let (terms, pending) = domains.run(move |d| (d.current_terms(), id.map(|id| d.needs_terms_acceptance(id)))).await;
+    let (terms, pending) = (terms.ok()??, pending.transpose().ok()?.unwrap_or(false));
+    let domain = domains.read();
+    Some(themed(&domain, &metadata, locale, "terms", context! { terms, pending, flash: &flash, csrf: &csrf.0 }))
+}
+
+#[post("/terms", data = "<acceptance>")]
+async fn accept_terms(user: User, domains: &State<Domains>, acceptance: Csrf<TermsAcceptance>, locale: &Locale) -> Result<Redirect, Flash<Redirect>> {
+    let version = acceptance.version;
+    match domains.run(move |d| d.accept_terms(user.0, version)).await {
+        Ok(0) => Err(Flash::error(Redirect::to(uri!(terms)), domains.read().catalog.get(&locale.0, "terms-changed"))),
+        Ok(_) => Ok(Redirect::to(uri!(index(_)))),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(terms)), domains.read().catalog.format(&locale.0, "db-error", &[&e]))),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+#[get("/login/totp")]
+fn login_totp_page(jar: &CookieJar<'_>, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Result<Template, Redirect> {
+    totp_pending(jar).ok_or_else(|| Redirect::to(uri!(login_page)))?;
+    let domain = domains.read();
+    Ok(themed(&domain, &metadata, locale, "login_totp", context! { flash: &flash, csrf: &csrf.0 }))
+}
+
+#[post("/login/totp", data = "<code>")]
+async fn post_login_totp(jar: &CookieJar<'_>, code: Csrf<TotpCode<'_>>, domains: &State<Domains>, agent: UserAgent, throttle: LoginThrottle,
+                         locale: &Locale) -> Result<Redirect, Flash<Redirect>> {
+    let user = match totp_pending(jar) {
+        Some(u) => u,
+        None => return Err(Flash::error(Redirect::to(uri!(login_page)), domains.read().catalog.get(&locale.0, "login-failed")))
+    };
+    let name = domains.get_user(user).await.map(|u| u.name).unwrap_or_default();
+    if throttle.is_locked(domains, &name).await {
+        return Err(Flash::error(Redirect::to(uri!(login_totp_page)), domains.read().catalog.get(&locale.0, "login-locked")))
+    }
+    let code = code.code.to_string();
+    match domains.run(move |d| d.verify_totp(user, &code)).await {
+        Ok(_) => {
+            jar.remove_private(Cookie::named("totp_user"));
+            start_session(jar, domains, user, agent).await
+                .map_err(|e| Flash::error(Redirect::to(uri!(login_page)), domains.read().catalog.format(&locale.0, "db-error", &[&e])))?;
+            Ok(after_login(domains, user).await)
+        }
+        Err(e) => {
+            throttle.failed(domains, &name).await;
+            Err(Flash::error(Redirect::to(uri!(login_totp_page)), payment_error(&domains.read(), locale, e)))
+        }
+    }
+}
+
+#[get("/totp")]
+async fn totp(user: User, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let enabled = domains.run(move |d| d.totp_enabled(user.0)).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "totp", context! {
+        user_id: user.0,
+        enabled,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/totp", rank = 2)]
+fn no_auth_totp() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/totp/enable", data = "<_csrf>")]
+async fn totp_enable(user: User, domains: &State<Domains>, _csrf: Csrf<NoFields>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Result<Template, Flash<Redirect>> {
+    match domains.run(move |d| d.enable_totp(user.0)).await {
+        Ok(uri) => Ok(themed(&domains.read(), &metadata, locale, "totp", context! {
+            csrf: &csrf.0,
+            user_id: user.0,
+            secret: uri.split("secret=").nth(1).and_then(|s| s.split('&').next()),
+            uri: &uri,
+        })),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(totp)), domains.read().catalog.format(&locale.0, "db-error", &[&e]))),
+    }
+}
+
+#[post("/totp/confirm", data = "<code>")]
+async fn totp_confirm(user: User, domains: &State<Domains>, code: Csrf<TotpCode<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Result<Template, Flash<Redirect>> {
+    let code = code.code.to_string();
+    match domains.run(move |d| d.confirm_totp(user.0, &code).and_then(|codes| {
+        d.audit(Some(user.0), "totp.enable", Some(user.0), "")?;
+        Ok(codes)
+    })).await {
+        Ok(codes) => Ok(themed(&domains.read(), &metadata, locale, "totp", context! {
+            csrf: &csrf.0,
+            user_id: user.0,
+            enabled: true,
+            codes,
+        })),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(totp)), payment_error(&domains.read(), locale, e))),
+    }
+}
+
+#[post("/totp/disable", data = "<code>")]
+async fn totp_disable(user: User, domains: &State<Domains>, code: Csrf<TotpCode<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let code = code.code.to_string();
+    let result = domains.run(move |d| d.verify_totp(user.0, &code).and_then(|_| {
+        d.disable_totp(user.0)?;
+        Ok(d.audit(Some(user.0), "totp.disable", Some(user.0), "")?)
+    })).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(totp)), domain.catalog.get(&locale.0, "totp-disabled")),
+        Err(e) => Flash::error(Redirect::to(uri!(totp)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/sessions")]
+async fn sessions(session: UserSession, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale,
+                  oidc: OidcEnabled) -> Template {
+    let user = session.0.user;
+    let (sessions, identities) = domains.run(move |d| (d.get_sessions(user).unwrap(), d.linked_identities(user).unwrap())).await;
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "sessions", context! {
+        user_id: user,
+        current: session.0.id,
+        sessions,
+        identities,
+        oidc: oidc.0,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/sessions", rank = 2)]
+fn no_auth_sessions() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/sessions/others", data = "<_csrf>")]
+async fn revoke_other_sessions(session: UserSession, domains: &State<Domains>, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let (user, keep) = (session.0.user, session.0.id);
+    let result = domains.run(move |d| d.revoke_other_sessions(user, keep)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(n) => Flash::success(Redirect::to(uri!(sessions)), catalog.format(&locale.0, "sessions-revoked", &[&n])),
+        Err(e) => Flash::error(Redirect::to(uri!(sessions)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/sessions/<id>/revoke", data = "<_csrf>")]
+async fn revoke_session(session: UserSession, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let user = session.0.user;
+    let result = domains.run(move |d| d.revoke_session(user, id)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(n) => Flash::success(Redirect::to(uri!(sessions)), catalog.format(&locale.0, "sessions-revoked", &[&n])),
+        Err(e) => Flash::error(Redirect::to(uri!(sessions)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+/// Stops logging in through the provider, the password still works.
+#[post("/sessions/identities/unlink", data = "<form>")]
+async fn unlink_identity(session: UserSession, domains: &State<Domains>, form: Csrf<IdentityForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let (user, issuer) = (session.0.user, form.issuer.to_string());
+    let result = domains.run(move |d| d.unlink_identity(user, &issuer)).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(sessions)), catalog.get(&locale.0, "oidc-unlinked")),
+        Err(e) => Flash::error(Redirect::to(uri!(sessions)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[get("/lang/<locale>")]
+fn set_locale(jar: &CookieJar<'_>, locale: &str) -> Redirect {
+    jar.add(Cookie::build("lang", locale.to_string()).path("/").permanent().finish());
+    Redirect::to(uri!(index(_)))
+}
+
+#[post("/password", data = "<password>")]
+async fn password(user: User, domains: &State<Domains>, password: Csrf<Password<'_>>, locale: &Locale) -> Option<Flash<Redirect>> {
+    let valid = simplets::hash(password.old) == domains.get_user(user.0).await.expect("database error: {}").password;
+    if !valid { return Some(Flash::error(Redirect::to(uri!(index(_))), domains.read().catalog.get(&locale.0, "password-old-invalid"))) }
+    let new = password.new.to_string();
+    let result = domains.run(move |d| {
+        d.set_password(user.0, &new)?;
+        Ok::<_, SimpletsError>(d.audit(Some(user.0), "password.change", Some(user.0), "")?)
+    }).await;
+    let domain = domains.read();
+    Some(match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(index(_))), domain.catalog.get(&locale.0, "password-changed")),
+        Err(e) if e.is_validation() => Flash::error(Redirect::to(uri!(index(_))), payment_error(&domain, locale, e)),
+        Err(_) => Flash::error(Redirect::to(uri!(index(_))), domain.catalog.get(&locale.0, "password-change-failed")),
+    })
+}
+
+#[get("/password")]
+fn password_page(_user: User, domains: &State<Domains>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "password", context! { csrf: &csrf.0, min_password_length: simplets::validation::MIN_PASSWORD_LENGTH })
+}
+
+#[get("/reset")]
+fn reset_page(domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "reset", context! { flash: &flash, csrf: &csrf.0 })
+}
+
+/// Emails a reset link if the account has an address. The answer is the same either way,
+/// so the form can't be used to find out who is a member.
+#[post("/reset", data = "<request>")]
+async fn post_reset(request: Csrf<ResetRequest<'_>>, domains: &State<Domains>, public_url: &State<PublicUrl>, locale: &Locale) -> Flash<Redirect> {
+    let key = request.account.trim().to_string();
+    let base = public_url.0.clone();
+    let lang = locale.0.clone();
+    let result = domains.run(move |d| {
+        let user = match key.contains('@') {
+            true => d.get_user_by_email(&key),
+            false => find_user(d, &key),
+        }?;
+        let email = match &user.email {
+            Some(e) if user.active => e.clone(),
+            _ => return Ok(())
+        };
+        let token = d.create_password_reset(user.id)?;
+        let body = d.catalog.format(&lang, "reset-email-body", &[&format!("{}/reset/{}", base.trim_end_matches('/'), token)]);
+        d.queue_email(user.id, &email, d.catalog.get(&lang, "reset-email-subject"), &body)
+    }).await;
+    if let Err(e) = result {
+        if e != Error::QueryReturnedNoRows { tracing::error!(error = %e, "password reset failed") }
+    }
+    Flash::success(Redirect::to(uri!(login_page)), domains.read().catalog.get(&locale.0, "reset-sent"))
+}
+
+#[get("/reset/<token>")]
+async fn reset_token_page(token: &str, domains: &State<Domains>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Result<Template, Flash<Redirect>> {
+    let key = token.to_string();
+    match domains.run(move |d| d.check_password_reset(&key)).await {
+        Ok(_) => Ok(themed(&domains.read(), &metadata, locale, "reset_password", context! { token, csrf: &csrf.0, min_password_length: simplets::validation::MIN_PASSWORD_LENGTH })),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(reset_page)), payment_error(&domains.read(), locale, e))),
+    }
+}
+
+#[post("/reset/<token>", data = "<password>")]
+async fn post_reset_token(token: &str, password: Csrf<NewPassword<'_>>, domains: &State<Domains>, locale: &Locale) -> Flash<Redirect> {
+    let (token, new) = (token.to_string(), password.new.to_string());
+    let result = domains.run(move |d| {
+        let user = d.reset_password(&token, &new)?;
+        d.audit(Some(user), "password.reset", Some(user), "")?;
+        Ok(user)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(login_page)), domain.catalog.get(&locale.0, "password-changed")),
+        Err(e) => Flash::error(Redirect::to(uri!(reset_page)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[get("/admin")]
+async fn admin(admin: Overseer, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let (system_accounts, demurrage_account, adjustment_account, webhooks, categories, disputes, participation, announcements) = domains.run(|d| (
+        d.get_system_accounts(), d.get_demurrage_account(), d.get_adjustment_account(), d.get_webhooks(), d.get_categories(), d.get_disputed_escrows(),
+        d.participation_metrics(), d.recent_announcements(20))).await;
+    let (system_accounts, demurrage_account, adjustment_account, webhooks, categories, disputes, participation, announcements) = (system_accounts.unwrap(),
+        demurrage_account.unwrap(), adjustment_account.unwrap(), webhooks.unwrap(), categories.unwrap().join("\n"), disputes.unwrap(),
+        participation.unwrap(), announcements.unwrap());
+    let user = admin.0;
+    let (terms, can) = domains.run(move |d| (d.current_terms(), capabilities(d, user))).await;
+    let (terms, can) = (terms.unwrap(), can.unwrap());
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "admin", context! {
+        user_id: admin.0,
+        can,
+        system_accounts,
+        demurrage_account,
+        adjustment_account,
+        webhooks,
+        announcements,
+        terms,
+        categories,
+        disputes,
+        credit_gini: format!("{:.2}", participation.credit_gini),
+        top_decile_percent: format!("{:.0}", participation.top_decile_share * 100.0),
+        dormant_days: simplets::DORMANT_DAYS,
+        participation,
+        privacy: domain.privacy,
+        leaderboard: domain.leaderboard,
+        branding: &domain.branding,
+        themes: THEMES,
+        currency_settings: &domain.currency,
+        name: &domain.name,
+        description: &domain.description,
+        join_info: &domain.join_info,
+        currency: &domain.currency.plural,
+        minimal_amount: domain.minimal_amount,
+        maximal_amount: domain.maximal_amount,
+        daily_limit: domain.daily_limit,
+        probation: domain.probation,
+        username_policy: &domain.username_policy,
+        reserved_names: domain.username_policy.reserved.join(", "),
+        message_rules: &domain.message_rules,
+        banned_words: domain.message_rules.banned_words.join(", "),
+        banned_patterns: domain.message_rules.banned_patterns.join("\n"),
+        limit_window: domain.limit_window,
+        confirmation_threshold: domain.confirmation_threshold,
+        confirmation_days: domain.confirmation_days,
+        fee_flat: domain.fee_flat,
+        fee_percent: domain.fee_percent,
+        fee_account: domain.fee_account,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/admin/audit?<user>&<from>&<to>")]
+async fn admin_audit(admin: Overseer, domains: &State<Domains>, user: Option<&str>, from: Option<&str>, to: Option<&str>,
+                     flash: Option<FlashMessage<'_>>, metadata: Metadata<'_>, locale: &Locale) -> Result<Template, Flash<Redirect>> {
+    let key = user.map(str::trim).filter(|u| !u.is_empty()).map(|u| u.to_string());
+    let since = from.filter(|d| !d.is_empty()).and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+    let until = to.filter(|d| !d.is_empty()).and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+    let result = domains.run(move |d| {
+        let user = key.map(|k| find_user(d, &k)).transpose()?;
+        d.get_audit_log(user.map(|u| u.id), since, until, 500)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(entries) => Ok(themed(&domain, &metadata, locale, "admin_audit", context! {
+            user_id: admin.0,
+            name: &domain.name,
+            entries,
+            user,
+            from,
+            to,
+            flash: &flash,
+        })),
+        Err(Error::QueryReturnedNoRows) => Err(Flash::error(Redirect::to(uri!(admin_audit(_, _, _))), domain.catalog.get(&locale.0, "user-not-found"))),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e]))),
+    }
+}
+
+/// The admin page of the account with the internal `id`, which is addressed by member number.
+/// Which capabilities the user has, by name, so the pages leave out the forms they can't use.
+fn capabilities(domain: &Domain, user: i64) -> Result<std::collections::HashMap<&'static str, bool>, Error> {
+    Capability::ALL.into_iter().map(|c| Ok((c.as_str(), domain.can(user, c)?))).collect()
+}
+
+async fn admin_user_page(domains: &Domains, id: i64) -> Redirect {
+    let number = domains.get_user(id).await.map(|u| u.member_number.to_string()).unwrap_or_default();
+    Redirect::to(uri!(admin_user(number)))
+}
+
+/// One account with its limits and their override, its freeze, and the latest audit entries about it.
+#[get("/admin/user?<user>")]
+async fn admin_user(admin: Overseer, domains: &State<Domains>, user: &str, flash: Option<FlashMessage<'_>>, csrf: CsrfToken,
+                    metadata: Metadata<'_>, locale: &Locale) -> Result<Template, Flash<Redirect>> {
+    let key = user.to_string();
+    let viewer = admin.0;
+    let result = domains.run(move |d| {
+        let user = find_user(d, &key)?;
+        Ok::<_, Error>((d.get_limits(user.id)?, d.get_limit_override(user.id)?, d.get_freeze(user.id)?, d.get_audit_log(Some(user.id), None, None, 20)?,
+                        capabilities(d, viewer)?, user))
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(((send_limit, receive_limit), limit_override, freeze, entries, can, user)) => Ok(themed(&domain, &metadata, locale, "admin_user", context! {
+            user_id: admin.0,
+            can,
+            name: &domain.name,
+            currency: &domain.currency.plural,
+            base_send_limit: user.send_limit(),
+            base_receive_limit: user.receive_limit(),
+            account: user,
+            send_limit,
+            receive_limit,
+            limit_override,
+            freeze,
+            entries,
+            csrf: &csrf.0,
+            flash: &flash,
+        })),
+        Err(Error::QueryReturnedNoRows) => Err(Flash::error(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "user-not-found"))),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e]))),
+    }
+}
+
+#[post("/admin/user/<id>/limits", data = "<form>")]
+async fn admin_limit_override(admin: MemberManager, domains: &State<Domains>, id: i64, form: Csrf<LimitOverrideForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let page = admin_user_page(domains, id).await;
+    let expiry = match form.expiry.filter(|e| !e.is_empty()).map(|e| NaiveDate::parse_from_str(e, "%Y-%m-%d")).transpose() {
+        Ok(date) => date.and_then(|d| d.and_hms_opt(23, 59, 59)),
+        Err(_) => return Flash::error(page, domains.read().catalog.get(&locale.0, "date-invalid")),
+    };
+    let (send_bonus, receive_bonus) = match (domains.read().parse_amount(form.send_bonus), domains.read().parse_amount(form.receive_bonus)) {
+        (Some(send_bonus), Some(receive_bonus)) => (send_bonus, receive_bonus),
+        _ => return Flash::error(page, domains.read().catalog.get(&locale.0, "amount-invalid")),
+    };
+    let result = domains.run(move |d| {
+        d.set_limit_override(id, send_bonus, receive_bonus, expiry)?;
+        let until = expiry.map(|e| format!(" until {}", e.date())).unwrap_or_default();
+        d.audit(Some(admin.0), "limits.override", Some(id), &format!("send +{} receive +{}{}", send_bonus, receive_bonus, until))
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(page, catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(page, catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/user/<id>/freeze", data = "<form>")]
+async fn admin_freeze(admin: MemberManager, domains: &State<Domains>, id: i64, form: Csrf<Freeze<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let page = admin_user_page(domains, id).await;
+    let reason = form.reason.trim().to_string();
+    if reason.is_empty() || reason.chars().count() > 140 {
+        return Flash::error(page, domains.read().catalog.get(&locale.0, "freeze-reason"))
+    }
+    let incoming = form.incoming;
+    let result = domains.run(move |d| {
+        d.freeze_user(id, &reason, incoming)?;
+        d.audit(Some(admin.0), "user.freeze", Some(id), &format!("{}{}", if incoming { "incoming too: " } else { "" }, reason))
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(page, catalog.get(&locale.0, "user-frozen")),
+        Err(e) => Flash::error(page, catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/user/<id>/role", data = "<form>")]
+async fn admin_role(admin: Admin, domains: &State<Domains>, id: i64, form: Csrf<RoleForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let page = admin_user_page(domains, id).await;
+    let Ok(role) = form.role.parse::<simplets::Role>() else { return Flash::error(page, domains.read().catalog.get(&locale.0, "role-invalid")) };
+    let result = domains.run(move |d| {
+        let changed = d.set_role(id, role)?;
+        if changed > 0 { d.audit(Some(admin.0), "user.role", Some(id), role.as_str())? }
+        Ok::<_, Error>(changed)
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(0) => Flash::error(page, catalog.get(&locale.0, "role-last-admin")),
+        Ok(_) => Flash::success(page, catalog.get(&locale.0, "role-set")),
+        Err(e) => Flash::error(page, catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/user/<id>/unfreeze", data = "<_csrf>")]
+async fn admin_unfreeze(admin: MemberManager, domains: &State<Domains>, id: i64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let page = admin_user_page(domains, id).await;
+    let result = domains.run(move |d| {
+        d.unfreeze_user(id)?;
+        d.audit(Some(admin.0), "user.unfreeze", Some(id), "")
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(page, catalog.get(&locale.0, "user-unfrozen")),
+        Err(e) => Flash::error(page, catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/user/<id>/closure", data = "<_csrf>")]
+async fn admin_begin_closure(admin: MemberManager, domains: &State<Domains>, id: i64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| {
+        let closed = d.begin_account_closure(id)?;
+        d.audit(Some(admin.0), "user.closure", Some(id), if closed { "closed" } else { "started" })?;
+        Ok::<_, Error>(closed)
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(true) => Flash::success(Redirect::to(uri!(admin_closures)), catalog.get(&locale.0, "account-closed")),
+        Ok(false) => Flash::success(Redirect::to(uri!(admin_closures)), catalog.get(&locale.0, "closure-started")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin_closures)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/user/<id>/closure/cancel", data = "<_csrf>")]
+async fn admin_cancel_closure(admin: MemberManager, domains: &State<Domains>, id: i64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| {
+        d.cancel_account_closure(id)?;
+        d.audit(Some(admin.0), "user.closure.cancel", Some(id), "")
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin_closures)), catalog.get(&locale.0, "closure-cancelled")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin_closures)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+/// The date `months` before `date`, on the last day of the month if it's shorter.
+fn months_before(date: NaiveDate, months: u32) -> NaiveDate {
+    let month0 = date.year() * 12 + date.month0() as i32 - months.min(12 * 1000) as i32;
+    let (year, month) = (month0.div_euclid(12), month0.rem_euclid(12) as u32 + 1);
+    (0..4).find_map(|back| NaiveDate::from_ymd_opt(year, month, date.day() - back)).unwrap_or(date)
+}
+
+/// Members who haven't traded for `months`, six by default.
+#[get("/admin/dormant?<months>")]
+async fn admin_dormant(admin: Overseer, domains: &State<Domains>, months: Option<u32>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken,
+                       metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let months = months.unwrap_or(6).max(1);
+    let since = months_before(domains.read().timezone.today(), months);
+    let dormant = domains.run(move |d| d.dormant_users(since)).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "admin_dormant", context! {
+        user_id: admin.0,
+        name: &domain.name,
+        currency: &domain.currency.plural,
+        months,
+        since: since.to_string(),
+        dormant,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+/// Reminds, freezes or lowers the limits of all members that `admin_dormant` lists.
+#[post("/admin/dormant", data = "<form>")]
+async fn admin_dormant_action(admin: MemberManager, domains: &State<Domains>, form: Csrf<DormantAction<'_>>, locale: &Locale) -> Option<Flash<Redirect>> {
+    let done = match form.action {
+        "remind" => "dormant-reminded",
+        "freeze" => "dormant-frozen",
+        "decay" => "dormant-decayed",
+        _ => return None
+    };
+    let months = form.months.max(1);
+    let page = Redirect::to(uri!(admin_dormant(Some(months))));
+    let since = months_before(domains.read().timezone.today(), months);
+    let (action, percent) = (form.action.to_string(), form.percent.unwrap_or(50));
+    let reason = domains.read().catalog.format(&locale.0, "dormant-freeze-reason", &[&since]);
+    let result = domains.run(move |d| {
+        let count = match action.as_str() {
+            "remind" => d.remind_dormant_users(since)?,
+            "freeze" => {
+                let dormant = d.dormant_users(since)?;
+                for user in dormant.iter() { d.freeze_user(user.id, &reason, false)?; }
+                dormant.len()
+            }
+            _ => {
+                let dormant = d.dormant_users(since)?;
+                for user in dormant.iter() { d.decay_limits(user.id, percent)?; }
+                dormant.len()
+            }
+        };
+        d.audit(Some(admin.0), &format!("dormant.{}", action), None, &format!("{} accounts idle since {}", count, since))?;
+        Ok::<_, Error>(count)
+    }).await;
+    let catalog = &domains.read().catalog;
+    Some(match result {
+        Ok(count) => Flash::success(page, catalog.format(&locale.0, done, &[&count])),
+        Err(e) => Flash::error(page, catalog.format(&locale.0, "db-error", &[&e])),
+    })
+}
+
+/// Accounts being closed, with the balance and what else each member still has to settle.
+#[get("/admin/closures")]
+async fn admin_closures(admin: Overseer, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let closures = domains.run(|d| d.get_account_closures()).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "admin_closures", context! {
+        user_id: admin.0,
+        name: &domain.name,
+        currency: &domain.currency.plural,
+        closures,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[get("/admin/events")]
+async fn admin_events(admin: Overseer, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let events = domains.run(|d| d.get_events()).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "admin_events", context! {
+        user_id: admin.0,
+        name: &domain.name,
+        events,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[post("/admin/events", data = "<event>")]
+async fn admin_open_event(admin: Admin, domains: &State<Domains>, event: Csrf<NewEvent<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let name = event.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > 140 {
+        return Flash::error(Redirect::to(uri!(admin_events)), domains.read().catalog.get(&locale.0, "event-name"))
+    }
+    let result = domains.run(move |d| {
+        let id = d.open_event(&name)?;
+        d.audit(Some(admin.0), "event.open", None, &format!("{}: {}", id, name))
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin_events)), catalog.get(&locale.0, "event-opened")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin_events)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/events/<id>/close", data = "<_csrf>")]
+async fn admin_close_event(admin: Admin, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| {
+        d.close_event(id)?;
+        d.audit(Some(admin.0), "event.close", None, &id.to_string())
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin_events)), catalog.get(&locale.0, "event-closed")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin_events)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+/// Totals of an event and what each stall took in.
+#[get("/admin/events/<id>")]
+async fn admin_event(admin: Overseer, domains: &State<Domains>, id: u64, metadata: Metadata<'_>, locale: &Locale) -> Option<Template> {
+    let summary = domains.run(move |d| d.event_summary(id)).await.ok()?;
+    let domain = domains.read();
+    Some(themed(&domain, &metadata, locale, "admin_event", context! {
+        user_id: admin.0,
+        name: &domain.name,
+        currency: &domain.currency.plural,
+        summary,
+    }))
+}
+
+/// Monthly activity of the domain, by default of the current year.
+#[get("/admin/turnover?<year>")]
+async fn admin_turnover(admin: Overseer, domains: &State<Domains>, metadata: Metadata<'_>, locale: &Locale, year: Option<i32>) -> Template {
+    let year = year.unwrap_or_else(|| domains.read().timezone.today().year());
+    let months = domains.run(move |d| d.turnover_report(year)).await.unwrap();
+    let peak = months.iter().map(|m| m.volume).max().unwrap_or(0).max(1);
+    let chart: Vec<_> = months.iter().map(|m| context! { month: &m.month, volume: m.volume, width: m.volume * 100 / peak }).collect();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "admin_turnover", context! {
+        user_id: admin.0,
+        name: &domain.name,
+        currency: &domain.currency.plural,
+        year,
+        previous: year - 1,
+        next: year + 1,
+        payments: months.iter().map(|m| m.payments).sum::<u64>(),
+        volume: months.iter().map(|m| m.volume).sum::<u64>(),
+        new_members: months.iter().map(|m| m.new_members).sum::<u64>(),
+        months: &months,
+        chart,
+    })
+}
+
+#[get("/admin/health")]
+async fn admin_health(admin: Overseer, domains: &State<Domains>, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let report = domains.run(|d| d.health_report()).await.unwrap();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "admin_health", context! {
+        user_id: admin.0,
+        name: &domain.name,
+        healthy: report.is_healthy(),
+        report,
+    })
+}
+
+/// Liveness probe, answers as long as the process serves requests.
+#[get("/healthz")]
+fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness probe: 200 when the database answers with the schema this build expects and, with
+/// `?ledger=true`, the balances sum to zero, 503 otherwise.
+#[get("/readyz?<ledger>")]
+async fn readyz(domains: &State<Domains>, ledger: Option<bool>) -> Result<status::Custom<Json<simplets::Readiness>>, status::Custom<Json<SimpletsError>>> {
+    let readiness = domains.run(move |d| d.readiness(ledger.unwrap_or(false))).await.map_err(|e| {
+        tracing::warn!(error = %e, "readiness check failed");
+        status::Custom(Status::ServiceUnavailable, Json(SimpletsError::Db(e)))
+    })?;
+    let status = if readiness.is_ready() { Status::Ok } else { Status::ServiceUnavailable };
+    Ok(status::Custom(status, Json(readiness)))
+}
+
+#[get("/admin/backup")]
+async fn admin_backup(admin: Admin, domains: &State<Domains>, key: &State<BackupKey>, locale: &Locale) -> Result<Download, Flash<Redirect>> {
+    let key = match key.0 {
+        Some(k) => k,
+        None => return Err(Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "backup-no-key")))
+    };
+    let result = domains.run(move |d| {
+        let data = simplets::backup::encrypted_snapshot(d, &key)?;
+        d.audit(Some(admin.0), "domain.backup", None, &data.len().to_string())?;
+        Ok::<_, simplets::DomainError>((data, d.name.clone()))
+    }).await;
+    match result {
+        Ok((data, name)) => {
+            let filename = format!("attachment; filename=\"{}-{}.backup\"", name, chrono::Local::now().format("%Y-%m-%d"));
+            Ok(Download(data, Header::new("Content-Disposition", filename)))
+        }
+        Err(e) => Err(Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.format(&locale.0, "db-error", &[&e]))),
+    }
+}
+
+/// Portable dump of the whole domain, see `Domain::export_archive`.
+#[get("/admin/archive")]
+async fn admin_archive(admin: Admin, domains: &State<Domains>, locale: &Locale) -> Result<JsonFile, Flash<Redirect>> {
+    let result = domains.run(move |d| {
+        let archive = d.export_archive()?;
+        d.audit(Some(admin.0), "domain.archive", None, &format!("{} users, {} payments", archive.users.len(), archive.payments.len()))?;
+        Ok::<_, Error>((serde_json::to_string_pretty(&archive).unwrap_or_default(), d.name.clone()))
+    }).await;
+    match result {
+        Ok((json, name)) => {
+            let filename = format!("attachment; filename=\"{}-{}.json\"", name, chrono::Local::now().format("%Y-%m-%d"));
+            Ok(JsonFile(json, Header::new("Content-Disposition", filename)))
+        }
+        Err(e) => Err(Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.format(&locale.0, "db-error", &[&e]))),
+    }
+}
+
+#[get("/admin/federation")]
+async fn admin_federation(admin: Overseer, domains: &State<Domains>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, metadata: Metadata<'_>, locale: &Locale) -> Template {
+    let peers = domains.run(|d| {
+        d.get_remote_domains()?.into_iter().map(|r| Ok((d.bridge_position(&r)?, r))).collect::<Result<Vec<_>, Error>>()
+    }).await.unwrap();
+    let peers: Vec<_> = peers.into_iter().map(|(position, remote)| context! { remote, position }).collect();
+    let domain = domains.read();
+    themed(&domain, &metadata, locale, "admin_federation", context! {
+        user_id: admin.0,
+        name: &domain.name,
+        peers,
+        csrf: &csrf.0,
+        flash: &flash,
+    })
+}
+
+#[post("/admin/federation", data = "<peer>")]
+async fn admin_add_peer(admin: Admin, domains: &State<Domains>, peer: Csrf<Peer<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let (name, url, secret) = (peer.name.trim().to_string(), peer.url.trim().to_string(), peer.secret.to_string());
+    if name.is_empty() || secret.is_empty() || !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Flash::error(Redirect::to(uri!(admin_federation)), domains.read().catalog.get(&locale.0, "peer-invalid"))
+    }
+    let result = domains.run(move |d| {
+        let remote = d.add_remote_domain(&name, &url, &secret)?;
+        d.audit(Some(admin.0), "federation.peer", Some(remote.bridge), &format!("{} {}", remote.name, remote.url))
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin_federation)), catalog.get(&locale.0, "peer-added")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin_federation)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/federation/<name>/rate", data = "<rate>")]
+async fn admin_exchange_rate(admin: Admin, domains: &State<Domains>, name: &str, rate: Csrf<ExchangeRate>, locale: &Locale) -> Flash<Redirect> {
+    let (rate, name) = (rate.rate, name.to_string());
+    let result = domains.run(move |d| {
+        let remote = d.get_remote_domain(&name)?;
+        d.set_exchange_rate(&remote.name, rate)?;
+        d.audit(Some(admin.0), "federation.rate", Some(remote.bridge), &format!("{} {}", remote.name, rate))?;
+        Ok::<_, SimpletsError>(())
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin_federation)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin_federation)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/admin/federation/<name>/reconcile", data = "<_csrf>")]
+async fn admin_reconcile(admin: Admin, domains: &State<Domains>, name: &str, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    #[cfg(feature = "federation")]
+    {
+        let name = name.to_string();
+        let result = domains.run(move |d| {
+            let remote = d.get_remote_domain(&name)?;
+            let reconciliation = simplets::federation::Client::default().reconcile(d, &remote)?;
+            d.audit(Some(admin.0), "federation.reconcile", Some(remote.bridge), &format!("{} {} {}", remote.name,
+                reconciliation.local.settled(), reconciliation.remote.settled()))?;
+            Ok::<_, simplets::federation::PeerError>(reconciliation)
+        }).await;
+        let catalog = &domains.read().catalog;
+        match result {
+            Ok(r) if r.is_balanced() => Flash::success(Redirect::to(uri!(admin_federation)), catalog.get(&locale.0, "federation-balanced")),
+            Ok(r) => Flash::error(Redirect::to(uri!(admin_federation)),
+                                  catalog.format(&locale.0, "federation-unbalanced", &[&r.local.settled(), &r.remote.settled()])),
+            Err(e) => Flash::error(Redirect::to(uri!(admin_federation)), catalog.format(&locale.0, "db-error", &[&e])),
+        }
+    }
+    #[cfg(not(feature = "federation"))]
+    {
+        let _ = (admin, name);
+        Flash::error(Redirect::to(uri!(admin_federation)), domains.read().catalog.get(&locale.0, "federation-disabled"))
+    }
+}
+
+#[post("/admin/leaderboard", data = "<form>")]
+async fn admin_leaderboard(admin: Admin, domains: &State<Domains>, form: Csrf<Leaderboard>, locale: &Locale) -> Flash<Redirect> {
+    let enabled = form.enabled;
+    let result = domains.run_mut(move |d| {
+        d.update_leaderboard(enabled)?;
+        d.audit(Some(admin.0), "leaderboard.update", None, if enabled { "on" } else { "off" })
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/branding", data = "<form>")]
+async fn admin_branding(admin: Admin, domains: &State<Domains>, form: Csrf<BrandingForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let theme = form.theme.trim();
+    let colors = [form.primary_color.trim(), form.background_color.trim()];
+    if !(theme.is_empty() || THEMES.contains(&theme)) || colors.iter().any(|c| !c.is_empty() && !css_color(c)) {
+        return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "branding-invalid"))
+    }
+    let branding = simplets::Branding {
+        theme: theme.to_string(),
+        title: form.title.trim().to_string(),
+        logo: form.logo.trim().to_string(),
+        primary_color: colors[0].to_string(),
+        background_color: colors[1].to_string(),
+    };
+    let result = domains.run_mut(move |d| {
+        let detail = if branding.theme.is_empty() { "classic".to_string() } else { branding.theme.clone() };
+        d.update_branding(branding)?;
+        d.audit(Some(admin.0), "branding.update", None, &detail)
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/privacy", data = "<privacy>")]
+async fn admin_privacy(admin: Admin, domains: &State<Domains>, privacy: Csrf<Privacy>, locale: &Locale) -> Flash<Redirect> {
+    let level = privacy.privacy;
+    if ![simplets::PRIVACY_PUBLIC, simplets::PRIVACY_MEMBERS, simplets::PRIVACY_PRIVATE].contains(&level) {
+        return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "privacy-invalid"))
+    }
+    let result = domains.run_mut(move |d| {
+        d.update_privacy(level)?;
+        d.audit(Some(admin.0), "privacy.update", None, &level.to_string())
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/categories", data = "<categories>")]
+async fn admin_categories(admin: Admin, domains: &State<Domains>, categories: Csrf<Categories<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let list = categories.categories.to_string();
+    let result = domains.run(move |d| {
+        d.set_categories(&list.lines().collect::<Vec<_>>())?;
+        d.audit(Some(admin.0), "categories.update", None, &d.get_categories()?.join(", "))
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/terms", data = "<form>")]
+async fn admin_terms(admin: Admin, domains: &State<Domains>, form: Csrf<TermsForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let text = form.text.trim().to_string();
+    if text.is_empty() { return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "terms-invalid")) }
+    let result = domains.run(move |d| {
+        let version = d.publish_terms(&text)?;
+        d.audit(Some(admin.0), "terms.publish", None, &version.to_string())
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "terms-published")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/announcements", data = "<announcement>")]
+async fn admin_announcement(admin: Admin, domains: &State<Domains>, announcement: Csrf<AnnouncementForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let (title, body) = (announcement.title.trim().to_string(), announcement.body.trim().to_string());
+    if title.is_empty() || body.is_empty() {
+        return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "announcement-invalid"))
+    }
+    let result = domains.run(move |d| {
+        let id = d.post_announcement(admin.0, &title, &body)?;
+        d.audit(Some(admin.0), "announcement.post", None, &format!("{} {}", id, title))?;
+        Ok(())
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "announcement-posted")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/admin/announcements/<id>/delete", data = "<_csrf>")]
+async fn admin_delete_announcement(admin: Admin, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| {
+        d.delete_announcement(id)?;
+        d.audit(Some(admin.0), "announcement.delete", None, &id.to_string())
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "announcement-deleted")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/webhooks", data = "<webhook>")]
+async fn admin_webhook(admin: Admin, domains: &State<Domains>, webhook: Csrf<Webhook<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let mut events = Vec::new();
+    if webhook.created { events.push(simplets::EVENT_PAYMENT_CREATED) }
+    if webhook.reversed { events.push(simplets::EVENT_PAYMENT_REVERSED) }
+    let url = webhook.url.trim().to_string();
+    if !(url.starts_with("http://") || url.starts_with("https://")) || events.is_empty() {
+        return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "webhook-invalid"))
+    }
+    let secret = webhook.secret.to_string();
+    let result = domains.run(move |d| {
+        let id = d.register_webhook(&url, &secret, &events)?;
+        d.audit(Some(admin.0), "webhook.register", None, &format!("{} {} {}", id, url, events.join(",")))
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/webhooks/<id>/delete", data = "<_csrf>")]
+async fn admin_delete_webhook(admin: Admin, domains: &State<Domains>, id: u64, _csrf: Csrf<NoFields>, locale: &Locale) -> Flash<Redirect> {
+    let result = domains.run(move |d| {
+        d.delete_webhook(id)?;
+        d.audit(Some(admin.0), "webhook.delete", None, &id.to_string())
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/fees", data = "<fees>")]
+async fn admin_fees(admin: Admin, domains: &State<Domains>, fees: Csrf<Fees<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let flat = match domains.read().parse_amount(fees.flat) {
+        Some(flat) => flat,
+        None => return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let (percent, account) = (fees.percent, fees.account);
+    let result = domains.run_mut(move |d| {
+        d.update_fees(flat, percent, account)?;
+        d.audit(Some(admin.0), "fees.update", account, &format!("flat {} percent {}", flat, percent))?;
+        Ok::<_, SimpletsError>(())
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/admin/limits", data = "<limits>")]
+async fn admin_limits(admin: Admin, domains: &State<Domains>, limits: Csrf<PaymentLimits<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let (maximal_amount, daily_limit) = match (domains.read().parse_amount(limits.maximal_amount), domains.read().parse_amount(limits.daily_limit)) {
+        (Some(maximal_amount), Some(daily_limit)) => (maximal_amount, daily_limit),
+        _ => return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "amount-invalid")),
+    };
+    let result = domains.run_mut(move |d| {
+        d.update_payment_limits(maximal_amount, daily_limit)?;
+        d.audit(Some(admin.0), "limits.update", None, &format!("maximal amount {} daily limit {}", maximal_amount, daily_limit))
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/limit-window", data = "<window>")]
+async fn admin_limit_window(admin: Admin, domains: &State<Domains>, window: Csrf<LimitWindow>, locale: &Locale) -> Flash<Redirect> {
+    let months = window.months;
+    let result = domains.run_mut(move |d| {
+        d.update_limit_window(months)?;
+        d.audit(Some(admin.0), "limits.window", None, &format!("{} months", months))
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/escrow/<id>/<decision>", data = "<_csrf>")]
+async fn admin_resolve_escrow(admin: Treasurer, domains: &State<Domains>, id: u64, decision: &str, _csrf: Csrf<NoFields>, locale: &Locale) -> Option<Flash<Redirect>> {
+    let release = match decision {
+        "release" => true,
+        "cancel" => false,
+        _ => return None,
+    };
+    let result = domains.run(move |d| {
+        d.resolve_escrow(id, release)?;
+        d.audit(Some(admin.0), if release { "escrow.release" } else { "escrow.cancel" }, None, &format!("escrow {}", id))?;
+        Ok::<_, SimpletsError>(())
+    }).await;
+    let domain = domains.read();
+    let key = if release { "escrow-released" } else { "escrow-cancelled" };
+    Some(match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, key)),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), payment_error(&domain, locale, e)),
+    })
+}
+
+#[post("/admin/confirmation", data = "<confirmation>")]
+async fn admin_confirmation(admin: Admin, domains: &State<Domains>, confirmation: Csrf<Confirmation<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let threshold = match domains.read().parse_amount(confirmation.threshold) {
+        Some(threshold) => threshold,
+        None => return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let days = confirmation.days.max(1);
+    let result = domains.run_mut(move |d| {
+        d.update_confirmation(threshold, days)?;
+        d.audit(Some(admin.0), "confirmation.update", None, &format!("over {} for {} days", threshold, days))
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/probation", data = "<form>")]
+async fn admin_probation(admin: Admin, domains: &State<Domains>, form: Csrf<ProbationForm>, locale: &Locale) -> Flash<Redirect> {
+    let probation = Probation { days: form.days, payments: form.payments, percent: form.percent, email: form.email };
+    let result = domains.run_mut(move |d| {
+        d.update_probation(probation)?;
+        d.audit(Some(admin.0), "probation.update", None,
+                &format!("{} days {} payments {}% email {}", probation.days, probation.payments, probation.percent, probation.email))
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/usernames", data = "<form>")]
+async fn admin_username_policy(admin: Admin, domains: &State<Domains>, form: Csrf<UsernamePolicyForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let policy = UsernamePolicy {
+        min_length: form.min_length,
+        max_length: form.max_length,
+        extra_chars: form.extra_chars.to_string(),
+        reserved: form.reserved.split(',').map(str::trim).filter(|r| !r.is_empty()).map(str::to_string).collect(),
+    };
+    let result = domains.run_mut(move |d| {
+        let detail = format!("{}-{} characters, extra {:?}, reserved {}", policy.min_length, policy.max_length, policy.extra_chars, policy.reserved.join(","));
+        d.update_username_policy(policy)?;
+        d.audit(Some(admin.0), "usernames.update", None, &detail)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/messages", data = "<form>")]
+async fn admin_message_rules(admin: Admin, domains: &State<Domains>, form: Csrf<MessageRulesForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let rules = MessageRules {
+        max_length: form.max_length,
+        banned_words: form.banned_words.split(',').map(str::trim).filter(|w| !w.is_empty()).map(str::to_string).collect(),
+        banned_patterns: form.banned_patterns.lines().map(str::trim).filter(|p| !p.is_empty()).map(str::to_string).collect(),
+        strip_urls: form.strip_urls,
+    };
+    if rules.max_length == 0 || !rules.is_valid() {
+        return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "message-rules-invalid"))
+    }
+    let result = domains.run_mut(move |d| {
+        let detail = format!("{} characters, {} banned words, {} patterns, strip urls {}", rules.max_length, rules.banned_words.len(), rules.banned_patterns.len(), rules.strip_urls);
+        d.update_message_rules(rules)?;
+        d.audit(Some(admin.0), "messages.update", None, &detail)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "message-rules-updated")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/settings", data = "<settings>")]
+async fn admin_settings(admin: Admin, domains: &State<Domains>, settings: Csrf<Settings<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let minimal_amount = match domains.read().parse_amount(settings.minimal_amount) {
+        Some(minimal_amount) => minimal_amount,
+        None => return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let (description, join_info) = (settings.description.to_string(), settings.join_info.to_string());
+    let result = domains.run_mut(move |d| {
+        d.update_settings(&description, &join_info, minimal_amount)?;
+        d.audit(Some(admin.0), "settings.update", None, &format!("minimal amount {}", minimal_amount))
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), domain.catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/currency", data = "<form>")]
+async fn admin_currency(admin: Admin, domains: &State<Domains>, form: Csrf<CurrencyForm<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let plural = form.plural.trim();
+    if plural.is_empty() || form.decimals > simplets::CurrencyFormat::MAX_DECIMALS {
+        return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "currency-invalid"))
+    }
+    let format = simplets::CurrencyFormat {
+        singular: Some(form.singular.trim()).filter(|s| !s.is_empty()).unwrap_or(plural).to_string(),
+        plural: plural.to_string(),
+        decimals: form.decimals,
+        decimal_separator: form.decimal_separator.to_string(),
+        thousands_separator: form.thousands_separator.to_string(),
+        time: form.time,
+    };
+    let result = domains.run_mut(move |d| {
+        let detail = if format.time { format!("{} / {}, hours and minutes", format.singular, format.plural) }
+        else { format!("{} / {}, {} decimals", format.singular, format.plural, format.decimals) };
+        d.update_currency(format)?;
+        d.audit(Some(admin.0), "currency.update", None, &detail)
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/system-accounts", data = "<account>")]
+async fn admin_system_account(admin: Admin, domains: &State<Domains>, account: Csrf<SystemAccount<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let name = account.name.trim().to_string();
+    let result = domains.run(move |d| {
+        let id = d.add_system_account(&name)?;
+        d.audit(Some(admin.0), "user.create", Some(id as i64), &name)
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "system-account-created")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/demurrage", data = "<account>")]
+async fn admin_demurrage(admin: Admin, domains: &State<Domains>, account: Csrf<DemurrageAccount>, locale: &Locale) -> Flash<Redirect> {
+    let account = account.account;
+    let result = domains.run(move |d| {
+        d.set_demurrage_account(account)?;
+        d.audit(Some(admin.0), "demurrage.account", Some(account), "")
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/adjustment-account", data = "<account>")]
+async fn admin_adjustment_account(admin: Admin, domains: &State<Domains>, account: Csrf<DemurrageAccount>, locale: &Locale) -> Flash<Redirect> {
+    let account = account.account;
+    let result = domains.run(move |d| {
+        d.set_adjustment_account(account)?;
+        d.audit(Some(admin.0), "adjustment.account", Some(account), "")
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "settings-saved")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[post("/admin/adjustment", data = "<adjustment>")]
+async fn admin_adjustment(admin: Treasurer, domains: &State<Domains>, adjustment: Csrf<Adjustment<'_>>, locale: &Locale) -> Flash<Redirect> {
+    if adjustment.reason.trim().is_empty() || adjustment.reason.len() > 140 {
+        return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "adjustment-reason"))
+    }
+    let amount = match domains.read().currency.parse(adjustment.amount) {
+        Some(amount) => amount,
+        None => return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "amount-invalid"))
+    };
+    let (user, reason) = (adjustment.user.to_string(), adjustment.reason.trim().to_string());
+    let result = domains.run(move |d| {
+        let user = find_user(d, &user)?;
+        d.add_adjustment(admin.0, user.id, amount, &reason)
+    }).await;
+    let domain = domains.read();
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "adjustment-booked")),
+        Err(SimpletsError::Db(Error::QueryReturnedNoRows)) => Flash::error(Redirect::to(uri!(admin)), domain.catalog.get(&locale.0, "user-not-found")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), payment_error(&domain, locale, e)),
+    }
+}
+
+#[post("/admin/bulk", data = "<bulk>")]
+async fn admin_bulk(admin: Treasurer, domains: &State<Domains>, bulk: Csrf<BulkPayment<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let payments = match simplets::parse_payments_csv(bulk.csv) {
+        Ok(p) => p,
+        Err(line) => return Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.format(&locale.0, "bulk-parse-error", &[&line]))
+    };
+    let count = payments.len();
+    let payer = bulk.payer.trim().to_string();
+    let result = domains.run(move |d| {
+        let payer = find_user(d, &payer).map_err(|e| (None, e.into()))?;
+        let mut resolved = Vec::new();
+        for (i, (payee, amount, message)) in payments.into_iter().enumerate() {
+            let payee = find_user(d, &payee).map_err(|e| (Some(i), e.into()))?;
+            resolved.push((payee.id, amount, message));
+        }
+        d.add_payments_bulk(payer.id, resolved).map_err(|(i, e)| (Some(i), e))?;
+        d.audit(Some(admin.0), "payment.bulk", Some(payer.id), &format!("{} payments", count)).map_err(|e| (None, e.into()))
+    }).await;
+    let domain = domains.read();
+    let catalog = &domain.catalog;
+    match result {
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.format(&locale.0, "bulk-success", &[&count])),
+        Err((None, SimpletsError::Db(Error::QueryReturnedNoRows))) => Flash::error(Redirect::to(uri!(admin)), catalog.get(&locale.0, "payer-not-found")),
+        Err((None, e)) => Flash::error(Redirect::to(uri!(admin)), payment_error(&domain, locale, e)),
+        Err((Some(i), e)) => {
+            let reason = match e {
+                SimpletsError::Db(Error::QueryReturnedNoRows) => catalog.get(&locale.0, "payee-not-found").to_string(),
+                e => payment_error(&domain, locale, e)
+            };
+            Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "bulk-line-error", &[&(i + 1), &reason]))
+        }
+    }
+}
+
+#[post("/admin/import", data = "<import>")]
+async fn admin_import_users(admin: MemberManager, domains: &State<Domains>, import: Csrf<UserImport<'_>>, locale: &Locale) -> Flash<Redirect> {
+    let csv = import.csv.to_string();
+    let result = domains.run(move |d| {
+        let ids = d.import_users_csv(csv.as_bytes())?;
+        d.audit(Some(admin.0), "user.import", None, &format!("{} members", ids.len()))?;
+        Ok::<_, ImportError>(ids.len())
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(count) => Flash::success(Redirect::to(uri!(admin)), catalog.format(&locale.0, "import-success", &[&count])),
+        Err(ImportError::Line(line)) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "bulk-parse-error", &[&line])),
+        Err(ImportError::NameTaken(name)) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "import-name-taken", &[&name])),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+#[get("/admin/user-data?<account>")]
+async fn admin_user_data(admin: MemberManager, domains: &State<Domains>, account: i64, locale: &Locale) -> Result<JsonFile, Flash<Redirect>> {
+    let result = domains.run(move |d| {
+        let data = d.export_user_data(account)?;
+        d.audit(Some(admin.0), "user.export", Some(account), "")?;
+        Ok::<_, Error>(data)
+    }).await;
+    match result {
+        Ok(data) => {
+            let filename = format!("attachment; filename=\"{}.json\"", account);
+            Ok(JsonFile(serde_json::to_string_pretty(&data).unwrap_or_default(), Header::new("Content-Disposition", filename)))
+        }
+        Err(Error::QueryReturnedNoRows) => Err(Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.get(&locale.0, "payee-not-found"))),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(admin)), domains.read().catalog.format(&locale.0, "db-error", &[&e]))),
+    }
+}
+
+#[post("/admin/anonymize", data = "<erasure>")]
+async fn admin_anonymize(admin: MemberManager, domains: &State<Domains>, erasure: Csrf<AccountErasure>, locale: &Locale) -> Flash<Redirect> {
+    let account = erasure.account;
+    let result = domains.run(move |d| {
+        let erased = d.anonymize_user(account)?;
+        if erased > 0 { d.audit(Some(admin.0), "user.anonymize", Some(account), "")?; }
+        Ok::<_, Error>(erased)
+    }).await;
+    let catalog = &domains.read().catalog;
+    match result {
+        Ok(0) => Flash::error(Redirect::to(uri!(admin)), catalog.get(&locale.0, "account-not-erasable")),
+        Ok(_) => Flash::success(Redirect::to(uri!(admin)), catalog.get(&locale.0, "account-erased")),
+        Err(e) => Flash::error(Redirect::to(uri!(admin)), catalog.format(&locale.0, "db-error", &[&e])),
+    }
+}
+
+/// Looks the user up by account number, or by name if `key` isn't a number.
+/// The account with the member number or name `key`.
+fn find_user(domain: &Domain, key: &str) -> Result<simplets::User, Error> {
+    match key.trim().parse() {
+        Ok(number) => domain.get_user_by_number(number),
+        Err(_) => domain.get_user_by_name(key.trim())
+    }
+}
+
+#[get("/password", rank = 2)]
+fn no_auth_password() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[allow(clippy::result_large_err)]
+#[rocket::main]
+async fn main() -> Result<(), rocket::Error> {
+    //let rct = rocket::ignite()
+    let rct = rocket::custom(config_figment());
+    init_logging(rct.figment());
+    let name = rct.figment().extract_inner::<String>("domain").unwrap_or_else(|_| "lets".to_string());
+    let mut config = DomainConfig::new(&name);
+    if let Ok(dir) = rct.figment().extract_inner::<String>("data_dir") {
+        config = config.data_dir(dir);
+    }
+    if let Ok(path) = rct.figment().extract_inner::<String>(&format!("domains.{}.db_path", name)) {
+        config = config.db_path(path);
+    }
+    if let Ok(dir) = rct.figment().extract_inner::<String>("attachment_dir") {
+        config = config.attachment_dir(dir);
+    }
+    let mut lets = match config.load() {
+        Ok(domain) => domain,
+        Err(e) => {
+            tracing::error!(error = %e, domain = %name, "cannot open domain");
+            std::process::exit(1)
+        }
+    };
+    if let Ok(dir) = rct.figment().extract_inner::<String>("locale_dir") {
+        lets.catalog.load_dir(dir).expect("read locale dir");
+    }
+    if let Ok(timezone) = rct.figment().extract_inner::<String>("timezone") {
+        lets.timezone = Timezone::parse(&timezone).expect("timezone must be local, utc or an offset like +02:00");
+    }
+    let timezone = lets.timezone;
+    let domains = AsyncDomain::new(lets);
+    {
+        let domains = domains.clone();
+        rocket::tokio::spawn(async move {
+            let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                if domains.read().is_closing() { break }
+                if let Err(e) = domains.run(|d| d.dispatch_outbox(100)).await {
+                    tracing::error!(error = %e, "outbox dispatch failed");
+                }
+            }
+        });
+    }
+    {
+        let domains = domains.clone();
+        rocket::tokio::spawn(async move {
+            let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                if domains.read().is_closing() { break }
+                if let Err(e) = domains.run(|d| d.expire_pending_payments()).await {
+                    tracing::error!(error = %e, "expiring pending payments failed");
+                }
+            }
+        });
+    }
+    #[cfg(feature = "email")]
+    if let Ok(host) = rct.figment().extract_inner::<String>("smtp_host") {
+        let figment = rct.figment();
+        let port = figment.extract_inner::<u16>("smtp_port").ok();
+        let credentials = figment.extract_inner::<String>("smtp_user").ok()
+            .map(|u| (u, figment.extract_inner::<String>("smtp_password").unwrap_or_default()));
+        let from = figment.extract_inner::<String>("smtp_from").expect("smtp_from must be set with smtp_host");
+        let mailer = std::sync::Arc::new(simplets::notify::Mailer::new(&host, port, credentials, &from).expect("smtp config"));
+        let domains = domains.clone();
+        rocket::tokio::spawn(async move {
+            let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                if domains.read().is_closing() { break }
+                let mailer = mailer.clone();
+                if let Err(e) = domains.run(move |d| mailer.deliver(d, 100)).await {
+                    tracing::error!(error = %e, "notification queue failed");
+                }
+            }
+        });
+    }
+    #[cfg(feature = "webhooks")]
+    {
+        let dispatcher = std::sync::Arc::new(simplets::webhook::Dispatcher::default());
+        let domains = domains.clone();
+        rocket::tokio::spawn(async move {
+            let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs(15));
+            loop {
+                interval.tick().await;
+                if domains.read().is_closing() { break }
+                let dispatcher = dispatcher.clone();
+                if let Err(e) = domains.run(move |d| dispatcher.deliver(d, 100)).await {
+                    tracing::error!(error = %e, "webhook queue failed");
+                }
+            }
+        });
+    }
+    #[cfg(feature = "federation")]
+    {
+        let client = std::sync::Arc::new(simplets::federation::Client::default());
+        let domains = domains.clone();
+        rocket::tokio::spawn(async move {
+            let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs(15));
+            loop {
+                interval.tick().await;
+                if domains.read().is_closing() { break }
+                let client = client.clone();
+                if let Err(e) = domains.run(move |d| client.deliver(d, 100)).await {
+                    tracing::error!(error = %e, "federation queue failed");
+                }
+            }
+        });
+    }
+    let backup_key = rct.figment().extract_inner::<String>("backup_key").ok()
+        .map(|k| simplets::backup::parse_key(&k).expect("backup_key must be 64 hex digits"));
+    if let Ok(dir) = rct.figment().extract_inner::<String>("backup_dir") {
+        // minutes between snapshots and how many to keep
+        let minutes = rct.figment().extract_inner::<u64>("backup_interval").unwrap_or(24 * 60).max(1);
+        let keep = rct.figment().extract_inner::<usize>("backup_keep").unwrap_or(7);
+        let domains = domains.clone();
+        rocket::tokio::spawn(async move {
+            let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs(minutes * 60));
+            loop {
+                interval.tick().await;
+                if domains.read().is_closing() { break }
+                let dir = std::path::PathBuf::from(&dir);
+                match domains.run(move |d| simplets::backup::scheduled(d, &dir, backup_key.as_ref(), keep)).await {
+                    Ok(path) => tracing::info!(path = %path.display(), "backup written"),
+                    Err(e) => tracing::error!(error = %e, "scheduled backup failed"),
+                }
+            }
+        });
+    }
+    let rct = rct
+        .attach(Template::custom(move |engines| {
+            engines.handlebars.register_helper("csrf_field", Box::new(csrf_field));
+            engines.handlebars.register_helper("idempotency_field", Box::new(idempotency_field));
+            engines.handlebars.register_helper("format_amount", Box::new(format_amount));
+            engines.handlebars.register_helper("input_amount", Box::new(input_amount));
+            engines.handlebars.register_helper("amount_step", Box::new(amount_step));
+            engines.handlebars.register_helper("amount_type", Box::new(amount_type));
+            engines.handlebars.register_helper("local", Box::new(move |h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext,
+                                                                      out: &mut dyn Output| local_time(timezone, h, out)));
+        }))
+        .attach(LocaleFairing)
+        .attach(RequestLog::default())
+        .attach(Drain)
+        .manage(domains)
+        //.mount("/", routes![no_auth_index])
+        .mount("/", routes![index, no_auth_index, login, login_page, post_login, logout, payment, no_auth_payment, trustlines, no_auth_trustlines, set_trustline, groups, no_auth_groups, create_group, add_manager, remove_manager, split, no_auth_split, post_split, password, no_auth_password, password_page,
+            requests, no_auth_requests, statement, no_auth_statement, statement_pdf, no_auth_statement_pdf, suggest_users, payment_preview, about, receipt, my_receipt, attachments, no_auth_attachments, attach_file, attachment, delete_message_template, api_me, api_stats, api_payment, create_request, accept_request, decline_request, pending, no_auth_pending, confirm_payment, reject_payment, escrows, no_auth_escrows, open_escrow, settle_escrow, set_locale,
+            admin, admin_audit, admin_user, admin_limit_override, admin_freeze, admin_role, admin_unfreeze, admin_begin_closure, admin_cancel_closure, admin_closures, admin_events, admin_open_event, admin_close_event, admin_event, admin_dormant, admin_dormant_action, admin_turnover, admin_health, healthz, readyz, admin_backup, admin_archive, admin_federation, admin_add_peer, admin_exchange_rate, admin_reconcile, admin_categories, admin_leaderboard, admin_branding, admin_privacy, admin_settings, admin_currency, admin_limits, admin_limit_window, admin_confirmation, admin_resolve_escrow, admin_probation, admin_username_policy, admin_message_rules, admin_bulk, admin_import_users, admin_user_data, admin_anonymize, admin_system_account, admin_demurrage, admin_adjustment_account, admin_adjustment, admin_fees, admin_webhook, admin_delete_webhook, admin_announcement, admin_delete_announcement, admin_terms, terms, accept_terms,
+            listings, no_auth_listings, create_listing, listing, update_listing, close_listing, messages, no_auth_messages, conversation, send_message,
+            notifications, no_auth_notifications, post_notifications, verify_email, reset_page, post_reset, reset_token_page, post_reset_token,
+            login_totp_page, post_login_totp, totp, no_auth_totp, totp_enable, totp_confirm, totp_disable,
+            sessions, no_auth_sessions, revoke_other_sessions, revoke_session, unlink_identity, activity, no_auth_activity,
+            profile, no_auth_profile, post_profile, leaderboard, no_auth_leaderboard, post_leaderboard, profile_data, no_auth_profile_data, erase_account, member, no_auth_member, add_contact, remove_contact, block_user, unblock_user, members, no_auth_members, qr, pay, no_auth_pay,
+            vouchers, no_auth_vouchers, issue_voucher, redeem_voucher, cancel_voucher,
+            federation, no_auth_federation, post_federation, federation_transfer, federation_balance]);
+
+    let public_url = rct.figment().extract_inner::<String>("public_url").unwrap_or_else(|_| "http://localhost:8000".to_string());
+    #[cfg(feature = "oidc")]
+    let rct = match rct.figment().extract_inner::<String>("oidc_issuer") {
+        Ok(issuer) => {
+            let figment = rct.figment();
+            let client_id = figment.extract_inner::<String>("oidc_client_id").expect("oidc_client_id must be set with oidc_issuer");
+            let client_secret = figment.extract_inner::<String>("oidc_client_secret").unwrap_or_default();
+            let redirect_url = format!("{}/login/oidc/callback", public_url.trim_end_matches('/'));
+            let provider = simplets::oidc::Provider::new(&issuer, &client_id, &client_secret, &redirect_url);
+            rct.manage(Oidc(std::sync::Arc::new(provider))).mount("/", routes![oidc_login, oidc_callback])
+        }
+        Err(_) => rct,
+    };
     let conf: Result<Vec<String>, figment::Error> = rct.figment().extract_inner("template_dir");
-    let _result = rct.manage(TemplateDir(if let Ok(dir) = conf {!dir.is_empty()} else {false}))
+    let _result = rct.manage(PublicUrl(public_url))
+        .manage(BackupKey(backup_key))
+        .manage(TemplateDir(if let Ok(dir) = conf {!dir.is_empty()} else {false}))
         .launch().await?;
     Ok(())
 }
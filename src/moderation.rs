@@ -0,0 +1,85 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Moderation of payment messages, applied by `Domain::check_message` wherever a message is
+//! booked or stored for later, so API and CLI callers can't bypass it.
+//!
+//! The domain's `MessageRules` come from its settings. A server can plug in its own
+//! `MessagePolicy` on top, e.g. a spam filter, with `Domain::set_message_policy`.
+
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use crate::SimpletsError;
+
+/// Checks a payment message and returns it as it should be kept, possibly changed.
+pub trait MessagePolicy: Send + Sync {
+    fn apply(&self, message: &str) -> Result<String, SimpletsError>;
+}
+
+/// The built-in policy an admin sets up.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageRules {
+    /// Most characters a message may have, after stripping links.
+    pub max_length: usize,
+    /// Words refused in any case, only as whole words.
+    pub banned_words: Vec<String>,
+    /// Regular expressions of refused messages, matched anywhere in the message.
+    pub banned_patterns: Vec<String>,
+    /// Removes web addresses instead of refusing the message.
+    pub strip_urls: bool,
+}
+
+impl Default for MessageRules {
+    fn default() -> Self {
+        MessageRules { max_length: 140, banned_words: Vec::new(), banned_patterns: Vec::new(), strip_urls: false }
+    }
+}
+
+impl MessageRules {
+    /// Whether every banned pattern is a valid regular expression.
+    pub fn is_valid(&self) -> bool {
+        self.banned_patterns.iter().all(|p| Regex::new(p).is_ok())
+    }
+
+    fn banned(&self, message: &str) -> Result<bool, SimpletsError> {
+        if !self.banned_words.is_empty() {
+            let words = self.banned_words.iter().map(|w| regex::escape(w)).collect::<Vec<_>>().join("|");
+            let words = RegexBuilder::new(&format!(r"\b(?:{})\b", words)).case_insensitive(true).build()
+                .map_err(|_| SimpletsError::Internal)?;
+            if words.is_match(message) { return Ok(true) }
+        }
+        for pattern in self.banned_patterns.iter() {
+            if Regex::new(pattern).map_err(|_| SimpletsError::Internal)?.is_match(message) { return Ok(true) }
+        }
+        Ok(false)
+    }
+}
+
+impl MessagePolicy for MessageRules {
+    fn apply(&self, message: &str) -> Result<String, SimpletsError> {
+        let mut message = message.trim().to_string();
+        if self.strip_urls {
+            let urls = Regex::new(r"(?i)\b(?:[a-z][a-z0-9+.-]*://|www\.)\S+").map_err(|_| SimpletsError::Internal)?;
+            message = urls.replace_all(&message, "").split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        if message.chars().count() > self.max_length {
+            return Err(SimpletsError::MessageTooLong { maximum: self.max_length as u64 })
+        }
+        if self.banned(&message)? { return Err(SimpletsError::MessageRefused) }
+        Ok(message)
+    }
+}
@@ -0,0 +1,70 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use rusqlite::Result;
+use crate::Domain;
+
+/// Sends queued notifications over SMTP.
+pub struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Mailer {
+    /// Connects with STARTTLS to `host`, or without TLS when `port` says so (25 or a local relay).
+    pub fn new(host: &str, port: Option<u16>, credentials: Option<(String, String)>, from: &str) -> std::result::Result<Self, String> {
+        let from = from.parse().map_err(|e| format!("invalid sender address: {}", e))?;
+        let mut builder = match port {
+            Some(25) => SmtpTransport::builder_dangerous(host).port(25),
+            Some(p) => SmtpTransport::starttls_relay(host).map_err(|e| e.to_string())?.port(p),
+            None => SmtpTransport::starttls_relay(host).map_err(|e| e.to_string())?,
+        };
+        if let Some((user, password)) = credentials {
+            builder = builder.credentials(Credentials::new(user, password));
+        }
+        Ok(Mailer { transport: builder.build(), from })
+    }
+
+    /// Sends up to `limit` pending notifications and returns how many went out. Failed ones
+    /// stay queued and are retried on the next call until they run out of attempts.
+    pub fn deliver(&self, domain: &Domain, limit: u32) -> Result<usize> {
+        let mut sent = 0;
+        for notification in domain.pending_notifications(limit)? {
+            let message = notification.email.parse().map_err(|e| format!("{}", e))
+                .and_then(|to| Message::builder()
+                    .from(self.from.clone())
+                    .to(to)
+                    .subject(&notification.subject)
+                    .body(notification.body.clone())
+                    .map_err(|e| e.to_string()));
+            match message.and_then(|m| self.transport.send(&m).map_err(|e| e.to_string())) {
+                Ok(_) => {
+                    domain.mark_notification_sent(notification.id)?;
+                    sent += 1;
+                }
+                Err(e) => {
+                    tracing::warn!(notification = notification.id, email = %notification.email, error = %e, "notification failed");
+                    domain.mark_notification_failed(notification.id)?;
+                }
+            }
+        }
+        Ok(sent)
+    }
+}
@@ -0,0 +1,193 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Logging in through an OpenID Connect provider the community already runs (Keycloak,
+//! Nextcloud, ...) with the authorization code flow. `Provider::authorization_url` sends the
+//! member there, the provider returns with a code that `Provider::exchange` trades for the ID
+//! token. The provider's subject is mapped to a local account by `Domain::link_identity`.
+//!
+//! The ID token comes straight from the token endpoint over TLS, so `claims` checks its issuer,
+//! audience, expiry and nonce but not its signature, as OpenID Connect Core 3.1.3.7 allows.
+
+use serde::Deserialize;
+
+/// Why logging in through the provider failed.
+#[derive(Debug)]
+pub enum OidcError {
+    /// The provider couldn't be reached or answered something unexpected.
+    Unreachable(String),
+    /// The provider answered with an error status.
+    Refused(u16, String),
+    /// The ID token is malformed or not meant for this login.
+    InvalidToken(&'static str),
+}
+
+impl std::fmt::Display for OidcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OidcError::Unreachable(e) => write!(f, "provider unreachable: {}", e),
+            OidcError::Refused(status, body) => write!(f, "provider refused with {}: {}", status, body),
+            OidcError::InvalidToken(reason) => write!(f, "invalid ID token: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for OidcError {}
+
+/// Who the provider says logged in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claims {
+    pub issuer: String,
+    pub subject: String,
+    /// Only given when the provider verified the address.
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IdToken {
+    iss: String,
+    sub: String,
+    /// A client id or a list of them.
+    aud: serde_json::Value,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+/// The claims of `id_token` if it was issued by `issuer` for `client_id` with `nonce` and hasn't
+/// expired at `now` (Unix time).
+pub fn claims(id_token: &str, issuer: &str, client_id: &str, nonce: &str, now: i64) -> Result<Claims, OidcError> {
+    let payload = id_token.split('.').nth(1).ok_or(OidcError::InvalidToken("not a JWT"))?;
+    let payload = base64url_decode(payload).ok_or(OidcError::InvalidToken("payload isn't base64url"))?;
+    let token: IdToken = serde_json::from_slice(&payload).map_err(|_| OidcError::InvalidToken("payload isn't an ID token"))?;
+    if token.iss != issuer { return Err(OidcError::InvalidToken("issuer differs")) }
+    let audience = match &token.aud {
+        serde_json::Value::String(aud) => aud == client_id,
+        serde_json::Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(client_id)),
+        _ => false,
+    };
+    if !audience { return Err(OidcError::InvalidToken("issued for another client")) }
+    if token.exp <= now { return Err(OidcError::InvalidToken("expired")) }
+    if token.nonce.as_deref() != Some(nonce) { return Err(OidcError::InvalidToken("nonce differs")) }
+    Ok(Claims { issuer: token.iss, subject: token.sub, email: token.email.filter(|_| token.email_verified) })
+}
+
+/// Decodes unpadded base64url as used in JWTs. `None` on other characters.
+fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(feature = "oidc")]
+pub use client::Provider;
+
+#[cfg(feature = "oidc")]
+mod client {
+    use std::sync::OnceLock;
+    use std::time::Duration;
+    use serde::Deserialize;
+    use crate::percent_encode;
+    use super::{claims, Claims, OidcError};
+
+    #[derive(Deserialize)]
+    struct Metadata {
+        authorization_endpoint: String,
+        token_endpoint: String,
+    }
+
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        id_token: String,
+    }
+
+    /// A client registered at the provider. The provider's endpoints are discovered on first use.
+    pub struct Provider {
+        issuer: String,
+        client_id: String,
+        client_secret: String,
+        redirect_url: String,
+        metadata: OnceLock<Metadata>,
+        agent: ureq::Agent,
+    }
+
+    impl Provider {
+        /// `redirect_url` is where the provider sends the member back, registered with the client.
+        pub fn new(issuer: &str, client_id: &str, client_secret: &str, redirect_url: &str) -> Self {
+            Provider {
+                issuer: issuer.to_string(),
+                client_id: client_id.to_string(),
+                client_secret: client_secret.to_string(),
+                redirect_url: redirect_url.to_string(),
+                metadata: OnceLock::new(),
+                agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            }
+        }
+
+        fn metadata(&self) -> Result<&Metadata, OidcError> {
+            if let Some(metadata) = self.metadata.get() { return Ok(metadata) }
+            let url = format!("{}/.well-known/openid-configuration", self.issuer.trim_end_matches('/'));
+            let metadata = json(self.agent.get(&url).call())?;
+            Ok(self.metadata.get_or_init(|| metadata))
+        }
+
+        /// Where to send the member to log in. `state` comes back with the code, `nonce` in the
+        /// ID token.
+        pub fn authorization_url(&self, state: &str, nonce: &str) -> Result<String, OidcError> {
+            let endpoint = &self.metadata()?.authorization_endpoint;
+            Ok(format!("{}{}response_type=code&scope=openid%20email&client_id={}&redirect_uri={}&state={}&nonce={}", endpoint,
+                       if endpoint.contains('?') { '&' } else { '?' }, percent_encode(&self.client_id),
+                       percent_encode(&self.redirect_url), percent_encode(state), percent_encode(nonce)))
+        }
+
+        /// Trades the code the provider sent the member back with for the claims of the ID token.
+        pub fn exchange(&self, code: &str, nonce: &str) -> Result<Claims, OidcError> {
+            let response: TokenResponse = json(self.agent.post(&self.metadata()?.token_endpoint)
+                .send_form(&[("grant_type", "authorization_code"), ("code", code), ("redirect_uri", &self.redirect_url),
+                    ("client_id", &self.client_id), ("client_secret", &self.client_secret)]))?;
+            claims(&response.id_token, &self.issuer, &self.client_id, nonce, chrono::Utc::now().timestamp())
+        }
+    }
+
+    fn json<T: serde::de::DeserializeOwned>(response: Result<ureq::Response, ureq::Error>) -> Result<T, OidcError> {
+        let body = match response {
+            Ok(r) => r.into_string().map_err(|e| OidcError::Unreachable(e.to_string()))?,
+            Err(ureq::Error::Status(status, r)) => return Err(OidcError::Refused(status, r.into_string().unwrap_or_default())),
+            Err(e) => return Err(OidcError::Unreachable(e.to_string())),
+        };
+        serde_json::from_str(&body).map_err(|e| OidcError::Unreachable(e.to_string()))
+    }
+}
@@ -0,0 +1,147 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Just enough PDF to print statements: A4 pages of text in the standard Helvetica and Courier
+//! fonts, which every viewer provides, so no font needs embedding. Text is encoded as WinAnsi
+//! with the Czech and Slovak letters it lacks put on the codes 128 to 159.
+
+pub const PAGE_WIDTH: f64 = 595.28;
+pub const PAGE_HEIGHT: f64 = 841.89;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Font {
+    Regular,
+    Bold,
+    /// Courier, every glyph is 0.6 of the size wide, see `mono_width`.
+    Mono,
+}
+
+impl Font {
+    fn resource(self) -> &'static str {
+        match self {
+            Font::Regular => "F1",
+            Font::Bold => "F2",
+            Font::Mono => "F3",
+        }
+    }
+}
+
+/// Letters outside Latin-1 and the glyph names the standard fonts know them by.
+const EXTRA_GLYPHS: [(char, &str); 25] = [
+    ('Č', "Ccaron"), ('č', "ccaron"), ('Ď', "Dcaron"), ('ď', "dcaron"), ('Ě', "Ecaron"), ('ě', "ecaron"),
+    ('Ň', "Ncaron"), ('ň', "ncaron"), ('Ř', "Rcaron"), ('ř', "rcaron"), ('Š', "Scaron"), ('š', "scaron"),
+    ('Ť', "Tcaron"), ('ť', "tcaron"), ('Ů', "Uring"), ('ů', "uring"), ('Ž', "Zcaron"), ('ž', "zcaron"),
+    ('Ľ', "Lcaron"), ('ľ', "lcaron"), ('Ĺ', "Lacute"), ('ĺ', "lacute"), ('Ŕ', "Racute"), ('ŕ', "racute"),
+    ('€', "Euro"),
+];
+
+/// Pages being drawn, coordinates in points from the bottom left corner.
+#[derive(Debug, Default)]
+pub struct Document {
+    pages: Vec<String>,
+    current: usize,
+}
+
+impl Document {
+    /// Adds a page, the following drawing goes on it.
+    pub fn add_page(&mut self) {
+        self.pages.push(String::new());
+        self.current = self.pages.len() - 1;
+    }
+
+    /// Continues drawing on an earlier page, e.g. to number the pages at the end.
+    pub fn select_page(&mut self, index: usize) {
+        self.current = index.min(self.pages.len().saturating_sub(1));
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn text(&mut self, x: f64, y: f64, font: Font, size: f64, text: &str) {
+        let content = self.content();
+        content.push_str(&format!("BT /{} {:.1} Tf {:.2} {:.2} Td (", font.resource(), size, x, y));
+        for byte in encode(text) {
+            match byte {
+                b'(' | b')' | b'\\' => { content.push('\\'); content.push(byte as char); }
+                0x20..=0x7e => content.push(byte as char),
+                _ => content.push_str(&format!("\\{:03o}", byte)),
+            }
+        }
+        content.push_str(") Tj ET\n");
+    }
+
+    /// Text in `Font::Mono` ending at `right`, for columns of numbers.
+    pub fn text_right(&mut self, right: f64, y: f64, size: f64, text: &str) {
+        self.text(right - mono_width(text, size), y, Font::Mono, size, text);
+    }
+
+    pub fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) {
+        self.content().push_str(&format!("0.5 w {:.2} {:.2} m {:.2} {:.2} l S\n", x1, y1, x2, y2));
+    }
+
+    fn content(&mut self) -> &mut String {
+        if self.pages.is_empty() { self.add_page() }
+        &mut self.pages[self.current]
+    }
+
+    /// Writes out the PDF file.
+    pub fn finish(&self) -> Vec<u8> {
+        let pages = if self.pages.is_empty() { vec![String::new()] } else { self.pages.clone() };
+        let differences: Vec<String> = EXTRA_GLYPHS.iter().map(|(_, name)| format!("/{}", name)).collect();
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!("<< /Type /Pages /Kids [{}] /Count {} >>",
+                    (0..pages.len()).map(|i| format!("{} 0 R", 7 + 2 * i)).collect::<Vec<_>>().join(" "), pages.len()),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding 6 0 R >>".to_string(),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding 6 0 R >>".to_string(),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Courier /Encoding 6 0 R >>".to_string(),
+            format!("<< /Type /Encoding /BaseEncoding /WinAnsiEncoding /Differences [128 {}] >>", differences.join(" ")),
+        ];
+        for (i, content) in pages.iter().enumerate() {
+            objects.push(format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+            /Resources << /Font << /F1 3 0 R /F2 4 0 R /F3 5 0 R >> >> /Contents {} 0 R >>", PAGE_WIDTH, PAGE_HEIGHT, 8 + 2 * i));
+            objects.push(format!("<< /Length {} >>\nstream\n{}endstream", content.len(), content));
+        }
+        let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+        }
+        let xref = pdf.len();
+        pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for offset in offsets {
+            pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        pdf.extend_from_slice(format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).as_bytes());
+        pdf
+    }
+}
+
+/// Width of `text` in `Font::Mono`.
+pub fn mono_width(text: &str, size: f64) -> f64 {
+    text.chars().count() as f64 * 0.6 * size
+}
+
+/// The text in the document's encoding, characters it can't show become `?`.
+pub fn encode(text: &str) -> Vec<u8> {
+    text.chars().map(|c| match c as u32 {
+        0x20..=0x7e | 0xa0..=0xff => c as u8,
+        _ => EXTRA_GLYPHS.iter().position(|(g, _)| *g == c).map(|i| 128 + i as u8).unwrap_or(b'?'),
+    }).collect()
+}
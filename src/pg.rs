@@ -0,0 +1,369 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! PostgreSQL `Ledger` for communities that outgrow a single SQLite file or run several servers.
+
+use std::cell::RefCell;
+use postgres::{Client, NoTls, Row};
+use rusqlite::{ffi, Connection, Error, Result};
+use crate::amount::sql_amount;
+use crate::{Amount, Booking, Direction, Ledger, Payment, PaymentFilter, User, ESCROW_DISPUTED, ESCROW_OPEN, PAYMENT_REGULAR};
+
+/// Schema version `migrate` brings the database to.
+pub const SCHEMA_VERSION: i32 = 9;
+
+/// Accounts and payments of one domain in a Postgres database. Errors of the server are
+/// reported as a generic `Error::SqliteFailure` carrying its message, a missing row as
+/// `Error::QueryReturnedNoRows`, so the payment logic handles them the same as with SQLite.
+pub struct PgLedger {
+    client: RefCell<Client>,
+}
+
+impl PgLedger {
+    /// Connects with a libpq style string, e.g. `host=localhost user=simplets dbname=lets`.
+    pub fn connect(params: &str) -> Result<PgLedger> {
+        let client = Client::connect(params, NoTls).map_err(pg_error)?;
+        Ok(PgLedger { client: RefCell::new(client) })
+    }
+
+    /// Creates or upgrades the tables, like `Domain::init_database` does for SQLite.
+    pub fn migrate(&self) -> Result<()> {
+        let mut client = self.client.borrow_mut();
+        client.batch_execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)").map_err(pg_error)?;
+        let version: Option<i32> = client.query_opt("SELECT version FROM schema_version", &[]).map_err(pg_error)?
+            .map(|row| row.get(0));
+        if version.is_none() {
+            client.execute("INSERT INTO schema_version (version) VALUES (0)", &[]).map_err(pg_error)?;
+        }
+        let version = version.unwrap_or(0);
+        if version > SCHEMA_VERSION {
+            return Err(failure(format!("schema version {} is newer than this build", version)))
+        }
+        if version < 1 {
+            client.batch_execute("
+                CREATE TABLE account (
+                    id              BIGINT PRIMARY KEY,
+                    name            TEXT,
+                    credit          BIGINT NOT NULL,
+                    payments_in     BIGINT NOT NULL,
+                    payments_out    BIGINT NOT NULL,
+                    password        TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    permission      BIGINT NOT NULL,
+                    active          BOOLEAN NOT NULL DEFAULT TRUE,
+                    account_type    BIGINT NOT NULL DEFAULT 0,
+                    email           TEXT,
+                    notify          BIGINT NOT NULL DEFAULT 0
+                );
+                CREATE TABLE payment (
+                    id              BIGSERIAL PRIMARY KEY,
+                    payer           BIGINT NOT NULL REFERENCES account(id),
+                    payee           BIGINT NOT NULL REFERENCES account(id),
+                    amount          BIGINT NOT NULL,
+                    created         TEXT NOT NULL,
+                    message         TEXT NOT NULL,
+                    kind            BIGINT NOT NULL DEFAULT 0,
+                    parent          BIGINT REFERENCES payment(id),
+                    category        TEXT
+                );
+                CREATE INDEX payment_payer ON payment (payer);
+                CREATE INDEX payment_payee ON payment (payee);
+                CREATE TABLE trustline (
+                    truster         BIGINT NOT NULL REFERENCES account(id),
+                    trustee         BIGINT NOT NULL REFERENCES account(id),
+                    extra_limit     BIGINT NOT NULL,
+                    created         TEXT NOT NULL,
+                    PRIMARY KEY(truster, trustee)
+                );
+                UPDATE schema_version SET version = 1;").map_err(pg_error)?;
+        }
+        if version < 2 {
+            client.batch_execute("
+                CREATE TABLE limit_override (
+                    account         BIGINT PRIMARY KEY REFERENCES account(id),
+                    send_bonus      BIGINT NOT NULL,
+                    receive_bonus   BIGINT NOT NULL,
+                    expiry          TEXT,
+                    created         TEXT NOT NULL
+                );
+                UPDATE schema_version SET version = 2;").map_err(pg_error)?;
+        }
+        if version < 3 {
+            client.batch_execute("
+                CREATE TABLE escrow (
+                    id              BIGSERIAL PRIMARY KEY,
+                    payer           BIGINT NOT NULL REFERENCES account(id),
+                    payee           BIGINT NOT NULL REFERENCES account(id),
+                    amount          BIGINT NOT NULL,
+                    message         TEXT NOT NULL,
+                    created         TEXT NOT NULL,
+                    status          BIGINT NOT NULL,
+                    settled         TEXT,
+                    payment         BIGINT REFERENCES payment(id)
+                );
+                UPDATE schema_version SET version = 3;").map_err(pg_error)?;
+        }
+        if version < 4 {
+            client.batch_execute("
+                CREATE TABLE account_freeze (
+                    account         BIGINT PRIMARY KEY REFERENCES account(id),
+                    reason          TEXT NOT NULL,
+                    incoming        BOOLEAN NOT NULL,
+                    created         TEXT NOT NULL
+                );
+                UPDATE schema_version SET version = 4;").map_err(pg_error)?;
+        }
+        if version < 5 {
+            client.batch_execute("
+                CREATE TABLE account_closure (
+                    account         BIGINT PRIMARY KEY REFERENCES account(id),
+                    started         TEXT NOT NULL,
+                    closed          TEXT
+                );
+                UPDATE schema_version SET version = 5;").map_err(pg_error)?;
+        }
+        if version < 6 {
+            client.batch_execute("
+                ALTER TABLE account ADD COLUMN member_number BIGINT;
+                UPDATE account SET member_number = id;
+                ALTER TABLE account ALTER COLUMN member_number SET NOT NULL;
+                CREATE UNIQUE INDEX account_member_number ON account(member_number);
+                UPDATE schema_version SET version = 6;").map_err(pg_error)?;
+        }
+        if version < 7 {
+            // times were stored in the server's zone, they are UTC from now on
+            client.batch_execute("
+                CREATE FUNCTION pg_temp.to_utc(t TEXT) RETURNS TEXT AS $$
+                    SELECT to_char((t::timestamp AT TIME ZONE current_setting('TimeZone')) AT TIME ZONE 'UTC',
+                                   'YYYY-MM-DD HH24:MI:SS')
+                $$ LANGUAGE SQL;
+                UPDATE account SET created = pg_temp.to_utc(created);
+                UPDATE payment SET created = pg_temp.to_utc(created);
+                UPDATE trustline SET created = pg_temp.to_utc(created);
+                UPDATE limit_override SET expiry = pg_temp.to_utc(expiry);
+                UPDATE limit_override SET created = pg_temp.to_utc(created);
+                UPDATE escrow SET created = pg_temp.to_utc(created);
+                UPDATE escrow SET settled = pg_temp.to_utc(settled);
+                UPDATE account_freeze SET created = pg_temp.to_utc(created);
+                UPDATE account_closure SET started = pg_temp.to_utc(started);
+                UPDATE account_closure SET closed = pg_temp.to_utc(closed);
+                UPDATE schema_version SET version = 7;").map_err(pg_error)?;
+        }
+        if version < 8 {
+            client.batch_execute("
+                ALTER TABLE account ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+                UPDATE schema_version SET version = 8;").map_err(pg_error)?;
+        }
+        if version < 9 {
+            client.batch_execute("
+                ALTER TABLE account ADD COLUMN role TEXT NOT NULL DEFAULT 'member';
+                UPDATE account SET role = 'admin' WHERE permission >= 2;
+                ALTER TABLE account DROP COLUMN permission;
+                UPDATE schema_version SET version = 9;").map_err(pg_error)?;
+        }
+        Ok(())
+    }
+
+    /// Runs `f` in a transaction, committed if it returns `Ok`. Run transfers through
+    /// `Domain::book_transfer` inside it.
+    pub fn transaction<T, E: From<Error>>(&self, f: impl FnOnce(&PgLedger) -> std::result::Result<T, E>) -> std::result::Result<T, E> {
+        self.client.borrow_mut().batch_execute("BEGIN ISOLATION LEVEL SERIALIZABLE").map_err(pg_error)?;
+        match f(self) {
+            Ok(v) => {
+                self.client.borrow_mut().batch_execute("COMMIT").map_err(pg_error)?;
+                Ok(v)
+            }
+            Err(e) => {
+                self.client.borrow_mut().batch_execute("ROLLBACK").map_err(pg_error)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Copies the accounts, payments and trustlines of an SQLite domain into the empty tables
+    /// in one transaction. Returns how many accounts and payments were copied.
+    pub fn import_sqlite(&self, conn: &Connection) -> Result<(usize, usize)> {
+        self.transaction(|pg| {
+            let mut client = pg.client.borrow_mut();
+            let users = {
+                let mut stmt = conn.prepare("SELECT * FROM user")?;
+                let iter = stmt.query_map([], crate::user_from_row)?;
+                iter.collect::<Result<Vec<User>>>()?
+            };
+            for u in users.iter() {
+                client.execute("INSERT INTO account (id, name, credit, payments_in, payments_out, password, created, role, \
+                active, account_type, email, notify, member_number, email_verified) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
+                               &[&u.id, &u.name, &u.credit, &(u.payments_in as i64), &(u.payments_out as i64), &u.password, &u.created,
+                                 &u.role.as_str(), &u.active, &u.account_type, &u.email, &u.notify, &(u.member_number as i64), &u.email_verified]).map_err(pg_error)?;
+            }
+            let payments = {
+                let mut stmt = conn.prepare("SELECT * FROM payment ORDER BY id")?;
+                let iter = stmt.query_map([], crate::payment_from_row)?;
+                iter.collect::<Result<Vec<Payment>>>()?
+            };
+            for p in payments.iter() {
+                client.execute("INSERT INTO payment (id, payer, payee, amount, created, message, kind, parent, category) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                               &[&(p.id as i64), &(p.payer as i64), &(p.payee as i64), &(p.amount as i64), &p.created, &p.message,
+                                 &p.kind, &p.parent, &p.category]).map_err(pg_error)?;
+            }
+            client.batch_execute("SELECT setval('payment_id_seq', COALESCE((SELECT MAX(id) FROM payment), 0) + 1, false)").map_err(pg_error)?;
+            let mut stmt = conn.prepare("SELECT truster, trustee, extra_limit, created FROM trustline")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let (truster, trustee, extra, created): (i64, i64, i64, String) = (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+                client.execute("INSERT INTO trustline (truster, trustee, extra_limit, created) VALUES ($1, $2, $3, $4)",
+                               &[&truster, &trustee, &extra, &created]).map_err(pg_error)?;
+            }
+            Ok((users.len(), payments.len()))
+        })
+    }
+
+    fn query_one(&self, sql: &str, params: &[&(dyn postgres::types::ToSql + Sync)]) -> Result<Row> {
+        self.client.borrow_mut().query_opt(sql, params).map_err(pg_error)?.ok_or(Error::QueryReturnedNoRows)
+    }
+}
+
+impl Ledger for PgLedger {
+    fn user(&self, id: i64) -> Result<User> {
+        self.query_one("SELECT * FROM account WHERE id = $1", &[&id]).map(|row| user_from_row(&row))
+    }
+
+    fn user_by_name(&self, name: &str) -> Result<User> {
+        self.query_one("SELECT * FROM account WHERE name = $1", &[&name]).map(|row| user_from_row(&row))
+    }
+
+    fn users(&self) -> Result<Vec<User>> {
+        let rows = self.client.borrow_mut().query("SELECT * FROM account", &[]).map_err(pg_error)?;
+        Ok(rows.iter().map(user_from_row).collect())
+    }
+
+    fn payments(&self, user: i64, filter: &PaymentFilter) -> Result<Vec<Payment>> {
+        let direction = filter.direction.map(|d| match d {
+            Direction::Incoming => "in",
+            Direction::Outgoing => "out",
+        });
+        let (from, to) = filter.bounds();
+        let rows = self.client.borrow_mut().query("SELECT * FROM payment \
+        WHERE (payer = $1 OR payee = $1) \
+        AND ($2::TEXT IS NULL OR created >= $2) AND ($3::TEXT IS NULL OR created < $3) \
+        AND ($4::BIGINT IS NULL OR payer = $4 OR payee = $4) \
+        AND ($5::BIGINT IS NULL OR amount >= $5) AND ($6::BIGINT IS NULL OR amount <= $6) \
+        AND ($7::TEXT IS NULL OR ($7 = 'in' AND payee = $1) OR ($7 = 'out' AND payer = $1)) \
+        ORDER BY created DESC", &[&user, &from, &to,
+            &filter.counterparty, &filter.min_amount.map(|a| a as i64), &filter.max_amount.map(|a| a as i64), &direction])
+            .map_err(pg_error)?;
+        Ok(rows.iter().map(payment_from_row).collect())
+    }
+
+    fn trustline(&self, a: i64, b: i64) -> Result<u64> {
+        let row = self.query_one("SELECT CASE WHEN COUNT(*) = 2 THEN MIN(extra_limit) ELSE 0 END FROM trustline \
+        WHERE (truster = $1 AND trustee = $2) OR (truster = $2 AND trustee = $1)", &[&a, &b])?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    fn sent_since(&self, user: i64, since: &str) -> Result<Amount> {
+        let row = self.query_one("SELECT COALESCE(SUM(amount), 0)::BIGINT FROM payment WHERE payer = $1 AND kind = $2 \
+        AND created >= $3", &[&user, &PAYMENT_REGULAR, &since])?;
+        sql_amount(row.get::<_, i64>(0))
+    }
+
+    fn turnover(&self, user: i64, months: u64) -> Result<(u64, u64)> {
+        let row = self.query_one("SELECT COUNT(*) FILTER (WHERE payee = $1), COUNT(*) FILTER (WHERE payer = $1) FROM payment \
+        WHERE (payer = $1 OR payee = $1) AND kind = $2 \
+        AND created >= to_char(now() AT TIME ZONE 'UTC' - make_interval(months => $3), 'YYYY-MM-DD HH24:MI:SS')",
+                                 &[&user, &PAYMENT_REGULAR, &(months.min(i32::MAX as u64) as i32)])?;
+        Ok((row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64))
+    }
+
+    fn limit_bonus(&self, user: i64) -> Result<(i64, i64)> {
+        let row = self.client.borrow_mut().query_opt("SELECT send_bonus, receive_bonus FROM limit_override WHERE account = $1 \
+        AND (expiry IS NULL OR expiry > to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'))", &[&user]).map_err(pg_error)?;
+        Ok(row.map(|row| (row.get(0), row.get(1))).unwrap_or((0, 0)))
+    }
+
+    fn escrowed(&self, user: i64) -> Result<Amount> {
+        let row = self.query_one("SELECT COALESCE(SUM(amount), 0)::BIGINT FROM escrow WHERE payer = $1 AND status IN ($2, $3)",
+                                 &[&user, &ESCROW_OPEN, &ESCROW_DISPUTED])?;
+        sql_amount(row.get::<_, i64>(0))
+    }
+
+    fn frozen(&self, user: i64) -> Result<Option<bool>> {
+        let row = self.client.borrow_mut().query_opt("SELECT incoming FROM account_freeze WHERE account = $1", &[&user]).map_err(pg_error)?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    fn closing(&self, user: i64) -> Result<bool> {
+        Ok(self.query_one("SELECT COUNT(*) > 0 FROM account_closure WHERE account = $1 AND closed IS NULL", &[&user])?.get(0))
+    }
+
+    fn book(&self, booking: &Booking) -> Result<Payment> {
+        let mut client = self.client.borrow_mut();
+        let (amount, counter) = (booking.amount.balance().get(), booking.counted as i64);
+        client.execute("UPDATE account SET credit = credit - $1, payments_out = payments_out + $2 WHERE id = $3",
+                       &[&amount, &counter, &booking.payer]).map_err(pg_error)?;
+        client.execute("UPDATE account SET credit = credit + $1, payments_in = payments_in + $2 WHERE id = $3",
+                       &[&amount, &counter, &booking.payee]).map_err(pg_error)?;
+        let row = client.query_one("INSERT INTO payment (payer, payee, amount, created, message, kind, parent, category) \
+        VALUES ($1, $2, $3, COALESCE($8, to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')), $4, $5, $6, $7) RETURNING *",
+                                   &[&booking.payer, &booking.payee, &amount, &booking.message, &booking.kind, &booking.parent, &booking.category,
+                                     &booking.created])
+            .map_err(pg_error)?;
+        Ok(payment_from_row(&row))
+    }
+}
+
+fn pg_error(e: postgres::Error) -> Error {
+    failure(e.to_string())
+}
+
+fn failure(message: String) -> Error {
+    Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_ERROR), Some(message))
+}
+
+fn user_from_row(row: &Row) -> User {
+    User {
+        id: row.get("id"),
+        name: row.get("name"),
+        credit: row.get("credit"),
+        payments_in: row.get::<_, i64>("payments_in") as u64,
+        payments_out: row.get::<_, i64>("payments_out") as u64,
+        password: row.get("password"),
+        created: row.get("created"),
+        role: row.get::<_, &str>("role").parse().unwrap_or_default(),
+        active: row.get("active"),
+        account_type: row.get("account_type"),
+        email: row.get("email"),
+        notify: row.get("notify"),
+        member_number: row.get::<_, i64>("member_number") as u64,
+        email_verified: row.get("email_verified"),
+    }
+}
+
+fn payment_from_row(row: &Row) -> Payment {
+    Payment {
+        id: row.get::<_, i64>("id") as u64,
+        payer: row.get::<_, i64>("payer") as u64,
+        payee: row.get::<_, i64>("payee") as u64,
+        amount: row.get::<_, i64>("amount") as u64,
+        created: row.get("created"),
+        message: row.get("message"),
+        kind: row.get("kind"),
+        parent: row.get("parent"),
+        category: row.get("category"),
+    }
+}
@@ -0,0 +1,181 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! What a user may do beyond their own account. Every user has one `Role`, the `role` table
+//! lists the `Capability`s each role has, starting with `Role::default_capabilities`. The
+//! `Domain` methods acting for a user check the capability with `Domain::can` themselves, the
+//! web guards only keep the pages away from those who couldn't use them.
+//!
+//! Auditors are read-only, see `Role::is_read_only`: whatever the `role` table says, they only
+//! get the capabilities that view.
+
+use std::str::FromStr;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Deserializer, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Member,
+    /// Books and corrects payments for the community.
+    Treasurer,
+    Admin,
+    /// Checks the books without changing anything.
+    Auditor,
+}
+
+/// Something only some roles may do, by the name it has in the `role` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum Capability {
+    /// See every member's balance and payments, the audit log and the reports.
+    ViewAllBalances,
+    /// Reverse a member's payment, see `Domain::reverse_payment`.
+    ReversePayment,
+    /// Book adjustments, bulk payments and escrow decisions.
+    BookAdjustments,
+    /// Freeze, close, import and erase members, override their limits.
+    ManageMembers,
+    /// Change the domain's settings, roles, terms, announcements and peers.
+    ManageSettings,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Member, Role::Treasurer, Role::Admin, Role::Auditor];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Treasurer => "treasurer",
+            Role::Admin => "admin",
+            Role::Auditor => "auditor",
+        }
+    }
+
+    /// Whether users with the role only look. The `Domain` refuses payments from and to them,
+    /// their payment requests, vouchers, listings and messages, and every capability that
+    /// changes something.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Role::Auditor)
+    }
+
+    /// What the role may do in a new domain, the `role` table can change it.
+    pub fn default_capabilities(&self) -> &'static [Capability] {
+        match self {
+            Role::Member => &[],
+            Role::Treasurer => &[Capability::ViewAllBalances, Capability::ReversePayment, Capability::BookAdjustments],
+            Role::Admin => &Capability::ALL,
+            Role::Auditor => &[Capability::ViewAllBalances],
+        }
+    }
+}
+
+impl Capability {
+    /// In the order of the discriminants, see `from_index`.
+    pub const ALL: [Capability; 5] = [Capability::ViewAllBalances, Capability::ReversePayment, Capability::BookAdjustments,
+        Capability::ManageMembers, Capability::ManageSettings];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::ViewAllBalances => "view_all_balances",
+            Capability::ReversePayment => "reverse_payment",
+            Capability::BookAdjustments => "book_adjustments",
+            Capability::ManageMembers => "manage_members",
+            Capability::ManageSettings => "manage_settings",
+        }
+    }
+
+    /// Whether the capability only shows things, so read-only roles may have it.
+    pub fn is_viewing(&self) -> bool {
+        matches!(self, Capability::ViewAllBalances)
+    }
+
+    /// The capability `c as u8` was, for use as a const generic.
+    pub fn from_index(index: u8) -> Option<Capability> {
+        Capability::ALL.get(index as usize).copied()
+    }
+}
+
+/// A role by name, or by the `permission` number of archives from before roles: 2 and above
+/// were admins.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Role, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Role(Role),
+        Permission(i64),
+    }
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::Role(role) => role,
+        Stored::Permission(permission) if permission >= 2 => Role::Admin,
+        Stored::Permission(_) => Role::Member,
+    })
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL.into_iter().find(|r| r.as_str() == s).ok_or_else(|| format!("no role {}", s))
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL.into_iter().find(|c| c.as_str() == s).ok_or_else(|| format!("no capability {}", s))
+    }
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str()?.parse().map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+impl ToSql for Capability {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for Capability {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str()?.parse().map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
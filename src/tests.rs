@@ -1,4 +1,5 @@
-use super::{Outcome, User};
+use super::{csv_field, Limit, SimpletsError, User};
+use proptest::prelude::*;
 
 fn new_user(id: i64, credit: i64, payments_in: u64, payments_out: u64) -> User {
     User {
@@ -9,7 +10,13 @@ fn new_user(id: i64, credit: i64, payments_in: u64, payments_out: u64) -> User {
         payments_out,
         password: "".to_string(),
         created: "".to_string(),
-        permission: 0
+        role: super::Role::Member,
+        active: true,
+        account_type: super::ACCOUNT_MEMBER,
+        email: None,
+        notify: 0,
+        member_number: id as u64,
+        email_verified: false,
     }
 }
 
@@ -18,19 +25,19 @@ fn payment_limit1() {
     let payer = new_user(0, 10, 1, 0);
     assert_eq!(payer.send_limit(), 424);
     let u2 = new_user(1, 0, 0, 0);
-    assert_eq!(payer.payment_limit(&u2), Outcome::PaymentSendLimit(424));
+    assert_eq!(payer.payment_limit(&u2), Limit::Send(424));
 }
 #[test]
 fn payment_limit2() {
     let payer = new_user(0, 3000, 0, 0);
     let u2 = new_user(1, 0, 0, 0);
-    assert_eq!(payer.payment_limit(&u2), Outcome::PaymentReceiveLimit(2500));
+    assert_eq!(payer.payment_limit(&u2), Limit::Receive(2500));
 }
 #[test]
 fn payment_limit3() {
     let payer = new_user(0, 10000, 3, 3);
     let u2 = new_user(1, -100, 2, 2);
-    assert_eq!(payer.payment_limit(&u2), Outcome::PaymentReceiveLimit(4430));
+    assert_eq!(payer.payment_limit(&u2), Limit::Receive(4430));
 }
 #[test]
 fn held_credit_over_limit() {
@@ -38,5 +45,1371 @@ fn held_credit_over_limit() {
     assert_eq!(user.receive_limit(), -7500);
     let u2 = new_user(1, 10, 0, 0);
     // this is solved by Domain.minimal_amount
-    assert_eq!(u2.payment_limit(&user), Outcome::PaymentReceiveLimit(-7500));
-}
\ No newline at end of file
+    assert_eq!(u2.payment_limit(&user), Limit::Receive(-7500));
+}
+#[test]
+fn csv_escaping() {
+    assert_eq!(csv_field("plain"), "plain");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+}
+#[test]
+fn locale_negotiation() {
+    let catalog = super::i18n::Catalog::default();
+    assert_eq!(catalog.negotiate(None, None), "cs");
+    assert_eq!(catalog.negotiate(None, Some("de-DE,en-US;q=0.8")), "en");
+    assert_eq!(catalog.negotiate(Some("cs"), Some("en")), "cs");
+    assert_eq!(catalog.negotiate(Some("xx"), Some("fr")), "cs");
+}
+#[test]
+fn catalog_format() {
+    let mut catalog = super::i18n::Catalog::new("cs");
+    catalog.load("cs", "min = Minimálně {} kr.\nonly-cs = ahoj");
+    catalog.load("en", "min = At least {} cr.");
+    assert_eq!(catalog.format("en", "min", &[&10]), "At least 10 cr.");
+    assert_eq!(catalog.get("en", "only-cs"), "ahoj");
+    assert_eq!(catalog.get("en", "missing"), "missing");
+}
+#[test]
+fn outcome_serialization() {
+    assert_eq!(serde_json::to_string(&SimpletsError::PaymentSendLimit { limit: 424 }).unwrap(), r#"{"code":"payment_send_limit","limit":424}"#);
+    assert_eq!(serde_json::to_string(&SimpletsError::PaymentSidesEq).unwrap(), r#"{"code":"payment_sides_eq"}"#);
+}
+#[test]
+fn payments_csv_parsing() {
+    let parsed = super::parse_payments_csv("alice,10,stall\n\n1234, 25 ,\"eggs, milk\"\nbob,5").unwrap();
+    assert_eq!(parsed, vec![
+        ("alice".to_string(), 10, "stall".to_string()),
+        ("1234".to_string(), 25, "eggs, milk".to_string()),
+        ("bob".to_string(), 5, "".to_string()),
+    ]);
+    assert_eq!(super::parse_payments_csv("alice,10\nbob,ten"), Err(2));
+}
+#[test]
+fn system_account_unlimited() {
+    let mut fund = new_user(0, -50000, 0, 0);
+    fund.account_type = super::ACCOUNT_SYSTEM;
+    let member = new_user(1, 0, 0, 0);
+    assert_eq!(fund.payment_limit(&member), Limit::Receive(2500));
+    assert_eq!(member.payment_limit(&fund), Limit::Send(0));
+    let rich = new_user(2, 100000, 0, 0);
+    assert_eq!(rich.payment_limit(&fund), Limit::Send(100000));
+}
+#[test]
+fn webhook_signature() {
+    // RFC 4231, test case 2
+    assert_eq!(super::webhook::sign("Jefe", "what do ya want for nothing?"),
+               "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+}
+#[test]
+fn totp_rfc6238() {
+    use super::totp;
+    let secret = b"12345678901234567890";
+    assert_eq!(totp::hotp(secret, 59 / totp::STEP, 8), 94287082);
+    assert_eq!(totp::hotp(secret, 1111111109 / totp::STEP, 8), 7081804);
+    assert_eq!(totp::verify(secret, "287082", 59), Some(1));
+    assert_eq!(totp::verify(secret, "287082", 89), Some(1));
+    assert_eq!(totp::verify(secret, "287082", 200), None);
+    assert_eq!(totp::base32_encode(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    assert_eq!(totp::base32_decode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(), secret);
+}
+#[test]
+fn login_lockout_backoff() {
+    assert_eq!(super::lockout_seconds(2), None);
+    assert_eq!(super::lockout_seconds(3), Some(30));
+    assert_eq!(super::lockout_seconds(4), Some(60));
+    assert_eq!(super::lockout_seconds(9), Some(1920));
+    assert_eq!(super::lockout_seconds(10), Some(3600));
+    assert_eq!(super::lockout_seconds(100), Some(3600));
+}
+#[test]
+fn payment_uri_roundtrip() {
+    use super::PaymentUri;
+    let uri = PaymentUri { payee: 42, domain: Some("lets".to_string()), amount: Some(50), message: Some("vejce & mléko".to_string()) };
+    assert_eq!(uri.to_string(), "simplets:42?domain=lets&amount=50&message=vejce%20%26%20ml%C3%A9ko");
+    assert_eq!(PaymentUri::parse(&uri.to_string()), Some(uri));
+    assert_eq!(PaymentUri::parse("simplets:7").unwrap().to_string(), "simplets:7");
+    assert_eq!(PaymentUri::parse("simplets:7?message=a+b").unwrap().message.as_deref(), Some("a b"));
+    assert_eq!(PaymentUri::parse("simplets:x?amount=5"), None);
+    assert_eq!(PaymentUri::parse("simplets:7?message=%E"), None);
+    assert_eq!(PaymentUri::parse("http://example.org"), None);
+}
+#[test]
+fn trustline_raises_limits() {
+    let payer = new_user(0, 10, 1, 0);
+    let payee = new_user(1, 0, 0, 0);
+    assert_eq!(payer.payment_limit_trusted(&payee, 0), payer.payment_limit(&payee));
+    assert_eq!(payer.payment_limit_trusted(&payee, 500), Limit::Send(924));
+    assert_eq!(payer.payment_limit_trusted(&new_user(2, 3000, 0, 0), 500), Limit::Receive(0));
+}
+#[test]
+fn in_memory_payment() {
+    let domain = super::Domain::new_in_memory("test", 10);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    domain.seed_payments(&[(bob, alice, 100)]).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 5, "", None, None), Err(SimpletsError::PaymentLessMin { minimum: 10 }));
+    assert_eq!(domain.add_payment(alice, alice, 50, "", None, None), Err(SimpletsError::PaymentSidesEq));
+    domain.add_payment(alice, bob, 60, "eggs", None, None).unwrap();
+    assert_eq!(domain.get_user(alice).unwrap().credit, 40);
+    assert_eq!(domain.get_user(bob).unwrap().credit, -40);
+    assert_eq!(domain.get_payments_by_user(bob, &Default::default()).unwrap().len(), 2);
+    assert!(domain.health_report().unwrap().is_healthy());
+}
+#[test]
+fn in_memory_domains_are_separate() {
+    let first = super::Domain::new_in_memory("test", 10);
+    let second = super::Domain::new_in_memory("test", 10);
+    first.seed_users(&["alice"]).unwrap();
+    assert_eq!(second.get_users().unwrap().len(), 0);
+}
+#[test]
+fn domain_config_path() {
+    use super::DomainConfig;
+    use std::path::PathBuf;
+    assert_eq!(DomainConfig::new("lets").path(), PathBuf::from("./lets.sqlite"));
+    assert_eq!(DomainConfig::new("lets").data_dir("/var/lib/simplets").path(), PathBuf::from("/var/lib/simplets/lets.sqlite"));
+    assert_eq!(DomainConfig::new("lets").data_dir("/var/lib/simplets").db_path("/srv/lets.db").path(), PathBuf::from("/srv/lets.db"));
+}
+#[test]
+fn domain_config_load_applies_stored_settings() {
+    use super::DomainConfig;
+    let dir = std::env::temp_dir().join(format!("simplets-load-{}", std::process::id()));
+    let path = dir.join("lets.sqlite");
+    assert_eq!(DomainConfig::new("lets").db_path(&path).load().unwrap().minimal_amount, 10);
+    assert_eq!(DomainConfig::new("lets").db_path(&path).minimal_amount(5).load().unwrap().minimal_amount, 5);
+    DomainConfig::new("lets").db_path(&path).load().unwrap().update_settings("", "", 3).unwrap();
+    assert_eq!(DomainConfig::new("lets").db_path(&path).minimal_amount(5).load().unwrap().minimal_amount, 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+#[test]
+fn domain_error_on_bad_database() {
+    use super::{DomainConfig, DomainError};
+    let dir = std::env::temp_dir().join(format!("simplets-test-{}", std::process::id()));
+    let newer = dir.join("newer.sqlite");
+    std::fs::create_dir_all(&dir).unwrap();
+    rusqlite::Connection::open(&newer).unwrap().execute_batch("PRAGMA user_version = 1000").unwrap();
+    match DomainConfig::new("newer").db_path(&newer).try_open() {
+        Err(DomainError::SchemaVersionTooNew { found: 1000, supported }) => assert_eq!(supported, super::SCHEMA_VERSION),
+        other => panic!("unexpected {:?}", other.map(|d| d.name)),
+    }
+    let corrupt = dir.join("corrupt.sqlite");
+    std::fs::write(&corrupt, "not a database, just some text long enough to fill a header").unwrap();
+    assert!(matches!(DomainConfig::new("corrupt").db_path(&corrupt).try_open(), Err(DomainError::Sqlite(_))));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+#[test]
+fn error_kinds() {
+    let db = SimpletsError::from(rusqlite::Error::QueryReturnedNoRows);
+    assert!(!db.is_validation());
+    assert!(std::error::Error::source(&db).is_some());
+    let limit = SimpletsError::PaymentReceiveLimit { limit: 30 };
+    assert!(limit.is_validation());
+    assert_eq!(limit.to_string(), "payee can receive at most 30");
+    assert_eq!(Limit::Send(20).exceeded(), SimpletsError::PaymentSendLimit { limit: 20 });
+}
+#[test]
+fn backup_and_restore() {
+    use super::backup;
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    domain.seed_payments(&[(ids[0], ids[1], 30)]).unwrap();
+    let path = std::env::temp_dir().join(format!("simplets-backup-test-{}.sqlite", std::process::id()));
+    domain.backup_to(&path).unwrap();
+    domain.seed_payments(&[(ids[0], ids[1], 20)]).unwrap();
+    domain.restore_from(&path).unwrap();
+    assert_eq!(domain.get_user(ids[1]).unwrap().credit, 30);
+    let key = [7u8; 32];
+    let sealed = backup::encrypt(&key, &std::fs::read(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(backup::decrypt(&key, &sealed).unwrap().len(), sealed.len() - backup::MAGIC.len() - 12 - 16);
+    assert_eq!(backup::decrypt(&[8u8; 32], &sealed), None);
+    assert_eq!(backup::parse_key(&"0a".repeat(32)), Some([10u8; 32]));
+}
+#[test]
+fn backup_rotation() {
+    let dir = std::env::temp_dir().join(format!("simplets-rotate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for file in ["lets-20240101-000000.sqlite", "lets-20240102-000000.backup", "lets-20240103-000000.sqlite", "lets.sqlite", "other-20240101-000000.sqlite"] {
+        std::fs::write(dir.join(file), "").unwrap();
+    }
+    let deleted = super::backup::rotate(&dir, "lets", 2).unwrap();
+    assert_eq!(deleted, vec![dir.join("lets-20240101-000000.sqlite")]);
+    assert!(dir.join("lets.sqlite").exists() && dir.join("other-20240101-000000.sqlite").exists());
+    let domain = super::Domain::new_in_memory("lets", 1);
+    let path = super::backup::scheduled(&domain, &dir, None, 1).unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+    assert!(path.exists());
+    assert_eq!(domain.get_audit_log(None, None, None, 10).unwrap()[0].action, "backup.created");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+#[test]
+fn hash_chain_detects_tampering() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    domain.seed_payments(&[(ids[0], ids[1], 10), (ids[1], ids[0], 20), (ids[0], ids[1], 30)]).unwrap();
+    assert_eq!(domain.verify_chain().unwrap(), super::ChainStatus { entries: 3, broken_at: None });
+    let conn = domain.writer();
+    assert!(conn.execute("UPDATE payment SET amount = 25 WHERE id = 2", []).is_err());
+    assert!(conn.execute("DELETE FROM payment WHERE id = 3", []).is_err());
+    conn.execute_batch("DROP TRIGGER payment_append_only_update; UPDATE payment SET amount = 25 WHERE id = 2").unwrap();
+    drop(conn);
+    assert_eq!(domain.verify_chain().unwrap(), super::ChainStatus { entries: 1, broken_at: Some(2) });
+    assert!(!domain.health_report().unwrap().is_healthy());
+}
+#[test]
+fn federation_transfer_between_domains() {
+    use super::{webhook, Reconciliation, TransferRequest};
+    let (here, there) = (super::Domain::new_in_memory("here", 1), super::Domain::new_in_memory("there", 1));
+    let alice = here.seed_users(&["alice"]).unwrap()[0];
+    let bob = there.seed_users(&["bob"]).unwrap()[0];
+    let remote = here.add_remote_domain("there", "https://there.example/", "s3cret").unwrap();
+    assert_eq!(remote.url, "https://there.example");
+    let peer = there.add_remote_domain("here", "https://here.example", "s3cret").unwrap();
+    assert_eq!(here.add_cross_domain_payment(alice, "elsewhere", bob, 10, "").unwrap_err(), SimpletsError::RemoteDomainUnknown);
+    assert!(matches!(here.add_cross_domain_payment(alice, "there", bob, 10, ""), Err(SimpletsError::PaymentSendLimit { .. })));
+    here.writer().execute("UPDATE user SET credit = 500 WHERE id = ?", [alice]).unwrap();
+    let transfer = here.add_cross_domain_payment(alice, "there", bob, 40, "eggs").unwrap();
+    let (_, pending) = here.pending_transfers(10).unwrap().pop().unwrap();
+    assert_eq!(pending.reference, transfer.reference);
+    let body = serde_json::to_string(&TransferRequest { domain: "here".to_string(), reference: transfer.reference.clone(),
+        payer: alice, payee: bob, amount: 40, original_amount: None, message: "eggs".to_string() }).unwrap();
+    assert_eq!(there.receive_transfer("here", "sha256=00", &body).unwrap_err(), SimpletsError::FederationDenied);
+    let signature = format!("sha256={}", webhook::sign("s3cret", &body));
+    let payment = there.receive_transfer("here", &signature, &body).unwrap();
+    assert_eq!(there.receive_transfer("here", &signature, &body).unwrap().id, payment.id);
+    assert_eq!(there.get_user(bob).unwrap().credit, 40);
+    let unconfirmed = Reconciliation { local: here.bridge_position(&remote).unwrap(), remote: there.bridge_position(&peer).unwrap() };
+    assert!(!unconfirmed.is_balanced());
+    here.mark_transfer_delivered(transfer.id).unwrap();
+    let confirmed = Reconciliation { local: here.bridge_position(&remote).unwrap(), remote: there.bridge_position(&peer).unwrap() };
+    assert!(confirmed.is_balanced());
+    let refused = here.add_cross_domain_payment(alice, "there", bob, 30, "").unwrap();
+    here.refund_transfer(refused.id, "payee limit").unwrap();
+    assert_eq!(here.get_user(alice).unwrap().credit, 460);
+    assert!(here.pending_transfers(10).unwrap().is_empty());
+}
+#[test]
+fn federation_exchange_rate() {
+    use super::{webhook, Reconciliation, TransferRequest};
+    let (here, there) = (super::Domain::new_in_memory("here", 1), super::Domain::new_in_memory("there", 1));
+    let alice = here.seed_users(&["alice"]).unwrap()[0];
+    let bob = there.seed_users(&["bob"]).unwrap()[0];
+    here.add_remote_domain("there", "https://there.example", "s3cret").unwrap();
+    there.add_remote_domain("here", "https://here.example", "s3cret").unwrap();
+    for rate in [0.0, -2.5, f64::NAN, f64::INFINITY] {
+        assert_eq!(here.set_exchange_rate("there", rate), Err(SimpletsError::ExchangeRateInvalid), "{}", rate);
+    }
+    here.set_exchange_rate("there", 2.5).unwrap();
+    let remote = here.get_remote_domain("there").unwrap();
+    here.writer().execute("UPDATE user SET credit = 500 WHERE id = ?", [alice]).unwrap();
+    let transfer = here.add_cross_domain_payment(alice, "there", bob, 15, "").unwrap();
+    assert_eq!((transfer.amount, transfer.remote_amount), (15, 38));
+    let body = serde_json::to_string(&TransferRequest { domain: "here".to_string(), reference: transfer.reference.clone(),
+        payer: alice, payee: bob, amount: transfer.remote_amount, original_amount: Some(transfer.amount), message: "".to_string() }).unwrap();
+    there.receive_transfer("here", &format!("sha256={}", webhook::sign("s3cret", &body)), &body).unwrap();
+    here.mark_transfer_delivered(transfer.id).unwrap();
+    assert_eq!(there.get_user(bob).unwrap().credit, 38);
+    let peer = there.get_remote_domain("here").unwrap();
+    let (local, remote) = (here.bridge_position(&remote).unwrap(), there.bridge_position(&peer).unwrap());
+    assert_eq!((local.settled(), local.mirrored, remote.settled(), remote.mirrored), (15, 38, -38, -15));
+    assert!(Reconciliation { local, remote }.is_balanced());
+}
+#[test]
+fn statement_pdf() {
+    assert_eq!(super::pdf::encode("Řeč (é)€"), vec![136, b'e', 129, b' ', b'(', 0xe9, b')', 152]);
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    domain.seed_payments(&[(ids[1], ids[0], 100)]).unwrap();
+    domain.add_payment(ids[0], ids[1], 30, "vejce (10)", None, None).unwrap();
+    let today = chrono::Local::now().naive_local().date();
+    let (opening, lines) = domain.statement(ids[0], Some(today), Some(today)).unwrap();
+    assert_eq!((opening, lines.len(), lines[1].balance), (0, 2, 70));
+    let pdf = domain.render_statement_pdf(ids[0], today, today, "en").unwrap();
+    let text = String::from_utf8_lossy(&pdf);
+    assert!(text.starts_with("%PDF-1.4") && text.ends_with("%%EOF\n"));
+    assert!(text.contains("(vejce \\(10\\)) Tj") && text.contains("(+100 kr.) Tj") && text.contains("(-30 kr.) Tj") && text.contains("/Count 1"));
+}
+#[test]
+fn import_users_with_opening_balances() {
+    use super::{ImportError, ImportedUser};
+    let parsed = super::parse_users_csv("name,email,balance\nalice,alice@example.com,120\n\n\"Novák, Jan\",,-20\nbob").unwrap();
+    assert_eq!(parsed[1], ImportedUser { name: "Novák, Jan".to_string(), email: None, balance: -20, legacy_id: None });
+    assert_eq!(super::parse_users_csv("alice,,ten"), Err(1));
+    assert_eq!(super::parse_users_csv("alice\nbob,not-an-email"), Err(2));
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.import_users_csv("alice,alice@example.com,120\nbob,,-20\ncarol".as_bytes()).unwrap();
+    assert_eq!(ids.len(), 3);
+    let alice = domain.get_user(ids[0] as i64).unwrap();
+    assert_eq!((alice.credit, alice.payments_in, alice.email.as_deref()), (120, 0, Some("alice@example.com")));
+    let migration = domain.get_user(domain.get_migration_account().unwrap().unwrap()).unwrap();
+    assert_eq!(migration.credit, -100);
+    assert!(matches!(domain.import_users_csv("dave\nbob".as_bytes()), Err(ImportError::NameTaken(n)) if n == "bob"));
+    assert!(domain.get_user_by_name("dave").is_err());
+    assert!(domain.health_report().unwrap().is_healthy());
+}
+#[test]
+fn import_payment_history() {
+    use super::ImportError;
+    let history = super::parse_history_csv("date,payer,payee,amount,message\n2019-05-02 10:30,c7,a1,40,eggs\n2019-03-01,a1,c7,15\n").unwrap();
+    assert_eq!(history[1].created.to_string(), "2019-03-01 00:00:00");
+    assert_eq!(super::parse_history_csv("2019-13-01,a1,c7,15"), Err(1));
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.import_users_csv("alice,,,a1\ncarol,,,c7".as_bytes()).unwrap();
+    let mut bad = history.clone();
+    bad[0].payee = "x9".to_string();
+    assert!(matches!(domain.import_payments(bad), Err(ImportError::UnknownMember(m)) if m == "x9"));
+    let mut bad = history.clone();
+    bad[1].payer = "carol".to_string();
+    assert!(matches!(domain.import_payments(bad), Err(ImportError::Line(2))));
+    assert_eq!(domain.import_payments(history).unwrap(), 2);
+    let alice = domain.get_user(ids[0] as i64).unwrap();
+    assert_eq!((alice.credit, alice.payments_in, alice.payments_out), (25, 1, 1));
+    let payments = domain.get_payments_by_user(alice.id, &Default::default()).unwrap();
+    assert_eq!((payments[1].created.as_str(), payments[1].amount), ("2019-03-01 00:00:00", 15));
+    assert!(domain.recompute_balances().unwrap().is_empty());
+    assert!(domain.verify_chain().unwrap().is_intact());
+}
+#[test]
+fn limits_decay_with_limit_window() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.import_users_csv("alice,,,a1\nbob,,,b2".as_bytes()).unwrap();
+    let (alice, bob) = (ids[0] as i64, ids[1] as i64);
+    let history = super::parse_history_csv("2019-01-01,b2,a1,10\n2019-02-01,b2,a1,10\n2019-03-01,b2,a1,10\n").unwrap();
+    domain.import_payments(history).unwrap();
+    assert_eq!(domain.get_limits(alice).unwrap().0, 1030);
+    domain.update_limit_window(12).unwrap();
+    assert_eq!(domain.get_limits(alice).unwrap().0, 30);
+    assert_eq!(domain.add_payment(alice, bob, 40, "", None, None), Err(SimpletsError::PaymentSendLimit { limit: 30 }));
+    assert_eq!(domain.get_limits(bob).unwrap().0, -30);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, alice, 5, "", None, None).unwrap();
+    assert_eq!(domain.get_limits(alice).unwrap().0, 449);
+}
+#[test]
+fn archive_roundtrip() {
+    use super::{Archive, ImportError};
+    let domain = super::Domain::new_in_memory("old", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    domain.seed_payments(&[(ids[1], ids[0], 100), (ids[0], ids[1], 30)]).unwrap();
+    domain.create_listing(ids[0], super::LISTING_OFFER, "food", "eggs", "free range").unwrap();
+    domain.set_categories(&["food"]).unwrap();
+    let json = serde_json::to_string(&domain.export_archive().unwrap()).unwrap();
+    let archive: Archive = serde_json::from_str(&json).unwrap();
+    let mut moved = super::Domain::new_in_memory("new", 1);
+    moved.import_archive(&archive).unwrap();
+    assert_eq!(moved.get_user(ids[1]).unwrap().credit, -70);
+    assert_eq!(moved.get_listings(None, None).unwrap()[0].owner_name, "alice");
+    assert_eq!(moved.get_categories().unwrap(), vec!["food"]);
+    assert!(moved.verify_chain().unwrap().is_intact() && moved.recompute_balances().unwrap().is_empty());
+    assert!(matches!(moved.import_archive(&archive), Err(ImportError::NotEmpty)));
+    let newer = Archive { version: super::ARCHIVE_VERSION + 1, ..archive };
+    assert!(matches!(super::Domain::new_in_memory("x", 1).import_archive(&newer), Err(ImportError::ArchiveVersion(_))));
+}
+#[test]
+fn user_data_export_and_erasure() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    domain.set_email(alice, Some("alice@example.com"), 0).unwrap();
+    domain.update_profile(alice, "Alice", "555 123", "", "bread").unwrap();
+    domain.seed_payments(&[(bob, alice, 100)]).unwrap();
+    domain.send_message(alice, bob, "hi").unwrap();
+    let data = domain.export_user_data(alice).unwrap();
+    assert_eq!(data["account"][0]["email"], "alice@example.com");
+    assert!(data["account"][0].get("password").is_none());
+    assert_eq!((data["payments"][0]["amount"].as_i64(), data["messages"][0]["body"].as_str()), (Some(100), Some("hi")));
+    assert_eq!(domain.anonymize_user(alice).unwrap(), 1);
+    let erased = domain.get_user(alice).unwrap();
+    assert_eq!((erased.name.as_str(), erased.email, erased.active, erased.credit), ("anonymized 1", None, false, 100));
+    assert!(domain.get_profile(alice).unwrap().display_name.is_empty());
+    assert!(domain.get_conversation(bob, alice).unwrap().is_empty());
+    assert_eq!(domain.get_payments_by_user(bob, &Default::default()).unwrap().len(), 1);
+    assert!(domain.health_report().unwrap().is_healthy());
+    let system = domain.add_system_account("fund").unwrap() as i64;
+    assert_eq!(domain.anonymize_user(system).unwrap(), 0);
+}
+#[test]
+fn maximal_amount_and_daily_limit() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 500)]).unwrap();
+    domain.update_payment_limits(100, 150).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 101, "", None, None), Err(SimpletsError::PaymentOverMax { maximum: 100 }));
+    domain.add_payment(alice, bob, 100, "", None, None).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 60, "", None, None), Err(SimpletsError::PaymentDailyLimit { remaining: 50 }));
+    domain.add_payment(alice, bob, 50, "", None, None).unwrap();
+    domain.add_payment(fund, bob, 300, "", None, None).unwrap();
+    assert_eq!(domain.create_request(bob, alice, 120, "").unwrap_err().code(), "payment_over_max");
+    domain.update_payment_limits(0, 0).unwrap();
+    domain.add_payment(alice, bob, 120, "", None, None).unwrap();
+}
+#[test]
+fn probation() {
+    let probation = super::Probation { days: 30, payments: 5, percent: 40, email: false };
+    let today = chrono::NaiveDate::from_ymd(2022, 3, 10);
+    let mut user = new_user(1, 0, 10, 0);
+    user.created = "2022-03-01 12:00:00".to_string();
+    assert!(probation.applies(&user, today));
+    user.created = "2022-01-01 12:00:00".to_string();
+    assert!(!probation.applies(&user, today));
+    user.payments_in = 4;
+    assert!(probation.applies(&user, today));
+    assert!(!super::Probation { percent: 40, ..Default::default() }.applies(&user, today));
+    assert_eq!(probation.reduce(1000), 400);
+    assert_eq!(probation.reduce(-20), -20);
+}
+#[test]
+fn probation_reduces_limits_of_new_members() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 1000)]).unwrap();
+    domain.update_probation(super::Probation { days: 30, payments: 0, percent: 50, email: false }).unwrap();
+    assert_eq!(domain.get_limits(alice).unwrap().0, 707);
+    assert_eq!(domain.add_payment(alice, bob, 800, "", None, None), Err(SimpletsError::PaymentSendLimit { limit: 707 }));
+    domain.update_probation(super::Probation::default()).unwrap();
+    domain.add_payment(alice, bob, 800, "", None, None).unwrap();
+}
+#[test]
+fn outbox_fires_once_per_committed_payment() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.register_webhook("http://localhost/hook", "secret", &[super::EVENT_PAYMENT_CREATED]).unwrap();
+    domain.set_email(bob, Some("bob@example.org"), super::NOTIFY_PAYMENT).unwrap();
+    domain.add_payment(fund, alice, 100, "", None, None).unwrap();
+    assert!(domain.add_payments_bulk(alice, vec![(bob, 50, "ok".to_string()), (bob, 1000, "too much".to_string())]).is_err());
+    domain.add_payment(alice, bob, 30, "bread", None, None).unwrap();
+    assert!(domain.pending_webhook_deliveries(10).unwrap().is_empty());
+    assert_eq!(domain.dispatch_outbox(10).unwrap(), 2);
+    assert_eq!(domain.dispatch_outbox(10).unwrap(), 0);
+    assert_eq!(domain.pending_webhook_deliveries(10).unwrap().len(), 2);
+    let notifications = domain.pending_notifications(10).unwrap();
+    assert_eq!(notifications.len(), 1);
+    assert!(notifications[0].body.contains("bread"));
+}
+#[test]
+fn outbox_fires_for_reversals() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.register_webhook("http://localhost/hook", "secret", &[super::EVENT_PAYMENT_REVERSED]).unwrap();
+    domain.add_payment(fund, alice, 100, "", None, None).unwrap();
+    let super::Submitted::Booked(payment) = domain.add_payment(alice, bob, 30, "bread", None, None).unwrap() else { panic!("not booked") };
+    let reversal = domain.reverse_payment(None, payment, "mistake").unwrap();
+    assert_eq!(domain.dispatch_outbox(10).unwrap(), 3);
+    let deliveries = domain.pending_webhook_deliveries(10).unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].event, super::EVENT_PAYMENT_REVERSED);
+    let payload: serde_json::Value = serde_json::from_str(&deliveries[0].payload).unwrap();
+    assert_eq!((payload["payment"]["id"].as_u64(), payload["payment"]["parent"].as_u64()), (Some(reversal.id), Some(payment)));
+}
+#[test]
+fn idempotency_key_pays_once() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, alice, 100, "", None, None).unwrap();
+    assert!(domain.add_payment(alice, bob, 600, "", None, Some("k1")).is_err());
+    domain.add_payment(alice, bob, 30, "", None, Some("k1")).unwrap();
+    domain.add_payment(alice, bob, 30, "", None, Some("k1")).unwrap();
+    assert_eq!(domain.get_user(bob).unwrap().credit, 30);
+    domain.add_payment(bob, alice, 10, "", None, Some("k1")).unwrap();
+    domain.add_payment(alice, bob, 30, "", None, Some("k2")).unwrap();
+    assert_eq!(domain.get_user(alice).unwrap().credit, 50);
+}
+#[test]
+fn pending_payments_wait_for_payee() {
+    use super::{Submitted, PENDING_CONFIRMED, PENDING_REJECTED};
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.update_confirmation(50, 7).unwrap();
+    assert_eq!(domain.add_payment(fund, alice, 300, "", None, None), Ok(Submitted::Booked(1)));
+    assert_eq!(domain.add_payment(alice, bob, 50, "", None, None), Ok(Submitted::Booked(2)));
+    assert!(matches!(domain.add_payment(alice, bob, 5000, "", None, None), Err(SimpletsError::PaymentSendLimit { .. })));
+    let first = match domain.add_payment(alice, bob, 100, "sofa", None, Some("sofa")).unwrap() {
+        Submitted::Pending(id) => id,
+        Submitted::Booked(_) => panic!("booked without confirmation"),
+    };
+    // a retry doesn't hold the payment a second time
+    assert_eq!(domain.add_payment(alice, bob, 100, "sofa", None, Some("sofa")), Ok(Submitted::Pending(first)));
+    assert_eq!(domain.get_user(bob).unwrap().credit, 50);
+    assert_eq!(domain.confirm_payment(first, alice).unwrap_err(), SimpletsError::PendingNotFound);
+    domain.confirm_payment(first, bob).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 100, "sofa", None, Some("sofa")), Ok(Submitted::Pending(first)));
+    assert_eq!(domain.get_user(bob).unwrap().credit, 150);
+    assert_eq!(domain.reject_payment(first, bob), Err(SimpletsError::PendingClosed));
+    let second = match domain.add_payment(alice, bob, 60, "", None, None).unwrap() {
+        Submitted::Pending(id) => id,
+        Submitted::Booked(_) => panic!("booked without confirmation"),
+    };
+    domain.reject_payment(second, bob).unwrap();
+    let incoming = domain.get_pending_incoming(bob).unwrap();
+    assert_eq!(incoming.iter().map(|p| p.status).collect::<Vec<_>>(), vec![PENDING_REJECTED, PENDING_CONFIRMED]);
+    assert_eq!(domain.get_user(alice).unwrap().credit, 150);
+    assert_eq!(domain.expire_pending_payments().unwrap(), 0);
+}
+#[test]
+fn escrow_reserves_send_limit() {
+    use super::{ESCROW_CANCELLED, ESCROW_RELEASED};
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    let (alice, bob, carol) = (ids[0], ids[1], ids[2]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, alice, 100, "", None, None).unwrap();
+    let first = domain.open_escrow(alice, bob, 300, "roof").unwrap();
+    assert_eq!(domain.get_limits(alice).unwrap().0, 214);
+    assert!(matches!(domain.open_escrow(alice, bob, 300, ""), Err(SimpletsError::PaymentSendLimit { .. })));
+    assert!(matches!(domain.add_payment(alice, carol, 250, "", None, None), Err(SimpletsError::PaymentSendLimit { .. })));
+    assert_eq!(domain.release_escrow(first, bob).unwrap_err(), SimpletsError::EscrowNotFound);
+    assert_eq!(domain.release_escrow(first, alice).unwrap().amount, 300);
+    assert_eq!(domain.get_escrow(first).unwrap().status, ESCROW_RELEASED);
+    assert_eq!(domain.get_user(bob).unwrap().credit, 300);
+    assert_eq!(domain.cancel_escrow(first, bob), Err(SimpletsError::EscrowClosed));
+    let second = domain.open_escrow(bob, carol, 200, "").unwrap();
+    domain.dispute_escrow(second, carol).unwrap();
+    assert_eq!(domain.cancel_escrow(second, carol), Err(SimpletsError::EscrowClosed));
+    assert_eq!(domain.get_disputed_escrows().unwrap().len(), 1);
+    assert!(domain.resolve_escrow(second, false).unwrap().is_none());
+    assert_eq!(domain.get_escrow(second).unwrap().status, ESCROW_CANCELLED);
+    assert_eq!(domain.get_user(carol).unwrap().credit, 0);
+}
+#[test]
+fn adjustments_need_admin_and_are_marked() {
+    use super::{Capability, Role, PAYMENT_ADJUSTMENT};
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    assert_eq!(domain.add_adjustment(alice, bob, 50, "grant").unwrap_err(), SimpletsError::AdjustmentNotConfigured);
+    let account = domain.add_system_account("committee").unwrap() as i64;
+    domain.set_adjustment_account(account).unwrap();
+    assert_eq!(domain.add_adjustment(alice, bob, 50, "grant").unwrap_err(), SimpletsError::NotPermitted(Capability::BookAdjustments));
+    domain.set_role(alice, Role::Treasurer).unwrap();
+    domain.add_adjustment(alice, bob, 50, "grant").unwrap();
+    domain.add_adjustment(alice, bob, -20, "levy").unwrap();
+    assert_eq!(domain.get_user(bob).unwrap().credit, 30);
+    assert_eq!(domain.get_user(bob).unwrap().payments_in, 0);
+    let (_, lines) = domain.statement(bob, None, None).unwrap();
+    assert!(lines.iter().all(|l| l.kind == PAYMENT_ADJUSTMENT));
+    assert!(domain.export_payments_csv(bob, None, None).unwrap().contains("[adjustment] levy"));
+    assert_eq!(domain.get_audit_log(None, None, None, 10).unwrap()[0].action, "ledger.adjustment");
+}
+#[test]
+fn limit_override_raises_limits() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    assert!(matches!(domain.add_payment(alice, bob, 40, "", None, None), Err(SimpletsError::PaymentSendLimit { .. })));
+    let past = chrono::NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0);
+    domain.set_limit_override(alice, 50, 0, Some(past)).unwrap();
+    assert_eq!(domain.get_limits(alice).unwrap().0, 0);
+    assert!(domain.add_payment(alice, bob, 40, "", None, None).is_err());
+    domain.set_limit_override(alice, 50, 0, None).unwrap();
+    assert_eq!(domain.get_limits(alice).unwrap().0, 50);
+    domain.add_payment(alice, bob, 40, "", None, None).unwrap();
+    assert_eq!(domain.get_limit_override(alice).unwrap().unwrap().send_bonus, 50);
+    domain.set_limit_override(alice, 0, 0, None).unwrap();
+    assert!(domain.get_limit_override(alice).unwrap().is_none());
+    assert_eq!(domain.get_limits(alice).unwrap().0, -40);
+}
+#[test]
+fn frozen_accounts_cannot_pay() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, alice, 100, "", None, None).unwrap();
+    domain.freeze_user(alice, "review", false).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 10, "", None, None).unwrap_err(), SimpletsError::AccountFrozen);
+    domain.add_payment(fund, alice, 10, "", None, None).unwrap();
+    domain.freeze_user(alice, "review", true).unwrap();
+    assert_eq!(domain.add_payment(fund, alice, 10, "", None, None).unwrap_err(), SimpletsError::AccountFrozen);
+    assert!(domain.get_freeze(alice).unwrap().unwrap().incoming);
+    domain.unfreeze_user(alice).unwrap();
+    domain.add_payment(alice, bob, 10, "", None, None).unwrap();
+}
+#[test]
+fn closing_accounts_settle_to_zero() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, alice, 100, "", None, None).unwrap();
+    assert!(!domain.begin_account_closure(alice).unwrap());
+    assert_eq!(domain.get_limits(alice).unwrap(), (100, 0));
+    assert!(matches!(domain.add_payment(alice, bob, 101, "", None, None), Err(SimpletsError::PaymentSendLimit { .. })));
+    assert!(matches!(domain.add_payment(fund, alice, 1, "", None, None), Err(SimpletsError::PaymentReceiveLimit { .. })));
+    domain.add_payment(alice, bob, 60, "", None, None).unwrap();
+    assert!(domain.get_account_closures().unwrap()[0].closed.is_none());
+    domain.add_payment(alice, bob, 40, "", None, None).unwrap();
+    assert!(domain.get_account_closures().unwrap()[0].closed.is_some());
+    assert!(!domain.get_user(alice).unwrap().active);
+    assert_eq!(domain.add_payment(bob, alice, 1, "", None, None).unwrap_err(), SimpletsError::UserInactive);
+}
+#[test]
+fn usernames_are_unique_and_follow_policy() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    domain.add_user("alice", "pw").unwrap();
+    assert_eq!(domain.add_user("alice", "pw").unwrap_err(), SimpletsError::UsernameTaken);
+    assert_eq!(domain.add_user("al", "pw").unwrap_err(), SimpletsError::UsernameInvalid);
+    assert_eq!(domain.add_user("Admin", "pw").unwrap_err(), SimpletsError::UsernameInvalid);
+    assert_eq!(domain.add_user("bob smith", "pw").unwrap_err(), SimpletsError::UsernameInvalid);
+    domain.add_user("Šárka_1", "pw").unwrap();
+    let policy = super::UsernamePolicy { extra_chars: " ".to_string(), reserved: Vec::new(), ..Default::default() };
+    domain.update_username_policy(policy).unwrap();
+    domain.add_user("bob smith", "pw").unwrap();
+    domain.add_user("admin", "pw").unwrap();
+    assert!(domain.add_system_account("alice").is_err());
+}
+#[test]
+fn accounts_get_sequential_member_numbers() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let alice = domain.add_user("alice", "pw").unwrap();
+    let fund = domain.add_system_account("fund").unwrap();
+    assert_eq!((alice, fund), (1, 2));
+    assert_eq!(domain.get_user_by_number(2).unwrap().name, "fund");
+    assert_eq!(domain.get_user(alice as i64).unwrap().member_number, 1);
+}
+#[test]
+fn times_are_stored_in_utc() {
+    use super::Timezone;
+    let prague = Timezone::parse("+01:00").unwrap();
+    assert_eq!((Timezone::parse("utc"), Timezone::parse("-05:30")), (Some(Timezone::Fixed(0)), Some(Timezone::Fixed(-19800))));
+    assert_eq!((Timezone::parse("CET"), prague.to_string()), (None, "+01:00".to_string()));
+    assert_eq!(prague.render("2024-03-01 23:30:00"), "2024-03-02 00:30:00");
+    assert_eq!(prague.day_start(chrono::NaiveDate::from_ymd(2024, 3, 2)), "2024-03-01 23:00:00");
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    domain.timezone = Timezone::Fixed(14 * 3600);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    domain.seed_payments(&[(ids[1], ids[0], 100)]).unwrap();
+    domain.add_payment(ids[0], ids[1], 30, "", None, None).unwrap();
+    let today = domain.timezone.today();
+    let filter = super::PaymentFilter { from: Some(today), to: Some(today), ..Default::default() };
+    let payments = domain.get_payments_by_user(ids[0], &filter).unwrap();
+    assert_eq!(payments.len(), 2);
+    assert!((chrono::Utc::now() - payments[0].created_at().unwrap()).num_seconds().abs() < 5);
+    let (_, lines) = domain.statement(ids[0], Some(today), Some(today)).unwrap();
+    assert_eq!(lines[1].created, domain.timezone.render(&payments[0].created));
+}
+#[test]
+fn turnover_report_counts_member_activity() {
+    use chrono::Datelike;
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, ids[0], 100, "", None, None).unwrap();
+    domain.add_payment(ids[0], ids[1], 30, "", None, None).unwrap();
+    let today = domain.timezone.today();
+    let months = domain.turnover_report(today.year()).unwrap();
+    assert_eq!(months.len(), 12);
+    let month = &months[today.month0() as usize];
+    assert_eq!(month.month, today.format("%Y-%m").to_string());
+    assert_eq!((month.payments, month.volume, month.active_members, month.new_members), (2, 130, 2, 3));
+    assert!(domain.turnover_report(today.year() - 1).unwrap().iter().all(|m| m.payments == 0 && m.new_members == 0));
+}
+#[test]
+fn participation_metrics_show_concentration() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol", "dave"]).unwrap();
+    let metrics = domain.participation_metrics().unwrap();
+    assert_eq!((metrics.members, metrics.credit_gini, metrics.top_decile_share, metrics.dormant), (4, 0.0, 0.0, 4));
+    domain.seed_payments(&[(ids[1], ids[0], 90)]).unwrap();
+    domain.add_payment(ids[0], ids[2], 30, "", None, None).unwrap();
+    let metrics = domain.participation_metrics().unwrap();
+    assert_eq!((metrics.in_debit, metrics.in_credit, metrics.min_balance, metrics.median_balance, metrics.max_balance), (1, 2, -90, 15, 60));
+    assert!((metrics.credit_gini - 7.0 / 12.0).abs() < 1e-9);
+    assert!((metrics.top_decile_share - 0.5).abs() < 1e-9);
+    assert_eq!(metrics.dormant, 1);
+}
+#[test]
+fn dormant_members_are_listed_and_reminded() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    domain.set_email(ids[2], Some("carol@example.com"), 0).unwrap();
+    let tomorrow = domain.timezone.today().succ();
+    domain.writer().execute("UPDATE user SET created = '2020-01-01 00:00:00'", []).unwrap();
+    domain.seed_payments(&[(ids[1], ids[0], 100)]).unwrap();
+    let since = domain.timezone.today();
+    let dormant: Vec<_> = domain.dormant_users(since).unwrap().into_iter().map(|u| u.id).collect();
+    assert_eq!(dormant, vec![ids[2]]);
+    assert_eq!(domain.dormant_users(tomorrow).unwrap().len(), 3);
+    assert_eq!(domain.remind_dormant_users(since).unwrap(), 1);
+    let alice = domain.get_user(ids[0]).unwrap();
+    domain.decay_limits(ids[0], 50).unwrap();
+    assert_eq!(domain.get_limits(ids[0]).unwrap().0, alice.send_limit() - alice.credit_limit() / 2);
+}
+#[test]
+fn top_traders_count_payments_and_respect_opt_out() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, ids[2], 500, "", None, None).unwrap();
+    domain.seed_payments(&[(ids[1], ids[0], 10), (ids[1], ids[0], 10), (ids[0], ids[2], 5)]).unwrap();
+    let period = domain.timezone.today().format("%Y-%m").to_string();
+    let top: Vec<_> = domain.top_traders(&period, 10).unwrap().into_iter().map(|t| (t.name, t.payments)).collect();
+    assert_eq!(top, vec![("alice".to_string(), 3), ("bob".to_string(), 2), ("carol".to_string(), 2)]);
+    domain.set_leaderboard_opt_out(ids[0], true).unwrap();
+    assert!(domain.leaderboard_opt_out(ids[0]).unwrap());
+    assert_eq!(domain.top_traders(&period, 1).unwrap()[0].name, "bob");
+    assert!(domain.top_traders("2000-01", 10).unwrap().is_empty());
+}
+#[test]
+fn event_payments_are_summed_up() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, ids[0], 500, "", None, None).unwrap();
+    let market = domain.open_event("market").unwrap();
+    domain.add_event_payment(market, ids[0], ids[1], 30, "eggs", None, None).unwrap();
+    domain.add_event_payment(market, ids[0], ids[2], 50, "honey", None, None).unwrap();
+    domain.add_event_payment(market, ids[1], ids[2], 10, "jam", None, None).unwrap();
+    domain.add_payment(ids[0], ids[1], 5, "not at the market", None, None).unwrap();
+    domain.confirmation_threshold = 100;
+    let pending = match domain.add_event_payment(market, ids[0], ids[1], 101, "basket", None, None).unwrap() {
+        super::Submitted::Pending(id) => id,
+        super::Submitted::Booked(_) => panic!("should wait for confirmation"),
+    };
+    domain.close_event(market).unwrap();
+    assert_eq!(domain.add_event_payment(market, ids[0], ids[1], 5, "", None, None).unwrap_err(), SimpletsError::EventClosed);
+    domain.confirm_payment(pending, ids[1]).unwrap();
+    let summary = domain.event_summary(market).unwrap();
+    assert_eq!((summary.payments, summary.volume, summary.participants), (4, 191, 3));
+    let stalls: Vec<_> = summary.stalls.iter().map(|s| (s.name.as_str(), s.received, s.payments)).collect();
+    assert_eq!(stalls, vec![("bob", 131, 2), ("carol", 60, 2)]);
+    assert!(summary.event.closed.is_some() && domain.get_open_events().unwrap().is_empty());
+}
+#[test]
+fn branding_is_kept_in_the_settings() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let branding = super::Branding { theme: "responsive".to_string(), title: "Green Valley".to_string(), logo: "/logo.png".to_string(),
+        primary_color: "#336699".to_string(), background_color: String::new() };
+    domain.update_branding(branding.clone()).unwrap();
+    domain.branding = super::Branding::default();
+    domain.load_settings().unwrap();
+    assert_eq!(domain.branding, branding);
+}
+
+#[test]
+fn amounts_are_formatted_with_the_currency() {
+    let mut format = super::CurrencyFormat { singular: "hour".to_string(), plural: "hours".to_string(), decimals: 0,
+        decimal_separator: ".".to_string(), thousands_separator: " ".to_string(), time: false };
+    assert_eq!(format.format(1), "1 hour");
+    assert_eq!(format.format(-1234567), "-1 234 567 hours");
+    format.decimals = 2;
+    assert_eq!(format.format(100), "1.00 hour");
+    assert_eq!(format.format(150), "1.50 hours");
+    assert_eq!(format.format(-5), "-0.05 hours");
+    assert_eq!(format.format(123456), "1 234.56 hours");
+    assert_eq!(super::CurrencyFormat::default().format(1500), "1500 kr.");
+}
+
+#[test]
+fn sub_unit_amounts() {
+    let format = super::CurrencyFormat { decimals: 2, thousands_separator: " ".to_string(), ..Default::default() };
+    assert_eq!(format.parse("1.5"), Some(150));
+    assert_eq!(format.parse("1,05"), Some(105));
+    assert_eq!(format.parse("-2"), Some(-200));
+    assert_eq!(format.parse("1 000.25"), Some(100025));
+    assert_eq!(format.parse("1.005"), None);
+    assert_eq!(format.parse(".5"), None);
+    assert_eq!(format.parse("1.5h"), None);
+    assert_eq!(format.plain(150), "1.50");
+    assert_eq!(format.plain(-5), "-0.05");
+    assert_eq!(super::CurrencyFormat::default().parse("1.5"), None);
+    // the limits are the same in whole units
+    let payer = new_user(0, 1000, 1, 0);
+    assert_eq!(payer.send_limit_in(100.0), 42421);
+    assert_eq!(payer.receive_limit_in(100.0), 249000);
+}
+
+#[test]
+fn time_amounts() {
+    let format = super::CurrencyFormat { singular: "h".to_string(), plural: "h".to_string(), time: true, ..Default::default() };
+    assert_eq!(format.parse("1:30"), Some(90));
+    assert_eq!(format.parse("2"), Some(120));
+    assert_eq!(format.parse("-0:05"), Some(-5));
+    for malformed in ["1:60", "1:5", "1.5", ":30", "1:30:00", "h"] {
+        assert_eq!(format.parse(malformed), None, "{}", malformed);
+    }
+    assert_eq!(format.format(90), "1:30 h");
+    assert_eq!(format.format(-5), "-0:05 h");
+    assert_eq!(format.plain(60), "1:00");
+    // a new member may receive 25 hours
+    assert_eq!(new_user(0, 0, 0, 0).receive_limit_in(format.limit_unit()), 1500);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(10_000))]
+    /// Counters and balances drawn across the whole range, the extremes more often than by chance.
+    #[test]
+    fn limits_never_overflow(
+        count in prop_oneof![1 => prop::sample::select(vec![0, 1, u64::MAX - 1, u64::MAX]), 1 => 0..1000u64, 2 => any::<u64>()],
+        credit in prop_oneof![1 => prop::sample::select(vec![i64::MIN, i64::MIN + 1, -1, 0, 1, i64::MAX]), 1 => -100_000..100_000i64,
+            2 => any::<i64>()],
+        unit in prop::sample::select(vec![1.0, 0.6, 100.0, 1e6, 0.0, -1.0, f64::NAN, f64::INFINITY]),
+    ) {
+        use super::limits::{credit_limit, receive_limit, send_limit};
+        let credit_limit = credit_limit(count, unit);
+        prop_assert!(credit_limit >= 0);
+        prop_assert_eq!(send_limit(count, credit, unit), credit_limit.saturating_add(credit));
+        prop_assert!(credit_limit <= super::limits::credit_limit(count.saturating_add(1), unit));
+        let receive = receive_limit(count, credit, unit);
+        prop_assert!(receive <= receive_limit(count.saturating_add(1), credit, unit));
+        prop_assert!(receive >= receive_limit(count, credit.saturating_add(1), unit));
+        if !unit.is_finite() || unit <= 0.0 {
+            prop_assert_eq!(credit_limit, 0);
+            prop_assert_eq!(receive, 0i64.saturating_sub(credit));
+        }
+    }
+}
+
+#[test]
+fn limits_of_pathological_values() {
+    use super::limits::{credit_limit, receive_limit, send_limit};
+    assert_eq!(receive_limit(0, i64::MIN, 1.0), i64::MAX);
+    assert_eq!(send_limit(0, i64::MIN, 1.0), i64::MIN);
+    assert_eq!(send_limit(u64::MAX, i64::MAX, 1.0), i64::MAX);
+    assert_eq!(receive_limit(u64::MAX, -1, 1e300), i64::MAX);
+    assert!(credit_limit(u64::MAX, 1e6) > 0);
+    assert_eq!(credit_limit(3, f64::NAN), 0);
+    // the same as the formulas on plain integers for ordinary values
+    assert_eq!(credit_limit(1, 1.0), 414);
+    assert_eq!(receive_limit(0, 10000, 1.0), -7500);
+}
+
+#[test]
+fn amounts_out_of_range_are_refused() {
+    use super::{Amount, Balance};
+    assert_eq!(Amount::new(u64::MAX), None);
+    assert_eq!(Amount::MAX.checked_add(Amount::new(1).unwrap()), None);
+    assert_eq!(Amount::try_from(-1i64), Err(SimpletsError::AmountOutOfRange));
+    assert_eq!(Balance::new(i64::MIN).checked_sub(Amount::new(1).unwrap()), None);
+    assert_eq!(Balance::new(-5).positive(), Amount::ZERO);
+    let domain = super::Domain::new_in_memory("test", 1);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    let reserve = domain.add_system_account("reserve").unwrap() as i64;
+    // once cast to i64 this was negative and passed every limit
+    assert_eq!(domain.add_payment(fund, reserve, u64::MAX, "", None, None), Err(SimpletsError::AmountOutOfRange));
+    domain.add_payment(fund, reserve, i64::MAX as u64, "", None, None).unwrap();
+    assert_eq!(domain.add_payment(fund, reserve, 1, "", None, None), Err(SimpletsError::AmountOutOfRange));
+    assert_eq!(domain.get_user(reserve).unwrap().credit, i64::MAX);
+}
+#[test]
+fn payment_preview() {
+    use super::Limit;
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 1000), (fund, bob, 2400)]).unwrap();
+    domain.update_fees(2, 0.0, Some(fund)).unwrap();
+    let send_limit = domain.get_limits(alice).unwrap().0;
+    let preview = domain.preview_payment(alice, bob, 50).unwrap();
+    assert_eq!((preview.limit, preview.fee, preview.payer_balance, preview.payee_balance, preview.error),
+               (Limit::Receive(100), 2, 948, 2450, None));
+    let preview = domain.preview_payment(alice, bob, 200).unwrap();
+    assert_eq!(preview.error, Some(SimpletsError::PaymentReceiveLimit { limit: 100 }));
+    domain.add_payment(bob, fund, 2000, "", None, None).unwrap();
+    let preview = domain.preview_payment(alice, bob, 200).unwrap();
+    assert_eq!((preview.limit, preview.error), (Limit::Send(send_limit), None));
+    // nothing was booked
+    assert_eq!(domain.get_user(alice).unwrap().credit, 1000);
+}
+#[test]
+fn receipts() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    let (alice, bob, carol) = (ids[0], ids[1], ids[2]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    let payment = match domain.add_payment(fund, alice, 300, "", None, None).unwrap() {
+        super::Submitted::Booked(id) => id,
+        super::Submitted::Pending(_) => panic!("should be booked"),
+    };
+    let token = domain.receipt_token(payment, alice).unwrap().unwrap();
+    assert_eq!(domain.receipt_token(payment, fund).unwrap(), Some(token.clone()));
+    assert_eq!(domain.receipt_token(payment, carol).unwrap(), None);
+    let receipt = domain.get_receipt(payment, &token).unwrap().unwrap();
+    assert_eq!((receipt.payer_name.as_str(), receipt.payee_name.as_str(), receipt.amount), ("fund", "alice", 300));
+    assert!(domain.get_receipt(payment, "0000").unwrap().is_none());
+    assert!(domain.get_receipt(payment + 1, &token).unwrap().is_none());
+    // a token is only good for its own payment
+    domain.add_payment(alice, bob, 50, "", None, None).unwrap();
+    assert!(domain.get_receipt(payment + 1, &token).unwrap().is_none());
+}
+#[test]
+fn recent_payees_and_message_templates() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol", "dave"]).unwrap();
+    let (alice, bob, carol, dave) = (ids[0], ids[1], ids[2], ids[3]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 1000)]).unwrap();
+    for (payee, amount) in [(bob, 10), (carol, 20), (bob, 30), (dave, 40)] {
+        domain.add_payment(alice, payee, amount, "", None, None).unwrap();
+    }
+    domain.deactivate_user(dave).unwrap();
+    let recent: Vec<_> = domain.recent_payees(alice, 5).unwrap().into_iter().map(|p| (p.name, p.amount)).collect();
+    assert_eq!(recent, [("bob".to_string(), 30), ("carol".to_string(), 20)]);
+    assert_eq!(domain.recent_payees(alice, 1).unwrap().len(), 1);
+    assert!(domain.recent_payees(bob, 5).unwrap().is_empty());
+    domain.add_message_template(alice, "vegetables").unwrap();
+    domain.add_message_template(alice, " vegetables ").unwrap();
+    domain.add_message_template(alice, "bread").unwrap();
+    let templates = domain.get_message_templates(alice).unwrap();
+    assert_eq!(templates.iter().map(|t| t.text.as_str()).collect::<Vec<_>>(), ["bread", "vegetables"]);
+    assert_eq!(domain.delete_message_template(templates[0].id, bob).unwrap(), 0);
+    domain.delete_message_template(templates[0].id, alice).unwrap();
+    assert_eq!(domain.get_message_templates(alice).unwrap().len(), 1);
+}
+#[test]
+fn contacts() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "bea", "carol"]).unwrap();
+    let (alice, bob, bea, carol) = (ids[0], ids[1], ids[2], ids[3]);
+    assert_eq!(domain.add_contact(alice, alice).unwrap(), 0);
+    assert_eq!(domain.add_contact(alice, 1000).unwrap(), 0);
+    domain.add_contact(alice, carol).unwrap();
+    domain.add_contact(alice, bob).unwrap();
+    assert_eq!(domain.add_contact(alice, bob).unwrap(), 0);
+    let names = |users: Vec<super::User>| users.into_iter().map(|u| u.name).collect::<Vec<_>>();
+    assert_eq!(names(domain.find_users_by_prefix("b", None, 10).unwrap()), ["bea", "bob"]);
+    assert_eq!(names(domain.find_users_by_prefix("b", Some(alice), 10).unwrap()), ["bob", "bea"]);
+    assert_eq!(domain.get_contacts(alice).unwrap().iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["bob", "carol"]);
+    assert!(domain.is_contact(alice, bob).unwrap() && !domain.is_contact(bob, alice).unwrap());
+    domain.remove_contact(alice, bob).unwrap();
+    domain.deactivate_user(carol).unwrap();
+    assert!(domain.get_contacts(alice).unwrap().is_empty());
+    assert!(!domain.is_contact(alice, bea).unwrap());
+}
+#[test]
+fn blocked_payers_are_refused() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 500)]).unwrap();
+    assert_eq!(domain.block_user(bob, bob).unwrap(), 0);
+    domain.block_user(bob, alice).unwrap();
+    assert!(domain.is_blocked(bob, alice).unwrap() && !domain.is_blocked(alice, bob).unwrap());
+    assert_eq!(domain.add_payment(alice, bob, 10, "hey", None, None), Err(SimpletsError::Blocked));
+    assert_eq!(domain.preview_payment(alice, bob, 10).unwrap().error, Some(SimpletsError::Blocked));
+    domain.update_confirmation(5, 7).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 10, "hey", None, None), Err(SimpletsError::Blocked));
+    // the other way round still works
+    domain.add_payment(fund, bob, 100, "", None, None).unwrap();
+    domain.update_confirmation(0, 7).unwrap();
+    domain.add_payment(bob, alice, 10, "", None, None).unwrap();
+    domain.unblock_user(bob, alice).unwrap();
+    domain.add_payment(alice, bob, 10, "", None, None).unwrap();
+}
+
+#[test]
+fn payment_messages_follow_the_message_rules() {
+    struct NoShouting;
+    impl super::MessagePolicy for NoShouting {
+        fn apply(&self, message: &str) -> Result<String, SimpletsError> {
+            if message.chars().any(char::is_lowercase) || message.is_empty() { Ok(message.to_string()) } else { Err(SimpletsError::MessageRefused) }
+        }
+    }
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 500)]).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 10, &"x".repeat(141), None, None), Err(SimpletsError::MessageTooLong { maximum: 140 }));
+    let rules = super::MessageRules { max_length: 20, banned_words: vec!["Spam".to_string()], banned_patterns: vec![r"\d{4} \d{4}".to_string()], strip_urls: true };
+    assert!(rules.is_valid() && !super::MessageRules { banned_patterns: vec!["(".to_string()], ..rules.clone() }.is_valid());
+    domain.update_message_rules(rules.clone()).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 10, "no spam here", None, None), Err(SimpletsError::MessageRefused));
+    assert_eq!(domain.create_request(bob, alice, 10, "card 1234 5678"), Err(SimpletsError::MessageRefused));
+    domain.add_payment(alice, bob, 10, "spammer's lunch", None, None).unwrap();
+    assert_eq!(domain.add_payment(alice, bob, 10, "see https://example.com for the whole menu", None, None), Err(SimpletsError::MessageTooLong { maximum: 20 }));
+    domain.add_payment(alice, bob, 10, "lunch https://example.com/a-very-long-link", None, None).unwrap();
+    let messages: Vec<_> = domain.get_payments_by_user(bob, &Default::default()).unwrap().into_iter().map(|p| p.message).collect();
+    assert!(messages.contains(&"lunch".to_string()) && messages.contains(&"spammer's lunch".to_string()));
+    domain.set_message_policy(NoShouting);
+    assert_eq!(domain.add_payment(alice, bob, 10, "LUNCH", None, None), Err(SimpletsError::MessageRefused));
+    domain.add_payment(alice, bob, 10, "Lunch", None, None).unwrap();
+    domain.message_rules = Default::default();
+    domain.load_settings().unwrap();
+    assert_eq!(domain.message_rules, rules);
+}
+
+#[test]
+fn input_is_validated_in_one_place() {
+    use super::validation;
+    assert_eq!(validation::username(&Default::default(), "root"), Err(SimpletsError::UsernameInvalid));
+    assert_eq!(validation::password("alice", "short"), Err(SimpletsError::PasswordTooShort { minimum: 8 }));
+    assert_eq!(validation::password("alice", "Alice1234"), Err(SimpletsError::PasswordWeak));
+    assert_eq!(validation::password("alice", "xxxxxxxxxx"), Err(SimpletsError::PasswordWeak));
+    validation::password("alice", "horse battery").unwrap();
+    assert_eq!(validation::amount(5, 10, 0), Err(SimpletsError::PaymentLessMin { minimum: 10 }));
+    assert_eq!(validation::amount(501, 10, 500), Err(SimpletsError::PaymentOverMax { maximum: 500 }));
+    validation::amount(u64::MAX, 10, 0).unwrap();
+    assert_eq!(serde_json::to_string(&SimpletsError::PasswordTooShort { minimum: 8 }).unwrap(), r#"{"code":"password_too_short","limit":8}"#);
+
+    let domain = super::Domain::new_in_memory("test", 1);
+    let alice = domain.seed_users(&["alice"]).unwrap()[0];
+    assert_eq!(domain.set_password(alice, "1234"), Err(SimpletsError::PasswordTooShort { minimum: 8 }));
+    assert_eq!(domain.set_password(alice, "horse battery").unwrap(), 1);
+    assert_eq!(domain.set_password(9999, "horse battery").unwrap(), 0);
+    let token = domain.create_password_reset(alice).unwrap();
+    assert_eq!(domain.reset_password(&token, "alice_alice"), Err(SimpletsError::PasswordWeak));
+    // a refused password leaves the token usable
+    assert_eq!(domain.reset_password(&token, "staple horse").unwrap(), alice);
+}
+
+#[test]
+fn readiness_checks_schema_and_ledger() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    domain.seed_payments(&[(ids[0], ids[1], 10)]).unwrap();
+    let readiness = domain.readiness(false).unwrap();
+    assert_eq!(readiness, super::Readiness { schema_version: super::SCHEMA_VERSION, credit_sum: None });
+    assert!(readiness.is_ready() && domain.readiness(true).unwrap().is_ready());
+    domain.writer().execute("UPDATE user SET credit = credit + 1 WHERE id = ?1", [ids[0]]).unwrap();
+    assert_eq!(domain.readiness(true).unwrap().credit_sum, Some(1));
+    assert!(!domain.readiness(true).unwrap().is_ready() && domain.readiness(false).unwrap().is_ready());
+}
+
+#[test]
+fn public_stats_hide_a_quiet_month() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol", "dave", "erin"]).unwrap();
+    domain.update_settings("trading circle", "write to the admin", 1).unwrap();
+    domain.seed_payments(&[(ids[0], ids[1], 10), (ids[2], ids[3], 20), (ids[3], ids[0], 5)]).unwrap();
+    let stats = domain.public_stats().unwrap();
+    assert_eq!(stats, super::PublicStats {
+        name: "test".to_string(), description: "trading circle".to_string(), join_info: "write to the admin".to_string(),
+        members: 5, monthly_payments: None, monthly_volume: None,
+    });
+    domain.seed_payments(&[(ids[4], ids[1], 15)]).unwrap();
+    let stats = domain.public_stats().unwrap();
+    assert_eq!((stats.monthly_payments, stats.monthly_volume), (Some(4), Some(50)));
+}
+
+#[test]
+fn only_admins_post_announcements() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    domain.set_role(ids[0], super::Role::Admin).unwrap();
+    assert_eq!(domain.post_announcement(ids[1], "market", "on Saturday"), Err(SimpletsError::NotPermitted(super::Capability::ManageSettings)));
+    let market = domain.post_announcement(ids[0], "market", "on Saturday").unwrap();
+    domain.post_announcement(ids[0], "meeting", "in the library").unwrap();
+    let recent = domain.recent_announcements(1).unwrap();
+    assert_eq!((recent.len(), recent[0].title.as_str(), recent[0].author_name.as_str()), (1, "meeting", "alice"));
+    assert_eq!(domain.delete_announcement(market).unwrap(), 1);
+    assert_eq!(domain.recent_announcements(10).unwrap().len(), 1);
+}
+
+#[test]
+fn roles_grant_capabilities() {
+    use super::{Capability, Role};
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    let (alice, bob, carol) = (ids[0], ids[1], ids[2]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 100)]).unwrap();
+    let super::Submitted::Booked(payment) = domain.add_payment(alice, bob, 10, "", None, None).unwrap() else { panic!("not booked") };
+    domain.set_role(alice, Role::Admin).unwrap();
+    domain.set_role(bob, Role::Auditor).unwrap();
+    assert!(domain.can(bob, Capability::ViewAllBalances).unwrap());
+    assert_eq!(domain.reverse_payment(Some(bob), payment, "mistake").unwrap_err(), SimpletsError::NotPermitted(Capability::ReversePayment));
+    domain.set_role(carol, Role::Treasurer).unwrap();
+    domain.reverse_payment(Some(carol), payment, "mistake").unwrap();
+    // the last admin stays one, another can take over
+    assert_eq!(domain.set_role(alice, Role::Member).unwrap(), 0);
+    domain.set_role(carol, Role::Admin).unwrap();
+    assert_eq!(domain.set_role(alice, Role::Member).unwrap(), 1);
+    assert_eq!(domain.role_capabilities(Role::Auditor).unwrap(), vec![Capability::ViewAllBalances]);
+    domain.set_role_capability(Role::Member, Capability::ManageMembers, true).unwrap();
+    assert!(domain.can(alice, Capability::ManageMembers).unwrap());
+    assert_eq!(domain.set_role_capability(Role::Admin, Capability::ManageSettings, false).unwrap(), 0);
+    // archives from before roles have the permission number
+    let mut archived = serde_json::to_value(domain.get_user(carol).unwrap()).unwrap();
+    archived.as_object_mut().unwrap().remove("role");
+    archived["permission"] = 2.into();
+    assert_eq!(serde_json::from_value::<super::User>(archived).unwrap().role, Role::Admin);
+}
+
+#[test]
+fn auditors_only_look() {
+    use super::{Capability, Role};
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 100), (fund, bob, 100)]).unwrap();
+    domain.set_role(bob, Role::Auditor).unwrap();
+    assert!(domain.can(bob, Capability::ViewAllBalances).unwrap());
+    assert_eq!(domain.get_balance_for(Some(bob), alice).unwrap(), 100);
+    assert_eq!(domain.add_payment(bob, alice, 10, "", None, None).unwrap_err(), SimpletsError::ReadOnly);
+    assert_eq!(domain.add_payment(alice, bob, 10, "", None, None).unwrap_err(), SimpletsError::ReadOnly);
+    assert_eq!(domain.create_request(bob, alice, 10, "").unwrap_err(), SimpletsError::ReadOnly);
+    assert_eq!(domain.send_message(bob, alice, "hi").unwrap_err(), SimpletsError::ReadOnly);
+    assert_eq!(domain.preview_payment(bob, alice, 10).unwrap().error, Some(SimpletsError::ReadOnly));
+    // neither the role table nor editing it lets an auditor change anything
+    assert_eq!(domain.set_role_capability(Role::Auditor, Capability::BookAdjustments, true).unwrap(), 0);
+    domain.writer().execute("INSERT INTO role (name, capability) VALUES ('auditor', 'book_adjustments')", []).unwrap();
+    assert!(!domain.can(bob, Capability::BookAdjustments).unwrap());
+    domain.set_adjustment_account(fund).unwrap();
+    assert_eq!(domain.add_adjustment(bob, alice, 5, "grant").unwrap_err(), SimpletsError::NotPermitted(Capability::BookAdjustments));
+    assert_eq!(domain.get_balance_for(Some(bob), bob).unwrap(), 100);
+}
+
+#[test]
+fn payments_keep_attached_files() {
+    use super::validation::MAX_ATTACHMENT_SIZE;
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    let (alice, bob, carol) = (ids[0], ids[1], ids[2]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 100)]).unwrap();
+    let super::Submitted::Booked(payment) = domain.add_payment(alice, bob, 10, "apples", None, None).unwrap() else { panic!("not booked") };
+    let photo = b"\xff\xd8\xff\xe0 a photo of apples";
+    assert_eq!(domain.attach_file(payment, bob, "apples.jpg", photo), Err(SimpletsError::AttachmentsDisabled));
+    let dir = std::env::temp_dir().join(format!("simplets-attachments-{}", std::process::id()));
+    domain.attachment_dir = Some(dir.clone());
+    let id = domain.attach_file(payment, bob, "C:\\photos\\apples.jpg", photo).unwrap();
+    assert_eq!(domain.attach_file(payment, bob, "run.exe", b"MZ\x90\x00\xff"), Err(SimpletsError::AttachmentType));
+    let large = vec![b'a'; MAX_ATTACHMENT_SIZE as usize + 1];
+    assert_eq!(domain.attach_file(payment, alice, "notes.txt", &large), Err(SimpletsError::AttachmentTooLarge { maximum: MAX_ATTACHMENT_SIZE }));
+    assert!(matches!(domain.attach_file(payment, carol, "apples.jpg", photo), Err(SimpletsError::Db(rusqlite::Error::QueryReturnedNoRows))));
+    let (attachment, content) = domain.read_attachment(alice, id).unwrap();
+    assert_eq!((attachment.name.as_str(), attachment.media_type.as_str(), content.as_slice()), ("apples.jpg", "image/jpeg", &photo[..]));
+    assert_eq!(domain.get_attachments_by_user(bob).unwrap().len(), 1);
+    // others see them only with a role that views all balances
+    assert_eq!(domain.read_attachment(carol, id).unwrap_err(), SimpletsError::PrivacyDenied);
+    domain.set_role(carol, super::Role::Auditor).unwrap();
+    assert_eq!(domain.get_attachments(carol, payment).unwrap().len(), 1);
+    assert_eq!(domain.attach_file(payment, carol, "apples.jpg", photo), Err(SimpletsError::ReadOnly));
+    let receipt = domain.attach_file(payment, alice, "receipt.pdf", b"%PDF-1.4 receipt").unwrap();
+    assert_eq!(domain.export_user_data(alice).unwrap()["attachments"].as_array().unwrap().len(), 2);
+    // erasing bob removes what bob uploaded, the payer's receipt stays
+    assert_eq!(domain.anonymize_user(bob).unwrap(), 1);
+    assert_eq!(domain.get_attachments(alice, payment).unwrap().iter().map(|a| a.id).collect::<Vec<_>>(), vec![receipt]);
+    assert!(!dir.join(id.to_string()).exists() && dir.join(receipt.to_string()).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn accepting_a_request_twice_pays_once() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 100)]).unwrap();
+    for _ in 0..3 {
+        let request = domain.create_request(bob, alice, 5, "eggs").unwrap();
+        // a double click: both accepts pass the check while the writer is busy
+        let results: Vec<_> = std::thread::scope(|s| {
+            let busy = domain.writer();
+            let accepts: Vec<_> = (0..2).map(|_| s.spawn(|| domain.accept_request(request, alice))).collect();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            drop(busy);
+            accepts.into_iter().map(|a| a.join().unwrap()).collect()
+        });
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.contains(&Err(SimpletsError::RequestClosed)));
+        assert_eq!(domain.decline_request(request, alice), Err(SimpletsError::RequestClosed));
+    }
+    assert_eq!(domain.get_user(alice).unwrap().credit, 85);
+}
+
+#[test]
+fn new_terms_are_accepted_once_per_version() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    assert!(domain.current_terms().unwrap().is_none() && !domain.needs_terms_acceptance(ids[0]).unwrap());
+    let first = domain.publish_terms("be nice").unwrap();
+    assert!(domain.needs_terms_acceptance(ids[0]).unwrap());
+    assert_eq!(domain.accept_terms(ids[0], first).unwrap(), 1);
+    assert!(!domain.needs_terms_acceptance(ids[0]).unwrap() && domain.needs_terms_acceptance(ids[1]).unwrap());
+    let second = domain.publish_terms("be nicer").unwrap();
+    assert_eq!(domain.current_terms().unwrap().map(|t| (t.version, t.text)), Some((second, "be nicer".to_string())));
+    assert!(domain.needs_terms_acceptance(ids[0]).unwrap());
+    // bob read the first version while the second was published
+    assert_eq!(domain.accept_terms(ids[1], first).unwrap(), 0);
+    assert!(domain.needs_terms_acceptance(ids[1]).unwrap());
+}
+
+#[test]
+fn unverified_email_keeps_members_on_probation() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let alice = ids[0];
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 1000)]).unwrap();
+    domain.update_probation(super::Probation { percent: 50, email: true, ..Default::default() }).unwrap();
+    assert_eq!(domain.get_limits(alice).unwrap().0, 707);
+    assert_eq!(domain.start_email_verification(alice, "alice", "http://lets"), Err(SimpletsError::EmailInvalid));
+    let first = domain.start_email_verification(alice, "alice@example.org", "http://lets").unwrap();
+    let token = domain.start_email_verification(alice, "alice@example.org", "http://lets").unwrap();
+    assert_eq!(domain.verify_email(&first), Err(SimpletsError::EmailTokenInvalid));
+    assert!(domain.pending_notifications(10).unwrap().iter().any(|n| n.body.contains(&format!("http://lets/verify/{}", token))));
+    assert_eq!(domain.verify_email(&token).unwrap(), alice);
+    assert!(domain.get_user(alice).unwrap().email_verified);
+    assert_eq!(domain.get_limits(alice).unwrap().0, 1414);
+    assert_eq!(domain.verify_email(&token), Err(SimpletsError::EmailTokenInvalid));
+    // a new address has to be verified again
+    domain.set_email(alice, Some("alice@example.com"), 0).unwrap();
+    assert!(!domain.get_user(alice).unwrap().email_verified);
+}
+
+#[test]
+fn identities_map_to_one_member() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let issuer = "https://id.example.org/realms/lets";
+    assert_eq!(domain.user_by_identity(issuer, "a1").unwrap(), None);
+    assert_eq!(domain.link_identity(ids[0], issuer, "a1").unwrap(), 1);
+    assert_eq!(domain.link_identity(ids[1], issuer, "a1").unwrap(), 0);
+    assert_eq!(domain.user_by_identity(issuer, "a1").unwrap(), Some(ids[0]));
+    assert_eq!(domain.linked_identities(ids[0]).unwrap().len(), 1);
+    domain.unlink_identity(ids[0], issuer).unwrap();
+    assert_eq!(domain.user_by_identity(issuer, "a1").unwrap(), None);
+
+    // {"iss":"https://id.example.org/realms/lets","sub":"a1","aud":["lets","account"],"exp":2000000000,"nonce":"n0","email":"a@example.org","email_verified":true}
+    let token = "eyJhbGciOiJSUzI1NiJ9.eyJpc3MiOiJodHRwczovL2lkLmV4YW1wbGUub3JnL3JlYWxtcy9sZXRzIiwic3ViIjoiYTEiLCJhdWQiOlsibGV0cyIsImFjY291bnQiXSwiZXhwIjoyMDAwMDAwMDAwLCJub25jZSI6Im4wIiwiZW1haWwiOiJhQGV4YW1wbGUub3JnIiwiZW1haWxfdmVyaWZpZWQiOnRydWV9.c2ln";
+    let claims = super::oidc::claims(token, issuer, "lets", "n0", 1_700_000_000).unwrap();
+    assert_eq!((claims.subject.as_str(), claims.email.as_deref()), ("a1", Some("a@example.org")));
+    assert!(super::oidc::claims(token, issuer, "other", "n0", 1_700_000_000).is_err());
+    assert!(super::oidc::claims(token, issuer, "lets", "n1", 1_700_000_000).is_err());
+    assert!(super::oidc::claims(token, issuer, "lets", "n0", 2_000_000_000).is_err());
+}
+
+#[test]
+fn closing_refuses_payments_and_drains_the_outbox() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.add_payment(fund, ids[0], 100, "", None, None).unwrap();
+    domain.add_payment(ids[0], ids[1], 10, "", None, None).unwrap();
+    domain.close();
+    assert!(domain.is_closing());
+    assert_eq!(domain.add_payment(ids[0], ids[1], 10, "", None, None), Err(SimpletsError::ShuttingDown));
+    assert_eq!(domain.add_payments_bulk(ids[0], vec![(ids[1], 10, String::new())]), Err((0, SimpletsError::ShuttingDown)));
+    assert_eq!(domain.drain().unwrap(), 2);
+    assert_eq!(domain.drain().unwrap(), 0);
+}
+
+#[test]
+fn configuration_from_the_environment() {
+    let dir = std::env::temp_dir().join(format!("simplets-env-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("secret"), "s3cret\n").unwrap();
+    std::env::set_var("SIMPLETS_TEST_TOKEN", "plain");
+    std::env::set_var("SIMPLETS_TEST_SECRET_FILE", dir.join("secret"));
+    std::env::set_var("SIMPLETS_TEST_EMPTY", "");
+    assert_eq!(super::env_setting("test_token").as_deref(), Some("plain"));
+    assert_eq!(super::env_setting("test_secret").as_deref(), Some("s3cret"));
+    assert_eq!(super::env_setting("test_empty"), None);
+    assert_eq!(super::env_setting("test_missing"), None);
+    std::env::set_var("SIMPLETS_DOMAIN", "demo");
+    std::env::set_var("SIMPLETS_DATA_DIR", &dir);
+    assert_eq!(super::DomainConfig::from_env().path(), dir.join("demo.sqlite"));
+    std::env::set_var("SIMPLETS_DB_PATH", dir.join("other.sqlite"));
+    assert_eq!(super::DomainConfig::from_env().path(), dir.join("other.sqlite"));
+    for key in ["TEST_TOKEN", "TEST_SECRET_FILE", "TEST_EMPTY", "DOMAIN", "DATA_DIR", "DB_PATH"] {
+        std::env::remove_var(format!("SIMPLETS_{}", key));
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn payments_are_reversed_with_their_fee() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob"]).unwrap();
+    let (alice, bob) = (ids[0], ids[1]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    let fees = domain.add_system_account("fees").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 500)]).unwrap();
+    domain.update_fees(2, 0.0, Some(fees)).unwrap();
+    let id = match domain.add_payment(alice, bob, 100, "lunch", None, None).unwrap() {
+        super::Submitted::Booked(id) => id,
+        pending => panic!("{:?}", pending),
+    };
+    let reversal = domain.reverse_payment(None, id, "mistake").unwrap();
+    assert_eq!((reversal.payer, reversal.payee, reversal.amount, reversal.kind), (bob as u64, alice as u64, 100, super::PAYMENT_REVERSAL));
+    assert_eq!(reversal.parent, Some(id as i64));
+    assert_eq!(domain.get_user(alice).unwrap().credit, 500);
+    assert_eq!((domain.get_user(bob).unwrap().credit, domain.get_user(fees).unwrap().credit), (0, 0));
+    assert_eq!(domain.reverse_payment(None, id, "again").unwrap_err(), SimpletsError::PaymentNotReversible);
+    assert_eq!(domain.reverse_payment(None, reversal.id, "back").unwrap_err(), SimpletsError::PaymentNotReversible);
+    assert!(domain.verify_chain().unwrap().is_intact() && domain.health_report().unwrap().mismatches.is_empty());
+}
+#[test]
+fn totp_code_logs_in_once() {
+    use super::totp;
+    let domain = super::Domain::new_in_memory("test", 1);
+    let alice = domain.seed_users(&["alice"]).unwrap()[0];
+    let uri = domain.enable_totp(alice).unwrap();
+    let secret = totp::base32_decode(uri.split("secret=").nth(1).unwrap().split('&').next().unwrap()).unwrap();
+    let code = || format!("{:06}", totp::hotp(&secret, chrono::Utc::now().timestamp() as u64 / totp::STEP, 6));
+    domain.confirm_totp(alice, &code()).unwrap();
+    domain.writer().execute("UPDATE totp SET last_step = 0", []).unwrap();
+    // two logins with one code both read the old step while the writer is busy
+    let code = code();
+    let results: Vec<_> = std::thread::scope(|s| {
+        let busy = domain.writer();
+        let logins: Vec<_> = (0..2).map(|_| s.spawn(|| domain.verify_totp(alice, &code))).collect();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        drop(busy);
+        logins.into_iter().map(|l| l.join().unwrap()).collect()
+    });
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+    assert!(results.contains(&Err(SimpletsError::TotpInvalid)));
+}
+#[test]
+fn split_payment_total_is_checked() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let ids = domain.seed_users(&["alice", "bob", "carol"]).unwrap();
+    let (alice, bob, carol) = (ids[0], ids[1], ids[2]);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.seed_payments(&[(fund, alice, 100)]).unwrap();
+    let near_max = i64::MAX as u64 - 1;
+    assert_eq!(domain.add_split_payment(alice, vec![(bob, near_max), (carol, near_max)], "all of it"),
+               Err((None, SimpletsError::AmountOutOfRange)));
+    // the fees count against the send limit as well
+    domain.update_fees(1, 0.0, Some(fund)).unwrap();
+    let send_limit = domain.get_limits(alice).unwrap().0 as u64;
+    let result = domain.add_split_payment(alice, vec![(bob, send_limit - 1), (carol, 1)], "");
+    assert_eq!(result, Err((None, SimpletsError::PaymentSendLimit { limit: send_limit as i64 })));
+    domain.add_split_payment(alice, vec![(bob, 10), (carol, 20)], "lunch").unwrap();
+    assert_eq!(domain.get_user(alice).unwrap().credit, 68);
+}
+#[test]
+fn demurrage_rate_is_checked() {
+    let domain = super::Domain::new_in_memory("test", 1);
+    let alice = domain.seed_users(&["alice"]).unwrap()[0];
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    domain.set_demurrage_account(fund).unwrap();
+    domain.seed_payments(&[(fund, alice, 100)]).unwrap();
+    for rate in [0.0, -0.01, 1.5, f64::NAN, f64::INFINITY] {
+        assert_eq!(domain.apply_demurrage(rate, "2026-10"), Err(SimpletsError::DemurrageRateInvalid), "{}", rate);
+    }
+    // a refused rate doesn't use up the period
+    assert_eq!(domain.apply_demurrage(0.1, "2026-10").unwrap(), vec![(alice, 10)]);
+    assert_eq!(domain.get_user(alice).unwrap().credit, 90);
+}
+#[test]
+fn fees_never_overflow() {
+    let mut domain = super::Domain::new_in_memory("test", 1);
+    let fund = domain.add_system_account("fund").unwrap() as i64;
+    for percent in [-1.0, 100.5, f64::NAN, f64::INFINITY] {
+        assert_eq!(domain.update_fees(1, percent, Some(fund)), Err(SimpletsError::FeePercentInvalid), "{}", percent);
+    }
+    domain.update_fees(1, 100.0, Some(fund)).unwrap();
+    assert_eq!(domain.fee(10).unwrap().get(), 11);
+    assert_eq!(domain.fee(i64::MAX as u64), Err(SimpletsError::AmountOutOfRange));
+    domain.fee_percent = 1e30;
+    assert_eq!(domain.fee(10), Err(SimpletsError::AmountOutOfRange));
+}
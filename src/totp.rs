@@ -0,0 +1,80 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+pub const STEP: u64 = 30;
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// HOTP value (RFC 4226) of `counter` truncated to `digits`.
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac takes any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    value % 10u32.pow(digits)
+}
+
+/// Checks a 6 digit TOTP code (RFC 6238) as shown by authenticator apps and returns the
+/// time step it matches, allowing one step of clock drift either way.
+pub fn verify(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let code: u32 = code.trim().parse().ok()?;
+    let step = unix_time / STEP;
+    [step, step.saturating_sub(1), step + 1].into_iter().find(|s| hotp(secret, *s, 6) == code)
+}
+
+/// Unpadded base32, the format authenticator apps expect secrets in.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 { out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char); }
+    out
+}
+
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = ALPHABET.iter().position(|a| *a == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// `otpauth://` URI for enrolling the secret, usually shown as a QR code.
+pub fn uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    let escape = |s: &str| s.bytes().map(|b| match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect::<String>();
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}", escape(issuer), escape(account), base32_encode(secret), escape(issuer))
+}
@@ -0,0 +1,83 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Checks of what members enter, made by the `Domain` methods that take it, so the web forms,
+//! the API and the CLI refuse the same input with the same `SimpletsError`. Front ends turn the
+//! error into a message by its `SimpletsError::code` and limit instead of checking again.
+
+use crate::{MessagePolicy, MessageRules, SimpletsError, UsernamePolicy};
+
+/// Largest file in bytes a payment can have attached, see `Domain::attach_file`.
+pub const MAX_ATTACHMENT_SIZE: u64 = 1024 * 1024;
+
+/// Fewest characters of a password a member picks.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// A new member's name, see `UsernamePolicy::allows`.
+pub fn username(policy: &UsernamePolicy, name: &str) -> Result<(), SimpletsError> {
+    if policy.allows(name) { Ok(()) } else { Err(SimpletsError::UsernameInvalid) }
+}
+
+/// A password member `name` picks: at least `MIN_PASSWORD_LENGTH` characters, not the name
+/// itself and not one character repeated.
+pub fn password(name: &str, password: &str) -> Result<(), SimpletsError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(SimpletsError::PasswordTooShort { minimum: MIN_PASSWORD_LENGTH as u64 })
+    }
+    let mut chars = password.chars();
+    let first = chars.next();
+    if password.to_lowercase().contains(&name.to_lowercase()) || chars.all(|c| Some(c) == first) {
+        return Err(SimpletsError::PasswordWeak)
+    }
+    Ok(())
+}
+
+/// An email address: an `@` with something on both sides, at most 254 characters.
+pub fn email(email: &str) -> Result<(), SimpletsError> {
+    let valid = matches!(email.split_once('@'), Some((local, domain)) if !local.is_empty() && !domain.is_empty());
+    if valid && email.len() <= 254 && !email.contains(char::is_whitespace) { Ok(()) } else { Err(SimpletsError::EmailInvalid) }
+}
+
+/// A payment amount in smallest units, `maximum` 0 for none.
+pub fn amount(amount: u64, minimum: u64, maximum: u64) -> Result<(), SimpletsError> {
+    if amount < minimum { return Err(SimpletsError::PaymentLessMin { minimum }) }
+    if maximum > 0 && amount > maximum { return Err(SimpletsError::PaymentOverMax { maximum }) }
+    Ok(())
+}
+
+/// A payment message as it may be kept: the domain's `rules` first, then a plugged in `policy`.
+pub fn message(rules: &MessageRules, policy: Option<&dyn MessagePolicy>, message: &str) -> Result<String, SimpletsError> {
+    let message = rules.apply(message)?;
+    match policy {
+        Some(policy) => policy.apply(&message),
+        None => Ok(message),
+    }
+}
+
+/// A file attached to a payment, at most `MAX_ATTACHMENT_SIZE` bytes. Returns its media type,
+/// told by the content rather than the name: a JPEG, PNG or WebP photo, a PDF, or UTF-8 text.
+pub fn attachment(content: &[u8]) -> Result<&'static str, SimpletsError> {
+    if content.len() as u64 > MAX_ATTACHMENT_SIZE { return Err(SimpletsError::AttachmentTooLarge { maximum: MAX_ATTACHMENT_SIZE }) }
+    Ok(match content {
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => "image/png",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [b'%', b'P', b'D', b'F', b'-', ..] => "application/pdf",
+        [_, ..] if std::str::from_utf8(content).is_ok() => "text/plain",
+        _ => return Err(SimpletsError::AttachmentType),
+    })
+}
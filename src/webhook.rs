@@ -0,0 +1,86 @@
+/*
+* Copyright 2022-2022 Michal Mauser
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Header carrying the signature of the request body.
+pub const SIGNATURE_HEADER: &str = "X-Simplets-Signature";
+
+/// Hex encoded HMAC-SHA256 of `body`, sent as `sha256=<hex>` so receivers can verify the call.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes any key length");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Checks a `sha256=<hex>` signature header in constant time.
+pub fn verify(secret: &str, body: &str, signature: &str) -> bool {
+    let expected = match signature.strip_prefix("sha256=").and_then(|h| hex::decode(h).ok()) {
+        Some(e) => e,
+        None => return false
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes any key length");
+    mac.update(body.as_bytes());
+    mac.verify_slice(&expected).is_ok()
+}
+
+#[cfg(feature = "webhooks")]
+pub use dispatch::Dispatcher;
+
+#[cfg(feature = "webhooks")]
+mod dispatch {
+    use std::time::Duration;
+    use rusqlite::Result;
+    use crate::Domain;
+    use super::{sign, SIGNATURE_HEADER};
+
+    /// Posts queued webhook calls.
+    pub struct Dispatcher {
+        agent: ureq::Agent,
+    }
+
+    impl Default for Dispatcher {
+        fn default() -> Self {
+            Dispatcher { agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build() }
+        }
+    }
+
+    impl Dispatcher {
+        /// Sends up to `limit` due calls and returns how many were accepted with a 2xx response.
+        /// Failed calls are retried later with exponential backoff.
+        pub fn deliver(&self, domain: &Domain, limit: u32) -> Result<usize> {
+            let mut delivered = 0;
+            for delivery in domain.pending_webhook_deliveries(limit)? {
+                let signature = format!("sha256={}", sign(&delivery.secret, &delivery.payload));
+                let response = self.agent.post(&delivery.url)
+                    .set("Content-Type", "application/json")
+                    .set("X-Simplets-Event", &delivery.event)
+                    .set(SIGNATURE_HEADER, &signature)
+                    .send_string(&delivery.payload);
+                match response {
+                    Ok(_) => {
+                        domain.mark_webhook_delivered(delivery.id)?;
+                        delivered += 1;
+                    }
+                    Err(e) => { domain.mark_webhook_failed(delivery.id, &e.to_string())?; }
+                }
+            }
+            Ok(delivered)
+        }
+    }
+}